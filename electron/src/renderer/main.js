@@ -13,10 +13,10 @@ const defaultWsPort = 8081
 const defaultHost = localStorage.getItem('webSocketAddress') || `ws://${window.location.hostname}:${defaultWsPort}`
 const webSocketHost = process.env.NODE_ENV === 'development' ? 'ws://192.168.100.201:8081' : defaultHost
 
-axios.get(`http://${window.location.host}/_ws.json`).then(res => {
-  const { wsPort } = res.data
-  if (wsPort) {
-    const wsHost = `ws://${window.location.hostname}:${wsPort}`
+axios.get(`http://${window.location.host}/api/bootstrap`).then(res => {
+  const { ws } = res.data
+  if (ws) {
+    const wsHost = ws
     if (wsHost !== webSocketHost) {
       localStorage.setItem('webSocketAddress', wsHost)
       window.location.reload()
@@ -0,0 +1,34 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use pisugar_core::{gpio_detect_tap, TapType};
+
+fn bench_tap_detect(c: &mut Criterion) {
+    let mut history = String::with_capacity(10);
+    c.bench_function("gpio_detect_tap idle", |b| {
+        b.iter(|| {
+            history.clear();
+            history.push_str("0000000000");
+            gpio_detect_tap(black_box(&mut history))
+        })
+    });
+    c.bench_function("gpio_detect_tap double", |b| {
+        b.iter(|| {
+            history.clear();
+            history.push_str("0001001100");
+            gpio_detect_tap(black_box(&mut history))
+        })
+    });
+}
+
+fn bench_event_payload(c: &mut Criterion) {
+    let tap_type = TapType::Double;
+    c.bench_function("event payload as_str", |b| {
+        b.iter(|| black_box(tap_type).as_str())
+    });
+    c.bench_function("event payload format", |b| {
+        b.iter(|| format!("{}", black_box(tap_type)))
+    });
+}
+
+criterion_group!(benches, bench_tap_detect, bench_event_payload);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use pisugar_core::{FakeI2c, PiSugarConfig, PiSugarCore, I2C_ADDR_BAT, I2C_READ_INTERVAL};
+
+/// PiSugar 2 on fake i2c
+fn pisugar2() -> (FakeI2c, PiSugarCore) {
+    let fake = FakeI2c::new();
+    fake.set_ip5209_voltage(4.0);
+    fake.inject_errors(I2C_ADDR_BAT, 1);
+    let core = PiSugarCore::new_with_opener(PiSugarConfig::default(), fake.opener()).unwrap();
+    (fake, core)
+}
+
+/// Polls of a single tap, from press to detection
+fn single_tap(fake: &FakeI2c, core: &Mutex<PiSugarCore>, t0: Instant, i: &mut u32) {
+    for pressed in &[true, false, false, false] {
+        fake.set_ip5209_button(*pressed);
+        *i += 1;
+        let mut core = core.lock().unwrap();
+        let core = &mut *core;
+        let _ = core.status.poll(&core.config, t0 + I2C_READ_INTERVAL * *i);
+    }
+}
+
+fn bench_tap_latency(c: &mut Criterion) {
+    for clients in &[0, 4, 16] {
+        let (fake, core) = pisugar2();
+        let core = Arc::new(Mutex::new(core));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..*clients)
+            .map(|_| {
+                let core = core.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    // like `get all`, host metrics are read after the lock is released
+                    while !stop.load(Ordering::Relaxed) {
+                        let snapshot = core.lock().unwrap().snapshot_status();
+                        let _ = snapshot.with_host_metrics(true, true);
+                    }
+                })
+            })
+            .collect();
+
+        let t0 = Instant::now();
+        let mut i = 0;
+        c.bench_function(&format!("single tap, {} clients", clients), |b| {
+            b.iter(|| single_tap(&fake, &core, t0, &mut i))
+        });
+
+        stop.store(true, Ordering::Relaxed);
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+}
+
+criterion_group!(benches, bench_tap_latency);
+criterion_main!(benches);
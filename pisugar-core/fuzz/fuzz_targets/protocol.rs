@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use pisugar_core::{Request, MAX_REQUEST_ARGS, MAX_REQUEST_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    // same as the tcp/uds stream
+    let req = String::from_utf8_lossy(data)
+        .replace("\r", "")
+        .replace("\n", "");
+
+    if let Some(request) = Request::parse(req.as_str()) {
+        assert!(req.len() <= MAX_REQUEST_LEN);
+        assert!(!request.cmd().is_empty());
+        assert!(request.args().len() <= MAX_REQUEST_ARGS);
+        for i in 0..=request.args().len() {
+            let _ = request.arg(i);
+            if let Some(rest) = request.rest(i) {
+                assert!(rest.starts_with(request.arg(i).unwrap()));
+            }
+        }
+    }
+});
@@ -0,0 +1,58 @@
+#![no_main]
+use std::time::Instant;
+
+use libfuzzer_sys::fuzz_target;
+
+use pisugar_core::{
+    execute_get, execute_history, split_request_id, FakeI2c, Listener, OutputFormat, PiSugarConfig,
+    PiSugarCore, Request, I2C_ADDR_BAT, I2C_READ_INTERVAL,
+};
+
+/// PiSugar 2 on fake i2c
+fn pisugar2() -> (FakeI2c, PiSugarCore) {
+    let fake = FakeI2c::new();
+    fake.set_ip5209_voltage(4.0);
+    fake.inject_errors(I2C_ADDR_BAT, 1);
+    let mut config = PiSugarConfig::default();
+    config.rtc_enabled = false;
+    let core = PiSugarCore::new_with_opener(config, fake.opener()).unwrap();
+    (fake, core)
+}
+
+/// Requests executed by the core, as `execute_request` of the server
+fn execute(core: &PiSugarCore, request: &Request) -> Option<String> {
+    let cmd = request.cmd();
+    if !core.listener_allows(Listener::Tcp, cmd) || !core.authorize(None, cmd) {
+        return None;
+    }
+    match cmd {
+        "get" => execute_get(core, request),
+        "history" => execute_history(core, request),
+        _ => None,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (fake, mut core) = pisugar2();
+    let t0 = Instant::now();
+
+    // one request per line, as the tcp/uds stream, the fake battery is polled in between
+    for (i, line) in String::from_utf8_lossy(data).lines().enumerate() {
+        fake.set_ip5209_button(i % 2 == 0);
+        let _ = core
+            .status
+            .poll(&core.config, t0 + I2C_READ_INTERVAL * (i as u32 + 1));
+
+        let line = line.replace("\r", "");
+        let (id, req) = split_request_id(line.as_str());
+        let (format, req) = match req.strip_prefix("json ") {
+            Some(req) => (OutputFormat::Json, req),
+            None => (OutputFormat::Text, req),
+        };
+        if let Some(request) = Request::parse(req) {
+            if let Some(resp) = execute(&core, &request) {
+                let _ = format.render_id(id, &resp);
+            }
+        }
+    }
+});
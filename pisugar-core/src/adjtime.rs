@@ -0,0 +1,93 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+
+/// Adjtime file of hwclock
+pub const ADJTIME_PATH: &str = "/etc/adjtime";
+
+/// Hwclock adjtime, drift factor (s/day), unix times of the last adjustment and calibration,
+/// and whether the rtc keeps local time or utc
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adjtime {
+    pub drift: f64,
+    pub last_adjust: i64,
+    pub last_calibration: i64,
+    pub local: bool,
+}
+
+impl Default for Adjtime {
+    fn default() -> Self {
+        Self {
+            drift: 0.0,
+            last_adjust: 0,
+            last_calibration: 0,
+            local: false,
+        }
+    }
+}
+
+impl Adjtime {
+    /// Parse adjtime, missing lines are defaults as in hwclock
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut adjtime = Self::default();
+        let mut lines = s.lines();
+        if let Some(line) = lines.next() {
+            let mut fields = line.split_whitespace();
+            if let Some(drift) = fields.next() {
+                adjtime.drift = drift.parse().ok()?;
+            }
+            if let Some(last_adjust) = fields.next() {
+                adjtime.last_adjust = last_adjust.parse().ok()?;
+            }
+        }
+        if let Some(line) = lines.next() {
+            adjtime.last_calibration = line.trim().parse().ok()?;
+        }
+        adjtime.local = lines.next().map(|l| l.trim()) == Some("LOCAL");
+        Some(adjtime)
+    }
+
+    /// Read adjtime, default if absent
+    pub fn read(path: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid adjtime")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write adjtime atomically, a temp file renamed over it, readers never see a partial file
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        std::fs::write(&tmp, self.to_string())?;
+        std::fs::rename(&tmp, path).map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            e
+        })
+    }
+}
+
+impl Display for Adjtime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:.6} {} 0.000000", self.drift, self.last_adjust)?;
+        writeln!(f, "{}", self.last_calibration)?;
+        writeln!(f, "{}", if self.local { "LOCAL" } else { "UTC" })
+    }
+}
+
+/// Record an rtc write at time as hwclock does, whether the rtc keeps local time is set if some,
+/// kept otherwise, as is the drift factor
+pub fn update_adjtime(path: &Path, t: DateTime<Local>, local: Option<bool>) -> io::Result<()> {
+    let mut adjtime = Adjtime::read(path)?;
+    adjtime.last_adjust = t.timestamp();
+    adjtime.last_calibration = t.timestamp();
+    if let Some(local) = local {
+        adjtime.local = local;
+    }
+    adjtime.write(path)
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::{Digest, Sha512};
+use serde::{Deserialize, Serialize};
+
+/// Any command
+pub const AUTH_ANY: &str = "*";
+
+/// Role of a token
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    /// Default commands of role
+    pub fn default_commands(&self) -> &'static [&'static str] {
+        match self {
+            Role::Viewer => &["get"],
+            Role::Operator => &[
+                "get",
+                "rtc_pi2rtc",
+                "rtc_rtc2pi",
+                "rtc_web",
+                "rtc_alarm_set",
+                "rtc_alarm_disable",
+            ],
+            Role::Admin => &[AUTH_ANY],
+        }
+    }
+
+    /// Role name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Listener of a connection, labels logs and metrics
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+    Tcp,
+    Ws,
+    Uds,
+    Http,
+    Grpc,
+}
+
+impl Listener {
+    /// Listener name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Listener::Tcp => "tcp",
+            Listener::Ws => "ws",
+            Listener::Uds => "uds",
+            Listener::Http => "http",
+            Listener::Grpc => "grpc",
+        }
+    }
+}
+
+/// Connections and requests of a listener
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ListenerStats {
+    pub connections: u64,
+    pub requests: u64,
+    /// Rejected by listener commands or role
+    pub rejected: u64,
+}
+
+/// Fingerprint of a token, persisted in place of the token, e.g. to revoke sessions of a removed
+/// token
+pub fn token_fingerprint(token: &str) -> String {
+    let digest = Sha512::digest(token.as_bytes());
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Role of token
+pub fn token_role(tokens: &HashMap<String, Role>, token: &str) -> Option<Role> {
+    tokens.get(token).copied()
+}
+
+/// Whether role may execute command, `role_commands` overrides defaults of a role
+pub fn command_allowed(role_commands: &HashMap<Role, Vec<String>>, role: Role, cmd: &str) -> bool {
+    match role_commands.get(&role) {
+        Some(commands) => commands.iter().any(|c| c == AUTH_ANY || c == cmd),
+        None => role
+            .default_commands()
+            .iter()
+            .any(|c| *c == AUTH_ANY || *c == cmd),
+    }
+}
+
+/// Whether listener may serve command, any if not listed in `listener_commands`
+pub fn listener_allowed(
+    listener_commands: &HashMap<Listener, Vec<String>>,
+    listener: Listener,
+    cmd: &str,
+) -> bool {
+    match listener_commands.get(&listener) {
+        Some(commands) => commands.iter().any(|c| c == AUTH_ANY || c == cmd),
+        None => true,
+    }
+}
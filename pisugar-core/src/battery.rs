@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+use crate::bus::I2cBus;
+use crate::{
+    PiSugarConfig, Result, BATTERY_CAPACITY_V2, BATTERY_CAPACITY_V2_PRO, I2C_ADDR_BAT, INA219,
+    IP5209, IP5312, MAX17048,
+};
+
+/// Battery chip, `battery_chip` of config, PiSugar chips are detected if not set
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryChip {
+    /// PiSugar 2
+    Ip5209,
+    /// PiSugar 2 Pro
+    Ip5312,
+    /// Current/voltage monitor of a shunt, DIY UPS
+    Ina219,
+    /// Fuel gauge, DIY UPS
+    Max17048,
+}
+
+impl BatteryChip {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatteryChip::Ip5209 => "ip5209",
+            BatteryChip::Ip5312 => "ip5312",
+            BatteryChip::Ina219 => "ina219",
+            BatteryChip::Max17048 => "max17048",
+        }
+    }
+
+    /// I2c address of chip
+    pub fn addr(&self) -> u16 {
+        match self {
+            BatteryChip::Ip5209 | BatteryChip::Ip5312 => I2C_ADDR_BAT,
+            BatteryChip::Ina219 => 0x40,
+            BatteryChip::Max17048 => 0x36,
+        }
+    }
+
+    /// Registers of chip, register dump
+    pub fn registers(&self) -> u16 {
+        match self {
+            BatteryChip::Ip5209 | BatteryChip::Ip5312 => 0x100,
+            BatteryChip::Ina219 => 0x06,
+            BatteryChip::Max17048 => 0x1c,
+        }
+    }
+
+    /// Battery capacity (mAh) of the board, `battery_capacity` of config if set
+    pub fn capacity(&self, config: &PiSugarConfig) -> f64 {
+        if config.battery_capacity > 0.0 {
+            config.battery_capacity
+        } else if *self == BatteryChip::Ip5312 {
+            BATTERY_CAPACITY_V2_PRO
+        } else {
+            BATTERY_CAPACITY_V2
+        }
+    }
+
+    /// Chips to probe in order, of config
+    pub fn candidates(config: &PiSugarConfig) -> Vec<BatteryChip> {
+        match config.battery_chip {
+            Some(chip) => vec![chip],
+            None => vec![BatteryChip::Ip5312, BatteryChip::Ip5209],
+        }
+    }
+}
+
+/// Battery operations, intensity (A) is positive when charging
+pub trait BatteryDevice: Send {
+    fn chip(&self) -> BatteryChip;
+
+    /// Board model, e.g. `PiSugar 2`
+    fn model(&self) -> &'static str;
+
+    fn read_voltage(&self) -> Result<f64>;
+
+    fn read_intensity(&self) -> Result<f64>;
+
+    /// Level of a fuel gauge (%), the voltage curve is used otherwise
+    fn read_level(&self) -> Result<Option<f64>> {
+        Ok(None)
+    }
+
+    /// Init of chip after detected
+    fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gpio button state, not pressed without a button
+    fn read_gpio_tap(&self) -> Result<u8> {
+        Ok(0)
+    }
+}
+
+/// Battery of chip on a bus
+pub fn open_battery(
+    chip: BatteryChip,
+    i2c: Box<dyn I2cBus>,
+    config: &PiSugarConfig,
+) -> Box<dyn BatteryDevice> {
+    match chip {
+        BatteryChip::Ip5209 => Box::new(IP5209::with_bus(i2c)),
+        BatteryChip::Ip5312 => Box::new(IP5312::with_bus(i2c)),
+        BatteryChip::Ina219 => Box::new(INA219::with_bus(i2c, config.battery_shunt_ohms)),
+        BatteryChip::Max17048 => Box::new(MAX17048::with_bus(i2c, chip.capacity(config))),
+    }
+}
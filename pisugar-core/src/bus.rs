@@ -0,0 +1,158 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rppal::i2c::{Error as I2cError, I2c};
+
+use crate::{Error, Result};
+
+/// I2C bus of a device, at a fixed slave address
+pub trait I2cBus: Send {
+    /// Read a byte of register
+    fn smbus_read_byte(&self, command: u8) -> Result<u8>;
+
+    /// Write a byte of register
+    fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()>;
+
+    /// Read registers from command
+    fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()>;
+
+    /// Write registers from command
+    fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()>;
+}
+
+impl I2cBus for I2c {
+    fn smbus_read_byte(&self, command: u8) -> Result<u8> {
+        Ok(I2c::smbus_read_byte(self, command)?)
+    }
+
+    fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()> {
+        Ok(I2c::smbus_write_byte(self, command, value)?)
+    }
+
+    fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()> {
+        Ok(I2c::block_read(self, command, buffer)?)
+    }
+
+    fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()> {
+        Ok(I2c::block_write(self, command, buffer)?)
+    }
+}
+
+/// Open a bus at slave address
+pub type I2cOpener = Arc<dyn Fn(u16) -> Result<Box<dyn I2cBus>> + Send + Sync>;
+
+/// Open i2c bus of the pi
+pub fn open_rppal(addr: u16) -> Result<Box<dyn I2cBus>> {
+    let mut i2c = I2c::new()?;
+    i2c.set_slave_address(addr)?;
+    Ok(Box::new(i2c))
+}
+
+/// Retry interval of a busy bus lock
+const BUS_LOCK_RETRY: Duration = Duration::from_millis(2);
+
+/// Max wait of a busy bus lock, transactions run under the core lock and fail after it
+const BUS_LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Bus shared with other processes, each transaction holds an advisory flock of the lock file,
+/// followed by a delay before the lock is released
+struct ArbitratedBus {
+    bus: Box<dyn I2cBus>,
+    lock: Option<File>,
+    delay: Duration,
+}
+
+impl ArbitratedBus {
+    fn flock(&self, operation: libc::c_int) -> Result<()> {
+        if let Some(lock) = &self.lock {
+            if unsafe { libc::flock(lock.as_raw_fd(), operation) } != 0 {
+                return Err(Error::I2c(I2cError::Io(io::Error::last_os_error())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lock the bus without blocking, retried until `BUS_LOCK_TIMEOUT`, a consumer holding the
+    /// lock never stalls polling
+    fn lock(&self) -> Result<()> {
+        let deadline = Instant::now() + BUS_LOCK_TIMEOUT;
+        loop {
+            match self.flock(libc::LOCK_EX | libc::LOCK_NB) {
+                Err(Error::I2c(I2cError::Io(e)))
+                    if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline =>
+                {
+                    thread::sleep(BUS_LOCK_RETRY)
+                }
+                Err(Error::I2c(I2cError::Io(e))) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(Error::I2c(I2cError::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Bus lock timed out",
+                    ))));
+                }
+                r => return r,
+            }
+        }
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&dyn I2cBus) -> Result<T>,
+    {
+        self.lock()?;
+        let r = f(self.bus.as_ref());
+        if self.delay > Duration::from_micros(0) {
+            thread::sleep(self.delay);
+        }
+        self.flock(libc::LOCK_UN)?;
+        r
+    }
+}
+
+impl I2cBus for ArbitratedBus {
+    fn smbus_read_byte(&self, command: u8) -> Result<u8> {
+        self.transaction(|bus| bus.smbus_read_byte(command))
+    }
+
+    fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()> {
+        self.transaction(|bus| bus.smbus_write_byte(command, value))
+    }
+
+    fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()> {
+        self.transaction(|bus| bus.block_read(command, buffer))
+    }
+
+    fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()> {
+        self.transaction(|bus| bus.block_write(command, buffer))
+    }
+}
+
+/// Opener of buses shared with other i2c consumers, lock file (none if empty) and delay between
+/// transactions, unchanged if neither is set
+pub fn arbitrated(opener: I2cOpener, lock_file: &str, delay: Duration) -> I2cOpener {
+    if lock_file.is_empty() && delay == Duration::from_micros(0) {
+        return opener;
+    }
+    let lock_file = lock_file.to_string();
+    Arc::new(move |addr| {
+        let lock = if lock_file.is_empty() {
+            None
+        } else {
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&lock_file)
+                .map_err(|e| Error::I2c(I2cError::Io(e)))?;
+            Some(f)
+        };
+        Ok(Box::new(ArbitratedBus {
+            bus: opener(addr)?,
+            lock,
+            delay,
+        }) as Box<dyn I2cBus>)
+    })
+}
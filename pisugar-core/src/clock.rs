@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+
+/// Min skew of wall time against boot time of a time jump (s)
+pub const TIME_JUMP_THRESHOLD: i64 = 30;
+
+/// Time since boot, suspend included unlike `Instant`
+pub fn boottime() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Duration in the two largest units, e.g. `2h 15m`, `1d 3h` or `45s`
+pub fn humanize_secs(secs: u64) -> String {
+    let units = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(secs, |rest, (unit, suffix)| {
+            let n = *rest / unit;
+            *rest %= unit;
+            Some((n, suffix))
+        })
+        .skip_while(|(n, _)| *n == 0)
+        .take(2)
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, suffix)| format!("{}{}", n, suffix))
+        .collect();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// Wall clock watch, a jump is a step of wall time not matched by boot time,
+/// e.g. ntp after boot or `rtc_rtc2pi`
+#[derive(Default)]
+pub struct ClockWatch {
+    last: Option<(Duration, DateTime<Local>)>,
+}
+
+impl ClockWatch {
+    /// Update with boot time and wall time, the jump in seconds if any, negative backwards
+    pub fn check(&mut self, boot: Duration, wall: DateTime<Local>) -> Option<i64> {
+        let jump = self
+            .last
+            .map(|(b, w)| {
+                let elapsed = boot.checked_sub(b).unwrap_or_default().as_millis() as i64;
+                ((wall - w).num_milliseconds() - elapsed) / 1000
+            })
+            .filter(|jump| jump.abs() >= TIME_JUMP_THRESHOLD);
+        self.last = Some((boot, wall));
+        jump
+    }
+}
@@ -0,0 +1,181 @@
+use crate::bus::I2cBus;
+use crate::Result;
+
+/// Default SSD1306 address
+pub const I2C_ADDR_DISPLAY: u16 = 0x3c;
+
+/// Display width, 128x32 SSD1306
+const WIDTH: usize = 128;
+
+/// Display pages of 8 rows
+const PAGES: usize = 4;
+
+/// Frame buffer, page by page, bit 0 is the top row of a page
+pub type Frame = [u8; WIDTH * PAGES];
+
+/// Control byte of commands
+const CONTROL_COMMAND: u8 = 0x00;
+
+/// Control byte of data
+const CONTROL_DATA: u8 = 0x40;
+
+/// Display off, sleep
+const DISPLAY_OFF: u8 = 0xae;
+
+/// Display on
+const DISPLAY_ON: u8 = 0xaf;
+
+/// Max bytes of an i2c block write
+const BLOCK_SIZE: usize = 32;
+
+/// Init sequence of 128x32
+const INIT_SEQUENCE: [u8; 25] = [
+    0xae, // display off
+    0xd5, 0x80, // clock
+    0xa8, 0x1f, // multiplex 32
+    0xd3, 0x00, // offset
+    0x40, // start line
+    0x8d, 0x14, // charge pump
+    0x20, 0x00, // horizontal addressing
+    0xa1, // segment remap
+    0xc8, // com scan descending
+    0xda, 0x02, // com pins of 128x32
+    0x81, 0x8f, // contrast
+    0xd9, 0xf1, // precharge
+    0xdb, 0x40, // vcom detect
+    0xa4, // resume to ram
+    0xa6, // normal, not inverted
+    0xaf, // display on
+];
+
+/// 5x7 glyph, columns, bit 0 is the top row
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0x3e, 0x51, 0x49, 0x45, 0x3e],
+        '1' => [0x00, 0x42, 0x7f, 0x40, 0x00],
+        '2' => [0x42, 0x61, 0x51, 0x49, 0x46],
+        '3' => [0x21, 0x41, 0x45, 0x4b, 0x31],
+        '4' => [0x18, 0x14, 0x12, 0x7f, 0x10],
+        '5' => [0x27, 0x45, 0x45, 0x45, 0x39],
+        '6' => [0x3c, 0x4a, 0x49, 0x49, 0x30],
+        '7' => [0x01, 0x71, 0x09, 0x05, 0x03],
+        '8' => [0x36, 0x49, 0x49, 0x49, 0x36],
+        '9' => [0x06, 0x49, 0x49, 0x29, 0x1e],
+        '%' => [0x23, 0x13, 0x08, 0x64, 0x62],
+        '+' => [0x08, 0x08, 0x3e, 0x08, 0x08],
+        '-' => [0x08, 0x08, 0x08, 0x08, 0x08],
+        ':' => [0x00, 0x36, 0x36, 0x00, 0x00],
+        '.' => [0x00, 0x60, 0x60, 0x00, 0x00],
+        'h' => [0x7f, 0x08, 0x04, 0x04, 0x78],
+        'm' => [0x7c, 0x04, 0x18, 0x04, 0x78],
+        'C' => [0x3e, 0x41, 0x41, 0x41, 0x22],
+        'G' => [0x3e, 0x41, 0x49, 0x49, 0x7a],
+        'H' => [0x7f, 0x08, 0x08, 0x08, 0x7f],
+        'V' => [0x1f, 0x20, 0x40, 0x20, 0x1f],
+        _ => [0x00; 5],
+    }
+}
+
+/// Set a pixel, out of range is clipped
+fn set_pixel(frame: &mut Frame, x: usize, y: usize) {
+    if x < WIDTH && y < PAGES * 8 {
+        frame[(y / 8) * WIDTH + x] |= 1 << (y % 8);
+    }
+}
+
+/// Draw text at pixel position, glyphs scaled
+fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, scale: usize) {
+    for (i, c) in text.chars().enumerate() {
+        let gx = x + i * 6 * scale;
+        for (col, bits) in glyph(c).iter().enumerate() {
+            for row in 0..7 {
+                if bits & (1 << row) == 0 {
+                    continue;
+                }
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        set_pixel(frame, gx + col * scale + dx, y + row * scale + dy);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Battery frame, level in large digits, charging and estimated time to full/empty
+pub fn render_battery(level: f64, charging: bool, eta_minutes: Option<u64>) -> Frame {
+    let mut frame = [0; WIDTH * PAGES];
+    let level = level.max(0.0).min(100.0).round();
+    draw_text(&mut frame, 0, 4, &format!("{}%", level), 3);
+    if charging {
+        draw_text(&mut frame, 86, 4, "CHG", 1);
+    }
+    if let Some(m) = eta_minutes {
+        let eta = format!("{}{}h{}m", if charging { '+' } else { '-' }, m / 60, m % 60);
+        draw_text(&mut frame, 86, 18, &eta, 1);
+    }
+    frame
+}
+
+/// Estimated minutes to full when charging, or to empty, capacity in mAh and intensity in A
+pub fn estimate_minutes(level: f64, intensity: f64, capacity: f64) -> Option<u64> {
+    let ma = intensity.abs() * 1000.0;
+    if ma < 1.0 {
+        return None;
+    }
+    let remaining = if intensity > 0.0 {
+        100.0 - level
+    } else {
+        level
+    };
+    let hours = remaining.max(0.0) / 100.0 * capacity / ma;
+    Some((hours * 60.0).round() as u64)
+}
+
+/// SSD1306 OLED, 128x32
+pub struct SSD1306 {
+    i2c: Box<dyn I2cBus>,
+    frame: Option<Frame>,
+    on: bool,
+}
+
+impl SSD1306 {
+    /// Create and init display
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Result<Self> {
+        let display = Self {
+            i2c,
+            frame: None,
+            on: true,
+        };
+        for cmd in INIT_SEQUENCE.iter() {
+            display.i2c.smbus_write_byte(CONTROL_COMMAND, *cmd)?;
+        }
+        Ok(display)
+    }
+
+    /// Draw frame, skipped if unchanged to spare the shared bus
+    pub fn draw(&mut self, frame: &Frame) -> Result<()> {
+        if self.frame.as_ref().map(|f| &f[..]) == Some(&frame[..]) {
+            return Ok(());
+        }
+        for cmd in &[0x21, 0, (WIDTH - 1) as u8, 0x22, 0, (PAGES - 1) as u8] {
+            self.i2c.smbus_write_byte(CONTROL_COMMAND, *cmd)?;
+        }
+        for chunk in frame.chunks(BLOCK_SIZE) {
+            self.i2c.block_write(CONTROL_DATA, chunk)?;
+        }
+        self.frame = Some(*frame);
+        Ok(())
+    }
+
+    /// Turn display on or off, frame is kept
+    pub fn set_on(&mut self, on: bool) -> Result<()> {
+        if self.on == on {
+            return Ok(());
+        }
+        let cmd = if on { DISPLAY_ON } else { DISPLAY_OFF };
+        self.i2c.smbus_write_byte(CONTROL_COMMAND, cmd)?;
+        self.on = on;
+        Ok(())
+    }
+}
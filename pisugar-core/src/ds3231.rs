@@ -0,0 +1,136 @@
+use crate::bus::{open_rppal, I2cBus};
+use crate::rtc::test_register;
+use crate::sd3078::{bcd_to_dec, dec_to_bcd};
+use crate::{Result, RtcDevice, RtcModel, SD3078Time};
+
+/// Alarm 1 registers, seconds/minutes/hours/day
+const ALARM1_REG: u8 = 0x07;
+
+/// Alarm 2 minutes, unused and a scratch register of self test
+const SCRATCH_REG: u8 = 0x0b;
+
+/// Control, A1IE bit0, INTCN bit2
+const CONTROL_REG: u8 = 0x0e;
+
+/// Status, A1F bit0
+const STATUS_REG: u8 = 0x0f;
+
+/// Hours to 24hr bcd
+fn hour_to_24(hour: u8) -> u8 {
+    if hour & 0b0100_0000 == 0 {
+        return hour & 0b0011_1111;
+    }
+    let pm = hour & 0b0010_0000 != 0;
+    let hour = bcd_to_dec(hour & 0b0001_1111) % 12;
+    dec_to_bcd(if pm { hour + 12 } else { hour })
+}
+
+/// DS3231, rtc chip
+pub struct DS3231 {
+    i2c: Box<dyn I2cBus>,
+}
+
+impl DS3231 {
+    /// Create new DS3231
+    pub fn new(i2c_addr: u16) -> Result<Self> {
+        Ok(Self::with_bus(open_rppal(i2c_addr)?))
+    }
+
+    /// Create DS3231 on a bus
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Self {
+        Self { i2c }
+    }
+}
+
+impl RtcDevice for DS3231 {
+    fn model(&self) -> RtcModel {
+        RtcModel::Ds3231
+    }
+
+    /// Read time, day of week 1-7
+    fn read_time(&self) -> Result<SD3078Time> {
+        let mut bcd_time = [0_u8; 7];
+        self.i2c.block_read(0, &mut bcd_time)?;
+
+        bcd_time[0] &= 0b0111_1111;
+        bcd_time[2] = hour_to_24(bcd_time[2]);
+        bcd_time[3] = (bcd_time[3] & 0b0000_0111).saturating_sub(1);
+        bcd_time[5] &= 0b0001_1111; // century
+
+        Ok(SD3078Time::from_raw(bcd_time))
+    }
+
+    /// Write time, 24hr
+    fn write_time(&self, t: SD3078Time) -> Result<()> {
+        let mut bcd_time = t.to_raw();
+        bcd_time[2] &= 0b0011_1111;
+        bcd_time[3] += 1;
+        self.i2c.block_write(0, bcd_time.as_ref())
+    }
+
+    /// Read alarm 1 time, date of today
+    fn read_alarm_time(&self) -> Result<SD3078Time> {
+        let mut alarm = [0_u8; 4];
+        self.i2c.block_read(ALARM1_REG, &mut alarm)?;
+
+        let mut bcd_time = self.read_time()?.to_raw();
+        bcd_time[0] = alarm[0] & 0b0111_1111;
+        bcd_time[1] = alarm[1] & 0b0111_1111;
+        bcd_time[2] = hour_to_24(alarm[2] & 0b0111_1111);
+
+        Ok(SD3078Time::from_raw(bcd_time))
+    }
+
+    /// Check alarm 1 interrupt enabled
+    fn read_alarm_enabled(&self) -> Result<bool> {
+        let control = self.i2c.smbus_read_byte(CONTROL_REG)?;
+        Ok(control & 0b0000_0101 == 0b0000_0101)
+    }
+
+    /// Read alarm 1 flag
+    fn read_alarm_flag(&self) -> Result<bool> {
+        let status = self.i2c.smbus_read_byte(STATUS_REG)?;
+        Ok(status & 0b0000_0001 != 0)
+    }
+
+    /// Clear alarm 1 flag
+    fn clear_alarm_flag(&self) -> Result<()> {
+        let status = self.i2c.smbus_read_byte(STATUS_REG)?;
+        self.i2c.smbus_write_byte(STATUS_REG, status & 0b1111_1110)
+    }
+
+    /// Disable alarm 1 interrupt
+    fn disable_alarm(&self) -> Result<()> {
+        let control = self.i2c.smbus_read_byte(CONTROL_REG)?;
+        self.i2c
+            .smbus_write_byte(CONTROL_REG, control & 0b1111_1110)
+    }
+
+    /// Set alarm 1, on a single weekday or daily, several weekdays fall back to daily
+    fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()> {
+        let t = t.to_raw();
+        let weekdays = weekday_repeat & 0b0111_1111;
+        let day = if weekdays.count_ones() == 1 {
+            // A1M4 clear, DY/DT day of week
+            0b0100_0000 | (weekdays.trailing_zeros() as u8 + 1)
+        } else {
+            if weekdays != 0b0111_1111 {
+                log::warn!("DS3231 alarm of weekdays {:#09b} repeats daily", weekdays);
+            }
+            // A1M4, hours/minutes/seconds match
+            0b1000_0000
+        };
+        let alarm = [t[0], t[1], t[2] & 0b0011_1111, day];
+        self.i2c.block_write(ALARM1_REG, alarm.as_ref())?;
+
+        self.clear_alarm_flag()?;
+        let control = self.i2c.smbus_read_byte(CONTROL_REG)?;
+        self.i2c
+            .smbus_write_byte(CONTROL_REG, control | 0b0000_0101)
+    }
+
+    /// Write patterns to alarm 2 minutes and read back, restored after
+    fn test_scratch(&self) -> Result<()> {
+        test_register(self.i2c.as_ref(), SCRATCH_REG)
+    }
+}
@@ -0,0 +1,139 @@
+use rppal::gpio::{Gpio, InputPin, Level};
+use serde::{Deserialize, Serialize};
+
+use crate::bus::{I2cBus, I2cOpener};
+use crate::{Error, Result};
+
+/// Default ADS1115 address
+pub const I2C_ADDR_ADS1115: u16 = 0x48;
+
+/// ADS1115 full scale of ±4.096V gain
+const ADS1115_FULL_SCALE: f64 = 4.096;
+
+fn default_ads1115_addr() -> u16 {
+    I2C_ADDR_ADS1115
+}
+
+/// External power sensor, for power sources the PiSugar can't sense
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum ExternalPowerSource {
+    /// Gpio input, high when power present unless active low
+    Gpio {
+        pin: u8,
+        #[serde(default)]
+        active_low: bool,
+    },
+    /// ADS1115 adc channel 0-3, power present at or above threshold volts
+    Ads1115 {
+        #[serde(default = "default_ads1115_addr")]
+        addr: u16,
+        channel: u8,
+        threshold: f64,
+    },
+}
+
+/// ADS1115 adc, continuous conversion of a single-ended channel
+pub struct ADS1115 {
+    i2c: Box<dyn I2cBus>,
+}
+
+impl ADS1115 {
+    /// Start continuous conversion of channel
+    pub fn with_bus(i2c: Box<dyn I2cBus>, channel: u8) -> Result<Self> {
+        if channel > 3 {
+            return Err(Error::Other(format!(
+                "ads1115: invalid channel {}",
+                channel
+            )));
+        }
+        // single-ended mux, ±4.096V, continuous, 128 SPS, comparator disabled
+        let config: u16 = ((4 + channel as u16) << 12) | (1 << 9) | (4 << 5) | 0b11;
+        i2c.block_write(0x01, &config.to_be_bytes())?;
+        Ok(Self { i2c })
+    }
+
+    /// Last conversion in volts
+    pub fn read_voltage(&self) -> Result<f64> {
+        let mut buf = [0u8; 2];
+        self.i2c.block_read(0x00, &mut buf)?;
+        let raw = i16::from_be_bytes(buf);
+        Ok(raw as f64 * ADS1115_FULL_SCALE / 32768.0)
+    }
+}
+
+/// Opened external power sensor
+pub enum ExternalPower {
+    Gpio(InputPin, bool),
+    Ads1115(ADS1115, f64),
+}
+
+impl ExternalPower {
+    /// Open sensor, adc on i2c bus of opener
+    pub fn open(source: &ExternalPowerSource, opener: &I2cOpener) -> Result<Self> {
+        match source {
+            ExternalPowerSource::Gpio { pin, active_low } => {
+                let gpio = Gpio::new().map_err(|e| Error::Other(format!("gpio: {}", e)))?;
+                let pin = gpio
+                    .get(*pin)
+                    .map_err(|e| Error::Other(format!("gpio {}: {}", pin, e)))?
+                    .into_input();
+                Ok(ExternalPower::Gpio(pin, *active_low))
+            }
+            ExternalPowerSource::Ads1115 {
+                addr,
+                channel,
+                threshold,
+            } => {
+                let adc = ADS1115::with_bus(opener(*addr)?, *channel)?;
+                Ok(ExternalPower::Ads1115(adc, *threshold))
+            }
+        }
+    }
+
+    /// Whether external power is present
+    pub fn present(&self) -> Result<bool> {
+        match self {
+            ExternalPower::Gpio(pin, active_low) => Ok((pin.read() == Level::High) != *active_low),
+            ExternalPower::Ads1115(adc, threshold) => Ok(adc.read_voltage()? >= *threshold),
+        }
+    }
+}
+
+/// Input powering the charger
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputSource {
+    /// On battery
+    None,
+    Usbc,
+    Pogo,
+    Solar,
+    /// Charging, but no input sensor reads power
+    Unknown,
+}
+
+impl Default for InputSource {
+    fn default() -> Self {
+        InputSource::None
+    }
+}
+
+impl InputSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputSource::None => "none",
+            InputSource::Usbc => "usbc",
+            InputSource::Pogo => "pogo",
+            InputSource::Solar => "solar",
+            InputSource::Unknown => "unknown",
+        }
+    }
+}
+
+/// Sensor of an input, `input_sensors` of config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputSensor {
+    pub input: InputSource,
+    pub sensor: ExternalPowerSource,
+}
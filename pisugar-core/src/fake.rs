@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rppal::i2c::Error as I2cError;
+
+use crate::bus::{I2cBus, I2cOpener};
+use crate::{Error, Result, I2C_ADDR_BAT};
+
+/// Scripted register write
+struct ScriptedWrite {
+    at: Duration,
+    addr: u16,
+    reg: u8,
+    value: u8,
+}
+
+#[derive(Default)]
+struct FakeState {
+    registers: HashMap<(u16, u8), u8>,
+    timeline: Vec<ScriptedWrite>,
+    failures: HashMap<u16, u32>,
+}
+
+impl FakeState {
+    fn fail(&mut self, addr: u16) -> Result<()> {
+        if let Some(n) = self.failures.get_mut(&addr) {
+            if *n > 0 {
+                *n -= 1;
+                return Err(Error::I2c(I2cError::Io(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Injected i2c error",
+                ))));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fake i2c devices, a scriptable register map shared by all addresses.
+/// Clones share the same registers, so a test keeps one to script the devices.
+#[derive(Clone, Default)]
+pub struct FakeI2c {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeI2c {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opener of buses, for `PiSugarCore::new_with_opener`
+    pub fn opener(&self) -> I2cOpener {
+        let fake = self.clone();
+        Arc::new(move |addr| {
+            Ok(Box::new(FakeBus {
+                addr,
+                fake: fake.clone(),
+            }) as Box<dyn I2cBus>)
+        })
+    }
+
+    /// Read a register
+    pub fn get(&self, addr: u16, reg: u8) -> u8 {
+        let state = self.state.lock().unwrap();
+        state.registers.get(&(addr, reg)).copied().unwrap_or(0)
+    }
+
+    /// Set a register
+    pub fn set(&self, addr: u16, reg: u8, value: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.registers.insert((addr, reg), value);
+    }
+
+    /// Schedule a register write at elapsed time
+    pub fn set_at(&self, at: Duration, addr: u16, reg: u8, value: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.timeline.push(ScriptedWrite {
+            at,
+            addr,
+            reg,
+            value,
+        });
+    }
+
+    /// Advance timeline, scheduled writes up to elapsed are applied in order
+    pub fn advance(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.timeline.sort_by_key(|w| w.at);
+        let due = state
+            .timeline
+            .iter()
+            .take_while(|w| w.at <= elapsed)
+            .count();
+        let writes: Vec<ScriptedWrite> = state.timeline.drain(..due).collect();
+        for w in writes {
+            state.registers.insert((w.addr, w.reg), w.value);
+        }
+    }
+
+    /// Fail next n accesses of address
+    pub fn inject_errors(&self, addr: u16, n: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.insert(addr, n);
+    }
+
+    /// IP5209 voltage registers of voltage (V)
+    pub fn ip5209_voltage_registers(voltage: f64) -> [(u8, u8); 2] {
+        let v = ((voltage * 1000.0 - 2600.0) / 0.26855).max(0.0) as u16;
+        [(0xa2, (v & 0xff) as u8), (0xa3, ((v >> 8) & 0x1f) as u8)]
+    }
+
+    /// Set IP5209 battery voltage (V)
+    pub fn set_ip5209_voltage(&self, voltage: f64) {
+        for (reg, value) in &Self::ip5209_voltage_registers(voltage) {
+            self.set(I2C_ADDR_BAT, *reg, *value);
+        }
+    }
+
+    /// Schedule IP5209 battery voltage (V), e.g. a discharge curve
+    pub fn set_ip5209_voltage_at(&self, at: Duration, voltage: f64) {
+        for (reg, value) in &Self::ip5209_voltage_registers(voltage) {
+            self.set_at(at, I2C_ADDR_BAT, *reg, *value);
+        }
+    }
+
+    /// Set IP5209 voltage registers of a removed battery, the full scale 4.8V of a floating pin,
+    /// out of the plausible range. Voltages below 2.6V can not be encoded
+    pub fn set_ip5209_battery_removed(&self) {
+        self.set(I2C_ADDR_BAT, 0xa2, 0xff);
+        self.set(I2C_ADDR_BAT, 0xa3, 0x1f);
+    }
+
+    /// Set IP5209 gpio button state
+    pub fn set_ip5209_button(&self, pressed: bool) {
+        self.set(I2C_ADDR_BAT, 0x55, if pressed { 1 } else { 0 });
+    }
+}
+
+/// Bus of a fake device
+struct FakeBus {
+    addr: u16,
+    fake: FakeI2c,
+}
+
+impl I2cBus for FakeBus {
+    fn smbus_read_byte(&self, command: u8) -> Result<u8> {
+        let mut state = self.fake.state.lock().unwrap();
+        state.fail(self.addr)?;
+        Ok(state
+            .registers
+            .get(&(self.addr, command))
+            .copied()
+            .unwrap_or(0))
+    }
+
+    fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()> {
+        let mut state = self.fake.state.lock().unwrap();
+        state.fail(self.addr)?;
+        state.registers.insert((self.addr, command), value);
+        Ok(())
+    }
+
+    fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()> {
+        let mut state = self.fake.state.lock().unwrap();
+        state.fail(self.addr)?;
+        for (i, b) in buffer.iter_mut().enumerate() {
+            let reg = command.wrapping_add(i as u8);
+            *b = state.registers.get(&(self.addr, reg)).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()> {
+        let mut state = self.fake.state.lock().unwrap();
+        state.fail(self.addr)?;
+        for (i, b) in buffer.iter().enumerate() {
+            let reg = command.wrapping_add(i as u8);
+            state.registers.insert((self.addr, reg), *b);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "sqlite")]
+pub use crate::sqlite::SqliteHistory;
+
+/// Default history sample interval (s)
+pub const HISTORY_DEFAULT_INTERVAL: u64 = 60;
+
+/// Default history capacity, a week of samples
+pub const HISTORY_DEFAULT_CAPACITY: usize = 7 * 24 * 60;
+
+/// Interval of applying history retention
+pub const HISTORY_RETENTION_INTERVAL: u64 = 3600;
+
+/// Ring file of history, csv lines
+pub const HISTORY_FILE: &str = "history.csv";
+
+/// Database of sqlite history
+pub const HISTORY_DB_FILE: &str = "history.db";
+
+fn default_raw_hours() -> i64 {
+    48
+}
+
+fn default_average_minutes() -> i64 {
+    5
+}
+
+fn default_average_days() -> i64 {
+    30
+}
+
+/// History retention, samples older than raw hours are averaged, averages older than days are dropped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRetention {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Hours of raw samples
+    #[serde(default = "default_raw_hours")]
+    pub raw_hours: i64,
+
+    /// Minutes of an average
+    #[serde(default = "default_average_minutes")]
+    pub average_minutes: i64,
+
+    /// Days of averages
+    #[serde(default = "default_average_days")]
+    pub average_days: i64,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        serde_json::from_str("{}").expect("Unexpected history retention default")
+    }
+}
+
+/// Average samples in buckets of minutes, oldest first, bucket start as time
+pub fn downsample(samples: &[HistorySample], minutes: i64) -> Vec<HistorySample> {
+    let secs = minutes.max(1) * 60;
+    let mut averages = Vec::new();
+    let mut i = 0;
+    while i < samples.len() {
+        let bucket = samples[i].time.timestamp().div_euclid(secs);
+        let n = samples[i..]
+            .iter()
+            .take_while(|s| s.time.timestamp().div_euclid(secs) == bucket)
+            .count();
+        let group = &samples[i..i + n];
+        let avg = |f: fn(&HistorySample) -> f64| group.iter().map(f).sum::<f64>() / n as f64;
+        averages.push(HistorySample {
+            time: Local.timestamp(bucket * secs, 0),
+            level: avg(|s| s.level),
+            voltage: avg(|s| s.voltage),
+            intensity: avg(|s| s.intensity),
+            charging: group.iter().filter(|s| s.charging).count() * 2 >= n,
+        });
+        i += n;
+    }
+    averages
+}
+
+/// Split samples at raw hours, older samples within days are averaged
+fn retain_samples(
+    samples: Vec<HistorySample>,
+    retention: &HistoryRetention,
+    now: DateTime<Local>,
+) -> Vec<HistorySample> {
+    let raw_from = now - Duration::hours(retention.raw_hours);
+    let average_from = now - Duration::days(retention.average_days);
+    let (old, raw): (Vec<_>, Vec<_>) = samples.into_iter().partition(|s| s.time < raw_from);
+    let old: Vec<_> = old.into_iter().filter(|s| s.time >= average_from).collect();
+    let mut samples = downsample(&old, retention.average_minutes);
+    samples.extend(raw);
+    samples
+}
+
+/// History storage backend
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    /// In-memory ring, appended to a ring file
+    Ring,
+    /// Sqlite database, feature `sqlite`
+    Sqlite,
+}
+
+impl Default for HistoryBackend {
+    fn default() -> Self {
+        HistoryBackend::Ring
+    }
+}
+
+/// Storage of history samples
+pub trait HistoryStore: Send {
+    /// Set capacity, oldest samples are dropped
+    fn set_capacity(&mut self, capacity: usize);
+
+    /// Record a sample
+    fn record(&mut self, sample: HistorySample) -> io::Result<()>;
+
+    /// Samples in [from, to], oldest first
+    fn query(
+        &self,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+    ) -> Vec<HistorySample>;
+
+    /// Downsample and drop samples by retention
+    fn apply_retention(
+        &mut self,
+        retention: &HistoryRetention,
+        now: DateTime<Local>,
+    ) -> io::Result<()>;
+
+    /// Batch writes until flushed, low write mode
+    fn set_batched(&mut self, batched: bool);
+
+    /// Write batched samples
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Open history store of backend in dir
+pub fn open_history(
+    backend: HistoryBackend,
+    dir: &Path,
+    capacity: usize,
+) -> io::Result<Box<dyn HistoryStore>> {
+    match backend {
+        HistoryBackend::Ring => {
+            let mut history = History::new(capacity);
+            history.load(&dir.join(HISTORY_FILE))?;
+            Ok(Box::new(history))
+        }
+        #[cfg(feature = "sqlite")]
+        HistoryBackend::Sqlite => {
+            let history = SqliteHistory::open(&dir.join(HISTORY_DB_FILE), capacity)?;
+            Ok(Box::new(history))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        HistoryBackend::Sqlite => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "sqlite history not built, feature `sqlite`",
+        )),
+    }
+}
+
+/// History sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistorySample {
+    pub time: DateTime<Local>,
+    pub level: f64,
+    pub voltage: f64,
+    pub intensity: f64,
+    pub charging: bool,
+}
+
+impl HistorySample {
+    /// Csv header
+    pub const CSV_HEADER: &'static str = "time,level,voltage,intensity,charging";
+
+    /// Csv line, without line ending
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.time.to_rfc3339(),
+            self.level,
+            self.voltage,
+            self.intensity,
+            self.charging
+        )
+    }
+
+    /// Parse a csv line
+    pub fn from_csv(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.trim_end().split(',').collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        Some(Self {
+            time: DateTime::parse_from_rfc3339(fields[0]).ok()?.into(),
+            level: fields[1].parse().ok()?,
+            voltage: fields[2].parse().ok()?,
+            intensity: fields[3].parse().ok()?,
+            charging: fields[4].parse().ok()?,
+        })
+    }
+}
+
+/// Battery history, in memory ring, appended to a ring file if loaded
+pub struct History {
+    samples: VecDeque<HistorySample>,
+    capacity: usize,
+    path: Option<PathBuf>,
+    /// Lines of the ring file, rewritten with the ring at twice the capacity
+    lines: usize,
+    /// Samples not yet written
+    pending: usize,
+    /// Ring file must be rewritten
+    stale: bool,
+    batched: bool,
+}
+
+impl History {
+    /// Create new history
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            capacity,
+            path: None,
+            lines: 0,
+            pending: 0,
+            stale: false,
+            batched: false,
+        }
+    }
+
+    /// Load from ring file, start empty if not exists, invalid lines are skipped
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.path = Some(path.to_path_buf());
+        if path.exists() {
+            let f = BufReader::new(File::open(path)?);
+            for line in f.lines() {
+                self.lines += 1;
+                if let Some(sample) = HistorySample::from_csv(&line?) {
+                    self.samples.push_back(sample);
+                }
+            }
+            self.truncate();
+        }
+        Ok(())
+    }
+
+    /// Latest sample
+    pub fn last(&self) -> Option<&HistorySample> {
+        self.samples.back()
+    }
+
+    fn truncate(&mut self) {
+        while self.samples.len() > self.capacity {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Write csv lines of samples, appended or truncated
+    fn write<'a>(
+        path: &Path,
+        samples: impl Iterator<Item = &'a HistorySample>,
+        append: bool,
+    ) -> io::Result<usize> {
+        let mut buff = String::new();
+        let mut lines = 0;
+        for s in samples {
+            buff.push_str(&s.to_csv());
+            buff.push('\n');
+            lines += 1;
+        }
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        let mut f = options.open(path)?;
+        f.write_all(buff.as_bytes())?;
+        Ok(lines)
+    }
+}
+
+impl HistoryStore for History {
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate();
+    }
+
+    fn record(&mut self, sample: HistorySample) -> io::Result<()> {
+        self.samples.push_back(sample);
+        self.truncate();
+        self.pending += 1;
+        if self.batched {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    fn query(
+        &self,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+    ) -> Vec<HistorySample> {
+        self.samples
+            .iter()
+            .filter(|s| from.map(|from| s.time >= from).unwrap_or(true))
+            .filter(|s| to.map(|to| s.time <= to).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    fn apply_retention(
+        &mut self,
+        retention: &HistoryRetention,
+        now: DateTime<Local>,
+    ) -> io::Result<()> {
+        let samples = self.samples.drain(..).collect();
+        self.samples = retain_samples(samples, retention, now).into();
+        self.stale = true;
+        if self.batched {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.batched = batched;
+    }
+
+    /// Append pending samples, or rewrite the ring file with the ring at twice the capacity
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let pending = self.pending;
+            let rewrite = self.stale
+                || pending > self.samples.len()
+                || self.lines + pending >= self.capacity.saturating_mul(2);
+            if rewrite {
+                self.lines = Self::write(path, self.samples.iter(), false)?;
+            } else if pending > 0 {
+                let skip = self.samples.len() - pending;
+                self.lines += Self::write(path, self.samples.iter().skip(skip), true)?;
+            }
+        }
+        self.pending = 0;
+        self.stale = false;
+        Ok(())
+    }
+}
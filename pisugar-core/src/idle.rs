@@ -0,0 +1,194 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Tcp sockets of ipv4 and ipv6
+const PROC_NET_TCP: [&str; 2] = ["/proc/net/tcp", "/proc/net/tcp6"];
+
+/// Interface counters
+const PROC_NET_DEV: &str = "/proc/net/dev";
+
+/// Load average
+const PROC_LOADAVG: &str = "/proc/loadavg";
+
+/// Established state of /proc/net/tcp
+const TCP_ESTABLISHED: &str = "01";
+
+fn default_idle_minutes() -> u64 {
+    30
+}
+
+fn default_max_load() -> f64 {
+    0.5
+}
+
+fn default_max_net_rate() -> f64 {
+    1024.0
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// Idle shutdown policy, power off when on battery and idle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdleShutdownPolicy {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Idle minutes before power off
+    #[serde(default = "default_idle_minutes")]
+    pub minutes: u64,
+
+    /// Max load average of 1 minute
+    #[serde(default = "default_max_load")]
+    pub max_load: f64,
+
+    /// Max network traffic, bytes per second of all interfaces but loopback
+    #[serde(default = "default_max_net_rate")]
+    pub max_net_rate: f64,
+
+    /// Established connections to this port are ssh sessions
+    #[serde(default = "default_ssh_port")]
+    pub ssh_port: u16,
+
+    /// Windows of never idle, `HH:MM-HH:MM`, may cross midnight
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Default for IdleShutdownPolicy {
+    fn default() -> Self {
+        serde_json::from_str("{}").expect("Unexpected idle shutdown default")
+    }
+}
+
+/// Whether time is in a `HH:MM-HH:MM` window, invalid windows are ignored
+pub fn in_windows(windows: &[String], time: NaiveTime) -> bool {
+    windows.iter().any(|w| {
+        let mut parts = w.splitn(2, '-');
+        let start = parts
+            .next()
+            .and_then(|s| NaiveTime::parse_from_str(s.trim(), "%H:%M").ok());
+        let end = parts
+            .next()
+            .and_then(|s| NaiveTime::parse_from_str(s.trim(), "%H:%M").ok());
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => time >= start && time < end,
+            (Some(start), Some(end)) => time >= start || time < end,
+            _ => {
+                log::debug!("Invalid window: {}", w);
+                false
+            }
+        }
+    })
+}
+
+/// Activity of the system
+#[derive(Debug, Clone)]
+pub struct IdleSample {
+    pub ssh_sessions: usize,
+    pub load: f64,
+    /// Total bytes received and sent, loopback excluded
+    pub net_bytes: u64,
+}
+
+impl IdleSample {
+    /// Read from /proc
+    pub fn read(ssh_port: u16) -> Result<Self> {
+        let port = format!(":{:04X}", ssh_port);
+        let mut ssh_sessions = 0;
+        for path in PROC_NET_TCP.iter() {
+            // tcp6 may be absent
+            let tcp = match fs::read_to_string(path) {
+                Ok(tcp) => tcp,
+                Err(_) => continue,
+            };
+            // sl local_address rem_address st ...
+            ssh_sessions += tcp
+                .lines()
+                .skip(1)
+                .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                .filter(|f| f.len() > 3 && f[1].ends_with(&port) && f[3] == TCP_ESTABLISHED)
+                .count();
+        }
+
+        let load = read_file(PROC_LOADAVG)?
+            .split_whitespace()
+            .next()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0.0);
+
+        let mut net_bytes = 0;
+        for line in read_file(PROC_NET_DEV)?.lines().skip(2) {
+            // iface: rx_bytes ... (8 fields) tx_bytes ...
+            let mut kv = line.splitn(2, ':');
+            let iface = kv.next().unwrap_or_default().trim();
+            if iface == "lo" {
+                continue;
+            }
+            let fields: Vec<u64> = kv
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|s| s.parse().unwrap_or(0))
+                .collect();
+            if fields.len() > 8 {
+                net_bytes += fields[0] + fields[8];
+            }
+        }
+
+        Ok(Self {
+            ssh_sessions,
+            load,
+            net_bytes,
+        })
+    }
+}
+
+/// Idle duration tracking of samples
+#[derive(Default)]
+pub struct IdleTracker {
+    idle_since: Option<Instant>,
+    last_net: Option<(Instant, u64)>,
+}
+
+impl IdleTracker {
+    /// Update with a sample, whether idle on battery for policy minutes
+    pub fn update(
+        &mut self,
+        policy: &IdleShutdownPolicy,
+        on_battery: bool,
+        sample: &IdleSample,
+        time: NaiveTime,
+        now: Instant,
+    ) -> bool {
+        let net_rate = match self.last_net {
+            Some((t, bytes)) if now > t => {
+                sample.net_bytes.saturating_sub(bytes) as f64 / (now - t).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        self.last_net = Some((now, sample.net_bytes));
+
+        let idle = on_battery
+            && sample.ssh_sessions == 0
+            && sample.load <= policy.max_load
+            && net_rate <= policy.max_net_rate
+            && !in_windows(&policy.exclude, time);
+        if !idle {
+            self.idle_since = None;
+            return false;
+        }
+        let since = *self.idle_since.get_or_insert(now);
+        now >= since + Duration::from_secs(policy.minutes * 60)
+    }
+}
+
+fn read_file(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path, e)))
+}
@@ -0,0 +1,56 @@
+use crate::bus::{open_rppal, I2cBus};
+
+use crate::{BatteryChip, BatteryDevice, Result};
+
+/// Shunt voltage, signed, 10uV
+const SHUNT_VOLTAGE_REG: u8 = 0x01;
+
+/// Bus voltage, bits 15-3, 4mV
+const BUS_VOLTAGE_REG: u8 = 0x02;
+
+/// INA219, current/voltage monitor, battery current through a shunt
+pub struct INA219 {
+    i2c: Box<dyn I2cBus>,
+    shunt_ohms: f64,
+}
+
+impl INA219 {
+    /// Create new INA219
+    pub fn new(i2c_addr: u16, shunt_ohms: f64) -> Result<Self> {
+        Ok(Self::with_bus(open_rppal(i2c_addr)?, shunt_ohms))
+    }
+
+    /// Create INA219 on a bus, shunt resistor (ohm)
+    pub fn with_bus(i2c: Box<dyn I2cBus>, shunt_ohms: f64) -> Self {
+        Self { i2c, shunt_ohms }
+    }
+
+    /// Read a big endian register
+    fn read_word(&self, reg: u8) -> Result<u16> {
+        let mut buf = [0_u8; 2];
+        self.i2c.block_read(reg, &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+}
+
+impl BatteryDevice for INA219 {
+    fn chip(&self) -> BatteryChip {
+        BatteryChip::Ina219
+    }
+
+    fn model(&self) -> &'static str {
+        "INA219"
+    }
+
+    /// Read bus voltage (V), of the battery side
+    fn read_voltage(&self) -> Result<f64> {
+        let v = self.read_word(BUS_VOLTAGE_REG)? >> 3;
+        Ok(v as f64 * 0.004)
+    }
+
+    /// Read intensity (A) of shunt voltage, positive is charging when wired from charger to battery
+    fn read_intensity(&self) -> Result<f64> {
+        let v = self.read_word(SHUNT_VOLTAGE_REG)? as i16;
+        Ok(v as f64 * 0.000_01 / self.shunt_ohms)
+    }
+}
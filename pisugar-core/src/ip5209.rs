@@ -1,55 +1,24 @@
-use rppal::i2c::I2c;
+use crate::bus::{open_rppal, I2cBus};
 
-use crate::Result;
+use crate::{BatteryChip, BatteryDevice, Result, MODEL_V2};
 
 /// Idle intensity
 const PI_ZERO_IDLE_INTENSITY: f64 = 0.11;
 
 /// IP5209, pi-zero bat chip
 pub struct IP5209 {
-    i2c: I2c,
+    i2c: Box<dyn I2cBus>,
 }
 
 impl IP5209 {
     /// Create new IP5209
     pub fn new(i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::new()?;
-        i2c.set_slave_address(i2c_addr)?;
-        Ok(Self { i2c })
+        Ok(Self::with_bus(open_rppal(i2c_addr)?))
     }
 
-    /// Read voltage (V)
-    pub fn read_voltage(&self) -> Result<f64> {
-        let low = self.i2c.smbus_read_byte(0xa2)? as u16;
-        let high = self.i2c.smbus_read_byte(0xa3)? as u16;
-
-        // check negative values
-        let voltage = if high & 0x20 == 0x20 {
-            let v = (((high | 0b1100_0000) << 8) + low) as i16;
-            2600.0 - (v as f64) * 0.26855
-        } else {
-            let v = ((high & 0x1f) << 8) + low;
-            2600.0 + (v as f64) * 0.26855
-        };
-
-        Ok(voltage / 1000.0)
-    }
-
-    /// Read intensity (A)
-    pub fn read_intensity(&self) -> Result<f64> {
-        let low = self.i2c.smbus_read_byte(0xa4)? as u16;
-        let high = self.i2c.smbus_read_byte(0xa5)? as u16;
-
-        // check negative value
-        let intensity = if high & 0x20 == 0x20 {
-            let i = (((high | 0b1100_0000) << 8) + low) as i16;
-            (i as f64) * 0.745985
-        } else {
-            let i = ((high & 0x1f) << 8) + low;
-            (i as f64) * 0.745985
-        };
-
-        Ok(intensity / 1000.0)
+    /// Create IP5209 on a bus
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Self {
+        Self { i2c }
     }
 
     /// Shutdown under light load (144mA and 8s)
@@ -103,9 +72,70 @@ impl IP5209 {
 
         Ok(())
     }
+}
+
+impl BatteryDevice for IP5209 {
+    fn chip(&self) -> BatteryChip {
+        BatteryChip::Ip5209
+    }
+
+    fn model(&self) -> &'static str {
+        MODEL_V2
+    }
+
+    /// Read voltage (V)
+    fn read_voltage(&self) -> Result<f64> {
+        let low = self.i2c.smbus_read_byte(0xa2)? as u16;
+        let high = self.i2c.smbus_read_byte(0xa3)? as u16;
+
+        // check negative values
+        let voltage = if high & 0x20 == 0x20 {
+            let v = (((high | 0b1100_0000) << 8) + low) as i16;
+            2600.0 - (v as f64) * 0.26855
+        } else {
+            let v = ((high & 0x1f) << 8) + low;
+            2600.0 + (v as f64) * 0.26855
+        };
+
+        Ok(voltage / 1000.0)
+    }
+
+    /// Read intensity (A), positive is charging and negative is discharging
+    fn read_intensity(&self) -> Result<f64> {
+        let low = self.i2c.smbus_read_byte(0xa4)? as u16;
+        let high = self.i2c.smbus_read_byte(0xa5)? as u16;
+
+        // check negative value
+        let intensity = if high & 0x20 == 0x20 {
+            let i = (((high | 0b1100_0000) << 8) + low) as i16;
+            (i as f64) * 0.745985
+        } else {
+            let i = ((high & 0x1f) << 8) + low;
+            (i as f64) * 0.745985
+        };
+
+        Ok(intensity / 1000.0)
+    }
+
+    /// Enable gpio and auto shutdown
+    fn init(&self) -> Result<()> {
+        if self.init_gpio().is_ok() {
+            log::info!("Init GPIO success");
+        } else {
+            log::error!("Init GPIO failed");
+        }
+
+        if self.init_auto_shutdown().is_ok() {
+            log::info!("Init auto shutdown success");
+        } else {
+            log::error!("Init auto shutdown failed");
+        }
+
+        Ok(())
+    }
 
     /// read gpio tap
-    pub fn read_gpio_tap(&self) -> Result<u8> {
+    fn read_gpio_tap(&self) -> Result<u8> {
         let v = self.i2c.smbus_read_byte(0x55)?;
         Ok(v)
     }
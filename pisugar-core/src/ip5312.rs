@@ -1,52 +1,26 @@
-use rppal::i2c::I2c;
+use crate::bus::{open_rppal, I2cBus};
 
 use crate::Error;
 use crate::I2cError;
-use crate::Result;
+use crate::{BatteryChip, BatteryDevice, Result, MODEL_V2_PRO};
 
 /// Idle intensity
 const PI_PRO_IDLE_INTENSITY: f64 = 0.25;
 
 /// IP5312, pi-3/4 bat chip
 pub struct IP5312 {
-    i2c: I2c,
+    i2c: Box<dyn I2cBus>,
 }
 
 impl IP5312 {
     /// Create new IP5312
     pub fn new(i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::new()?;
-        i2c.set_slave_address(i2c_addr)?;
-        Ok(Self { i2c })
+        Ok(Self::with_bus(open_rppal(i2c_addr)?))
     }
 
-    /// Read voltage (V)
-    pub fn read_voltage(&self) -> Result<f64> {
-        let low = self.i2c.smbus_read_byte(0xd0)? as u16;
-        let high = self.i2c.smbus_read_byte(0xd1)? as u16;
-
-        if low == 0 && high == 0 {
-            return Err(Error::I2c(I2cError::FeatureNotSupported));
-        }
-
-        let v = ((high & 0b0011_1111) << 8) + low;
-        let v = (v as f64) * 0.26855 + 2600.0;
-        Ok(v / 1000.0)
-    }
-
-    /// Read intensity (A)
-    pub fn read_intensity(&self) -> Result<f64> {
-        let low = self.i2c.smbus_read_byte(0xd2)? as u16;
-        let high = self.i2c.smbus_read_byte(0xd3)? as u16;
-
-        let intensity = if high & 0x20 != 0 {
-            let i = (((high | 0b1100_0000) << 8) + low) as i16;
-            (i as f64) * 2.68554
-        } else {
-            let i = ((high & 0x1f) << 8) + low;
-            (i as f64) * 2.68554
-        };
-        Ok(intensity / 1000.0)
+    /// Create IP5312 on a bus
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Self {
+        Self { i2c }
     }
 
     /// Shutdown under light load (126mA and 8s)
@@ -99,14 +73,6 @@ impl IP5312 {
         Ok(())
     }
 
-    /// Read gpio tap
-    pub fn read_gpio_tap(&self) -> Result<u8> {
-        let mut v = self.i2c.smbus_read_byte(0x58)?;
-        v &= 0b0000_0010;
-
-        Ok(v)
-    }
-
     /// Force shutdown
     pub fn force_shutdown(&self) -> Result<()> {
         // enable force shutdown
@@ -122,3 +88,67 @@ impl IP5312 {
         Ok(())
     }
 }
+
+impl BatteryDevice for IP5312 {
+    fn chip(&self) -> BatteryChip {
+        BatteryChip::Ip5312
+    }
+
+    fn model(&self) -> &'static str {
+        MODEL_V2_PRO
+    }
+
+    /// Read voltage (V)
+    fn read_voltage(&self) -> Result<f64> {
+        let low = self.i2c.smbus_read_byte(0xd0)? as u16;
+        let high = self.i2c.smbus_read_byte(0xd1)? as u16;
+
+        if low == 0 && high == 0 {
+            return Err(Error::I2c(I2cError::FeatureNotSupported));
+        }
+
+        let v = ((high & 0b0011_1111) << 8) + low;
+        let v = (v as f64) * 0.26855 + 2600.0;
+        Ok(v / 1000.0)
+    }
+
+    /// Read intensity (A), positive is charging and negative is discharging
+    fn read_intensity(&self) -> Result<f64> {
+        let low = self.i2c.smbus_read_byte(0xd2)? as u16;
+        let high = self.i2c.smbus_read_byte(0xd3)? as u16;
+
+        let intensity = if high & 0x20 != 0 {
+            let i = (((high | 0b1100_0000) << 8) + low) as i16;
+            (i as f64) * 2.68554
+        } else {
+            let i = ((high & 0x1f) << 8) + low;
+            (i as f64) * 2.68554
+        };
+        Ok(intensity / 1000.0)
+    }
+
+    /// Enable gpio and auto shutdown
+    fn init(&self) -> Result<()> {
+        if self.init_gpio().is_ok() {
+            log::info!("Init GPIO success");
+        } else {
+            log::error!("Init GPIO failed");
+        }
+
+        if self.init_auto_shutdown().is_ok() {
+            log::info!("Init auto shutdown success");
+        } else {
+            log::error!("Init auto shutdown failed");
+        }
+
+        Ok(())
+    }
+
+    /// Read gpio tap
+    fn read_gpio_tap(&self) -> Result<u8> {
+        let mut v = self.i2c.smbus_read_byte(0x58)?;
+        v &= 0b0000_0010;
+
+        Ok(v)
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// Max jobs kept, oldest are dropped
+const JOBS_CAPACITY: usize = 32;
+
+/// Job event, `job <id> <state> <kind>`, progress (%) appended while running
+pub const JOB: &str = "job";
+
+/// State of a job
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Background job of a long operation, e.g. `rtc_web`, result or error once finished
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: String,
+    pub state: JobState,
+    /// Progress (%), 100 once done
+    pub progress: u8,
+    pub result: Option<String>,
+    pub started_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+}
+
+impl Job {
+    /// State event payload
+    pub fn event(&self) -> String {
+        match self.state {
+            JobState::Running => format!(
+                "{} {} {} {} {}",
+                JOB,
+                self.id,
+                self.state.as_str(),
+                self.kind,
+                self.progress
+            ),
+            _ => format!("{} {} {} {}", JOB, self.id, self.state.as_str(), self.kind),
+        }
+    }
+}
+
+/// Recent jobs by id, ids start at 1 and are not reused in a run
+#[derive(Default)]
+pub struct JobStore {
+    jobs: VecDeque<Job>,
+    next_id: u64,
+}
+
+impl JobStore {
+    /// Start a running job of kind, id of the job
+    pub fn start(&mut self, kind: &str) -> u64 {
+        self.next_id += 1;
+        if self.jobs.len() == JOBS_CAPACITY {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back(Job {
+            id: self.next_id,
+            kind: kind.to_string(),
+            state: JobState::Running,
+            progress: 0,
+            result: None,
+            started_at: Local::now(),
+            finished_at: None,
+        });
+        self.next_id
+    }
+
+    fn running_mut(&mut self, id: u64) -> Option<&mut Job> {
+        self.jobs
+            .iter_mut()
+            .find(|j| j.id == id && j.state == JobState::Running)
+    }
+
+    /// Progress (%) of a running job, none if not running
+    pub fn set_progress(&mut self, id: u64, progress: u8) -> Option<&Job> {
+        let job = self.running_mut(id)?;
+        job.progress = progress.min(100);
+        Some(job)
+    }
+
+    /// Finish a running job with its result or error, none if not running, e.g. cancelled
+    pub fn finish(&mut self, id: u64, result: Result<String, String>) -> Option<&Job> {
+        let job = self.running_mut(id)?;
+        let (state, result) = match result {
+            Ok(r) => (JobState::Done, r),
+            Err(e) => (JobState::Failed, e),
+        };
+        if state == JobState::Done {
+            job.progress = 100;
+        }
+        job.state = state;
+        job.result = Some(result);
+        job.finished_at = Some(Local::now());
+        Some(job)
+    }
+
+    /// Cancel a running job, its result is discarded, none if not running
+    pub fn cancel(&mut self, id: u64) -> Option<&Job> {
+        let job = self.running_mut(id)?;
+        job.state = JobState::Cancelled;
+        job.finished_at = Some(Local::now());
+        Some(job)
+    }
+
+    /// Whether job is running, jobs check it before side effects
+    pub fn running(&self, id: u64) -> bool {
+        self.get(id).map(|j| j.state == JobState::Running) == Some(true)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    /// Jobs, oldest first
+    pub fn list(&self) -> Vec<&Job> {
+        self.jobs.iter().collect()
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike, Utc};
+
+use crate::{wake_schedule, Error, Result, RtcDevice, RtcModel, SD3078Time, SCHEDULE_DAYS};
+
+/// Rtc class devices of the kernel
+const SYS_CLASS_RTC: &str = "/sys/class/rtc";
+
+/// Ioctls of linux/rtc.h
+const RTC_AIE_OFF: libc::c_ulong = 0x7002;
+const RTC_RD_TIME: libc::c_ulong = 0x8024_7009;
+const RTC_SET_TIME: libc::c_ulong = 0x4024_700a;
+const RTC_WKALM_SET: libc::c_ulong = 0x4028_700f;
+const RTC_WKALM_RD: libc::c_ulong = 0x8028_7010;
+
+/// `struct rtc_time`, fields of `struct tm`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct RtcTime {
+    tm_sec: libc::c_int,
+    tm_min: libc::c_int,
+    tm_hour: libc::c_int,
+    tm_mday: libc::c_int,
+    tm_mon: libc::c_int,
+    tm_year: libc::c_int,
+    tm_wday: libc::c_int,
+    tm_yday: libc::c_int,
+    tm_isdst: libc::c_int,
+}
+
+/// `struct rtc_wkalrm`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct RtcWkalrm {
+    enabled: libc::c_uchar,
+    pending: libc::c_uchar,
+    time: RtcTime,
+}
+
+impl RtcTime {
+    /// Local time of the rtc time, the kernel rtc keeps UTC. Alarms of RTC_WKALM_RD have -1 of
+    /// unset fields, unset dates are today and an unset time of day is no alarm
+    fn to_local(self) -> Option<DateTime<Local>> {
+        if self.tm_sec < 0 || self.tm_min < 0 || self.tm_hour < 0 {
+            return None;
+        }
+        let today = Utc::today();
+        let year = if self.tm_year < 0 {
+            today.year()
+        } else {
+            self.tm_year + 1900
+        };
+        let month = if self.tm_mon < 0 {
+            today.month()
+        } else {
+            self.tm_mon as u32 + 1
+        };
+        let day = if self.tm_mday < 0 {
+            today.day()
+        } else {
+            self.tm_mday as u32
+        };
+        let utc = Utc
+            .ymd_opt(year, month, day)
+            .and_hms_opt(self.tm_hour as u32, self.tm_min as u32, self.tm_sec as u32)
+            .single()?;
+        Some(utc.with_timezone(&Local))
+    }
+}
+
+/// Local time of the chip to UTC of the kernel rtc
+impl From<SD3078Time> for RtcTime {
+    fn from(t: SD3078Time) -> Self {
+        let utc = match DateTime::<Local>::try_from(t) {
+            Ok(dt) => dt.with_timezone(&Utc).naive_utc(),
+            Err(_) => {
+                log::warn!("Invalid local time {}, written to kernel rtc as UTC", t);
+                return Self {
+                    tm_sec: t.second() as libc::c_int,
+                    tm_min: t.minute() as libc::c_int,
+                    tm_hour: t.hour() as libc::c_int,
+                    tm_mday: t.day() as libc::c_int,
+                    tm_mon: t.month() as libc::c_int - 1,
+                    tm_year: t.year() as libc::c_int - 1900,
+                    tm_wday: t.weekday() as libc::c_int,
+                    tm_yday: 0,
+                    tm_isdst: 0,
+                };
+            }
+        };
+        Self {
+            tm_sec: utc.second() as libc::c_int,
+            tm_min: utc.minute() as libc::c_int,
+            tm_hour: utc.hour() as libc::c_int,
+            tm_mday: utc.day() as libc::c_int,
+            tm_mon: utc.month0() as libc::c_int,
+            tm_year: utc.year() as libc::c_int - 1900,
+            tm_wday: utc.weekday().num_days_from_sunday() as libc::c_int,
+            tm_yday: utc.ordinal0() as libc::c_int,
+            tm_isdst: 0,
+        }
+    }
+}
+
+/// Rtc device of the kernel driver bound to the chip at i2c address, e.g. `/dev/rtc0` of
+/// `/sys/class/rtc/rtc0/device` -> `1-0032`
+pub fn kernel_rtc_device(addr: u16) -> Option<PathBuf> {
+    let suffix = format!("-{:04x}", addr);
+    for entry in fs::read_dir(SYS_CLASS_RTC).ok()?.flatten() {
+        let device = match fs::canonicalize(entry.path().join("device")) {
+            Ok(device) => device,
+            Err(_) => continue,
+        };
+        let bound = device
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.ends_with(&suffix))
+            == Some(true);
+        if bound {
+            return Some(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    None
+}
+
+/// Rtc through the kernel rtc driver, `/dev/rtc*` ioctls instead of raw i2c, the bus is not
+/// shared with the driver
+pub struct KernelRtc {
+    model: RtcModel,
+    path: PathBuf,
+    file: File,
+}
+
+impl KernelRtc {
+    /// Open rtc device of model
+    pub fn open(model: RtcModel, path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+        Ok(Self {
+            model,
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    fn ioctl<T>(&self, request: libc::c_ulong, arg: &mut T) -> Result<()> {
+        let r = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg as *mut T) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            return Err(Error::Other(format!("{}: {}", self.path.display(), e)));
+        }
+        Ok(())
+    }
+
+    fn read_wkalrm(&self) -> Result<RtcWkalrm> {
+        let mut alarm = RtcWkalrm::default();
+        self.ioctl(RTC_WKALM_RD, &mut alarm)?;
+        Ok(alarm)
+    }
+}
+
+impl RtcDevice for KernelRtc {
+    fn model(&self) -> RtcModel {
+        self.model
+    }
+
+    fn kernel_device(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn read_time(&self) -> Result<SD3078Time> {
+        let mut t = RtcTime::default();
+        self.ioctl(RTC_RD_TIME, &mut t)?;
+        t.to_local()
+            .map(SD3078Time::from)
+            .ok_or_else(|| Error::Other(format!("Invalid time of {}", self.path.display())))
+    }
+
+    fn write_time(&self, t: SD3078Time) -> Result<()> {
+        let mut t: RtcTime = t.into();
+        self.ioctl(RTC_SET_TIME, &mut t)
+    }
+
+    fn read_alarm_time(&self) -> Result<SD3078Time> {
+        self.read_wkalrm()?
+            .time
+            .to_local()
+            .map(SD3078Time::from)
+            .ok_or_else(|| Error::Other(format!("No alarm of {}", self.path.display())))
+    }
+
+    fn read_alarm_enabled(&self) -> Result<bool> {
+        Ok(self.read_wkalrm()?.enabled != 0)
+    }
+
+    fn read_alarm_flag(&self) -> Result<bool> {
+        Ok(self.read_wkalrm()?.pending != 0)
+    }
+
+    /// Set the alarm again, drivers clear the flag on set
+    fn clear_alarm_flag(&self) -> Result<()> {
+        let mut alarm = self.read_wkalrm()?;
+        alarm.pending = 0;
+        self.ioctl(RTC_WKALM_SET, &mut alarm)
+    }
+
+    fn disable_alarm(&self) -> Result<()> {
+        self.ioctl(RTC_AIE_OFF, &mut 0)
+    }
+
+    /// Set the next alarm of weekdays, kernel alarms do not repeat, the alarm of config is set
+    /// again on start after each wake up
+    fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()> {
+        let alarm: DateTime<Local> = t
+            .try_into()
+            .map_err(|_| Error::Other(format!("Invalid alarm time {}", t)))?;
+        let next = wake_schedule(alarm, weekday_repeat, Local::now(), SCHEDULE_DAYS)
+            .first()
+            .map(|e| e.time)
+            .ok_or_else(|| Error::Other("No alarm of weekdays".to_string()))?;
+        let mut alarm = RtcWkalrm {
+            enabled: 1,
+            pending: 0,
+            time: SD3078Time::from(next).into(),
+        };
+        self.ioctl(RTC_WKALM_SET, &mut alarm)
+    }
+
+    fn test_scratch(&self) -> Result<()> {
+        Err(Error::Other(format!(
+            "No scratch register through the kernel driver of {}",
+            self.path.display()
+        )))
+    }
+}
@@ -1,27 +1,98 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::{From, TryInto};
 use std::fmt;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Display, Formatter, Write as _};
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Datelike, Local, Timelike};
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Timelike};
 use rppal::i2c::Error as I2cError;
 use serde::export::Result::Err;
 use serde::{Deserialize, Serialize};
 
+mod adjtime;
+mod auth;
+mod battery;
+mod bus;
+mod clock;
+mod display;
+mod ds3231;
+mod external;
+#[cfg(feature = "fake-i2c")]
+mod fake;
+mod history;
+mod idle;
+mod ina219;
 mod ip5209;
 mod ip5312;
+mod job;
+mod kernel_rtc;
+mod loadshed;
+mod max17048;
+mod notify;
+mod pcf8563;
+mod power;
+mod protocol;
+mod query;
+mod rtc;
+mod schedule;
 mod sd3078;
-
+mod selftest;
+mod session;
+mod shutdown;
+mod signature;
+mod soc;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod system;
+mod template;
+mod throttled;
+mod wol;
+
+pub use adjtime::*;
+pub use auth::*;
+pub use battery::*;
+pub use bus::*;
+pub use clock::*;
+pub use display::*;
+pub use ds3231::*;
+pub use external::*;
+#[cfg(feature = "fake-i2c")]
+pub use fake::*;
+pub use history::*;
+pub use idle::*;
+pub use ina219::INA219;
 pub use ip5209::IP5209;
 pub use ip5312::IP5312;
+pub use job::*;
+pub use kernel_rtc::*;
+pub use loadshed::*;
+pub use max17048::MAX17048;
+pub use notify::*;
+pub use pcf8563::*;
+pub use power::*;
+pub use protocol::*;
+pub use query::*;
+pub use rtc::*;
+pub use schedule::*;
 pub use sd3078::*;
+pub use selftest::*;
+pub use session::*;
+pub use shutdown::*;
+pub use signature::*;
+pub use soc::*;
+pub use stats::*;
+pub use system::*;
+pub use template::*;
+pub use throttled::*;
+pub use wol::*;
 
 /// Time host
 pub const TIME_HOST: &str = "http://cdn.pisugar.com";
@@ -30,14 +101,67 @@ pub const TIME_HOST: &str = "http://cdn.pisugar.com";
 pub const I2C_READ_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
 /// RTC address, SD3078
-const I2C_ADDR_RTC: u16 = 0x32;
+pub const I2C_ADDR_RTC: u16 = 0x32;
 
 /// Battery address, IP5209/IP5312
-const I2C_ADDR_BAT: u16 = 0x75;
+pub const I2C_ADDR_BAT: u16 = 0x75;
+
+/// Max spread of steady current readings (A)
+const CURRENT_STEADY_RANGE: f64 = 0.02;
+
+/// Max plausible current zero offset (A)
+const CURRENT_ZERO_MAX_OFFSET: f64 = 0.05;
+
+/// Consecutive polls before a charging change is accepted
+const CHARGING_DEBOUNCE_POLLS: u32 = 3;
+
+/// Marker of the alarm of `suspend_for` armed before a halt, in the state dir, the alarm of
+/// config is restored on the next start
+const ALARM_RESTORE_FILE: &str = "alarm_restore";
+
+/// Plausible voltage range of a li-ion cell, out of range is a missing battery
+const BATTERY_PLAUSIBLE_VOLTAGE: (f64, f64) = (2.5, 4.5);
+
+/// Config fields not readable over protocol
+const CONFIG_SECRET_FIELDS: [&str; 1] = ["auth_tokens"];
+
+/// Config keys of secrets and endpoints, redacted at any depth, e.g. notifier tokens and users
+const CONFIG_SECRET_KEYS: [&str; 7] = [
+    "token", "password", "secret", "key", "url", "user", "server",
+];
+
+/// Redact secrets of config json by key
+pub fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if CONFIG_SECRET_KEYS.iter().any(|r| k.contains(r)) && !v.is_null() {
+                    *v = serde_json::Value::String("<redacted>".to_string());
+                } else {
+                    redact_secrets(v);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => (),
+    }
+}
+
+/// Suspend of systemd
+const SUSPEND_SHELL: &str = "systemctl suspend";
+
+/// Power off of a failed `shutdown_command`, systemd shutdown or busybox poweroff
+const SHUTDOWN_SHELL: &str = "shutdown --poweroff 0 || poweroff";
+
+/// Max events held in quiet hours, oldest are dropped
+const HELD_EVENTS_CAPACITY: usize = 64;
 
 pub const MODEL_V2: &str = "PiSugar 2";
 pub const MODEL_V2_PRO: &str = "PiSugar 2 Pro";
 
+/// Power-on of PiSugar 2 and 2 Pro, boost output starts by button or external power, not configurable
+pub const POWER_ON_MODE_BUTTON: &str = "button";
+
 /// PiSugar error
 #[derive(Debug)]
 pub enum Error {
@@ -103,9 +227,10 @@ fn convert_battery_voltage_to_level(voltage: f64) -> f64 {
     0.0
 }
 
-pub fn sys_write_time(dt: DateTime<Local>) {
+/// Set system time, and the rtc of the kernel by `hwclock -w` unless the daemon is the hwclock
+pub fn sys_write_time(dt: DateTime<Local>, hwclock: bool) {
     let cmd = format!(
-        "/bin/date -s {}-{}-{} {}:{}:{}",
+        "date -s \"{}-{}-{} {}:{}:{}\"",
         dt.year(),
         dt.month(),
         dt.day(),
@@ -114,7 +239,10 @@ pub fn sys_write_time(dt: DateTime<Local>) {
         dt.second()
     );
     if let Ok(_) = execute_shell(cmd.as_str()) {
-        let cmd = "/sbin/hwclock -w";
+        if !hwclock {
+            return;
+        }
+        let cmd = "hwclock -w";
         if let Ok(_) = execute_shell(cmd) {
             return;
         }
@@ -122,8 +250,56 @@ pub fn sys_write_time(dt: DateTime<Local>) {
     log::error!("Failed to write time to system");
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_event_buffer() -> usize {
+    16
+}
+
+fn default_shutdown_warning() -> u64 {
+    30 * 60
+}
+
+fn default_shutdown_prepare_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_command() -> String {
+    "systemctl poweroff".to_string()
+}
+
+fn default_shutdown_grace() -> u64 {
+    60
+}
+
+fn default_quiet_poll_interval() -> u64 {
+    60
+}
+
+fn default_battery_shunt_ohms() -> f64 {
+    0.1
+}
+
+fn default_session_ttl() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_low_battery_level() -> f64 {
+    15.0
+}
+
+fn default_low_write_flush() -> u64 {
+    600
+}
+
+fn default_display_addr() -> u16 {
+    I2C_ADDR_DISPLAY
+}
+
 /// PiSugar configuration
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_wake_time: Option<DateTime<Local>>,
@@ -131,6 +307,10 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_wake_repeat: u8,
 
+    /// Clear rtc alarm flag once `alarm_fired` is sent
+    #[serde(default)]
+    pub alarm_auto_clear: bool,
+
     #[serde(default)]
     pub single_tap_enable: bool,
 
@@ -151,6 +331,218 @@ pub struct PiSugarConfig {
 
     #[serde(default)]
     pub auto_shutdown_level: f64,
+
+    #[serde(default)]
+    pub soc_algorithm: SocAlgorithm,
+
+    #[serde(default)]
+    pub level_rounding: LevelRounding,
+
+    #[serde(default)]
+    pub level_hold_full: bool,
+
+    #[serde(default)]
+    pub current_zero_offset: f64,
+
+    #[serde(default)]
+    pub current_zero_auto: bool,
+
+    #[serde(default)]
+    pub throttled_enable: bool,
+
+    #[serde(default)]
+    pub system_metrics_enable: bool,
+
+    #[serde(default)]
+    pub on_rtc_battery_low_shell: String,
+
+    #[serde(default)]
+    pub on_i2c_error_shell: String,
+
+    #[serde(default)]
+    pub wol_macs: Vec<String>,
+
+    #[serde(default)]
+    pub wol_on_power_restored: bool,
+
+    #[serde(default)]
+    pub wol_times: Vec<String>,
+
+    #[serde(default)]
+    pub history_interval: u64,
+
+    #[serde(default)]
+    pub history_capacity: usize,
+
+    /// History storage, `ring` file or `sqlite` database in state dir
+    #[serde(default)]
+    pub history_backend: HistoryBackend,
+
+    /// Downsampling of old history, the capacity is unlimited if enabled
+    #[serde(default)]
+    pub history_retention: HistoryRetention,
+
+    #[serde(default)]
+    pub readonly: bool,
+
+    #[serde(default)]
+    pub gps_source: String,
+
+    #[serde(default)]
+    pub ntp_cooperate: bool,
+
+    /// Update /etc/adjtime on rtc writes, as hwclock does
+    #[serde(default)]
+    pub adjtime_enable: bool,
+
+    #[serde(default = "default_true")]
+    pub rtc_enabled: bool,
+
+    /// Rtc chip, sd3078, ds3231 or pcf8563
+    #[serde(default)]
+    pub rtc_model: RtcModel,
+
+    /// Use `/dev/rtc*` of a kernel rtc driver bound to the chip instead of raw i2c
+    #[serde(default = "default_true")]
+    pub rtc_kernel_driver: bool,
+
+    #[serde(default = "default_true")]
+    pub battery_enabled: bool,
+
+    /// Battery chip, ip5209, ip5312, ina219 or max17048, PiSugar chips are detected if not set
+    #[serde(default)]
+    pub battery_chip: Option<BatteryChip>,
+
+    /// Shunt resistor (ohm) of ina219
+    #[serde(default = "default_battery_shunt_ohms")]
+    pub battery_shunt_ohms: f64,
+
+    /// Battery capacity (mAh), default of the board if 0
+    #[serde(default)]
+    pub battery_capacity: f64,
+
+    #[serde(default)]
+    pub auth_tokens: HashMap<String, Role>,
+
+    #[serde(default)]
+    pub auth_roles: HashMap<Role, Vec<String>>,
+
+    /// Commands served by a listener, e.g. a public tcp listener of `get` only
+    #[serde(default)]
+    pub listener_commands: HashMap<Listener, Vec<String>>,
+
+    #[serde(default = "default_event_buffer")]
+    pub event_buffer: usize,
+
+    #[serde(default)]
+    pub debug_enable: bool,
+
+    #[serde(default)]
+    pub heartbeat_url: String,
+
+    #[serde(default)]
+    pub heartbeat_interval: u64,
+
+    /// Announce host name and addresses in the heartbeat and the retained mqtt `network` topic
+    #[serde(default)]
+    pub announce_network: bool,
+
+    #[serde(default)]
+    pub syslog_server: String,
+
+    #[serde(default)]
+    pub mqtt_templates: HashMap<String, String>,
+
+    #[serde(default)]
+    pub notifiers: Vec<Notifier>,
+
+    #[serde(default)]
+    pub display_enabled: bool,
+
+    #[serde(default = "default_display_addr")]
+    pub display_addr: u16,
+
+    #[serde(default)]
+    pub i2c_lock_file: String,
+
+    #[serde(default)]
+    pub i2c_delay_us: u64,
+
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: u64,
+
+    #[serde(default = "default_shutdown_warning")]
+    pub shutdown_warning: u64,
+
+    /// Max seconds of waiting for shutdown participants to acknowledge `prepare_shutdown`
+    #[serde(default = "default_shutdown_prepare_timeout")]
+    pub shutdown_prepare_timeout: u64,
+
+    /// Power off command, a template of the shutdown record, e.g. `poweroff` of openrc or buildroot
+    #[serde(default = "default_shutdown_command")]
+    pub shutdown_command: String,
+
+    /// Seconds after boot without auto shutdown, readings are unreliable and voltage sags during boot
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace: u64,
+
+    /// Windows of quiet hours, `HH:MM-HH:MM`, may cross midnight
+    #[serde(default)]
+    pub quiet_hours: Vec<String>,
+
+    /// Seconds between battery and rtc reads in quiet hours, no tap polls
+    #[serde(default = "default_quiet_poll_interval")]
+    pub quiet_poll_interval: u64,
+
+    #[serde(default)]
+    pub idle_shutdown: IdleShutdownPolicy,
+
+    #[serde(default)]
+    pub load_shedding: Vec<LoadShedRule>,
+
+    #[serde(default)]
+    pub external_power: Option<ExternalPowerSource>,
+
+    /// Sensors of charger inputs, the first reading power is the input source
+    #[serde(default)]
+    pub input_sensors: Vec<InputSensor>,
+
+    #[serde(default = "default_low_battery_level")]
+    pub low_battery_level: f64,
+
+    /// Low battery of averaged voltage, disabled if 0
+    #[serde(default)]
+    pub low_battery_voltage: f64,
+
+    /// Auto shutdown of averaged voltage, disabled if 0
+    #[serde(default)]
+    pub auto_shutdown_voltage: f64,
+
+    /// Batch history writes and skip syncing state files, for SD cards
+    #[serde(default)]
+    pub low_write: bool,
+
+    /// Flush interval of batched writes (s)
+    #[serde(default = "default_low_write_flush")]
+    pub low_write_flush: u64,
+}
+
+/// History capacity of config, unlimited with retention
+fn history_capacity(config: &PiSugarConfig) -> usize {
+    if config.history_retention.enabled {
+        usize::MAX
+    } else if config.history_capacity > 0 {
+        config.history_capacity
+    } else {
+        HISTORY_DEFAULT_CAPACITY
+    }
+}
+
+/// Default config, same as an empty config file
+impl Default for PiSugarConfig {
+    fn default() -> Self {
+        serde_json::from_str("{}").expect("Unexpected config default")
+    }
 }
 
 impl PiSugarConfig {
@@ -176,71 +568,179 @@ impl PiSugarConfig {
 
 /// PiSugar status
 pub struct PiSugarStatus {
-    ip5209: IP5209,
-    ip5312: IP5312,
-    sd3078: SD3078,
+    opener: I2cOpener,
+    bus_opener: I2cOpener,
+    battery: Option<Box<dyn BatteryDevice>>,
+    rtc_device: Option<Box<dyn RtcDevice>>,
+    display: Option<SSD1306>,
+    load_shedder: Option<LoadShedder>,
+    external_power: Option<ExternalPower>,
+    input_sensors: Vec<(InputSource, ExternalPower)>,
+    input_source: InputSource,
+    hardware_id: Option<String>,
+    battery_enabled: bool,
+    hardware_error: Option<String>,
     model: String,
     voltage: f64,
     intensity: f64,
     level: f64,
     level_records: VecDeque<f64>,
+    voltage_records: VecDeque<f64>,
+    intensity_records: VecDeque<f64>,
+    current_zero_offset: Option<f64>,
+    soc: SocEstimator,
+    stats: PiSugarStats,
+    history: Box<dyn HistoryStore>,
+    history_updated_at: Option<Instant>,
+    history_retained_at: Option<Instant>,
+    history_flushed_at: Option<Instant>,
+    shutdown_history: ShutdownHistory,
     updated_at: Instant,
     rtc_time: DateTime<Local>,
+    /// Rtc time minus system time (s) of the last rtc read
+    rtc_drift: Option<f64>,
+    rtc_battery_low: bool,
+    charging: bool,
+    charging_pending: u32,
+    battery_present: bool,
+    present_pending: u32,
+    wol_scheduled_at: String,
+    rtc_i2c_error: bool,
+    bat_i2c_error: bool,
     gpio_tap_history: String,
+    full: bool,
+    full_at: Option<DateTime<Local>>,
+    hold_full: bool,
+    power_state: PowerState,
+    /// Rtc alarm flag seen, `alarm_fired` is sent once until cleared
+    alarm_fired: bool,
+    /// Critical power state suppressed in the startup grace, logged once when entered
+    grace_suppressed: bool,
+    /// Automatic shutdown prepared, waiting for participants
+    shutdown_pending: bool,
+    shutdown_deadline: Option<Instant>,
+    /// Shutdown participants, acknowledged or not
+    shutdown_participants: HashMap<u64, bool>,
+    next_participant: u64,
+    events: VecDeque<BatteryEvent>,
+    /// In quiet hours, events are held
+    quiet: bool,
+    held_events: VecDeque<BatteryEvent>,
 }
 
 impl PiSugarStatus {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: &PiSugarConfig) -> Result<Self> {
+        Self::new_with_opener(config, Arc::new(open_rppal))
+    }
+
+    /// Create with i2c buses of opener, e.g. fake devices
+    pub fn new_with_opener(config: &PiSugarConfig, opener: I2cOpener) -> Result<Self> {
+        let bus_opener = arbitrated(
+            opener.clone(),
+            &config.i2c_lock_file,
+            Duration::from_micros(config.i2c_delay_us),
+        );
         let mut level_records = VecDeque::with_capacity(10);
+        let mut voltage_records = VecDeque::with_capacity(10);
+        let mut intensity_records = VecDeque::with_capacity(10);
 
         let mut model = String::from(MODEL_V2);
         let mut voltage = 0.0;
         let mut intensity = 0.0;
+        let mut gauge_level = None;
 
-        let ip5209 = IP5209::new(I2C_ADDR_BAT)?;
-        let ip5312 = IP5312::new(I2C_ADDR_BAT)?;
-        let sd3078 = SD3078::new(I2C_ADDR_RTC)?;
-
-        if let Ok(v) = ip5312.read_voltage() {
-            log::info!("PiSugar with IP5312");
-            model = String::from(MODEL_V2_PRO);
-            voltage = v;
-            intensity = ip5312.read_intensity().unwrap_or(0.0);
+        let (mut batteries, rtc_device, hardware_error) = match open_i2c(config, &bus_opener) {
+            Ok((batteries, rtc_device)) => (batteries, rtc_device, None),
+            Err(e) => {
+                log::error!("I2C unavailable, degraded mode: {}", e);
+                (Vec::new(), None, Some(e.to_string()))
+            }
+        };
+        if !config.rtc_enabled {
+            log::info!("RTC disabled");
+        }
+        let display = if config.display_enabled && hardware_error.is_none() {
+            match bus_opener(config.display_addr).and_then(SSD1306::with_bus) {
+                Ok(display) => Some(display),
+                Err(e) => {
+                    log::error!("Display unavailable: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
-            if ip5312.init_gpio().is_ok() {
-                log::info!("Init GPIO success");
-            } else {
-                log::error!("Init GPIO failed");
+        // opened with hardware, recovered status takes the pins
+        let load_shedder = if config.load_shedding.is_empty() || hardware_error.is_some() {
+            None
+        } else {
+            match LoadShedder::new(&config.load_shedding) {
+                Ok(shedder) => Some(shedder),
+                Err(e) => {
+                    log::error!("Load shedding unavailable: {}", e);
+                    None
+                }
             }
+        };
 
-            if ip5312.init_auto_shutdown().is_ok() {
-                log::info!("Init auto shutdown success");
-            } else {
-                log::error!("Init auto shutdown failed");
+        let external_power = match &config.external_power {
+            Some(source) if hardware_error.is_none() => {
+                match ExternalPower::open(source, &bus_opener) {
+                    Ok(sensor) => Some(sensor),
+                    Err(e) => {
+                        log::error!("External power sensor unavailable: {}", e);
+                        None
+                    }
+                }
             }
-        } else if let Ok(v) = ip5209.read_voltage() {
-            log::info!("PiSugar with IP5209");
-            model = String::from(MODEL_V2);
-            voltage = v;
-            intensity = ip5209.read_intensity().unwrap_or(0.0);
+            _ => None,
+        };
 
-            if ip5209.init_gpio().is_ok() {
-                log::info!("Init GPIO success");
-            } else {
-                log::error!("Init GPIO failed");
+        let mut input_sensors = Vec::new();
+        for s in config.input_sensors.iter() {
+            if hardware_error.is_some() {
+                break;
             }
+            match ExternalPower::open(&s.sensor, &bus_opener) {
+                Ok(sensor) => input_sensors.push((s.input, sensor)),
+                Err(e) => log::error!("Input sensor of {} unavailable: {}", s.input.as_str(), e),
+            }
+        }
 
-            if ip5209.init_auto_shutdown().is_ok() {
-                log::info!("Init auto shutdown success");
-            } else {
-                log::error!("Init auto shutdown failed");
+        // first chip answering, or the last candidate
+        let detected = if !config.battery_enabled {
+            log::info!("Battery disabled");
+            None
+        } else {
+            let answering = batteries.iter().position(|b| b.read_voltage().is_ok());
+            answering.map(|i| batteries.remove(i))
+        };
+        let battery = if let Some(battery) = detected {
+            log::info!("PiSugar with {}", battery.chip().as_str().to_uppercase());
+            model = String::from(battery.model());
+            voltage = battery.read_voltage().unwrap_or(0.0);
+            intensity = battery.read_intensity().unwrap_or(0.0);
+            gauge_level = battery.read_level().unwrap_or(None);
+            if let Err(e) = battery.init() {
+                log::error!("Init {} failed: {}", battery.chip().as_str(), e);
             }
+            Some(battery)
         } else {
-            log::error!("PiSugar not found");
-        }
+            if config.battery_enabled && hardware_error.is_none() {
+                log::error!("PiSugar not found");
+            }
+            batteries.pop()
+        };
+        let chip = battery
+            .as_ref()
+            .map(|b| b.chip())
+            .unwrap_or(BatteryChip::Ip5209);
 
         // battery level, default 100
-        let level = if voltage > 0.0 {
+        let level = if let Some(level) = gauge_level {
+            level
+        } else if voltage > 0.0 {
             convert_battery_voltage_to_level(voltage)
         } else {
             100.0
@@ -248,32 +748,204 @@ impl PiSugarStatus {
         for _ in 0..level_records.capacity() {
             level_records.push_back(level);
         }
+        for _ in 0..voltage_records.capacity() {
+            voltage_records.push_back(voltage);
+        }
+        for _ in 0..intensity_records.capacity() {
+            intensity_records.push_back(intensity);
+        }
+        let capacity = chip.capacity(config);
+        let soc = SocEstimator::new(SocAlgorithm::default(), capacity, level);
+        let stats = PiSugarStats {
+            boot: BatteryStats::new(voltage, intensity, level),
+            full_charge: None,
+        };
+
+        // board id, rtc device id or serial number of the pi
+        let hardware_id = match rtc_device
+            .as_ref()
+            .map(|rtc| (rtc.model(), rtc.device_id()))
+        {
+            Some((model, Ok(Some(id)))) if id.iter().any(|b| *b != 0 && *b != 0xff) => {
+                let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+                Some(format!("{}-{}", model.as_str(), hex))
+            }
+            _ => pi_serial().ok().map(|s| format!("pi-{}", s)),
+        };
 
-        let rtc_now = match sd3078.read_time() {
-            Ok(t) => t.try_into().unwrap_or(Local::now()),
-            Err(_) => Local::now(),
+        let rtc_now = match rtc_device.as_ref().map(|rtc| rtc.read_time()) {
+            Some(Ok(t)) => t.try_into().unwrap_or(Local::now()),
+            _ => Local::now(),
         };
 
         Ok(Self {
-            ip5209,
-            ip5312,
-            sd3078,
+            opener,
+            bus_opener,
+            battery,
+            rtc_device,
+            display,
+            load_shedder,
+            external_power,
+            input_sensors,
+            input_source: InputSource::default(),
+            hardware_id,
+            battery_enabled: config.battery_enabled && hardware_error.is_none(),
+            hardware_error,
             model,
             voltage,
             intensity,
             level,
             level_records,
+            voltage_records,
+            intensity_records,
+            current_zero_offset: None,
+            soc,
+            stats,
+            history: Box::new(History::new(HISTORY_DEFAULT_CAPACITY)),
+            history_updated_at: None,
+            history_retained_at: None,
+            history_flushed_at: None,
+            shutdown_history: ShutdownHistory::default(),
             updated_at: Instant::now(),
             rtc_time: rtc_now,
+            rtc_drift: None,
+            rtc_battery_low: false,
+            charging: false,
+            charging_pending: 0,
+            battery_present: true,
+            present_pending: 0,
+            wol_scheduled_at: String::new(),
+            rtc_i2c_error: false,
+            bat_i2c_error: false,
             gpio_tap_history: String::with_capacity(10),
+            full: false,
+            full_at: None,
+            hold_full: false,
+            power_state: PowerState::default(),
+            alarm_fired: false,
+            grace_suppressed: false,
+            shutdown_pending: false,
+            shutdown_deadline: None,
+            shutdown_participants: HashMap::new(),
+            next_participant: 0,
+            events: VecDeque::new(),
+            quiet: false,
+            held_events: VecDeque::new(),
         })
     }
 
+    /// Reopen i2c handles
+    pub fn reopen(&mut self, config: &PiSugarConfig) -> Result<()> {
+        if self.hardware_error.is_some() {
+            return Ok(());
+        }
+        if let Some(chip) = self.battery.as_ref().map(|b| b.chip()) {
+            self.battery = Some(open_battery(chip, (self.bus_opener)(chip.addr())?, config));
+        }
+        let rtc_i2c = self
+            .rtc_device
+            .as_ref()
+            .filter(|rtc| rtc.kernel_device().is_none());
+        if let Some(model) = rtc_i2c.map(|rtc| rtc.model()) {
+            self.rtc_device = Some(open_rtc(model, (self.bus_opener)(model.addr())?));
+        }
+        Ok(())
+    }
+
+    /// Read battery voltage of the battery chip
+    fn read_voltage(&self) -> Result<f64> {
+        if let Some(e) = &self.hardware_error {
+            return Err(Error::Other(format!("Hardware unavailable: {}", e)));
+        }
+        let voltage = self.battery.as_ref().map(|b| b.read_voltage());
+        voltage.unwrap_or_else(|| Err(Error::Other("Battery disabled".to_string())))
+    }
+
+    /// RTC, error if disabled
+    fn rtc(&self) -> Result<&dyn RtcDevice> {
+        if let Some(e) = &self.hardware_error {
+            return Err(Error::Other(format!("Hardware unavailable: {}", e)));
+        }
+        self.rtc_device
+            .as_deref()
+            .ok_or_else(|| Error::Other("RTC disabled".to_string()))
+    }
+
+    /// RTC enabled
+    pub fn rtc_enabled(&self) -> bool {
+        self.rtc_device.is_some()
+    }
+
+    /// Battery enabled
+    pub fn battery_enabled(&self) -> bool {
+        self.battery_enabled
+    }
+
+    /// I2C open error, degraded mode
+    pub fn hardware_error(&self) -> Option<&str> {
+        self.hardware_error.as_deref()
+    }
+
     /// PiSugar model
     pub fn mode(&self) -> &str {
         self.model.as_str()
     }
 
+    /// Time of the last battery read
+    pub fn updated_at(&self) -> Instant {
+        self.updated_at
+    }
+
+    /// Board unique id, rtc device id or serial number of the pi
+    pub fn hardware_id(&self) -> Option<&str> {
+        self.hardware_id.as_deref()
+    }
+
+    /// Hex dump of battery chip and rtc registers, `--` if unreadable
+    pub fn register_dump(&self) -> String {
+        let mut chips = Vec::new();
+        if let Some(chip) = self.battery.as_ref().map(|b| b.chip()) {
+            chips.push((self.mode().to_string(), chip.addr(), chip.registers()));
+        }
+        let rtc_i2c = self
+            .rtc_device
+            .as_ref()
+            .filter(|rtc| rtc.kernel_device().is_none());
+        if let Some(model) = rtc_i2c.map(|rtc| rtc.model()) {
+            chips.push((
+                model.as_str().to_uppercase(),
+                model.addr(),
+                model.registers(),
+            ));
+        }
+        let mut dump = String::new();
+        for (name, addr, count) in chips {
+            let _ = writeln!(dump, "{} {:#04x}", name, addr);
+            let bus = match (self.bus_opener)(addr) {
+                Ok(bus) => bus,
+                Err(e) => {
+                    let _ = writeln!(dump, "error: {}", e);
+                    continue;
+                }
+            };
+            for reg in 0..count {
+                if reg % 16 == 0 {
+                    let _ = write!(dump, "{:02x}:", reg);
+                }
+                match bus.smbus_read_byte(reg as u8) {
+                    Ok(v) => {
+                        let _ = write!(dump, " {:02x}", v);
+                    }
+                    Err(_) => dump.push_str(" --"),
+                }
+                if reg % 16 == 15 || reg == count - 1 {
+                    dump.push('\n');
+                }
+            }
+        }
+        dump
+    }
+
     /// Battery level
     pub fn level(&self) -> f64 {
         self.level
@@ -286,47 +958,227 @@ impl PiSugarStatus {
 
     /// Update battery voltage
     pub fn update_voltage(&mut self, voltage: f64, now: Instant) {
+        self.update_level(voltage, convert_battery_voltage_to_level(voltage), now)
+    }
+
+    /// Update battery voltage with a level of a fuel gauge, in place of the voltage curve
+    fn update_level(&mut self, voltage: f64, curve_level: f64, now: Instant) {
         self.updated_at = now;
         self.voltage = voltage;
-        self.level = convert_battery_voltage_to_level(voltage);
+        self.level = self.soc.update(curve_level, self.intensity, now);
         self.level_records.pop_front();
-        self.level_records.push_back(self.level);
+        self.level_records.push_back(curve_level);
+        self.voltage_records.pop_front();
+        self.voltage_records.push_back(voltage);
+    }
+
+    /// Battery voltage averaged over recent readings, sags of load peaks are smoothed
+    pub fn voltage_avg(&self) -> f64 {
+        self.voltage_records.iter().sum::<f64>() / self.voltage_records.len().max(1) as f64
+    }
+
+    /// State of charge algorithm
+    pub fn soc_algorithm(&self) -> SocAlgorithm {
+        self.soc.algorithm()
+    }
+
+    /// Set state of charge algorithm
+    pub fn set_soc_algorithm(&mut self, algorithm: SocAlgorithm) {
+        self.soc.set_algorithm(algorithm)
     }
 
-    /// Battery intensity
+    /// Battery intensity (A), signed: positive is charging, negative is discharging
     pub fn intensity(&self) -> f64 {
         self.intensity
     }
 
-    /// Update battery intensity
+    /// Battery power (W), same sign convention as intensity
+    pub fn power(&self) -> f64 {
+        self.voltage * self.intensity
+    }
+
+    /// Update battery intensity, positive is charging
     pub fn update_intensity(&mut self, intensity: f64, now: Instant) {
         self.updated_at = now;
         self.intensity = intensity
     }
 
-    /// PiSugar battery alive
-    pub fn is_alive(&self, now: Instant) -> bool {
-        if self.updated_at + Duration::from_secs(3) >= now {
-            return true;
+    /// Update battery intensity with a raw reading, compensating current zero offset
+    fn update_raw_intensity(&mut self, raw: f64, config: &PiSugarConfig, now: Instant) {
+        self.intensity_records.pop_front();
+        self.intensity_records.push_back(raw);
+
+        // fully charged, not charging and near zero, battery current should be zero, a load or a
+        // charge current is never taken as the offset
+        let full = self.level_records.iter().all(|l| *l >= 100.0);
+        let idle = !self.charging && raw.abs() <= CURRENT_ZERO_MAX_OFFSET;
+        if config.current_zero_auto && full && idle {
+            if let Ok(offset) = self.measure_current_zero() {
+                log::debug!("Current zero offset: {}", offset);
+                self.current_zero_offset = Some(offset);
+            }
         }
-        false
+
+        let offset = self
+            .current_zero_offset
+            .unwrap_or(config.current_zero_offset);
+        self.update_intensity(raw - offset, now);
     }
 
-    /// PiSugar is charging, with voltage linear regression
-    pub fn is_charging(&self, now: Instant) -> bool {
-        if self.is_alive(now) {
-            log::debug!("levels: {:?}", self.level_records);
-            let capacity = self.level_records.capacity() as f64;
-            let x_sum = (0.0 + capacity - 1.0) * capacity / 2.0;
-            let x_bar = x_sum / capacity;
-            let y_sum: f64 = self.level_records.iter().sum();
-            let _y_bar = y_sum / capacity;
-            // k = Sum(yi * (xi - x_bar)) / Sum(xi - x_bar)^2
-            let mut iter = self.level_records.iter();
-            let mut a = 0.0;
-            let mut b = 0.0;
-            for i in 0..self.level_records.capacity() {
-                let xi = i as f64;
+    /// Measure current zero offset, battery current must be known zero
+    pub fn measure_current_zero(&self) -> Result<f64> {
+        let max = self
+            .intensity_records
+            .iter()
+            .cloned()
+            .fold(f64::MIN, f64::max);
+        let min = self
+            .intensity_records
+            .iter()
+            .cloned()
+            .fold(f64::MAX, f64::min);
+        if max - min > CURRENT_STEADY_RANGE {
+            return Err(Error::Other("Current not steady".to_string()));
+        }
+
+        let offset =
+            self.intensity_records.iter().sum::<f64>() / self.intensity_records.len() as f64;
+        if offset.abs() > CURRENT_ZERO_MAX_OFFSET {
+            return Err(Error::Other("Current not zero".to_string()));
+        }
+        Ok(offset)
+    }
+
+    /// Current zero offset (A)
+    pub fn current_zero_offset(&self) -> Option<f64> {
+        self.current_zero_offset
+    }
+
+    /// Set current zero offset (A)
+    pub fn set_current_zero_offset(&mut self, offset: f64) {
+        self.current_zero_offset = Some(offset)
+    }
+
+    /// Electrical readings statistics
+    pub fn stats(&self) -> &PiSugarStats {
+        &self.stats
+    }
+
+    /// Record statistics, restart full charge session while battery is full
+    fn record_stats(&mut self) {
+        let (voltage, intensity, level) = (self.voltage, self.intensity, self.level);
+        self.stats.boot.record(voltage, intensity, level);
+        if self.level_records.iter().all(|l| *l >= 100.0) {
+            self.stats.full_charge = Some(BatteryStats::new(voltage, intensity, level));
+        } else if let Some(full_charge) = &mut self.stats.full_charge {
+            full_charge.record(voltage, intensity, level);
+        }
+    }
+
+    /// Battery history
+    pub fn history(&self) -> &dyn HistoryStore {
+        self.history.as_ref()
+    }
+
+    /// Record history sample every interval
+    fn record_history(&mut self, config: &PiSugarConfig, now: Instant) {
+        let interval = if config.history_interval > 0 {
+            config.history_interval
+        } else {
+            HISTORY_DEFAULT_INTERVAL
+        };
+        if let Some(t) = self.history_updated_at {
+            if now < t + Duration::from_secs(interval) {
+                return;
+            }
+        }
+        self.history_updated_at = Some(now);
+
+        self.history.set_capacity(history_capacity(config));
+        self.history.set_batched(config.low_write);
+        let sample = HistorySample {
+            time: Local::now(),
+            level: self.level,
+            voltage: self.voltage,
+            intensity: self.intensity,
+            charging: self.charging,
+        };
+        if let Err(e) = self.history.record(sample) {
+            log::warn!("Failed to save history: {}", e);
+        }
+
+        if config.low_write {
+            let flushed_at = *self.history_flushed_at.get_or_insert(now);
+            if now >= flushed_at + Duration::from_secs(config.low_write_flush) {
+                self.history_flushed_at = Some(now);
+                self.flush_history();
+            }
+        }
+
+        let retention = &config.history_retention;
+        if retention.enabled {
+            if let Some(t) = self.history_retained_at {
+                if now < t + Duration::from_secs(HISTORY_RETENTION_INTERVAL) {
+                    return;
+                }
+            }
+            self.history_retained_at = Some(now);
+            if let Err(e) = self.history.apply_retention(retention, Local::now()) {
+                log::warn!("Failed to apply history retention: {}", e);
+            }
+        }
+    }
+
+    /// Write batched history samples
+    pub fn flush_history(&mut self) {
+        if let Err(e) = self.history.flush() {
+            log::warn!("Failed to flush history: {}", e);
+        }
+    }
+
+    /// Shutdown history
+    pub fn shutdown_history(&self) -> &ShutdownHistory {
+        &self.shutdown_history
+    }
+
+    /// Record a shutdown with battery snapshot, batched history is flushed
+    pub fn record_shutdown(&mut self, cause: ShutdownCause) {
+        self.flush_history();
+        let record = ShutdownRecord {
+            time: Local::now(),
+            cause,
+            level: self.level,
+            voltage: self.voltage,
+            intensity: self.intensity,
+        };
+        if let Err(e) = self.shutdown_history.record(record) {
+            log::warn!("Failed to save shutdown history: {}", e);
+        }
+    }
+
+    /// PiSugar battery alive
+    pub fn is_alive(&self, now: Instant) -> bool {
+        if self.updated_at + Duration::from_secs(3) >= now {
+            return true;
+        }
+        false
+    }
+
+    /// PiSugar is charging, with voltage linear regression
+    pub fn is_charging(&self, now: Instant) -> bool {
+        if self.is_alive(now) {
+            log::debug!("levels: {:?}", self.level_records);
+            let capacity = self.level_records.capacity() as f64;
+            let x_sum = (0.0 + capacity - 1.0) * capacity / 2.0;
+            let x_bar = x_sum / capacity;
+            let y_sum: f64 = self.level_records.iter().sum();
+            let _y_bar = y_sum / capacity;
+            // k = Sum(yi * (xi - x_bar)) / Sum(xi - x_bar)^2
+            let mut iter = self.level_records.iter();
+            let mut a = 0.0;
+            let mut b = 0.0;
+            for i in 0..self.level_records.capacity() {
+                let xi = i as f64;
                 let yi = iter.next().unwrap().clone();
                 a += yi * (xi - x_bar);
                 b += (xi - x_bar) * (xi - x_bar);
@@ -338,6 +1190,210 @@ impl PiSugarStatus {
         false
     }
 
+    /// Charging, debounced over polls
+    pub fn charging(&self) -> bool {
+        self.charging
+    }
+
+    /// Full charge reached and still charging
+    pub fn hold_full(&self) -> bool {
+        self.hold_full
+    }
+
+    /// Time of last full charge
+    pub fn full_at(&self) -> Option<DateTime<Local>> {
+        self.full_at
+    }
+
+    /// External power sensor reads power present, errors are taken as absent
+    fn external_power_present(&self) -> bool {
+        match self.external_power.as_ref().map(|s| s.present()) {
+            Some(Ok(present)) => present,
+            Some(Err(e)) => {
+                log::debug!("External power sensor error: {}", e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Input source of sensors, otherwise unknown while charging
+    fn sense_input_source(&self) -> InputSource {
+        for (input, sensor) in self.input_sensors.iter() {
+            match sensor.present() {
+                Ok(true) => return *input,
+                Ok(false) => (),
+                Err(e) => log::debug!("Input sensor of {} error: {}", input.as_str(), e),
+            }
+        }
+        if self.charging {
+            InputSource::Unknown
+        } else {
+            InputSource::None
+        }
+    }
+
+    /// Input powering the charger
+    pub fn input_source(&self) -> InputSource {
+        self.input_source
+    }
+
+    /// Cut loads of `cut_on_suspend`
+    pub fn cut_loads_for_suspend(&mut self) {
+        if let Some(shedder) = &mut self.load_shedder {
+            shedder.cut_for_suspend();
+        }
+    }
+
+    /// Take a pending battery event, held in quiet hours but urgent ones, held events first after
+    pub fn take_event(&mut self) -> Option<BatteryEvent> {
+        if self.quiet {
+            while let Some(event) = self.events.pop_front() {
+                if event.urgent() {
+                    return Some(event);
+                }
+                if self.held_events.len() == HELD_EVENTS_CAPACITY {
+                    self.held_events.pop_front();
+                }
+                self.held_events.push_back(event);
+            }
+            return None;
+        }
+        self.held_events
+            .pop_front()
+            .or_else(|| self.events.pop_front())
+    }
+
+    /// In quiet hours
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Enter or leave quiet hours, display is off in quiet hours
+    fn set_quiet(&mut self, quiet: bool) {
+        if quiet == self.quiet {
+            return;
+        }
+        if quiet {
+            log::info!("Quiet hours started");
+        } else {
+            log::info!("Quiet hours ended, {} events held", self.held_events.len());
+        }
+        self.quiet = quiet;
+        if let Some(display) = &mut self.display {
+            if let Err(e) = display.set_on(!quiet) {
+                log::debug!("Display error: {}", e);
+            }
+        }
+    }
+
+    /// Battery connected, by voltage plausibility, debounced over polls
+    pub fn battery_present(&self) -> bool {
+        self.battery_present
+    }
+
+    /// Power management state
+    pub fn power_state(&self) -> PowerState {
+        self.power_state
+    }
+
+    /// Enter power state, actions are driven by transitions
+    fn transition(&mut self, state: PowerState) {
+        log::info!(
+            "Power state: {} -> {}",
+            self.power_state.as_str(),
+            state.as_str()
+        );
+        self.power_state = state;
+        self.events.push_back(BatteryEvent::Transition(state));
+        match state {
+            PowerState::Low => {
+                log::warn!("Low battery, {}%", self.level);
+                self.events.push_back(BatteryEvent::Low);
+            }
+            PowerState::Critical => {
+                self.record_shutdown(ShutdownCause::LowBattery);
+                self.transition(PowerState::ShuttingDown);
+                // powered off by poll once participants acknowledged
+                self.events.push_back(BatteryEvent::PrepareShutdown);
+                self.shutdown_pending = true;
+            }
+            _ => (),
+        }
+    }
+
+    /// Power off once all participants acknowledged `prepare_shutdown`, or at the deadline
+    fn poll_shutdown(&mut self, config: &PiSugarConfig, now: Instant) {
+        let timeout = Duration::from_secs(config.shutdown_prepare_timeout);
+        let deadline = *self.shutdown_deadline.get_or_insert(now + timeout);
+        let acked = self.shutdown_participants.values().all(|acked| *acked);
+        if !acked && now < deadline {
+            return;
+        }
+        if !acked {
+            log::warn!("Shutdown participants not acknowledged, timed out");
+        }
+        loop {
+            log::error!("Low battery, will power off...");
+            let _ = execute_shutdown(config, self.shutdown_history.records().back());
+            thread::sleep(std::time::Duration::from_millis(3000));
+        }
+    }
+
+    /// Register a shutdown participant, id of acknowledgment
+    pub fn add_shutdown_participant(&mut self) -> u64 {
+        self.next_participant += 1;
+        self.shutdown_participants
+            .insert(self.next_participant, false);
+        self.next_participant
+    }
+
+    /// Unregister a shutdown participant, e.g. disconnected
+    pub fn remove_shutdown_participant(&mut self, id: u64) {
+        self.shutdown_participants.remove(&id);
+    }
+
+    /// Acknowledge `prepare_shutdown`, false if not registered
+    pub fn ack_shutdown(&mut self, id: u64) -> bool {
+        match self.shutdown_participants.get_mut(&id) {
+            Some(acked) => {
+                *acked = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Debounce raw battery present flag, true if changed
+    fn debounce_present(&mut self, raw: bool) -> bool {
+        if raw == self.battery_present {
+            self.present_pending = 0;
+            return false;
+        }
+        self.present_pending += 1;
+        if self.present_pending < CHARGING_DEBOUNCE_POLLS {
+            return false;
+        }
+        self.battery_present = raw;
+        self.present_pending = 0;
+        true
+    }
+
+    /// Debounce raw charging flag, true if changed
+    fn debounce_charging(&mut self, raw: bool) -> bool {
+        if raw == self.charging {
+            self.charging_pending = 0;
+            return false;
+        }
+        self.charging_pending += 1;
+        if self.charging_pending < CHARGING_DEBOUNCE_POLLS {
+            return false;
+        }
+        self.charging = raw;
+        self.charging_pending = 0;
+        true
+    }
+
     pub fn rtc_time(&self) -> DateTime<Local> {
         self.rtc_time
     }
@@ -346,29 +1402,185 @@ impl PiSugarStatus {
         self.rtc_time = rtc_time
     }
 
+    /// Rtc time minus system time (s), of 1s resolution, none until the rtc is read
+    pub fn rtc_drift(&self) -> Option<f64> {
+        self.rtc_drift
+    }
+
+    /// Update display with level, charging and estimated time, off in quiet hours
+    fn update_display(&mut self) {
+        if self.quiet {
+            return;
+        }
+        let capacity = self.soc.capacity();
+        if let Some(display) = &mut self.display {
+            let eta = estimate_minutes(self.level, self.intensity, capacity);
+            let frame = render_battery(self.level, self.charging, eta);
+            if let Err(e) = display.draw(&frame) {
+                log::debug!("Display error: {}", e);
+            }
+        }
+    }
+
+    /// Poll battery, level and actions depending on it
+    fn poll_battery(&mut self, config: &PiSugarConfig, now: Instant) {
+        self.set_soc_algorithm(config.soc_algorithm);
+
+        // battery
+        let readings = self
+            .battery
+            .as_ref()
+            .map(|b| (b.read_voltage(), b.read_intensity(), b.read_level()));
+        if let Some((r, i, l)) = readings {
+            track_i2c_error(&mut self.bat_i2c_error, &r, config);
+            if let Ok(v) = r {
+                log::debug!("voltage {}", v);
+                match l {
+                    Ok(Some(level)) => self.update_level(v, level, now),
+                    _ => self.update_voltage(v, now),
+                }
+            }
+            if let Ok(i) = i {
+                log::debug!("intensity {}", i);
+                self.update_raw_intensity(i, config, now);
+            }
+        }
+
+        // battery removed, mains only
+        let (min_v, max_v) = BATTERY_PLAUSIBLE_VOLTAGE;
+        let plausible = self.voltage >= min_v && self.voltage <= max_v;
+        if self.debounce_present(plausible) {
+            if self.battery_present {
+                log::info!("Battery inserted, {}V", self.voltage);
+                self.events.push_back(BatteryEvent::Inserted);
+            } else {
+                log::warn!("Battery missing, implausible {}V", self.voltage);
+                self.events.push_back(BatteryEvent::Removed);
+            }
+        }
+
+        // statistics
+        self.record_stats();
+
+        // history
+        self.record_history(config, now);
+
+        // fully charged, calibration point of soc
+        let full = self.level_records.iter().all(|l| *l >= 100.0);
+        if full && !self.full {
+            log::info!("Battery fully charged");
+            self.full_at = Some(Local::now());
+            self.soc.calibrate(100.0);
+            self.hold_full = true;
+            self.events.push_back(BatteryEvent::Full);
+        }
+        self.full = full;
+
+        // charging, debounced, or power of external sensor
+        let charging = self.is_charging(now) || self.external_power_present();
+        if self.debounce_charging(charging) {
+            log::info!("Charging: {}", self.charging);
+            if self.charging {
+                self.events.push_back(BatteryEvent::PowerRestored);
+            } else {
+                self.hold_full = false;
+                self.events.push_back(BatteryEvent::PowerLoss);
+            }
+
+            // charger terminated at full level
+            if !self.charging && self.full {
+                log::info!("Charge complete");
+                self.events.push_back(BatteryEvent::ChargeComplete);
+            }
+
+            // wake-on-lan, power restored
+            if self.charging && config.wol_on_power_restored {
+                log::info!("Power restored, wake-on-lan");
+                wake_on_lan(&config.wol_macs);
+            }
+        }
+
+        // input source, changes sent
+        let input_source = self.sense_input_source();
+        if input_source != self.input_source {
+            log::info!("Input source: {}", input_source.as_str());
+            self.input_source = input_source;
+            self.events
+                .push_back(BatteryEvent::InputSource(input_source));
+        }
+
+        // load shedding
+        let level = self.level();
+        let voltage = self.voltage_avg();
+        if let Some(shedder) = &mut self.load_shedder {
+            shedder.update(level, voltage);
+        }
+
+        // power state, auto shutdown when critical
+        log::debug!("Battery level: {}", self.level());
+        let mut inputs = PowerInputs {
+            // never critical on a missing battery, even before debounced
+            battery_present: plausible && self.battery_present,
+            charging: self.charging,
+            full: self.hold_full,
+            level: self.level(),
+            low_level: config.low_battery_level,
+            critical_level: config.auto_shutdown_level,
+            voltage,
+            low_voltage: config.low_battery_voltage,
+            critical_voltage: config.auto_shutdown_voltage,
+        };
+        let uptime = boottime();
+        let suppressed = inputs.critical() && uptime.as_secs() < config.shutdown_grace;
+        if suppressed {
+            if !self.grace_suppressed {
+                log::warn!(
+                    "Auto shutdown suppressed in startup grace, {}s after boot",
+                    uptime.as_secs()
+                );
+            }
+            inputs.critical_level = f64::NEG_INFINITY;
+            inputs.critical_voltage = 0.0;
+        }
+        self.grace_suppressed = suppressed;
+        let state = self.power_state.next(&inputs);
+        if state != self.power_state {
+            self.transition(state);
+        }
+    }
+
     pub fn poll(&mut self, config: &PiSugarConfig, now: Instant) -> Result<Option<TapType>> {
+        if self.shutdown_pending {
+            self.poll_shutdown(config, now);
+        }
+
+        // quiet hours, no tap polls, slow reads at the quiet interval
+        self.set_quiet(in_windows(&config.quiet_hours, Local::now().time()));
+        if self.quiet {
+            let interval = Duration::from_secs(config.quiet_poll_interval);
+            if now > self.updated_at && now.duration_since(self.updated_at) > interval {
+                self.refresh(config, now);
+            }
+            return Ok(None);
+        }
+
         if self.gpio_tap_history.len() == self.gpio_tap_history.capacity() {
             self.gpio_tap_history.remove(0);
         }
 
         // gpio tap detect
-        if self.mode() == MODEL_V2 {
-            if let Ok(t) = self.ip5209.read_gpio_tap() {
-                log::debug!("gpio button state: {}", t);
-                if t != 0 {
-                    self.gpio_tap_history.push('1');
-                } else {
-                    self.gpio_tap_history.push('0');
-                }
-            }
+        let gpio_tap = if !self.battery_enabled {
+            // no battery chip
+            None
         } else {
-            if let Ok(t) = self.ip5312.read_gpio_tap() {
-                log::debug!("gpio button state: {}", t);
-                if t != 0 {
-                    self.gpio_tap_history.push('1');
-                } else {
-                    self.gpio_tap_history.push('0');
-                }
+            self.battery.as_ref().map(|b| b.read_gpio_tap())
+        };
+        if let Some(Ok(t)) = gpio_tap {
+            log::debug!("gpio button state: {}", t);
+            if t != 0 {
+                self.gpio_tap_history.push('1');
+            } else {
+                self.gpio_tap_history.push('0');
             }
         }
         if let Some(tap_type) = gpio_detect_tap(&mut self.gpio_tap_history) {
@@ -398,71 +1610,87 @@ impl PiSugarStatus {
                 }
             };
             if let Some(script) = script {
-                log::debug!("execute script \"{}\"", script);
-                match execute_shell(script) {
-                    Ok(r) => log::debug!("script ok, code: {:?}", r.code()),
-                    Err(e) => log::error!("{}", e),
-                }
+                execute_hook(script);
             }
 
             return Ok(Some(tap_type));
         }
 
-        // rtc
-        if let Ok(rtc_time) = self.sd3078.read_time() {
-            self.set_rtc_time(rtc_time.try_into().unwrap_or(Local::now()))
+        // others, slower, tap polls are kept to a single i2c read
+        if now > self.updated_at && now.duration_since(self.updated_at) > I2C_READ_INTERVAL * 4 {
+            self.refresh(config, now);
         }
 
-        // others, slower
-        if now > self.updated_at && now.duration_since(self.updated_at) > I2C_READ_INTERVAL * 4 {
-            // battery
-            if self.mode() == MODEL_V2 {
-                if let Ok(v) = self.ip5209.read_voltage() {
-                    log::debug!("voltage {}", v);
-                    self.update_voltage(v, now);
-                }
-                if let Ok(i) = self.ip5209.read_intensity() {
-                    log::debug!("intensity {}", i);
-                    self.update_intensity(i, now);
-                }
-            } else {
-                if let Ok(v) = self.ip5312.read_voltage() {
-                    log::debug!("voltage {}", v);
-                    self.update_voltage(v, now)
-                }
-                if let Ok(i) = self.ip5312.read_intensity() {
-                    log::debug!("intensity {}", i);
-                    self.update_intensity(i, now)
-                }
+        Ok(None)
+    }
+
+    /// Read rtc and battery, and actions depending on them, of slow polls or on demand
+    pub fn refresh(&mut self, config: &PiSugarConfig, now: Instant) {
+        // rtc
+        if let Some(rtc) = &self.rtc_device {
+            let r = rtc.read_time();
+            track_i2c_error(&mut self.rtc_i2c_error, &r, config);
+            if let Ok(rtc_time) = r {
+                let sys_time = Local::now();
+                self.rtc_time = rtc_time.try_into().unwrap_or(sys_time);
+                let drift = self.rtc_time.signed_duration_since(sys_time);
+                self.rtc_drift = Some(drift.num_milliseconds() as f64 / 1000.0);
             }
 
-            // auto shutdown
-            log::debug!("Battery level: {}", self.level());
-            if self.level() <= config.auto_shutdown_level {
-                loop {
-                    log::error!("Low battery, will power off...");
-                    let _ = execute_shell("/sbin/shutdown --poweroff 0");
-                    thread::sleep(std::time::Duration::from_millis(3000));
+            // alarm fired, e.g. woken by it
+            if let Ok(flag) = rtc.read_alarm_flag() {
+                if flag && !self.alarm_fired {
+                    let actual = self.rtc_time;
+                    let scheduled = rtc
+                        .read_alarm_time()
+                        .ok()
+                        .and_then(|alarm| alarm_scheduled(&alarm, actual))
+                        .unwrap_or(actual);
+                    log::info!("Alarm fired, scheduled at {}", scheduled);
+                    self.events
+                        .push_back(BatteryEvent::AlarmFired { scheduled, actual });
+                    if config.alarm_auto_clear {
+                        if let Err(e) = rtc.clear_alarm_flag() {
+                            log::warn!("Failed to clear alarm flag: {}", e);
+                        }
+                    }
                 }
+                self.alarm_fired = flag;
             }
+        }
 
-            // rtc battery charging
-            if (self.sd3078.read_battery_low_flag().ok() == Some(true))
-                && (self.sd3078.read_battery_charging_flag().ok() == Some(false))
-            {
-                log::debug!("Enable rtc charging");
-                let _ = self.sd3078.toggle_charging(true);
-            } else {
-                if (self.sd3078.read_battery_high_flag().ok() == Some(true))
-                    && (self.sd3078.read_battery_charging_flag().ok() == Some(true))
-                {
-                    log::debug!("Disable rtc charging");
-                    let _ = self.sd3078.toggle_charging(false);
-                }
+        if self.battery_enabled {
+            self.poll_battery(config, now);
+        } else {
+            self.updated_at = now;
+        }
+        self.update_display();
+
+        // wake-on-lan, scheduled HH:MM
+        let local_now = Local::now();
+        let hm = if config.wol_times.is_empty() {
+            String::new()
+        } else {
+            local_now.format("%H:%M").to_string()
+        };
+        if config.wol_times.contains(&hm) {
+            let scheduled_at = local_now.format("%Y-%m-%d %H:%M").to_string();
+            if self.wol_scheduled_at != scheduled_at {
+                log::info!("Scheduled wake-on-lan at {}", hm);
+                wake_on_lan(&config.wol_macs);
+                self.wol_scheduled_at = scheduled_at;
             }
         }
 
-        Ok(None)
+        if let Some(rtc) = &self.rtc_device {
+            // rtc battery low, and charging of chips able to
+            let rtc_battery_low = rtc.poll_backup_battery().unwrap_or(false);
+            if rtc_battery_low && !self.rtc_battery_low {
+                log::warn!("RTC battery low");
+                execute_hook(config.on_rtc_battery_low_shell.as_str());
+            }
+            self.rtc_battery_low = rtc_battery_low;
+        }
     }
 }
 
@@ -474,36 +1702,145 @@ pub enum TapType {
     Long,
 }
 
-impl Display for TapType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
+impl TapType {
+    /// Event name, static
+    pub fn as_str(&self) -> &'static str {
+        match self {
             TapType::Single => "single",
             TapType::Double => "double",
             TapType::Long => "long",
-        };
-        write!(f, "{}", s)
+        }
     }
 }
 
-/// Detect button tap
-fn gpio_detect_tap(gpio_history: &mut String) -> Option<TapType> {
-    let long_pattern = "111111110";
-    let double_pattern = vec!["1010", "10010", "10110", "100110", "101110", "1001110"];
-    let single_pattern = "1000";
+impl Display for TapType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
-    if gpio_history.contains(long_pattern) {
+/// Battery event
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BatteryEvent {
+    /// Level reaches 100%
+    Full,
+    /// Charging stops at full level
+    ChargeComplete,
+    /// Charging or external power starts
+    PowerRestored,
+    /// Charging and external power stop
+    PowerLoss,
+    /// Battery voltage becomes plausible
+    Inserted,
+    /// Battery voltage out of plausible range
+    Removed,
+    /// Level at or below `low_battery_level` on battery
+    Low,
+    /// Power state transition
+    Transition(PowerState),
+    /// Automatic shutdown follows, participants should acknowledge
+    PrepareShutdown,
+    /// Input powering the charger changes
+    InputSource(InputSource),
+    /// Rtc alarm flag set, rtc times
+    AlarmFired {
+        scheduled: DateTime<Local>,
+        actual: DateTime<Local>,
+    },
+}
+
+impl BatteryEvent {
+    /// Sent in quiet hours, shutdown follows
+    pub fn urgent(&self) -> bool {
+        match self {
+            BatteryEvent::PrepareShutdown => true,
+            BatteryEvent::Transition(state) => {
+                *state == PowerState::Critical || *state == PowerState::ShuttingDown
+            }
+            _ => false,
+        }
+    }
+
+    /// Event payload, static
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatteryEvent::Full => "battery_full",
+            BatteryEvent::ChargeComplete => "charge_complete",
+            BatteryEvent::PowerRestored => "power_restored",
+            BatteryEvent::PowerLoss => "power_loss",
+            BatteryEvent::Inserted => "battery_inserted",
+            BatteryEvent::Removed => "battery_removed",
+            BatteryEvent::Low => "low_battery",
+            BatteryEvent::Transition(state) => state.event(),
+            BatteryEvent::PrepareShutdown => "prepare_shutdown",
+            BatteryEvent::InputSource(_) => "input_source",
+            BatteryEvent::AlarmFired { .. } => "alarm_fired",
+        }
+    }
+}
+
+/// Event payload, name and args, e.g. `alarm_fired <scheduled> <actual>`
+impl Display for BatteryEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatteryEvent::AlarmFired { scheduled, actual } => write!(
+                f,
+                "{} {} {}",
+                self.as_str(),
+                scheduled.to_rfc3339(),
+                actual.to_rfc3339()
+            ),
+            BatteryEvent::InputSource(input) => write!(f, "{} {}", self.as_str(), input.as_str()),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+/// Latest time of alarm at or before actual time, alarm is a time of day
+fn alarm_scheduled(alarm: &SD3078Time, actual: DateTime<Local>) -> Option<DateTime<Local>> {
+    let time = NaiveTime::from_hms_opt(
+        alarm.hour().into(),
+        alarm.minute().into(),
+        alarm.second().into(),
+    )?;
+    let scheduled = Local
+        .from_local_datetime(&actual.date().naive_local().and_time(time))
+        .single()?;
+    if scheduled > actual {
+        Some(scheduled - chrono::Duration::days(1))
+    } else {
+        Some(scheduled)
+    }
+}
+
+/// Long tap pattern
+const LONG_TAP_PATTERN: &str = "111111110";
+
+/// Double tap patterns
+const DOUBLE_TAP_PATTERNS: [&str; 6] = ["1010", "10010", "10110", "100110", "101110", "1001110"];
+
+/// Single tap pattern
+const SINGLE_TAP_PATTERN: &str = "1000";
+
+/// Max polls from button release to single tap detection, latency is bounded by this
+/// times `I2C_READ_INTERVAL`
+pub const TAP_LATENCY_POLLS: usize = SINGLE_TAP_PATTERN.len() - 1;
+
+/// Detect button tap, gpio history of '0' and '1', cleared on detection
+pub fn gpio_detect_tap(gpio_history: &mut String) -> Option<TapType> {
+    if gpio_history.contains(LONG_TAP_PATTERN) {
         gpio_history.clear();
         return Some(TapType::Long);
     }
 
-    for pattern in double_pattern {
+    for pattern in &DOUBLE_TAP_PATTERNS {
         if gpio_history.contains(pattern) {
             gpio_history.clear();
             return Some(TapType::Double);
         }
     }
 
-    if gpio_history.contains(single_pattern) {
+    if gpio_history.contains(SINGLE_TAP_PATTERN) {
         gpio_history.clear();
         return Some(TapType::Single);
     }
@@ -511,30 +1848,202 @@ fn gpio_detect_tap(gpio_history: &mut String) -> Option<TapType> {
     None
 }
 
-/// Execute shell with sh
+/// Execute hook shell, ignore empty
+fn execute_hook(script: &str) {
+    if script.is_empty() {
+        return;
+    }
+    log::debug!("execute script \"{}\"", script);
+    match execute_shell(script) {
+        Ok(r) => log::debug!("script ok, code: {:?}", r.code()),
+        Err(e) => log::error!("{}", e),
+    }
+}
+
+/// Track i2c error, execute hook when it first occurs
+fn track_i2c_error<T>(failed: &mut bool, r: &Result<T>, config: &PiSugarConfig) {
+    match r {
+        Ok(_) => *failed = false,
+        Err(e) => {
+            if !*failed {
+                log::error!("I2c error: {}", e);
+                execute_hook(config.on_i2c_error_shell.as_str());
+            }
+            *failed = true;
+        }
+    }
+}
+
+/// Open i2c devices
+fn open_i2c(
+    config: &PiSugarConfig,
+    open: &I2cOpener,
+) -> Result<(Vec<Box<dyn BatteryDevice>>, Option<Box<dyn RtcDevice>>)> {
+    let mut batteries = Vec::new();
+    for chip in BatteryChip::candidates(config) {
+        batteries.push(open_battery(chip, open(chip.addr())?, config));
+    }
+    let rtc_device: Option<Box<dyn RtcDevice>> = if config.rtc_enabled {
+        let model = config.rtc_model;
+        let kernel_device = if config.rtc_kernel_driver {
+            kernel_rtc_device(model.addr())
+        } else {
+            None
+        };
+        match kernel_device {
+            Some(path) => {
+                log::info!("RTC {} of kernel driver {}", model.as_str(), path.display());
+                Some(Box::new(KernelRtc::open(model, &path)?))
+            }
+            None => Some(open_rtc(model, open(model.addr())?)),
+        }
+    } else {
+        None
+    };
+    Ok((batteries, rtc_device))
+}
+
+/// Dir of config file
+fn config_dir(config_path: &Path) -> &Path {
+    match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Execute shell with sh, commands are resolved in PATH
 fn execute_shell(shell: &str) -> io::Result<ExitStatus> {
     let args = ["-c", shell];
-    let mut child = Command::new("/bin/sh").args(&args).spawn()?;
+    let mut child = Command::new("sh").args(&args).spawn()?;
     child.wait()
 }
 
+/// Run `shutdown_command` of config, the builtin power off if it fails
+fn execute_shutdown(
+    config: &PiSugarConfig,
+    record: Option<&ShutdownRecord>,
+) -> io::Result<ExitStatus> {
+    let command = shutdown_command(&config.shutdown_command, record);
+    match execute_shell(&command) {
+        Ok(status) if status.success() => Ok(status),
+        r => {
+            log::error!("Shutdown command `{}` failed: {:?}", command, r);
+            execute_shell(SHUTDOWN_SHELL)
+        }
+    }
+}
+
+/// Status snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct PiSugarSnapshot {
+    pub model: String,
+    pub hardware_id: Option<String>,
+    pub battery_present: bool,
+    pub battery: f64,
+    pub battery_v: f64,
+    pub battery_i: f64,
+    pub battery_power_w: f64,
+    pub battery_charging: bool,
+    pub power_state: PowerState,
+    pub input_source: InputSource,
+    pub rtc_time: DateTime<Local>,
+    /// Rtc time minus system time (s), if rtc read
+    pub rtc_drift: Option<f64>,
+    pub throttled: Option<u32>,
+    pub system: Option<SystemMetrics>,
+    /// Seconds until safe shutdown, if discharging with a safe shutdown level
+    pub shutdown_eta: Option<u64>,
+}
+
+impl PiSugarSnapshot {
+    /// Read host metrics (vcgencmd and /proc), slow, better outside of core lock
+    pub fn with_host_metrics(mut self, throttled: bool, system: bool) -> Self {
+        if throttled {
+            self.throttled = Throttled::read().ok().map(|t| t.0);
+        }
+        if system {
+            self.system = SystemMetrics::read().ok();
+        }
+        self
+    }
+}
+
 /// Core
 pub struct PiSugarCore {
     pub config_path: Option<String>,
     pub config: PiSugarConfig,
     pub status: PiSugarStatus,
+    config_changed_at: Option<Instant>,
+    config_signed: bool,
+    sessions: SessionStore,
+    jobs: JobStore,
+    listener_stats: BTreeMap<Listener, ListenerStats>,
+    hwclock_compat: bool,
+    state_dir: Option<PathBuf>,
 }
 
 impl PiSugarCore {
     pub fn new(config: PiSugarConfig) -> Result<Self> {
-        let status = PiSugarStatus::new()?;
+        let status = PiSugarStatus::new(&config)?;
+        Self::with_status(config, status)
+    }
+
+    /// Create with i2c buses of opener, e.g. fake devices
+    pub fn new_with_opener(config: PiSugarConfig, opener: I2cOpener) -> Result<Self> {
+        let status = PiSugarStatus::new_with_opener(&config, opener)?;
+        Self::with_status(config, status)
+    }
+
+    fn with_status(config: PiSugarConfig, status: PiSugarStatus) -> Result<Self> {
         Ok(Self {
             config_path: None,
             config,
             status,
+            config_changed_at: None,
+            config_signed: false,
+            sessions: SessionStore::default(),
+            jobs: JobStore::default(),
+            listener_stats: BTreeMap::new(),
+            hwclock_compat: false,
+            state_dir: None,
         })
     }
 
+    /// Load signed config, signature in `<config>.sig` must be valid, no auto recovery
+    pub fn new_with_signed_path(config_path: &str, public_key: &str) -> Result<Self> {
+        let config_path = PathBuf::from(config_path);
+        let content = verify_file(config_path.as_path(), public_key)
+            .map_err(|e| Error::Other(format!("Invalid config signature: {}", e)))?;
+
+        // the verified content is parsed, the file may have changed since
+        let config: PiSugarConfig = serde_json::from_slice(&content)
+            .map_err(|e| Error::Other(format!("Failed to load config file: {}", e)))?;
+        let mut core = Self::new(config)?;
+        core.config_path = Some(config_path.to_string_lossy().to_string());
+        core.config_signed = true;
+        core.load_state(config_dir(config_path.as_path()));
+        core.init_alarm();
+        core.check_shutdown_command();
+        Ok(core)
+    }
+
+    /// Config as json, secrets removed
+    pub fn config_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(&self.config).unwrap_or_default();
+        if let Some(fields) = value.as_object_mut() {
+            for field in &CONFIG_SECRET_FIELDS {
+                fields.remove(*field);
+            }
+        }
+        redact_secrets(&mut value);
+        value
+    }
+
+    /// Config is signed, and immutable
+    pub fn config_signed(&self) -> bool {
+        self.config_signed
+    }
+
     pub fn new_with_path(config_path: &str, auto_recovery: bool) -> Result<Self> {
         let config_path = PathBuf::from(config_path);
         if config_path.is_dir() {
@@ -542,13 +2051,10 @@ impl PiSugarCore {
         }
 
         match Self::load_config(config_path.as_path()) {
-            Ok(core) => {
-                if let Some(datetime) = core.config.auto_wake_time {
-                    match core.set_alarm(datetime.into(), core.config.auto_wake_repeat) {
-                        Ok(_) => log::info!("Init alarm success"),
-                        Err(e) => log::warn!("Init alarm failed: {}", e),
-                    }
-                }
+            Ok(mut core) => {
+                core.load_state(config_dir(config_path.as_path()));
+                core.init_alarm();
+                core.check_shutdown_command();
                 Ok(core)
             }
             Err(_) => {
@@ -557,10 +2063,12 @@ impl PiSugarCore {
                     let config = PiSugarConfig::default();
                     let mut core = Self::new(config)?;
                     core.config_path = Some(config_path.to_string_lossy().to_string());
+                    core.load_state(config_dir(config_path.as_path()));
                     match core.save_config() {
                         Ok(_) => log::info!("Auto recovery success"),
                         Err(e) => log::warn!("Auto recovery failed: {}", e),
                     }
+                    core.check_shutdown_command();
                     return Ok(core);
                 } else {
                     return Err(Error::Other("Not recoverable".to_string()));
@@ -569,6 +2077,158 @@ impl PiSugarCore {
         }
     }
 
+    /// Set alarm of config
+    fn init_alarm(&self) {
+        if let (true, Some(datetime)) = (self.rtc_enabled(), self.config.auto_wake_time) {
+            match self.set_alarm(datetime.into(), self.config.auto_wake_repeat) {
+                Ok(_) => log::info!("Init alarm success"),
+                Err(e) => log::warn!("Init alarm failed: {}", e),
+            }
+        }
+    }
+
+    /// Check `shutdown_command` of config on loads and recoveries, the program may be missing, e.g.
+    /// removed since the last start, the builtin power off is the fallback
+    pub fn check_shutdown_command(&self) {
+        let command = shutdown_command(&self.config.shutdown_command, None);
+        if let Err(e) = validate_shutdown_command(&command) {
+            log::error!("Invalid shutdown command, fallback to poweroff: {}", e);
+        }
+    }
+
+    /// I2C open error, serving in degraded mode
+    pub fn hardware_error(&self) -> Option<&str> {
+        self.status.hardware_error()
+    }
+
+    /// Whether role may execute command, always allowed if no token configured
+    pub fn authorize(&self, role: Option<Role>, cmd: &str) -> bool {
+        if self.config.auth_tokens.is_empty() {
+            return true;
+        }
+        match role {
+            Some(role) => command_allowed(&self.config.auth_roles, role, cmd),
+            None => false,
+        }
+    }
+
+    /// Self test of i2c reads, rtc read and scratch write, and config write access
+    pub fn self_test(&self) -> SelfTestReport {
+        let mut report = SelfTestReport::default();
+        report.check(
+            "i2c_battery",
+            self.status.read_voltage().map(|v| format!("{:.3}V", v)),
+        );
+        report.check(
+            "rtc_read",
+            self.status.rtc().and_then(|rtc| rtc.read_time()),
+        );
+        report.check(
+            "rtc_scratch",
+            self.status
+                .rtc()
+                .and_then(|rtc| rtc.test_scratch())
+                .map(|_| "ok"),
+        );
+        let config = match &self.config_path {
+            Some(_) if self.config_signed => Ok("signed, not written".to_string()),
+            Some(path) => OpenOptions::new()
+                .append(true)
+                .open(path)
+                .map(|_| path.clone())
+                .map_err(|e| Error::Other(format!("{}: {}", path, e))),
+            None => Err(Error::Other("No config file".to_string())),
+        };
+        report.check("config_write", config);
+        report
+    }
+
+    /// Whether listener may serve command of `listener_commands`
+    pub fn listener_allows(&self, listener: Listener, cmd: &str) -> bool {
+        listener_allowed(&self.config.listener_commands, listener, cmd)
+    }
+
+    /// Stats of listeners since start
+    pub fn listener_stats(&self) -> &BTreeMap<Listener, ListenerStats> {
+        &self.listener_stats
+    }
+
+    pub fn listener_stats_mut(&mut self, listener: Listener) -> &mut ListenerStats {
+        self.listener_stats.entry(listener).or_default()
+    }
+
+    /// Retry opening hardware in degraded mode
+    pub fn recover(&mut self) -> Result<()> {
+        let mut status = PiSugarStatus::new_with_opener(&self.config, self.status.opener.clone())?;
+        if let Some(e) = status.hardware_error() {
+            return Err(Error::Other(e.to_string()));
+        }
+        // history of load_state, ring file or sqlite
+        std::mem::swap(&mut status.history, &mut self.status.history);
+        std::mem::swap(
+            &mut status.shutdown_history,
+            &mut self.status.shutdown_history,
+        );
+        std::mem::swap(
+            &mut status.shutdown_participants,
+            &mut self.status.shutdown_participants,
+        );
+        status.next_participant = self.status.next_participant;
+        self.status = status;
+        self.init_alarm();
+        self.check_shutdown_command();
+        Ok(())
+    }
+
+    /// Reopen i2c handles, e.g. of a stalled bus
+    pub fn reopen(&mut self) -> Result<()> {
+        self.status.reopen(&self.config)
+    }
+
+    /// Load state files in dir, next to config file by default
+    pub fn load_state(&mut self, dir: &Path) {
+        self.state_dir = Some(dir.to_path_buf());
+        let path = dir.join(SHUTDOWN_HISTORY_FILE);
+        if let Err(e) = self.status.shutdown_history.load(path.as_path()) {
+            log::warn!("Failed to load shutdown history: {}", e);
+        }
+        let path = dir.join(SESSIONS_FILE);
+        if let Err(e) = self.sessions.load(path.as_path()) {
+            log::warn!("Failed to load sessions: {}", e);
+        }
+        if let Err(e) = self
+            .sessions
+            .revoke_removed_tokens(&self.config.auth_tokens)
+        {
+            log::warn!("Failed to save sessions: {}", e);
+        }
+        let capacity = history_capacity(&self.config);
+        self.sessions.set_low_write(self.config.low_write);
+        match open_history(self.config.history_backend, dir, capacity) {
+            Ok(history) => self.status.history = history,
+            Err(e) => log::warn!("Failed to load history: {}", e),
+        }
+        self.restore_alarm_after_halt(dir);
+    }
+
+    /// Restore alarm of config if woken from a halt of `suspend_for`, the marker is kept until
+    /// restored, e.g. with the rtc unavailable
+    fn restore_alarm_after_halt(&self, dir: &Path) {
+        let path = dir.join(ALARM_RESTORE_FILE);
+        if !path.exists() {
+            return;
+        }
+        match self.restore_alarm() {
+            Ok(_) => {
+                log::info!("Alarm restored after suspend_for halt");
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => log::warn!("Restore alarm after suspend_for halt failed: {}", e),
+        }
+    }
+
     fn load_config(path: &Path) -> Result<Self> {
         if path.exists() && path.is_file() {
             let mut config = PiSugarConfig::default();
@@ -583,6 +2243,9 @@ impl PiSugarCore {
     }
 
     pub fn save_config(&self) -> Result<()> {
+        if self.config_signed {
+            return Err(Error::Other("Config is signed".to_string()));
+        }
         if let Some(config_path) = &self.config_path {
             let path = Path::new(config_path);
             if self.config.save_to(path).is_ok() {
@@ -592,6 +2255,50 @@ impl PiSugarCore {
         Err(Error::Other("Failed to save config file".to_string()))
     }
 
+    /// Queue config save, rapid changes are coalesced
+    pub fn save_config_later(&mut self) {
+        if self.config_signed {
+            log::warn!("Config is signed, not saved");
+            return;
+        }
+        self.config_changed_at = Some(Instant::now());
+    }
+
+    /// Take queued config save after debounce delay, returns path and serialized config
+    pub fn take_config_save(&mut self, now: Instant, delay: Duration) -> Option<(String, String)> {
+        match self.config_changed_at {
+            Some(t) if now >= t + delay => {
+                self.config_changed_at = None;
+                let path = self.config_path.clone()?;
+                match serde_json::to_string_pretty(&self.config) {
+                    Ok(s) => Some((path, s)),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    pub fn sessions_mut(&mut self) -> &mut SessionStore {
+        &mut self.sessions
+    }
+
+    /// Background jobs of commands
+    pub fn jobs(&self) -> &JobStore {
+        &self.jobs
+    }
+
+    pub fn jobs_mut(&mut self) -> &mut JobStore {
+        &mut self.jobs
+    }
+
     pub fn status(&self) -> &PiSugarStatus {
         &self.status
     }
@@ -604,6 +2311,11 @@ impl PiSugarCore {
         self.status.model.clone()
     }
 
+    /// Power-on behavior of the board
+    pub fn power_on_mode(&self) -> &'static str {
+        POWER_ON_MODE_BUTTON
+    }
+
     pub fn voltage(&self) -> f64 {
         self.status.voltage()
     }
@@ -612,13 +2324,113 @@ impl PiSugarCore {
         self.status.intensity()
     }
 
+    pub fn power(&self) -> f64 {
+        self.status.power()
+    }
+
+    pub fn stats(&self) -> &PiSugarStats {
+        self.status.stats()
+    }
+
+    pub fn history(&self) -> &dyn HistoryStore {
+        self.status.history()
+    }
+
+    pub fn shutdown_history(&self) -> &ShutdownHistory {
+        self.status.shutdown_history()
+    }
+
+    /// Raspberry Pi firmware throttled status, if enabled
+    pub fn throttled(&self) -> Result<Throttled> {
+        if !self.config.throttled_enable {
+            return Err(Error::Other("Throttled status not enabled".to_string()));
+        }
+        Throttled::read()
+    }
+
+    /// System metrics, if enabled
+    pub fn system_metrics(&self) -> Result<SystemMetrics> {
+        if !self.config.system_metrics_enable {
+            return Err(Error::Other("System metrics not enabled".to_string()));
+        }
+        SystemMetrics::read()
+    }
+
+    /// Status snapshot
+    pub fn snapshot(&self) -> PiSugarSnapshot {
+        let config = &self.config;
+        self.snapshot_status()
+            .with_host_metrics(config.throttled_enable, config.system_metrics_enable)
+    }
+
+    /// Snapshot of battery and rtc only, cheap enough to take under lock
+    pub fn snapshot_status(&self) -> PiSugarSnapshot {
+        PiSugarSnapshot {
+            model: self.model(),
+            hardware_id: self.status.hardware_id().map(String::from),
+            battery_present: self.battery_present(),
+            battery: self.level(),
+            battery_v: self.voltage(),
+            battery_i: self.intensity(),
+            battery_power_w: self.power(),
+            battery_charging: self.charging(),
+            power_state: self.power_state(),
+            input_source: self.status.input_source(),
+            rtc_time: self.read_time(),
+            rtc_drift: self.status.rtc_drift(),
+            throttled: None,
+            system: None,
+            shutdown_eta: self.shutdown_eta(),
+        }
+    }
+
+    /// Calibrate current zero offset, battery current must be known zero
+    pub fn calibrate_current_zero(&mut self) -> Result<f64> {
+        let offset = self.status.measure_current_zero()?;
+        self.status.set_current_zero_offset(offset);
+        self.config.current_zero_offset = offset;
+        self.save_config_later();
+        Ok(offset)
+    }
+
+    /// Reported level, rounded and clamped by config policy
     pub fn level(&self) -> f64 {
-        self.status.level()
+        if self.config.level_hold_full && self.status.hold_full() {
+            return 100.0;
+        }
+        self.config.level_rounding.apply(self.status.level())
     }
 
     pub fn charging(&self) -> bool {
-        let now = Instant::now();
-        self.status.is_charging(now)
+        self.status.charging()
+    }
+
+    /// Time of last full charge
+    pub fn battery_full_at(&self) -> Option<DateTime<Local>> {
+        self.status.full_at()
+    }
+
+    pub fn rtc_enabled(&self) -> bool {
+        self.status.rtc_enabled()
+    }
+
+    pub fn battery_enabled(&self) -> bool {
+        self.status.battery_enabled()
+    }
+
+    /// Power management state
+    pub fn power_state(&self) -> PowerState {
+        self.status.power_state()
+    }
+
+    /// Input powering the charger
+    pub fn input_source(&self) -> InputSource {
+        self.status.input_source()
+    }
+
+    /// Battery enabled and connected, not running on mains only
+    pub fn battery_present(&self) -> bool {
+        self.status.battery_enabled() && self.status.battery_present()
     }
 
     pub fn read_time(&self) -> DateTime<Local> {
@@ -626,42 +2438,207 @@ impl PiSugarCore {
     }
 
     pub fn read_raw_time(&self) -> SD3078Time {
-        match self.status.sd3078.read_time() {
+        match self.status.rtc().and_then(|rtc| rtc.read_time()) {
             Ok(t) => t,
             Err(_) => self.status.rtc_time.into(),
         }
     }
 
     pub fn write_time(&self, dt: DateTime<Local>) -> Result<()> {
-        self.status.sd3078.write_time(dt.into())
+        self.status.rtc()?.write_time(dt.into())?;
+        if self.config.adjtime_enable || self.hwclock_compat {
+            // the rtc keeps local time only if the daemon is the hwclock, the kernel rtc keeps utc
+            let local = if self.hwclock_compat {
+                Some(!self.kernel_rtc())
+            } else {
+                None
+            };
+            if let Err(e) = update_adjtime(Path::new(ADJTIME_PATH), dt, local) {
+                log::warn!("Failed to update {}: {}", ADJTIME_PATH, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the daemon is the hwclock, `--hwclock-compat`
+    pub fn hwclock_compat(&self) -> bool {
+        self.hwclock_compat
+    }
+
+    /// The daemon is the hwclock, `hwclock -w` is not run and /etc/adjtime is updated on rtc
+    /// writes
+    pub fn set_hwclock_compat(&mut self, compat: bool) {
+        self.hwclock_compat = compat;
+    }
+
+    /// Whether the rtc is a `/dev/rtc*` of the kernel rtc driver, it keeps utc
+    fn kernel_rtc(&self) -> bool {
+        self.status
+            .rtc()
+            .map(|rtc| rtc.kernel_device().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Whether `hwclock -w` follows system time writes of `sys_write_time`, not in hwclock compat
+    /// mode or with the kernel rtc, which the kernel already keeps. Taken under the core lock, the
+    /// system time is written after it is released
+    pub fn sys_write_hwclock(&self) -> bool {
+        !self.hwclock_compat && !self.kernel_rtc()
     }
 
     pub fn set_alarm(&self, t: SD3078Time, weakday_repeat: u8) -> Result<()> {
-        self.status.sd3078.set_alarm(t, weakday_repeat)
+        self.status.rtc()?.set_alarm(t, weakday_repeat)
     }
 
     pub fn read_alarm_time(&self) -> Result<SD3078Time> {
-        self.status.sd3078.read_alarm_time()
+        self.status.rtc()?.read_alarm_time()
     }
 
     pub fn read_alarm_enabled(&self) -> Result<bool> {
-        self.status.sd3078.read_alarm_enabled()
+        self.status.rtc()?.read_alarm_enabled()
     }
 
     pub fn read_alarm_flag(&self) -> Result<bool> {
-        self.status.sd3078.read_alarm_flag()
+        self.status.rtc()?.read_alarm_flag()
     }
 
     pub fn clear_alarm_flag(&self) -> Result<()> {
-        self.status.sd3078.clear_alarm_flag()
+        self.status.rtc()?.clear_alarm_flag()
     }
 
     pub fn disable_alarm(&self) -> Result<()> {
-        self.status.sd3078.disable_alarm()
+        self.status.rtc()?.disable_alarm()
     }
 
     pub fn test_wake(&self) -> Result<()> {
-        self.status.sd3078.set_test_wake()
+        self.status.rtc()?.set_test_wake()
+    }
+
+    /// Arm rtc alarm after seconds of rtc time, cut loads of suspend, then suspend or power off,
+    /// returns wakeup time
+    pub fn suspend_for(&mut self, seconds: u64, halt: bool) -> Result<DateTime<Local>> {
+        let rtc_now: DateTime<Local> = self
+            .status
+            .rtc()?
+            .read_time()?
+            .try_into()
+            .map_err(|_| Error::Other("Invalid rtc time".to_string()))?;
+        let wakeup = rtc_now + chrono::Duration::seconds(seconds as i64);
+        let weekday = 1 << wakeup.weekday().num_days_from_sunday();
+        self.set_alarm(wakeup.into(), weekday)?;
+        log::info!("Suspend, wakeup at {}", wakeup);
+
+        self.status.cut_loads_for_suspend();
+        if halt {
+            // alarm of config is restored on the next start
+            match &self.state_dir {
+                Some(dir) => {
+                    let path = dir.join(ALARM_RESTORE_FILE);
+                    if let Err(e) = std::fs::write(&path, wakeup.to_rfc3339()) {
+                        log::warn!("Failed to write {}: {}", path.display(), e);
+                    }
+                }
+                None => log::warn!("No state dir, alarm of suspend_for is not restored"),
+            }
+            self.power_off(ShutdownCause::Suspend)?;
+        } else {
+            self.status.record_shutdown(ShutdownCause::Suspend);
+            execute_shell(SUSPEND_SHELL).map_err(|e| Error::Other(e.to_string()))?;
+        }
+        Ok(wakeup)
+    }
+
+    /// Restore alarm of config after a suspend, disabled if none
+    pub fn restore_alarm(&self) -> Result<()> {
+        match self.config.auto_wake_time {
+            Some(t) => self.set_alarm(t.into(), self.config.auto_wake_repeat),
+            None => self.disable_alarm(),
+        }
+    }
+
+    /// Write batched state, before exit
+    pub fn flush_state(&mut self) {
+        self.status.flush_history();
+    }
+
+    /// Wall clock jumped, expiries of sessions created since start follow the jump
+    pub fn time_jumped(&mut self, seconds: i64) {
+        if let Err(e) = self.sessions.shift_created(seconds) {
+            log::error!("Failed to save sessions: {}", e);
+        }
+    }
+
+    /// Register a shutdown participant of `prepare_shutdown`, id of acknowledgment
+    pub fn add_shutdown_participant(&mut self) -> u64 {
+        self.status.add_shutdown_participant()
+    }
+
+    pub fn remove_shutdown_participant(&mut self, id: u64) {
+        self.status.remove_shutdown_participant(id)
+    }
+
+    pub fn ack_shutdown(&mut self, id: u64) -> bool {
+        self.status.ack_shutdown(id)
+    }
+
+    /// Record shutdown and power off
+    pub fn power_off(&mut self, cause: ShutdownCause) -> Result<()> {
+        self.status.record_shutdown(cause);
+        self.status.transition(PowerState::ShuttingDown);
+        let record = self.status.shutdown_history().records().back();
+        execute_shutdown(&self.config, record).map_err(|e| Error::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Average current (A) of history samples within last seconds (at most `MAX_QUERY_RANGE`),
+    /// current reading if none
+    pub fn intensity_avg(&self, seconds: u64) -> f64 {
+        let seconds = seconds.min(MAX_QUERY_RANGE as u64) as i64;
+        let samples = match Local::now().checked_sub_signed(chrono::Duration::seconds(seconds)) {
+            Some(from) => self.history().query(Some(from), None),
+            None => Vec::new(),
+        };
+        if samples.is_empty() {
+            return self.intensity();
+        }
+        samples.iter().map(|s| s.intensity).sum::<f64>() / samples.len() as f64
+    }
+
+    /// Seconds until safe shutdown at `auto_shutdown_level`, estimated by discharge current
+    pub fn shutdown_eta(&self) -> Option<u64> {
+        let level = self.level();
+        let shutdown_level = self.config.auto_shutdown_level;
+        let ma = -self.intensity() * 1000.0;
+        if !self.battery_present() || shutdown_level <= 0.0 || level <= shutdown_level || ma < 1.0 {
+            return None;
+        }
+        let hours = (level - shutdown_level) / 100.0 * self.status.soc.capacity() / ma;
+        Some((hours * 3600.0).round() as u64)
+    }
+
+    /// Upcoming wake alarm, wake-on-lan and estimated safe shutdown within days, by time
+    pub fn schedule(&self, now: DateTime<Local>, days: i64) -> Vec<ScheduledEvent> {
+        let mut events = Vec::new();
+        if let Some(alarm) = self.config.auto_wake_time {
+            if self.read_alarm_enabled().unwrap_or(false) {
+                let repeat = self.config.auto_wake_repeat;
+                events.extend(wake_schedule(alarm, repeat, now, days));
+            }
+        }
+        events.extend(wol_schedule(&self.config.wol_times, now, days));
+
+        if let Some(eta) = self.shutdown_eta() {
+            let time = now + chrono::Duration::seconds(eta as i64);
+            if time <= now + chrono::Duration::days(days) {
+                events.push(ScheduledEvent {
+                    kind: ScheduleKind::Shutdown,
+                    time,
+                    estimated: true,
+                });
+            }
+        }
+        events.sort_by_key(|e| e.time);
+        events
     }
 
     pub fn config(&self) -> &PiSugarConfig {
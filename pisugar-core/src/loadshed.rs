@@ -0,0 +1,153 @@
+use rppal::gpio::{Gpio, OutputPin};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Default hysteresis of level (%)
+const LEVEL_HYSTERESIS: f64 = 5.0;
+
+/// Default hysteresis of voltage (V)
+const VOLTAGE_HYSTERESIS: f64 = 0.1;
+
+/// Battery reading of a threshold
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShedBy {
+    /// Battery level (%)
+    Level,
+    /// Averaged battery voltage (V), e.g. LiFePO4 packs of a poor level curve
+    Voltage,
+}
+
+impl Default for ShedBy {
+    fn default() -> Self {
+        ShedBy::Level
+    }
+}
+
+/// Load shedding output, the load is cut at or below threshold, restored above threshold + hysteresis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadShedRule {
+    /// BCM gpio number
+    pub pin: u8,
+
+    /// Battery level % or voltage of cutting the load
+    pub threshold: f64,
+
+    /// Reading of threshold
+    #[serde(default)]
+    pub by: ShedBy,
+
+    /// Above threshold of restoring the load, 5% or 0.1V by default
+    #[serde(default)]
+    pub hysteresis: Option<f64>,
+
+    /// Load is powered when pin is low
+    #[serde(default)]
+    pub active_low: bool,
+
+    /// Load is cut by `suspend_for`, restored by battery level after wakeup
+    #[serde(default)]
+    pub cut_on_suspend: bool,
+}
+
+impl LoadShedRule {
+    /// Whether load is shed at level or voltage, previous state kept within hysteresis
+    pub fn shed(&self, shed: bool, level: f64, voltage: f64) -> bool {
+        let (value, hysteresis) = match self.by {
+            ShedBy::Level => (level, self.hysteresis.unwrap_or(LEVEL_HYSTERESIS)),
+            ShedBy::Voltage => (voltage, self.hysteresis.unwrap_or(VOLTAGE_HYSTERESIS)),
+        };
+        if value <= self.threshold {
+            true
+        } else if value >= self.threshold + hysteresis {
+            false
+        } else {
+            shed
+        }
+    }
+}
+
+/// Gpio output of a rule
+struct LoadShedOutput {
+    rule: LoadShedRule,
+    pin: OutputPin,
+    shed: bool,
+}
+
+impl LoadShedOutput {
+    fn drive(&mut self) {
+        // powered is high, unless active low
+        if self.shed != self.rule.active_low {
+            self.pin.set_low();
+        } else {
+            self.pin.set_high();
+        }
+    }
+}
+
+/// Load shedding gpio outputs, driven by battery level
+pub struct LoadShedder {
+    outputs: Vec<LoadShedOutput>,
+}
+
+impl LoadShedder {
+    /// Open gpio outputs of rules, loads are powered initially
+    pub fn new(rules: &[LoadShedRule]) -> Result<Self> {
+        let gpio = Gpio::new().map_err(|e| Error::Other(format!("gpio: {}", e)))?;
+        let mut outputs = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let mut pin = gpio
+                .get(rule.pin)
+                .map_err(|e| Error::Other(format!("gpio {}: {}", rule.pin, e)))?
+                .into_output();
+            // keep the load state after exit
+            pin.set_reset_on_drop(false);
+            let mut output = LoadShedOutput {
+                rule: rule.clone(),
+                pin,
+                shed: false,
+            };
+            output.drive();
+            outputs.push(output);
+        }
+        Ok(Self { outputs })
+    }
+
+    /// Cut loads of `cut_on_suspend`
+    pub fn cut_for_suspend(&mut self) {
+        for output in self.outputs.iter_mut() {
+            if output.rule.cut_on_suspend && !output.shed {
+                log::info!("Suspend, load of gpio {} cut", output.rule.pin);
+                output.shed = true;
+                output.drive();
+            }
+        }
+    }
+
+    /// Update outputs with battery level and averaged voltage
+    pub fn update(&mut self, level: f64, voltage: f64) {
+        for output in self.outputs.iter_mut() {
+            let shed = output.rule.shed(output.shed, level, voltage);
+            if shed != output.shed {
+                if shed {
+                    log::warn!(
+                        "Battery {:.0}% {:.2}V, load of gpio {} cut",
+                        level,
+                        voltage,
+                        output.rule.pin
+                    );
+                } else {
+                    log::info!(
+                        "Battery {:.0}% {:.2}V, load of gpio {} restored",
+                        level,
+                        voltage,
+                        output.rule.pin
+                    );
+                }
+                output.shed = shed;
+                output.drive();
+            }
+        }
+    }
+}
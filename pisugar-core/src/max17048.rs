@@ -0,0 +1,66 @@
+use crate::bus::{open_rppal, I2cBus};
+
+use crate::{BatteryChip, BatteryDevice, Result};
+
+/// Cell voltage, 78.125uV
+const VCELL_REG: u8 = 0x02;
+
+/// State of charge, 1/256%
+const SOC_REG: u8 = 0x04;
+
+/// Charge or discharge rate, signed, 0.208%/hr
+const CRATE_REG: u8 = 0x16;
+
+/// MAX17048, fuel gauge of a single cell
+pub struct MAX17048 {
+    i2c: Box<dyn I2cBus>,
+    capacity: f64,
+}
+
+impl MAX17048 {
+    /// Create new MAX17048
+    pub fn new(i2c_addr: u16, capacity: f64) -> Result<Self> {
+        Ok(Self::with_bus(open_rppal(i2c_addr)?, capacity))
+    }
+
+    /// Create MAX17048 on a bus, battery capacity (mAh) of intensity
+    pub fn with_bus(i2c: Box<dyn I2cBus>, capacity: f64) -> Self {
+        Self { i2c, capacity }
+    }
+
+    /// Read a big endian register
+    fn read_word(&self, reg: u8) -> Result<u16> {
+        let mut buf = [0_u8; 2];
+        self.i2c.block_read(reg, &mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+}
+
+impl BatteryDevice for MAX17048 {
+    fn chip(&self) -> BatteryChip {
+        BatteryChip::Max17048
+    }
+
+    fn model(&self) -> &'static str {
+        "MAX17048"
+    }
+
+    /// Read cell voltage (V)
+    fn read_voltage(&self) -> Result<f64> {
+        let v = self.read_word(VCELL_REG)?;
+        Ok(v as f64 * 0.000_078_125)
+    }
+
+    /// Read intensity (A), of charge rate and capacity, no current sense
+    fn read_intensity(&self) -> Result<f64> {
+        let rate = self.read_word(CRATE_REG)? as i16;
+        let percent_per_hour = rate as f64 * 0.208;
+        Ok(percent_per_hour / 100.0 * self.capacity / 1000.0)
+    }
+
+    /// Read state of charge (%)
+    fn read_level(&self) -> Result<Option<f64>> {
+        let soc = self.read_word(SOC_REG)?;
+        Ok(Some((soc as f64 / 256.0).min(100.0)))
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Pushover api
+pub const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+/// Header of the event id, the same on every attempt of a notification
+pub const EVENT_ID_HEADER: &str = "X-PiSugar-Event-Id";
+
+/// Random uuid (v4) of an event, consumers deduplicate retried notifications by it. Without
+/// /dev/urandom, of time, pid and a counter, never empty
+pub fn new_event_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut buf = [0u8; 16];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        log::warn!("Event id of urandom failed: {}", e);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed) | (std::process::id() as u64) << 32;
+        buf[..8].copy_from_slice(&nanos.to_be_bytes());
+        buf[8..].copy_from_slice(&n.to_be_bytes());
+    }
+    buf[6] = (buf[6] & 0x0f) | 0x40;
+    buf[8] = (buf[8] & 0x3f) | 0x80;
+    let hex: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Notification backend
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyBackend {
+    /// ntfy.sh or self-hosted ntfy, url of topic
+    Ntfy,
+    /// Gotify server url, app token
+    Gotify,
+    /// Pushover, app token and user key
+    Pushover,
+}
+
+/// Notification target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifier {
+    pub backend: NotifyBackend,
+
+    /// Topic url of ntfy, server url of gotify, ignored by pushover
+    #[serde(default)]
+    pub url: String,
+
+    /// Access token of ntfy, app token of gotify and pushover
+    #[serde(default)]
+    pub token: String,
+
+    /// User key of pushover
+    #[serde(default)]
+    pub user: String,
+
+    /// Events to notify, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Http post of a notification, url, headers and body
+pub struct NotifyRequest {
+    pub url: String,
+    pub headers: Vec<String>,
+    pub body: String,
+}
+
+impl Notifier {
+    /// Whether event is notified
+    pub fn accepts(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+
+    /// Http post of notification, event id in a header and in gotify extras
+    pub fn request(&self, event_id: &str, title: &str, message: &str) -> NotifyRequest {
+        let json = "Content-Type: application/json".to_string();
+        let id = format!("{}: {}", EVENT_ID_HEADER, event_id);
+        match self.backend {
+            NotifyBackend::Ntfy => {
+                let mut headers = vec![format!("Title: {}", title), id];
+                if !self.token.is_empty() {
+                    headers.push(format!("Authorization: Bearer {}", self.token));
+                }
+                NotifyRequest {
+                    url: self.url.clone(),
+                    headers,
+                    body: message.to_string(),
+                }
+            }
+            NotifyBackend::Gotify => NotifyRequest {
+                url: format!("{}/message", self.url.trim_end_matches('/')),
+                headers: vec![json, id, format!("X-Gotify-Key: {}", self.token)],
+                body: json!({
+                    "title": title,
+                    "message": message,
+                    "extras": { "pisugar::event": { "id": event_id } },
+                })
+                .to_string(),
+            },
+            NotifyBackend::Pushover => NotifyRequest {
+                url: PUSHOVER_URL.to_string(),
+                headers: vec![json, id],
+                body: json!({
+                    "token": self.token,
+                    "user": self.user,
+                    "title": title,
+                    "message": message,
+                })
+                .to_string(),
+            },
+        }
+    }
+}
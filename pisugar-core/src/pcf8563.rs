@@ -0,0 +1,131 @@
+use crate::bus::{open_rppal, I2cBus};
+use crate::rtc::test_register;
+use crate::{Result, RtcDevice, RtcModel, SD3078Time};
+
+/// Control status 2, AIE bit1, AF bit3
+const CONTROL2_REG: u8 = 0x01;
+
+/// Time registers, seconds/minutes/hours/days/weekdays/months/years
+const TIME_REG: u8 = 0x02;
+
+/// Alarm registers, minute/hour/day/weekday, AE bit7 disables a register
+const ALARM_REG: u8 = 0x09;
+
+/// Timer value, unused and a scratch register of self test
+const SCRATCH_REG: u8 = 0x0f;
+
+/// PCF8563, rtc chip
+pub struct PCF8563 {
+    i2c: Box<dyn I2cBus>,
+}
+
+impl PCF8563 {
+    /// Create new PCF8563
+    pub fn new(i2c_addr: u16) -> Result<Self> {
+        Ok(Self::with_bus(open_rppal(i2c_addr)?))
+    }
+
+    /// Create PCF8563 on a bus
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Self {
+        Self { i2c }
+    }
+}
+
+impl RtcDevice for PCF8563 {
+    fn model(&self) -> RtcModel {
+        RtcModel::Pcf8563
+    }
+
+    /// Read time, days before weekdays in registers
+    fn read_time(&self) -> Result<SD3078Time> {
+        let mut regs = [0_u8; 7];
+        self.i2c.block_read(TIME_REG, &mut regs)?;
+
+        Ok(SD3078Time::from_raw([
+            regs[0] & 0b0111_1111,
+            regs[1] & 0b0111_1111,
+            regs[2] & 0b0011_1111,
+            regs[4] & 0b0000_0111,
+            regs[3] & 0b0011_1111,
+            regs[5] & 0b0001_1111,
+            regs[6],
+        ]))
+    }
+
+    /// Write time, clears VL
+    fn write_time(&self, t: SD3078Time) -> Result<()> {
+        let t = t.to_raw();
+        let regs = [t[0], t[1], t[2], t[4], t[3], t[5], t[6]];
+        self.i2c.block_write(TIME_REG, regs.as_ref())
+    }
+
+    /// Read alarm time, no alarm seconds, date of today
+    fn read_alarm_time(&self) -> Result<SD3078Time> {
+        let mut alarm = [0_u8; 4];
+        self.i2c.block_read(ALARM_REG, &mut alarm)?;
+
+        let mut bcd_time = self.read_time()?.to_raw();
+        bcd_time[0] = 0;
+        bcd_time[1] = alarm[0] & 0b0111_1111;
+        bcd_time[2] = alarm[1] & 0b0011_1111;
+
+        Ok(SD3078Time::from_raw(bcd_time))
+    }
+
+    /// Check alarm interrupt enabled
+    fn read_alarm_enabled(&self) -> Result<bool> {
+        let control2 = self.i2c.smbus_read_byte(CONTROL2_REG)?;
+        Ok(control2 & 0b0000_0010 != 0)
+    }
+
+    /// Read alarm flag
+    fn read_alarm_flag(&self) -> Result<bool> {
+        let control2 = self.i2c.smbus_read_byte(CONTROL2_REG)?;
+        Ok(control2 & 0b0000_1000 != 0)
+    }
+
+    /// Clear alarm flag
+    fn clear_alarm_flag(&self) -> Result<()> {
+        let control2 = self.i2c.smbus_read_byte(CONTROL2_REG)?;
+        self.i2c
+            .smbus_write_byte(CONTROL2_REG, control2 & 0b1111_0111)
+    }
+
+    /// Disable alarm interrupt
+    fn disable_alarm(&self) -> Result<()> {
+        let control2 = self.i2c.smbus_read_byte(CONTROL2_REG)?;
+        self.i2c
+            .smbus_write_byte(CONTROL2_REG, control2 & 0b1111_1101)
+    }
+
+    /// Set alarm of minute, on a single weekday or daily, several weekdays fall back to daily
+    fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()> {
+        let t = t.to_raw();
+        let weekdays = weekday_repeat & 0b0111_1111;
+        let weekday = if weekdays.count_ones() == 1 {
+            weekdays.trailing_zeros() as u8
+        } else {
+            if weekdays != 0b0111_1111 {
+                log::warn!("PCF8563 alarm of weekdays {:#09b} repeats daily", weekdays);
+            }
+            0b1000_0000
+        };
+        let alarm = [t[1], t[2] & 0b0011_1111, 0b1000_0000, weekday];
+        self.i2c.block_write(ALARM_REG, alarm.as_ref())?;
+
+        let control2 = self.i2c.smbus_read_byte(CONTROL2_REG)?;
+        self.i2c
+            .smbus_write_byte(CONTROL2_REG, (control2 & 0b1111_0111) | 0b0000_0010)
+    }
+
+    /// Write patterns to timer value and read back, restored after
+    fn test_scratch(&self) -> Result<()> {
+        test_register(self.i2c.as_ref(), SCRATCH_REG)
+    }
+
+    /// Voltage low flag of seconds register, cleared by setting time
+    fn poll_backup_battery(&self) -> Result<bool> {
+        let seconds = self.i2c.smbus_read_byte(TIME_REG)?;
+        Ok(seconds & 0b1000_0000 != 0)
+    }
+}
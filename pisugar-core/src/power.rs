@@ -0,0 +1,101 @@
+use serde::Serialize;
+
+/// Power management state, actions are driven by transitions
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    /// External power and charging, or mains only without a battery
+    Normal,
+    /// External power, charger stopped at full level
+    ChargingLimited,
+    /// Discharging
+    OnBattery,
+    /// Discharging at or below `low_battery_level` or `low_battery_voltage`
+    Low,
+    /// At or below `auto_shutdown_level` or `auto_shutdown_voltage`, shutdown follows
+    Critical,
+    /// Shutdown requested, final
+    ShuttingDown,
+}
+
+impl Default for PowerState {
+    fn default() -> Self {
+        PowerState::Normal
+    }
+}
+
+/// Inputs of power state transitions
+#[derive(Debug, Clone)]
+pub struct PowerInputs {
+    pub battery_present: bool,
+    pub charging: bool,
+    /// Battery held full
+    pub full: bool,
+    pub level: f64,
+    pub low_level: f64,
+    pub critical_level: f64,
+    /// Averaged voltage, sags of load peaks are smoothed
+    pub voltage: f64,
+    /// Voltage thresholds, disabled if 0
+    pub low_voltage: f64,
+    pub critical_voltage: f64,
+}
+
+impl PowerInputs {
+    /// At or below critical level or voltage
+    pub fn critical(&self) -> bool {
+        self.level <= self.critical_level
+            || (self.critical_voltage > 0.0 && self.voltage <= self.critical_voltage)
+    }
+
+    /// At or below low level or voltage
+    pub fn low(&self) -> bool {
+        self.level <= self.low_level || (self.low_voltage > 0.0 && self.voltage <= self.low_voltage)
+    }
+}
+
+impl PowerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerState::Normal => "normal",
+            PowerState::ChargingLimited => "charging_limited",
+            PowerState::OnBattery => "on_battery",
+            PowerState::Low => "low",
+            PowerState::Critical => "critical",
+            PowerState::ShuttingDown => "shutting_down",
+        }
+    }
+
+    /// Transition event payload, `power_state <state>`
+    pub fn event(&self) -> &'static str {
+        match self {
+            PowerState::Normal => "power_state normal",
+            PowerState::ChargingLimited => "power_state charging_limited",
+            PowerState::OnBattery => "power_state on_battery",
+            PowerState::Low => "power_state low",
+            PowerState::Critical => "power_state critical",
+            PowerState::ShuttingDown => "power_state shutting_down",
+        }
+    }
+
+    /// Next state of inputs, shutting down is final, critical even if charging
+    pub fn next(&self, inputs: &PowerInputs) -> PowerState {
+        if *self == PowerState::ShuttingDown {
+            return PowerState::ShuttingDown;
+        }
+        if !inputs.battery_present {
+            return PowerState::Normal;
+        }
+        if inputs.critical() {
+            PowerState::Critical
+        } else if inputs.charging && inputs.full {
+            PowerState::ChargingLimited
+        } else if inputs.charging {
+            PowerState::Normal
+        } else if inputs.low() {
+            PowerState::Low
+        } else {
+            PowerState::OnBattery
+        }
+    }
+}
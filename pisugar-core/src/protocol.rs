@@ -0,0 +1,259 @@
+use std::convert::TryInto;
+
+use chrono::{DateTime, Local};
+
+use crate::{HistoryQuery, PiSugarCore, MAX_QUERY_RANGE, SCHEDULE_DAYS};
+
+/// Max request length (bytes), longer requests are rejected
+pub const MAX_REQUEST_LEN: usize = 4096;
+
+/// Max arguments of a request
+pub const MAX_REQUEST_ARGS: usize = 64;
+
+/// Max length of a request id
+pub const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Max days of `get schedule [days]`
+pub const MAX_SCHEDULE_DAYS: i64 = 31;
+
+/// Split a correlation id of `#<id> <request>`, id of alphanumerics, `-` and `_`, echoed in the
+/// response, the request is kept as is without a valid id
+pub fn split_request_id(raw: &str) -> (Option<&str>, &str) {
+    let mut parts = match raw.strip_prefix('#') {
+        Some(rest) => rest.splitn(2, ' '),
+        None => return (None, raw),
+    };
+    let id = parts.next().unwrap_or_default();
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match parts.next() {
+        Some(rest) if valid => (Some(id), rest),
+        _ => (None, raw),
+    }
+}
+
+/// Request of the text protocol, `<cmd> [args...]` separated by spaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request<'a> {
+    raw: &'a str,
+    parts: Vec<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request line, None if empty, too long, too many arguments or has NUL
+    pub fn parse(raw: &'a str) -> Option<Self> {
+        if raw.is_empty() || raw.len() > MAX_REQUEST_LEN || raw.contains('\0') {
+            return None;
+        }
+        let parts: Vec<&str> = raw.split(' ').collect();
+        if parts[0].is_empty() || parts.len() > MAX_REQUEST_ARGS + 1 {
+            return None;
+        }
+        Some(Self { raw, parts })
+    }
+
+    /// Command
+    pub fn cmd(&self) -> &'a str {
+        self.parts[0]
+    }
+
+    /// Argument at index, after the command
+    pub fn arg(&self, index: usize) -> Option<&'a str> {
+        self.parts.get(index + 1).copied()
+    }
+
+    /// Arguments, after the command
+    pub fn args(&self) -> &[&'a str] {
+        &self.parts[1..]
+    }
+
+    /// Raw text from argument at index, spaces are kept, e.g. shell commands
+    pub fn rest(&self, index: usize) -> Option<&'a str> {
+        if index + 1 >= self.parts.len() {
+            return None;
+        }
+        let skip: usize = self.parts[..index + 1].iter().map(|p| p.len() + 1).sum();
+        self.raw.get(skip..)
+    }
+
+    /// Raw request line
+    pub fn as_str(&self) -> &'a str {
+        self.raw
+    }
+}
+
+/// Execute `get <field> [args...]` on the core, the response line, None if invalid
+pub fn execute_get(core: &PiSugarCore, request: &Request) -> Option<String> {
+    let field = request.arg(0)?;
+    let resp = match field {
+        "model" => core.model().to_string(),
+        "power_on_mode" => core.power_on_mode().to_string(),
+        "power_state" => core.power_state().as_str().to_string(),
+        "job" => {
+            let job = core.jobs().get(request.arg(1)?.parse().ok()?)?;
+            serde_json::to_string(job).unwrap_or_default()
+        }
+        "jobs" => serde_json::to_string(&core.jobs().list()).unwrap_or_default(),
+        "input_source" => core.input_source().as_str().to_string(),
+        "quiet" => core.status().quiet().to_string(),
+        "hardware_id" => match core.status().hardware_id() {
+            Some(id) => id.to_string(),
+            None => "unknown".to_string(),
+        },
+        "battery_present" => core.battery_present().to_string(),
+        "battery" | "battery_v" | "battery_i" | "battery_i_avg" | "battery_power_w"
+        | "battery_charging" | "battery_full_at" | "stats"
+            if !core.battery_present() =>
+        {
+            "not present".to_string()
+        }
+        "battery" => core.level().to_string(),
+        "battery_v" => core.voltage().to_string(),
+        "battery_i" => core.intensity().to_string(),
+        "battery_i_avg" => match request.arg(1).and_then(|s| s.parse::<u64>().ok()) {
+            Some(seconds) if seconds > 0 && seconds <= MAX_QUERY_RANGE as u64 => {
+                core.intensity_avg(seconds).to_string()
+            }
+            _ => {
+                log::error!("get {}: invalid seconds", field);
+                return None;
+            }
+        },
+        "battery_power_w" => core.power().to_string(),
+        "battery_charging" => core.charging().to_string(),
+        "battery_full_at" => match core.battery_full_at() {
+            Some(t) => format!("{:?}", t),
+            None => "none".to_string(),
+        },
+        "stats" => serde_json::to_string(core.stats()).unwrap_or_default(),
+        "listeners" => serde_json::to_string(core.listener_stats()).unwrap_or_default(),
+        "shutdown_history" => {
+            serde_json::to_string(core.shutdown_history().records()).unwrap_or_default()
+        }
+        "system" => match core.system_metrics() {
+            Ok(metrics) => serde_json::to_string(&metrics).unwrap_or_default(),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "throttled" => match core.throttled() {
+            Ok(throttled) => throttled.to_string(),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "rtc_time" | "rtc_time_list" | "rtc_drift" if !core.rtc_enabled() => {
+            log::error!("RTC disabled");
+            return None;
+        }
+        "rtc_time" => format!("{:?}", core.read_time()),
+        "rtc_time_list" => format!("{}", core.read_raw_time()),
+        "rtc_drift" => match core.status().rtc_drift() {
+            Some(drift) => drift.to_string(),
+            None => "none".to_string(),
+        },
+        "rtc_alarm_flag" => match core.read_alarm_flag() {
+            Ok(flag) => format!("{}", flag),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "rtc_alarm_time" => match core.read_alarm_time() {
+            Ok(time) => {
+                let datetime: DateTime<Local> = time.try_into().ok()?;
+                format!("{:?}", datetime)
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "rtc_alarm_time_list" => match core.read_alarm_time() {
+            Ok(time) => time.to_string(),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "rtc_alarm_enabled" => match core.read_alarm_enabled() {
+            Ok(enabled) => format!("{}", enabled),
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        },
+        "alarm_repeat" => format!("{}", core.config().auto_wake_repeat),
+        "shutdown_eta" => match core.shutdown_eta() {
+            Some(eta) => format!("{}", eta),
+            None => "none".to_string(),
+        },
+        "schedule" => {
+            let days = request
+                .arg(1)
+                .and_then(|s| s.parse::<i64>().ok())
+                .unwrap_or(SCHEDULE_DAYS)
+                .max(1)
+                .min(MAX_SCHEDULE_DAYS);
+            let events = core.schedule(Local::now(), days);
+            serde_json::to_string(&events).unwrap_or_default()
+        }
+        "safe_shutdown_level" => {
+            format!("{}", core.config().auto_shutdown_level)
+        }
+        "button_enable" => {
+            let tap = request.arg(1)?;
+            let enable = match tap {
+                "single" => core.config().single_tap_enable,
+                "double" => core.config().double_tap_enable,
+                "long" => core.config().long_tap_enable,
+                _ => {
+                    log::error!("get {}: unknown tap type", field);
+                    return None;
+                }
+            };
+            format!("{} {}", tap, enable)
+        }
+        "button_shell" => {
+            let tap = request.arg(1)?;
+            let shell = match tap {
+                "single" => core.config().single_tap_shell.as_str(),
+                "double" => core.config().double_tap_shell.as_str(),
+                "long" => core.config().long_tap_shell.as_str(),
+                _ => {
+                    log::error!("get {}: unknown tap type", field);
+                    return None;
+                }
+            };
+            format!("{} {}", tap, shell)
+        }
+        "config" => core.config_json().to_string(),
+        // any other config field
+        field => match core.config_json().get(field)? {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        },
+    };
+    Some(format!("{}: {}\n", field, resp))
+}
+
+/// Execute `history query <field> <aggregation> <bucket> last <range>` on the core, the response
+/// line, None if invalid
+pub fn execute_history(core: &PiSugarCore, request: &Request) -> Option<String> {
+    match (
+        request.arg(0),
+        HistoryQuery::parse(request.args().get(1..).unwrap_or_default()),
+    ) {
+        (Some("query"), Some(query)) => {
+            let series = query.run(core.history(), Local::now());
+            let json = serde_json::to_string(&series).unwrap_or_default();
+            Some(format!("history: {}\n", json))
+        }
+        _ => None,
+    }
+}
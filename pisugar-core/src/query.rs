@@ -0,0 +1,144 @@
+use chrono::{DateTime, Duration, Local};
+
+use crate::{HistorySample, HistoryStore};
+
+/// Max buckets of a history query
+pub const MAX_QUERY_BUCKETS: i64 = 1000;
+
+/// Max range of a history query (s), a year
+pub const MAX_QUERY_RANGE: i64 = 366 * 24 * 3600;
+
+/// Seconds of a duration, e.g. `30s`, `5m`, `1h`, `7d`, `2w`
+pub fn parse_duration(s: &str) -> Option<i64> {
+    if s.len() < 2 {
+        return None;
+    }
+    let (n, unit) = s.split_at(s.len() - 1);
+    let n: i64 = n.parse().ok().filter(|n| *n > 0)?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        _ => return None,
+    };
+    n.checked_mul(unit)
+}
+
+/// Field of history samples
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HistoryField {
+    Level,
+    Voltage,
+    Intensity,
+    /// Charging as 1, otherwise 0
+    Charging,
+}
+
+impl HistoryField {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "level" => Some(HistoryField::Level),
+            "voltage" => Some(HistoryField::Voltage),
+            "intensity" => Some(HistoryField::Intensity),
+            "charging" => Some(HistoryField::Charging),
+            _ => None,
+        }
+    }
+
+    fn value(&self, sample: &HistorySample) -> f64 {
+        match self {
+            HistoryField::Level => sample.level,
+            HistoryField::Voltage => sample.voltage,
+            HistoryField::Intensity => sample.intensity,
+            HistoryField::Charging => (sample.charging as u8).into(),
+        }
+    }
+}
+
+/// Aggregation of a bucket
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Last,
+}
+
+impl Aggregation {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "avg" => Some(Aggregation::Avg),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "last" => Some(Aggregation::Last),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, values: &[f64]) -> f64 {
+        match self {
+            Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Last => values.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Aggregation query of history, `<field> <avg|min|max|last> <bucket> last <range>`,
+/// e.g. `level avg 1h last 7d`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub field: HistoryField,
+    pub aggregation: Aggregation,
+    /// Bucket seconds
+    pub bucket: i64,
+    /// Range seconds before now
+    pub range: i64,
+}
+
+impl HistoryQuery {
+    /// Parse query arguments, at most `MAX_QUERY_BUCKETS` buckets within `MAX_QUERY_RANGE`
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        match args {
+            [field, aggregation, bucket, "last", range] => {
+                let query = Self {
+                    field: HistoryField::parse(field)?,
+                    aggregation: Aggregation::parse(aggregation)?,
+                    bucket: parse_duration(bucket)?,
+                    range: parse_duration(range)?,
+                };
+                if query.range > MAX_QUERY_RANGE || query.range / query.bucket > MAX_QUERY_BUCKETS {
+                    return None;
+                }
+                Some(query)
+            }
+            _ => None,
+        }
+    }
+
+    /// Series of `(bucket start unix time, value)`, oldest first, empty buckets are skipped
+    pub fn run(&self, history: &dyn HistoryStore, now: DateTime<Local>) -> Vec<(i64, f64)> {
+        let range = Duration::seconds(self.range.min(MAX_QUERY_RANGE));
+        let from = match now.checked_sub_signed(range) {
+            Some(from) => from,
+            None => return Vec::new(),
+        };
+        let samples = history.query(Some(from), Some(now));
+        let mut series = Vec::new();
+        let mut i = 0;
+        while i < samples.len() {
+            let bucket = samples[i].time.timestamp().div_euclid(self.bucket);
+            let values: Vec<f64> = samples[i..]
+                .iter()
+                .take_while(|s| s.time.timestamp().div_euclid(self.bucket) == bucket)
+                .map(|s| self.field.value(s))
+                .collect();
+            i += values.len();
+            series.push((bucket * self.bucket, self.aggregation.apply(&values)));
+        }
+        series
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::bus::I2cBus;
+use crate::{Error, Result, SD3078Time, DS3231, I2C_ADDR_RTC, PCF8563, SD3078};
+
+/// Rtc chip, `rtc_model` of config
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RtcModel {
+    /// PiSugar 2 builtin
+    Sd3078,
+    Ds3231,
+    Pcf8563,
+}
+
+impl Default for RtcModel {
+    fn default() -> Self {
+        RtcModel::Sd3078
+    }
+}
+
+impl RtcModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtcModel::Sd3078 => "sd3078",
+            RtcModel::Ds3231 => "ds3231",
+            RtcModel::Pcf8563 => "pcf8563",
+        }
+    }
+
+    /// I2c address of chip
+    pub fn addr(&self) -> u16 {
+        match self {
+            RtcModel::Sd3078 => I2C_ADDR_RTC,
+            RtcModel::Ds3231 => 0x68,
+            RtcModel::Pcf8563 => 0x51,
+        }
+    }
+
+    /// Registers of chip, register dump
+    pub fn registers(&self) -> u16 {
+        match self {
+            RtcModel::Sd3078 => 0x72,
+            RtcModel::Ds3231 => 0x13,
+            RtcModel::Pcf8563 => 0x10,
+        }
+    }
+}
+
+/// Rtc operations, times are bcd `SD3078Time` of any chip
+pub trait RtcDevice: Send {
+    fn model(&self) -> RtcModel;
+
+    /// Rtc device of the kernel driver, raw i2c if none
+    fn kernel_device(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Device id burnt in at factory, if any
+    fn device_id(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn read_time(&self) -> Result<SD3078Time>;
+
+    fn write_time(&self, t: SD3078Time) -> Result<()>;
+
+    fn read_alarm_time(&self) -> Result<SD3078Time>;
+
+    fn read_alarm_enabled(&self) -> Result<bool>;
+
+    fn read_alarm_flag(&self) -> Result<bool>;
+
+    fn clear_alarm_flag(&self) -> Result<()>;
+
+    fn disable_alarm(&self) -> Result<()>;
+
+    /// Set alarm, weekday_repeat from sunday 0-6
+    fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()>;
+
+    /// Set a test wake up after 1 minutes
+    fn set_test_wake(&self) -> Result<()> {
+        let now = Local::now();
+        self.write_time(now.into())?;
+
+        let duration = chrono::Duration::seconds(90);
+        let then = now + duration;
+        self.set_alarm(then.into(), 0b0111_1111)?;
+
+        log::error!("Will wake up after 1min 30sec, please power-off");
+
+        Ok(())
+    }
+
+    /// Write patterns to a scratch register and read back, self test
+    fn test_scratch(&self) -> Result<()> {
+        Err(Error::Other(format!(
+            "No scratch register of {}",
+            self.model().as_str()
+        )))
+    }
+
+    /// Backup battery low, charging is maintained by chips able to
+    fn poll_backup_battery(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Rtc of model on a bus
+pub fn open_rtc(model: RtcModel, i2c: Box<dyn I2cBus>) -> Box<dyn RtcDevice> {
+    match model {
+        RtcModel::Sd3078 => Box::new(SD3078::with_bus(i2c)),
+        RtcModel::Ds3231 => Box::new(DS3231::with_bus(i2c)),
+        RtcModel::Pcf8563 => Box::new(PCF8563::with_bus(i2c)),
+    }
+}
+
+/// Write patterns to a register and read back, restored after
+pub(crate) fn test_register(i2c: &dyn I2cBus, reg: u8) -> Result<()> {
+    let saved = i2c.smbus_read_byte(reg)?;
+    let tested = (|| -> Result<()> {
+        for pattern in &[0x55, 0xaa] {
+            i2c.smbus_write_byte(reg, *pattern)?;
+            let read = i2c.smbus_read_byte(reg)?;
+            if read != *pattern {
+                return Err(Error::Other(format!(
+                    "Scratch read {:#04x}, written {:#04x}",
+                    read, pattern
+                )));
+            }
+        }
+        Ok(())
+    })();
+    i2c.smbus_write_byte(reg, saved)?;
+    tested
+}
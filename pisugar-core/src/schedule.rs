@@ -0,0 +1,78 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Timelike};
+use serde::Serialize;
+
+/// Days of upcoming schedule
+pub const SCHEDULE_DAYS: i64 = 7;
+
+/// Kind of scheduled event
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleKind {
+    /// Rtc wake alarm
+    Wake,
+    /// Wake-on-lan of `wol_times`
+    WakeOnLan,
+    /// Safe shutdown at `auto_shutdown_level`, estimated by discharge current
+    Shutdown,
+}
+
+/// Upcoming event
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledEvent {
+    pub kind: ScheduleKind,
+    pub time: DateTime<Local>,
+    /// Estimated, not a fixed time
+    pub estimated: bool,
+}
+
+/// Times of a daily time-of-day within days after now, on weekdays of mask (bit 0 is sunday)
+fn daily_times(
+    time: NaiveTime,
+    weekdays: u8,
+    now: DateTime<Local>,
+    days: i64,
+) -> Vec<DateTime<Local>> {
+    let end = now + Duration::days(days);
+    (0..=days)
+        .map(|d| now.date() + Duration::days(d))
+        .filter(|date| weekdays & (1 << date.weekday().num_days_from_sunday()) != 0)
+        .filter_map(|date| {
+            Local
+                .from_local_datetime(&date.naive_local().and_time(time))
+                .earliest()
+        })
+        .filter(|t| *t > now && *t <= end)
+        .collect()
+}
+
+/// Wake alarm times, time-of-day of alarm repeated on weekdays
+pub fn wake_schedule(
+    alarm: DateTime<Local>,
+    weekday_repeat: u8,
+    now: DateTime<Local>,
+    days: i64,
+) -> Vec<ScheduledEvent> {
+    let time = NaiveTime::from_hms(alarm.hour(), alarm.minute(), alarm.second());
+    daily_times(time, weekday_repeat, now, days)
+        .into_iter()
+        .map(|time| ScheduledEvent {
+            kind: ScheduleKind::Wake,
+            time,
+            estimated: false,
+        })
+        .collect()
+}
+
+/// Wake-on-lan times, `HH:MM` every day, invalid times are skipped
+pub fn wol_schedule(wol_times: &[String], now: DateTime<Local>, days: i64) -> Vec<ScheduledEvent> {
+    wol_times
+        .iter()
+        .filter_map(|hm| NaiveTime::parse_from_str(hm, "%H:%M").ok())
+        .flat_map(|time| daily_times(time, 0x7f, now, days))
+        .map(|time| ScheduledEvent {
+            kind: ScheduleKind::WakeOnLan,
+            time,
+            estimated: false,
+        })
+        .collect()
+}
@@ -3,9 +3,12 @@ use std::fmt::{self, Display};
 use chrono::prelude::*;
 use std::convert::TryFrom;
 
-use crate::Result;
+use crate::bus::{open_rppal, I2cBus};
+use crate::{Error, Result, RtcDevice, RtcModel};
 use chrono::LocalResult;
-use rppal::i2c::I2c;
+
+/// Scratch register of user ram, self test
+const SCRATCH_REG: u8 = 0x2c;
 
 /// SD3078 time, always 24hr
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -61,6 +64,11 @@ impl SD3078Time {
         bcd_to_dec(self.0[0])
     }
 
+    /// To raw bcd, e.g. of other rtc chips
+    pub fn to_raw(&self) -> [u8; 7] {
+        self.0
+    }
+
     /// To dec
     pub fn to_dec(&self) -> [u8; 7] {
         [
@@ -122,15 +130,18 @@ impl TryFrom<SD3078Time> for DateTime<Local> {
 
 /// SD3078, rtc chip
 pub struct SD3078 {
-    i2c: I2c,
+    i2c: Box<dyn I2cBus>,
 }
 
 impl SD3078 {
     /// Create new SD3078
     pub fn new(i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::new()?;
-        i2c.set_slave_address(i2c_addr)?;
-        Ok(Self { i2c })
+        Ok(Self::with_bus(open_rppal(i2c_addr)?))
+    }
+
+    /// Create SD3078 on a bus
+    pub fn with_bus(i2c: Box<dyn I2cBus>) -> Self {
+        Self { i2c }
     }
 
     /// Disable write protect
@@ -181,6 +192,13 @@ impl SD3078 {
         Ok(v & 0b1000_0000 != 0)
     }
 
+    /// Read device id, 8 bytes burnt in at factory
+    pub fn read_id(&self) -> Result<[u8; 8]> {
+        let mut id = [0_u8; 8];
+        self.i2c.block_read(0x72, &mut id)?;
+        Ok(id)
+    }
+
     /// Toggle rtc battery charging
     pub fn toggle_charging(&self, enable: bool) -> Result<()> {
         self.enable_write()?;
@@ -188,9 +206,19 @@ impl SD3078 {
         self.i2c.smbus_write_byte(0x18, v)?;
         self.disable_write()
     }
+}
+
+impl RtcDevice for SD3078 {
+    fn model(&self) -> RtcModel {
+        RtcModel::Sd3078
+    }
+
+    fn device_id(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.read_id()?.to_vec()))
+    }
 
     /// Read time
-    pub fn read_time(&self) -> Result<SD3078Time> {
+    fn read_time(&self) -> Result<SD3078Time> {
         let mut bcd_time = [0_u8; 7];
         self.i2c.block_read(0, &mut bcd_time)?;
 
@@ -205,7 +233,7 @@ impl SD3078 {
     }
 
     /// Write time
-    pub fn write_time(&self, t: SD3078Time) -> Result<()> {
+    fn write_time(&self, t: SD3078Time) -> Result<()> {
         // 24h
         let mut bcd_time = t.0.clone();
         bcd_time[2] |= 0b1000_0000;
@@ -218,7 +246,7 @@ impl SD3078 {
     }
 
     /// Read alarm time
-    pub fn read_alarm_time(&self) -> Result<SD3078Time> {
+    fn read_alarm_time(&self) -> Result<SD3078Time> {
         let mut bcd_time = [0_u8; 7];
         self.i2c.block_read(0x07, &mut bcd_time)?;
 
@@ -233,7 +261,7 @@ impl SD3078 {
     }
 
     /// Check alarm enabled
-    pub fn read_alarm_enabled(&self) -> Result<bool> {
+    fn read_alarm_enabled(&self) -> Result<bool> {
         let v = self.i2c.smbus_read_byte(0x0e)?;
         if v & 0b0000_0111 == 0 {
             return Ok(false);
@@ -248,7 +276,7 @@ impl SD3078 {
     }
 
     /// Read alarm flag
-    pub fn read_alarm_flag(&self) -> Result<bool> {
+    fn read_alarm_flag(&self) -> Result<bool> {
         // CTR1 - INTDF and INTAF
         let data = self.i2c.smbus_read_byte(0x0f)?;
         if data & 0b0010_0000 != 0 || data & 0b0001_0000 != 0 {
@@ -259,7 +287,7 @@ impl SD3078 {
     }
 
     /// Clear alarm flag
-    pub fn clear_alarm_flag(&self) -> Result<()> {
+    fn clear_alarm_flag(&self) -> Result<()> {
         if let Ok(true) = self.read_alarm_flag() {
             self.enable_write()?;
             let mut ctr1 = self.i2c.smbus_read_byte(0x0f)?;
@@ -272,7 +300,7 @@ impl SD3078 {
     }
 
     /// Disable alarm
-    pub fn disable_alarm(&self) -> Result<()> {
+    fn disable_alarm(&self) -> Result<()> {
         self.enable_write()?;
 
         // CTR2 - INTS1, clear
@@ -290,7 +318,7 @@ impl SD3078 {
     }
 
     /// Set alarm, weekday_repeat from sunday 0-6
-    pub fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()> {
+    fn set_alarm(&self, t: SD3078Time, weekday_repeat: u8) -> Result<()> {
         let mut bcd_time = t.0.clone();
         bcd_time[3] = weekday_repeat;
 
@@ -313,25 +341,48 @@ impl SD3078 {
         Ok(())
     }
 
-    /// Set a test wake up after 1 minutes
-    pub fn set_test_wake(&self) -> Result<()> {
-        let now = Local::now();
-        self.write_time(now.into())?;
-
-        let duration = chrono::Duration::seconds(90);
-        let then = now + duration;
-        self.set_alarm(then.into(), 0b0111_1111)?;
-
-        log::error!("Will wake up after 1min 30sec, please power-off");
+    /// Write patterns to a scratch register of user ram and read back, restored after
+    fn test_scratch(&self) -> Result<()> {
+        let saved = self.i2c.smbus_read_byte(SCRATCH_REG)?;
+        self.enable_write()?;
+        let tested = (|| -> Result<()> {
+            for pattern in &[0x55, 0xaa] {
+                self.i2c.smbus_write_byte(SCRATCH_REG, *pattern)?;
+                let read = self.i2c.smbus_read_byte(SCRATCH_REG)?;
+                if read != *pattern {
+                    return Err(Error::Other(format!(
+                        "Scratch read {:#04x}, written {:#04x}",
+                        read, pattern
+                    )));
+                }
+            }
+            Ok(())
+        })();
+        let _ = self.i2c.smbus_write_byte(SCRATCH_REG, saved);
+        self.disable_write()?;
+        tested
+    }
 
-        Ok(())
+    /// Rtc battery low flag, charging enabled while low and disabled once high
+    fn poll_backup_battery(&self) -> Result<bool> {
+        let low = self.read_battery_low_flag()?;
+        if low && (self.read_battery_charging_flag().ok() == Some(false)) {
+            log::debug!("Enable rtc charging");
+            let _ = self.toggle_charging(true);
+        } else if (self.read_battery_high_flag().ok() == Some(true))
+            && (self.read_battery_charging_flag().ok() == Some(true))
+        {
+            log::debug!("Disable rtc charging");
+            let _ = self.toggle_charging(false);
+        }
+        Ok(low)
     }
 }
 
-fn bcd_to_dec(bcd: u8) -> u8 {
+pub(crate) fn bcd_to_dec(bcd: u8) -> u8 {
     (bcd & 0x0F) + (((bcd & 0xF0) >> 4) * 10)
 }
 
-fn dec_to_bcd(dec: u8) -> u8 {
+pub(crate) fn dec_to_bcd(dec: u8) -> u8 {
     dec % 10 + ((dec / 10) << 4)
 }
@@ -0,0 +1,38 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::Result;
+
+/// Check of a self test
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    /// Reading or error
+    pub detail: String,
+}
+
+/// Self test report, e.g. of provisioning to catch mis-seated boards
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SelfTestReport {
+    /// All checks passed
+    pub passed: bool,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Add a check of result
+    pub fn check<T: Display>(&mut self, name: &'static str, result: Result<T>) {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail.to_string()),
+            Err(e) => (false, e.to_string()),
+        };
+        self.checks.push(SelfTestCheck {
+            name,
+            passed,
+            detail,
+        });
+        self.passed = self.checks.iter().all(|c| c.passed);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{token_fingerprint, Role};
+
+/// Session file, next to config file
+pub const SESSIONS_FILE: &str = "sessions.json";
+
+/// Max sessions, oldest are dropped
+const SESSIONS_CAPACITY: usize = 64;
+
+/// Random bytes of a session id
+const SESSION_ID_LEN: usize = 16;
+
+/// Output format of responses
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// `<cmd>: <value>` lines
+    Text,
+    /// `{"key": "<cmd>", "cmd": "<cmd>", "value": "<value>"}` lines
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+impl OutputFormat {
+    /// Parse `text` or `json`
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Render a text response, invalid requests are `{"error": "..."}` in json
+    pub fn render(&self, resp: &str) -> String {
+        self.render_id(None, resp)
+    }
+
+    /// Render an event, `event_json` lines in json
+    pub fn render_event(&self, event: &str) -> String {
+        match self {
+            OutputFormat::Text => event.to_string(),
+            OutputFormat::Json => format!("{}\n", event_json(event)),
+        }
+    }
+
+    /// Render a text response of a request id, `#<id> <resp>` in text, `"id"` field in json
+    pub fn render_id(&self, id: Option<&str>, resp: &str) -> String {
+        match self {
+            OutputFormat::Text => match id {
+                Some(id) => format!("#{} {}", id, resp),
+                None => resp.to_string(),
+            },
+            OutputFormat::Json => {
+                let line = resp.trim_end_matches('\n');
+                let mut json = match line.find(": ") {
+                    Some(i) => {
+                        let key = &line[..i];
+                        json!({ "key": key, "cmd": key, "value": &line[i + 2..] })
+                    }
+                    None => json!({ "error": line }),
+                };
+                if let Some(id) = id {
+                    json["id"] = json!(id);
+                }
+                format!("{}\n", json)
+            }
+        }
+    }
+}
+
+/// Json of an event, `{"key": .., "event": .., "data": ..}` of the event name and arguments,
+/// json data is embedded, no `data` without arguments
+pub fn event_json(event: &str) -> serde_json::Value {
+    let name = event.split(' ').next().unwrap_or_default();
+    let data = event[name.len()..].trim_start();
+    let mut json = json!({ "key": name, "event": name });
+    if !data.is_empty() {
+        json["data"] = serde_json::from_str(data).unwrap_or_else(|_| json!(data));
+    }
+    json
+}
+
+/// Persisted session, role and preferences of a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    pub role: Option<Role>,
+    /// Fingerprint of the token authorizing the session, revoked with the token
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Humanized durations in json responses
+    #[serde(default)]
+    pub humanize: bool,
+    pub expires: DateTime<Local>,
+    /// Created since start, expiry is relative to the wall clock of this run
+    #[serde(skip)]
+    pub created: bool,
+}
+
+/// Sessions by id, survive restarts of the daemon
+#[derive(Default)]
+pub struct SessionStore {
+    path: Option<PathBuf>,
+    sessions: HashMap<String, SessionState>,
+    /// Not synced to disk on save
+    low_write: bool,
+}
+
+/// Random hex session id
+fn new_session_id() -> io::Result<String> {
+    let mut buf = [0u8; SESSION_ID_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl SessionStore {
+    /// Load from file, start empty if not exists
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.path = Some(path.to_path_buf());
+        if path.exists() {
+            let mut f = File::open(path)?;
+            let mut buff = String::new();
+            let _ = f.read_to_string(&mut buff)?;
+            self.sessions = serde_json::from_str(&buff)?;
+        }
+        Ok(())
+    }
+
+    /// Skip syncing on save, sessions are flushed by the kernel
+    pub fn set_low_write(&mut self, low_write: bool) {
+        self.low_write = low_write;
+    }
+
+    /// Create a session valid for ttl seconds, of the fingerprint of the authorizing token if any,
+    /// returns id
+    pub fn create(
+        &mut self,
+        role: Option<Role>,
+        token: Option<String>,
+        format: OutputFormat,
+        humanize: bool,
+        ttl: u64,
+    ) -> io::Result<String> {
+        let now = Local::now();
+        self.sessions.retain(|_, s| s.expires > now);
+        while self.sessions.len() >= SESSIONS_CAPACITY {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, s)| s.expires)
+                .map(|(id, _)| id.clone());
+            match oldest {
+                Some(id) => self.sessions.remove(&id),
+                None => break,
+            };
+        }
+        let id = new_session_id()?;
+        let state = SessionState {
+            role,
+            token,
+            format,
+            humanize,
+            expires: now + Duration::seconds(ttl as i64),
+            created: true,
+        };
+        self.sessions.insert(id.clone(), state);
+        self.save()?;
+        Ok(id)
+    }
+
+    /// Session of id, if not expired
+    pub fn get(&self, id: &str) -> Option<&SessionState> {
+        self.sessions.get(id).filter(|s| s.expires > Local::now())
+    }
+
+    /// Update format of a session and save
+    pub fn set_format(&mut self, id: &str, format: OutputFormat) -> io::Result<()> {
+        if let Some(s) = self.sessions.get_mut(id) {
+            s.format = format;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Update humanized durations of a session and save
+    pub fn set_humanize(&mut self, id: &str, humanize: bool) -> io::Result<()> {
+        if let Some(s) = self.sessions.get_mut(id) {
+            s.humanize = humanize;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Shift expiries of sessions created since start by a wall clock jump and save
+    pub fn shift_created(&mut self, seconds: i64) -> io::Result<()> {
+        let mut shifted = false;
+        for s in self.sessions.values_mut().filter(|s| s.created) {
+            s.expires = s.expires + Duration::seconds(seconds);
+            shifted = true;
+        }
+        if shifted {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remove sessions of tokens no longer configured and save
+    pub fn revoke_removed_tokens(&mut self, tokens: &HashMap<String, Role>) -> io::Result<()> {
+        let fingerprints: Vec<String> = tokens.keys().map(|t| token_fingerprint(t)).collect();
+        let count = self.sessions.len();
+        self.sessions.retain(|_, s| match &s.token {
+            Some(token) => fingerprints.contains(token),
+            None => true,
+        });
+        if self.sessions.len() != count {
+            log::info!(
+                "Revoked {} sessions of removed tokens",
+                count - self.sessions.len()
+            );
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remove a session and save
+    pub fn remove(&mut self, id: &str) -> io::Result<()> {
+        if self.sessions.remove(id).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Save atomically, a temp file renamed over it, a power loss never leaves a partial file
+    fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+            // session ids are credentials
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true).mode(0o600);
+            let mut f = options.open(&tmp)?;
+            let s = serde_json::to_string_pretty(&self.sessions)?;
+            f.write_all(s.as_bytes())?;
+            if !self.low_write {
+                f.sync_all()?;
+            }
+            std::fs::rename(&tmp, path).map_err(|e| {
+                let _ = std::fs::remove_file(&tmp);
+                e
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::render_template;
+
+/// Shutdown history file, next to config file
+pub const SHUTDOWN_HISTORY_FILE: &str = "shutdown_history.json";
+
+/// Max shutdown records
+const SHUTDOWN_HISTORY_CAPACITY: usize = 50;
+
+/// Shutdown cause
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownCause {
+    LowBattery,
+    Idle,
+    Suspend,
+}
+
+/// Shutdown record, with battery snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownRecord {
+    pub time: DateTime<Local>,
+    pub cause: ShutdownCause,
+    pub level: f64,
+    pub voltage: f64,
+    pub intensity: f64,
+}
+
+/// Rolling log of shutdowns
+#[derive(Default)]
+pub struct ShutdownHistory {
+    path: Option<PathBuf>,
+    records: VecDeque<ShutdownRecord>,
+}
+
+impl ShutdownHistory {
+    /// Load from file, start empty if not exists
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.path = Some(path.to_path_buf());
+        if path.exists() {
+            let mut f = File::open(path)?;
+            let mut buff = String::new();
+            let _ = f.read_to_string(&mut buff)?;
+            self.records = serde_json::from_str(&buff)?;
+        }
+        Ok(())
+    }
+
+    /// Records, oldest first
+    pub fn records(&self) -> &VecDeque<ShutdownRecord> {
+        &self.records
+    }
+
+    /// Add a record and save
+    pub fn record(&mut self, record: ShutdownRecord) -> io::Result<()> {
+        self.records.push_back(record);
+        while self.records.len() > SHUTDOWN_HISTORY_CAPACITY {
+            self.records.pop_front();
+        }
+        self.save()
+    }
+
+    /// Save atomically, a temp file renamed over it, saved right at shutdown or power loss
+    fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            let mut f = options.open(&tmp)?;
+            let s = serde_json::to_string_pretty(&self.records)?;
+            f.write_all(s.as_bytes())?;
+            f.sync_all()?;
+            std::fs::rename(&tmp, path).map_err(|e| {
+                let _ = std::fs::remove_file(&tmp);
+                e
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Shutdown command of a template, `{{cause}}`, `{{level}}` and `{{voltage}}` of the last record
+pub fn shutdown_command(template: &str, record: Option<&ShutdownRecord>) -> String {
+    let ctx = record
+        .and_then(|r| serde_json::to_value(r).ok())
+        .unwrap_or_default();
+    render_template(template, &ctx)
+}
+
+/// Check the program of a shutdown command, an executable path or found in PATH
+pub fn validate_shutdown_command(command: &str) -> Result<(), String> {
+    let program = match command.split_whitespace().next() {
+        Some(program) => program,
+        None => return Err("Empty shutdown command".to_string()),
+    };
+    let executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    let found = if program.contains('/') {
+        executable(Path::new(program))
+    } else {
+        env::var_os("PATH")
+            .map(|paths| env::split_paths(&paths).any(|dir| executable(&dir.join(program))))
+            .unwrap_or(false)
+    };
+    if found {
+        Ok(())
+    } else {
+        Err(format!("{} not found", program))
+    }
+}
@@ -0,0 +1,44 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+
+/// Signature file extension, e.g. config.json.sig
+pub const SIGNATURE_EXT: &str = "sig";
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Decode hex string, whitespaces are ignored
+fn decode_hex(s: &str) -> io::Result<Vec<u8>> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(invalid_data("Invalid hex"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(invalid_data))
+        .collect()
+}
+
+/// Signature file of a file
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".");
+    s.push(SIGNATURE_EXT);
+    PathBuf::from(s)
+}
+
+/// Verify ed25519 signature of a file, public key and signature in hex, returns the verified
+/// content, to be parsed instead of reading the file again
+pub fn verify_file(path: &Path, public_key: &str) -> io::Result<Vec<u8>> {
+    let public_key = PublicKey::from_bytes(&decode_hex(public_key)?).map_err(invalid_data)?;
+    let content = fs::read(path)?;
+    let sig = decode_hex(&fs::read_to_string(signature_path(path))?)?;
+    let sig = Signature::try_from(sig.as_slice()).map_err(invalid_data)?;
+    public_key.verify(&content, &sig).map_err(invalid_data)?;
+    Ok(content)
+}
@@ -0,0 +1,164 @@
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// PiSugar 2 battery capacity (mAh)
+pub const BATTERY_CAPACITY_V2: f64 = 1200.0;
+
+/// PiSugar 2 Pro battery capacity (mAh)
+pub const BATTERY_CAPACITY_V2_PRO: f64 = 5000.0;
+
+/// EMA smoothing factor
+const EMA_ALPHA: f64 = 0.1;
+
+/// Kalman process noise, per second
+const KALMAN_PROCESS_NOISE: f64 = 0.01;
+
+/// Kalman measurement noise of the voltage curve
+const KALMAN_MEASUREMENT_NOISE: f64 = 25.0;
+
+/// State of charge algorithm
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocAlgorithm {
+    /// Voltage curve only
+    Curve,
+    /// Exponential moving average of the voltage curve
+    Ema,
+    /// Coulomb counting corrected by the voltage curve
+    Kalman,
+}
+
+impl Default for SocAlgorithm {
+    fn default() -> Self {
+        SocAlgorithm::Curve
+    }
+}
+
+/// Rounding of reported level
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelRounding {
+    /// No rounding
+    None,
+    /// Round half away from zero
+    Round,
+    /// Round down
+    Floor,
+    /// Round half to even
+    Bankers,
+}
+
+impl Default for LevelRounding {
+    fn default() -> Self {
+        LevelRounding::None
+    }
+}
+
+impl LevelRounding {
+    /// Round and clamp level to [0, 100]
+    pub fn apply(&self, level: f64) -> f64 {
+        let level = match self {
+            LevelRounding::None => level,
+            LevelRounding::Round => level.round(),
+            LevelRounding::Floor => level.floor(),
+            LevelRounding::Bankers => {
+                if (level - level.trunc()).abs() == 0.5 {
+                    2.0 * (level / 2.0).round()
+                } else {
+                    level.round()
+                }
+            }
+        };
+        level.max(0.0).min(100.0)
+    }
+}
+
+/// State of charge estimator
+pub struct SocEstimator {
+    algorithm: SocAlgorithm,
+    capacity: f64,
+    soc: f64,
+    variance: f64,
+    updated_at: Option<Instant>,
+}
+
+impl SocEstimator {
+    /// Create new estimator, capacity in mAh
+    pub fn new(algorithm: SocAlgorithm, capacity: f64, level: f64) -> Self {
+        Self {
+            algorithm,
+            capacity,
+            soc: level,
+            variance: KALMAN_MEASUREMENT_NOISE,
+            updated_at: None,
+        }
+    }
+
+    /// Current algorithm
+    pub fn algorithm(&self) -> SocAlgorithm {
+        self.algorithm
+    }
+
+    /// Switch algorithm, restart from current estimation
+    pub fn set_algorithm(&mut self, algorithm: SocAlgorithm) {
+        if self.algorithm != algorithm {
+            self.algorithm = algorithm;
+            self.variance = KALMAN_MEASUREMENT_NOISE;
+        }
+    }
+
+    /// Battery capacity (mAh)
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    /// Calibrate at a known level, e.g. full charge
+    pub fn calibrate(&mut self, level: f64) {
+        self.soc = level;
+        self.variance = KALMAN_MEASUREMENT_NOISE;
+    }
+
+    /// Estimated level
+    pub fn level(&self) -> f64 {
+        self.soc
+    }
+
+    /// Feed a new sample, level from the voltage curve and intensity in A (positive is charging)
+    pub fn update(&mut self, curve_level: f64, intensity: f64, now: Instant) -> f64 {
+        let dt = match self.updated_at {
+            Some(t) if now > t => now.duration_since(t).as_secs_f64(),
+            _ => 0.0,
+        };
+        self.updated_at = Some(now);
+
+        self.soc = match self.algorithm {
+            SocAlgorithm::Curve => curve_level,
+            SocAlgorithm::Ema => EMA_ALPHA * curve_level + (1.0 - EMA_ALPHA) * self.soc,
+            SocAlgorithm::Kalman => {
+                // predict, coulomb counting
+                let delta = intensity * 1000.0 * dt / 3600.0 / self.capacity * 100.0;
+                let predicted = self.soc + delta;
+                self.variance += KALMAN_PROCESS_NOISE * dt;
+
+                // correct, voltage curve
+                let gain = self.variance / (self.variance + KALMAN_MEASUREMENT_NOISE);
+                let mut soc = predicted + gain * (curve_level - predicted);
+                self.variance *= 1.0 - gain;
+
+                // monotonic while discharging
+                if intensity <= 0.0 && soc > self.soc {
+                    soc = self.soc;
+                }
+                soc
+            }
+        };
+        if self.soc > 100.0 {
+            self.soc = 100.0;
+        }
+        if self.soc < 0.0 {
+            self.soc = 0.0;
+        }
+        self.soc
+    }
+}
@@ -0,0 +1,166 @@
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Local, TimeZone};
+use rusqlite::{params, Connection};
+
+use crate::{downsample, HistoryRetention, HistorySample, HistoryStore};
+
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("sqlite: {}", e))
+}
+
+fn insert(conn: &Connection, sample: &HistorySample) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO history (time, level, voltage, intensity, charging)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            sample.time.timestamp_millis(),
+            sample.level,
+            sample.voltage,
+            sample.intensity,
+            sample.charging
+        ],
+    )
+}
+
+/// Battery history in a sqlite database, for months of samples
+pub struct SqliteHistory {
+    conn: Connection,
+    capacity: usize,
+    /// Samples not yet inserted, low write mode
+    pending: Vec<HistorySample>,
+    batched: bool,
+}
+
+impl SqliteHistory {
+    /// Open or create database
+    pub fn open(path: &Path, capacity: usize) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(sqlite_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                time INTEGER NOT NULL,
+                level REAL NOT NULL,
+                voltage REAL NOT NULL,
+                intensity REAL NOT NULL,
+                charging INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_time ON history (time);",
+        )
+        .map_err(sqlite_error)?;
+        Ok(Self {
+            conn,
+            capacity,
+            pending: Vec::new(),
+            batched: false,
+        })
+    }
+}
+
+impl HistoryStore for SqliteHistory {
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    fn record(&mut self, sample: HistorySample) -> io::Result<()> {
+        self.pending.push(sample);
+        if self.batched {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    fn query(
+        &self,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+    ) -> Vec<HistorySample> {
+        let from = from.map(|t| t.timestamp_millis()).unwrap_or(i64::MIN);
+        let to = to.map(|t| t.timestamp_millis()).unwrap_or(i64::MAX);
+        let mut stmt = match self.conn.prepare(
+            "SELECT time, level, voltage, intensity, charging FROM history
+            WHERE time >= ?1 AND time <= ?2 ORDER BY time",
+        ) {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                log::error!("History query failed: {}", e);
+                return Vec::new();
+            }
+        };
+        let rows = stmt.query_map(params![from, to], |row| {
+            Ok(HistorySample {
+                time: Local.timestamp_millis(row.get(0)?),
+                level: row.get(1)?,
+                voltage: row.get(2)?,
+                intensity: row.get(3)?,
+                charging: row.get(4)?,
+            })
+        });
+        let mut samples: Vec<HistorySample> = match rows {
+            Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+            Err(e) => {
+                log::error!("History query failed: {}", e);
+                Vec::new()
+            }
+        };
+        // batched samples are not inserted yet
+        samples.extend(self.pending.iter().cloned().filter(|s| {
+            let t = s.time.timestamp_millis();
+            t >= from && t <= to
+        }));
+        samples
+    }
+
+    fn apply_retention(
+        &mut self,
+        retention: &HistoryRetention,
+        now: DateTime<Local>,
+    ) -> io::Result<()> {
+        self.flush()?;
+        let raw_from = now - Duration::hours(retention.raw_hours);
+        let average_from = now - Duration::days(retention.average_days);
+        let old: Vec<_> = self
+            .query(Some(average_from), Some(raw_from))
+            .into_iter()
+            .filter(|s| s.time < raw_from)
+            .collect();
+        let averages = downsample(&old, retention.average_minutes);
+
+        let tx = self.conn.transaction().map_err(sqlite_error)?;
+        tx.execute(
+            "DELETE FROM history WHERE time < ?1",
+            params![raw_from.timestamp_millis()],
+        )
+        .map_err(sqlite_error)?;
+        for sample in averages.iter() {
+            insert(&tx, sample).map_err(sqlite_error)?;
+        }
+        tx.commit().map_err(sqlite_error)
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.batched = batched;
+    }
+
+    /// Insert pending samples in a transaction
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction().map_err(sqlite_error)?;
+        for sample in self.pending.iter() {
+            insert(&tx, sample).map_err(sqlite_error)?;
+        }
+        // oldest by time are dropped, rowids are not in time order after retention
+        let capacity = self.capacity.min(i64::MAX as usize) as i64;
+        tx.execute(
+            "DELETE FROM history WHERE rowid IN
+            (SELECT rowid FROM history ORDER BY time DESC LIMIT -1 OFFSET ?1)",
+            params![capacity],
+        )
+        .map_err(sqlite_error)?;
+        tx.commit().map_err(sqlite_error)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
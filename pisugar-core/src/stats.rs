@@ -0,0 +1,49 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// Electrical readings statistics of a session
+#[derive(Debug, Clone, Serialize)]
+pub struct BatteryStats {
+    /// Session start
+    pub since: DateTime<Local>,
+    /// Min voltage (V)
+    pub min_voltage: f64,
+    /// Max voltage (V)
+    pub max_voltage: f64,
+    /// Peak charging intensity (A)
+    pub peak_charging_intensity: f64,
+    /// Peak discharging intensity (A), negative
+    pub peak_discharging_intensity: f64,
+    /// Lowest level
+    pub min_level: f64,
+}
+
+impl BatteryStats {
+    /// New session from now
+    pub fn new(voltage: f64, intensity: f64, level: f64) -> Self {
+        Self {
+            since: Local::now(),
+            min_voltage: voltage,
+            max_voltage: voltage,
+            peak_charging_intensity: intensity.max(0.0),
+            peak_discharging_intensity: intensity.min(0.0),
+            min_level: level,
+        }
+    }
+
+    /// Record a sample
+    pub fn record(&mut self, voltage: f64, intensity: f64, level: f64) {
+        self.min_voltage = self.min_voltage.min(voltage);
+        self.max_voltage = self.max_voltage.max(voltage);
+        self.peak_charging_intensity = self.peak_charging_intensity.max(intensity);
+        self.peak_discharging_intensity = self.peak_discharging_intensity.min(intensity);
+        self.min_level = self.min_level.min(level);
+    }
+}
+
+/// Statistics since boot and since last full charge
+#[derive(Debug, Clone, Serialize)]
+pub struct PiSugarStats {
+    pub boot: BatteryStats,
+    pub full_charge: Option<BatteryStats>,
+}
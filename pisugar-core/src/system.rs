@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::Serialize;
+
+use crate::{Error, Result};
+
+/// CPU thermal zone
+const CPU_THERMAL: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// Load average
+const PROC_LOADAVG: &str = "/proc/loadavg";
+
+/// Memory info
+const PROC_MEMINFO: &str = "/proc/meminfo";
+
+/// Serial number of the pi
+const PI_SERIAL: &str = "/sys/firmware/devicetree/base/serial-number";
+
+/// Serial number of the pi, from device tree
+pub fn pi_serial() -> Result<String> {
+    let serial = read_file(PI_SERIAL)?;
+    let serial = serial.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if serial.is_empty() {
+        return Err(Error::Other(format!("{}: empty", PI_SERIAL)));
+    }
+    Ok(serial.to_string())
+}
+
+/// Host name
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+/// Host name of the pi
+pub fn hostname() -> Result<String> {
+    let name = read_file(HOSTNAME)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::Other(format!("{}: empty", HOSTNAME)));
+    }
+    Ok(name.to_string())
+}
+
+/// Link-local address, not announced
+fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Addresses of the network interfaces, sorted, loopback and link-local excluded
+pub fn ip_addrs() -> Result<Vec<IpAddr>> {
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(Error::Other(format!(
+            "getifaddrs: {}",
+            io::Error::last_os_error()
+        )));
+    }
+    let mut addrs = Vec::new();
+    let mut ifa = ifap;
+    while !ifa.is_null() {
+        let addr = unsafe { (*ifa).ifa_addr };
+        if !addr.is_null() {
+            match i32::from(unsafe { (*addr).sa_family }) {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(addr as *const libc::sockaddr_in) };
+                    let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                    addrs.push(IpAddr::V4(ip));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(addr as *const libc::sockaddr_in6) };
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        ifa = unsafe { (*ifa).ifa_next };
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    addrs.retain(|a| !a.is_loopback() && !is_link_local(a));
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+/// System metrics of the pi
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemMetrics {
+    /// CPU temperature (°C)
+    pub cpu_temp: f64,
+    /// Load average of 1, 5, 15 minutes
+    pub load_avg: [f64; 3],
+    /// Total memory (KiB)
+    pub mem_total: u64,
+    /// Available memory (KiB)
+    pub mem_available: u64,
+}
+
+impl SystemMetrics {
+    /// Read system metrics
+    pub fn read() -> Result<Self> {
+        let cpu_temp = read_file(CPU_THERMAL)?
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| Error::Other(format!("{}: {}", CPU_THERMAL, e)))?
+            / 1000.0;
+
+        let mut load_avg = [0.0; 3];
+        let loadavg = read_file(PROC_LOADAVG)?;
+        for (i, s) in loadavg.split_whitespace().take(3).enumerate() {
+            load_avg[i] = s.parse().unwrap_or(0.0);
+        }
+
+        let mut mem_total = 0;
+        let mut mem_available = 0;
+        for line in read_file(PROC_MEMINFO)?.lines() {
+            // MemTotal:         443000 kB
+            let mut parts = line.split_whitespace();
+            let key = parts.next().unwrap_or_default();
+            let value = parts.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+            match key {
+                "MemTotal:" => mem_total = value,
+                "MemAvailable:" => mem_available = value,
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            cpu_temp,
+            load_avg,
+            mem_total,
+            mem_available,
+        })
+    }
+}
+
+fn read_file(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Error::Other(format!("{}: {}", path, e)))
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Value of a dot separated path, e.g. `system.cpu_temp` or `list.0`
+fn lookup<'a>(ctx: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut v = ctx;
+    for key in path.split('.') {
+        v = match v {
+            Value::Object(map) => map.get(key)?,
+            Value::Array(list) => list.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(v)
+}
+
+/// Value as text, strings are raw, null is empty
+fn text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    }
+}
+
+/// Evaluate `path` or `helper path`, helpers are `round`, `floor`, `milli` (x1000, rounded)
+/// and `json` (json encoded, e.g. quoted strings)
+fn evaluate(ctx: &Value, expr: &str) -> String {
+    let parts: Vec<&str> = expr.split_whitespace().collect();
+    let (helper, path) = match parts.as_slice() {
+        [path] => (None, *path),
+        [helper, path] => (Some(*helper), *path),
+        _ => {
+            log::debug!("Invalid template expression: {}", expr);
+            return String::new();
+        }
+    };
+    let v = match lookup(ctx, path) {
+        Some(v) => v,
+        None => return String::new(),
+    };
+    match helper {
+        None => text(v),
+        Some("json") => v.to_string(),
+        Some("round") => v
+            .as_f64()
+            .map(|n| n.round().to_string())
+            .unwrap_or_default(),
+        Some("floor") => v
+            .as_f64()
+            .map(|n| n.floor().to_string())
+            .unwrap_or_default(),
+        Some("milli") => v
+            .as_f64()
+            .map(|n| (n * 1000.0).round().to_string())
+            .unwrap_or_default(),
+        Some(helper) => {
+            log::debug!("Unknown template helper: {}", helper);
+            String::new()
+        }
+    }
+}
+
+/// Render a Handlebars-style template, `{{path}}` or `{{helper path}}`, missing values are empty
+pub fn render_template(template: &str, ctx: &Value) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                out.push_str(&evaluate(ctx, after[..end].trim()));
+                rest = &after[end + 2..];
+            }
+            None => {
+                // unclosed, kept as is
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Payload of kind (`state`, `event`), rendered by the configured template or json of context
+pub fn render_payload(templates: &HashMap<String, String>, kind: &str, ctx: &Value) -> String {
+    match templates.get(kind) {
+        Some(template) => render_template(template, ctx),
+        None => ctx.to_string(),
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::process::Command;
+
+use crate::{Error, Result};
+
+/// Firmware throttled status in sysfs, newer kernels
+const SYSFS_THROTTLED: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// Throttled flag bits and names
+const THROTTLED_FLAGS: [(u32, &str); 8] = [
+    (0, "under_voltage"),
+    (1, "freq_capped"),
+    (2, "throttled"),
+    (3, "soft_temp_limit"),
+    (16, "under_voltage_occurred"),
+    (17, "freq_capped_occurred"),
+    (18, "throttled_occurred"),
+    (19, "soft_temp_limit_occurred"),
+];
+
+/// Raspberry Pi firmware throttled status
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Throttled(pub u32);
+
+impl Throttled {
+    /// Read from sysfs, fallback to vcgencmd
+    pub fn read() -> Result<Self> {
+        if let Ok(s) = fs::read_to_string(SYSFS_THROTTLED) {
+            if let Ok(v) = u32::from_str_radix(s.trim(), 16) {
+                return Ok(Self(v));
+            }
+        }
+
+        let output = Command::new("vcgencmd")
+            .arg("get_throttled")
+            .output()
+            .map_err(|e| Error::Other(format!("vcgencmd: {}", e)))?;
+        let s = String::from_utf8_lossy(&output.stdout);
+        // throttled=0x50005
+        let s = s
+            .trim()
+            .trim_start_matches("throttled=")
+            .trim_start_matches("0x");
+        match u32::from_str_radix(s, 16) {
+            Ok(v) => Ok(Self(v)),
+            Err(_) => Err(Error::Other(format!("Invalid throttled status: {}", s))),
+        }
+    }
+
+    /// Under-voltage detected now
+    pub fn under_voltage(&self) -> bool {
+        self.0 & 0b0001 != 0
+    }
+
+    /// Names of flags set
+    pub fn flags(&self) -> Vec<&'static str> {
+        THROTTLED_FLAGS
+            .iter()
+            .filter(|(bit, _)| self.0 & (1u32 << *bit) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = self.flags();
+        if flags.is_empty() {
+            write!(f, "{:#x}", self.0)
+        } else {
+            write!(f, "{:#x} {}", self.0, flags.join(","))
+        }
+    }
+}
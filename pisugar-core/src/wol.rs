@@ -0,0 +1,46 @@
+use std::io;
+use std::net::UdpSocket;
+
+/// Wake-on-LAN broadcast address
+const WOL_ADDR: &str = "255.255.255.255:9";
+
+/// Parse mac address, e.g. 01:23:45:67:89:ab
+fn parse_mac(mac: &str) -> io::Result<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let parts: Vec<&str> = mac.split(|c: char| c == ':' || c == '-').collect();
+    if parts.len() != 6 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid mac address",
+        ));
+    }
+    for (i, part) in parts.iter().enumerate() {
+        bytes[i] = u8::from_str_radix(part, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    Ok(bytes)
+}
+
+/// Send Wake-on-LAN magic packet
+pub fn send_magic_packet(mac: &str) -> io::Result<()> {
+    let mac = parse_mac(mac)?;
+    let mut packet = vec![0xff_u8; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&packet, WOL_ADDR)?;
+    Ok(())
+}
+
+/// Wake all macs
+pub fn wake_on_lan(macs: &[String]) {
+    for mac in macs {
+        match send_magic_packet(mac) {
+            Ok(_) => log::info!("Wake-on-LAN sent to {}", mac),
+            Err(e) => log::error!("Wake-on-LAN {}: {}", mac, e),
+        }
+    }
+}
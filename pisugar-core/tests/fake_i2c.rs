@@ -0,0 +1,386 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use pisugar_core::{
+    execute_get, execute_history, BatteryChip, Error, FakeI2c, HistoryQuery, I2cOpener,
+    PiSugarConfig, PiSugarCore, PowerState, Request, TapType, HISTORY_FILE, I2C_ADDR_BAT,
+    I2C_READ_INTERVAL, MODEL_V2, TAP_LATENCY_POLLS,
+};
+
+/// PiSugar 2 on fake i2c, battery at voltage
+fn pisugar2(voltage: f64) -> (FakeI2c, PiSugarCore) {
+    let fake = FakeI2c::new();
+    fake.set_ip5209_voltage(voltage);
+    // IP5312 probe fails, falls back to IP5209
+    fake.inject_errors(I2C_ADDR_BAT, 1);
+
+    let mut config = PiSugarConfig::default();
+    config.rtc_enabled = false;
+    let core = PiSugarCore::new_with_opener(config, fake.opener()).unwrap();
+    (fake, core)
+}
+
+#[test]
+fn detect_model() {
+    let (_, core) = pisugar2(4.0);
+    assert_eq!(core.model(), MODEL_V2);
+    assert!((core.voltage() - 4.0).abs() < 0.01);
+}
+
+#[test]
+fn max17048_gauge_level() {
+    let fake = FakeI2c::new();
+    let addr = BatteryChip::Max17048.addr();
+    // vcell 3.9V of 78.125uV, soc 60%
+    fake.set(addr, 0x02, 0xc3);
+    fake.set(addr, 0x03, 0x00);
+    fake.set(addr, 0x04, 60);
+
+    let mut config = PiSugarConfig::default();
+    config.rtc_enabled = false;
+    config.battery_chip = Some(BatteryChip::Max17048);
+    let mut core = PiSugarCore::new_with_opener(config, fake.opener()).unwrap();
+    assert_eq!(core.model(), "MAX17048");
+    assert!((core.voltage() - 3.9).abs() < 0.01);
+
+    fake.set(addr, 0x04, 55);
+    core.status
+        .poll(&core.config, Instant::now() + Duration::from_secs(10))
+        .unwrap();
+    assert!((core.level() - 55.0).abs() < 1.0);
+}
+
+#[test]
+fn double_tap() {
+    let (fake, mut core) = pisugar2(4.0);
+    let now = Instant::now();
+    let mut tap = None;
+    for pressed in &[true, false, true, false] {
+        fake.set_ip5209_button(*pressed);
+        tap = core.status.poll(&core.config, now).unwrap();
+    }
+    assert_eq!(tap, Some(TapType::Double));
+}
+
+#[test]
+fn discharge_curve() {
+    let (fake, mut core) = pisugar2(4.1);
+    for (i, v) in [4.0, 3.9, 3.8].iter().enumerate() {
+        fake.set_ip5209_voltage_at(Duration::from_secs(i as u64 * 10 + 10), *v);
+    }
+
+    let t0 = Instant::now();
+    for secs in (10..=30).step_by(10) {
+        let elapsed = Duration::from_secs(secs);
+        fake.advance(elapsed);
+        core.status.poll(&core.config, t0 + elapsed).unwrap();
+    }
+    assert!((core.voltage() - 3.8).abs() < 0.01);
+    assert!(core.level() < 100.0);
+}
+
+#[test]
+fn battery_removed() {
+    let (fake, mut core) = pisugar2(4.0);
+    fake.set_ip5209_battery_removed();
+
+    // mains only, no auto shutdown at 0%
+    core.config.auto_shutdown_level = 0.0;
+    core.config.shutdown_grace = u64::MAX;
+    let t0 = Instant::now();
+    for secs in (10..=50).step_by(10) {
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+    }
+    assert!(!core.battery_present());
+    let event = core.status.take_event().map(|e| e.as_str());
+    assert_eq!(event, Some("battery_removed"));
+}
+
+#[test]
+fn power_state_low() {
+    let (fake, mut core) = pisugar2(4.0);
+    core.config.low_battery_level = 50.0;
+
+    let t0 = Instant::now();
+    core.status
+        .poll(&core.config, t0 + Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(core.power_state(), PowerState::OnBattery);
+
+    fake.set_ip5209_voltage(3.6);
+    for secs in (20..=100).step_by(10) {
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+    }
+    assert_eq!(core.power_state(), PowerState::Low);
+    let events: Vec<_> = std::iter::from_fn(|| core.status.take_event())
+        .map(|e| e.as_str())
+        .collect();
+    assert!(events.contains(&"low_battery"));
+}
+
+#[test]
+fn quiet_hours_hold_events() {
+    let (fake, mut core) = pisugar2(4.0);
+    core.config.low_battery_level = 50.0;
+    core.config.quiet_hours = vec!["00:00-23:59".to_string(), "23:59-00:00".to_string()];
+    core.config.quiet_poll_interval = 0;
+
+    let t0 = Instant::now();
+    fake.set_ip5209_voltage(3.6);
+    for secs in (10..=100).step_by(10) {
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+    }
+    assert_eq!(core.power_state(), PowerState::Low);
+    assert!(core.status.take_event().is_none());
+
+    core.config.quiet_hours.clear();
+    core.status
+        .poll(&core.config, t0 + Duration::from_secs(110))
+        .unwrap();
+    let events: Vec<_> = std::iter::from_fn(|| core.status.take_event())
+        .map(|e| e.as_str())
+        .collect();
+    assert!(events.contains(&"low_battery"));
+}
+
+#[test]
+fn shutdown_suppressed_in_grace() {
+    let (fake, mut core) = pisugar2(4.0);
+    core.config.auto_shutdown_level = 50.0;
+    core.config.shutdown_grace = u64::MAX;
+
+    let t0 = Instant::now();
+    fake.set_ip5209_voltage(3.6);
+    for secs in (10..=100).step_by(10) {
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+    }
+    assert_ne!(core.power_state(), PowerState::ShuttingDown);
+    let events: Vec<_> = std::iter::from_fn(|| core.status.take_event())
+        .map(|e| e.as_str())
+        .collect();
+    assert!(!events.contains(&"prepare_shutdown"));
+}
+
+#[test]
+fn shutdown_by_voltage() {
+    let (fake, mut core) = pisugar2(4.0);
+    core.config.auto_shutdown_voltage = 3.7;
+    core.config.shutdown_grace = 0;
+
+    let t0 = Instant::now();
+    fake.set_ip5209_voltage(3.6);
+    let mut secs = 10;
+    while core.power_state() != PowerState::ShuttingDown {
+        assert!(secs <= 200, "Not critical");
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+        secs += 10;
+    }
+    // averaged, not at the first reading
+    assert!(secs > 20);
+}
+
+#[test]
+fn shutdown_waits_for_participants() {
+    let (fake, mut core) = pisugar2(4.0);
+    core.config.auto_shutdown_level = 50.0;
+    core.config.shutdown_grace = 0;
+    let id = core.add_shutdown_participant();
+
+    let t0 = Instant::now();
+    fake.set_ip5209_voltage(3.6);
+    let mut secs = 10;
+    while core.power_state() != PowerState::ShuttingDown {
+        assert!(secs <= 100, "Not critical");
+        core.status
+            .poll(&core.config, t0 + Duration::from_secs(secs))
+            .unwrap();
+        secs += 10;
+    }
+    let events: Vec<_> = std::iter::from_fn(|| core.status.take_event())
+        .map(|e| e.as_str())
+        .collect();
+    assert!(events.contains(&"prepare_shutdown"));
+
+    // not acknowledged, within timeout
+    core.status
+        .poll(&core.config, t0 + Duration::from_secs(secs))
+        .unwrap();
+    assert!(core.ack_shutdown(id));
+    core.remove_shutdown_participant(id);
+    assert!(!core.ack_shutdown(id));
+}
+
+#[test]
+fn self_test_without_rtc() {
+    let (_, core) = pisugar2(4.0);
+    let report = core.self_test();
+    let passed = |name: &str| report.checks.iter().any(|c| c.name == name && c.passed);
+    assert!(passed("i2c_battery"));
+    assert!(!passed("rtc_read"));
+    assert!(!report.passed);
+}
+
+#[test]
+fn refresh_out_of_interval() {
+    let (fake, mut core) = pisugar2(4.0);
+    let now = Instant::now() + Duration::from_secs(10);
+    core.status.poll(&core.config, now).unwrap();
+
+    // next poll waits for the slow interval, refresh doesn't
+    fake.set_ip5209_voltage(3.8);
+    core.status.poll(&core.config, now).unwrap();
+    assert!((core.voltage() - 4.0).abs() < 0.01);
+    core.status.refresh(&core.config, now);
+    assert!((core.voltage() - 3.8).abs() < 0.01);
+}
+
+#[test]
+fn execute_get_out_of_range() {
+    let (_, core) = pisugar2(4.0);
+    let request = Request::parse("get battery_i_avg 99999999999999").unwrap();
+    assert!(execute_get(&core, &request).is_none());
+    let request = Request::parse("get battery_v").unwrap();
+    assert!(execute_get(&core, &request)
+        .unwrap()
+        .starts_with("battery_v: "));
+    let request = Request::parse("history query level avg 10000000000000w last 10000000000000w");
+    assert!(execute_history(&core, &request.unwrap()).is_none());
+}
+
+#[test]
+fn history_survives_recover() {
+    let fake = FakeI2c::new();
+    fake.set_ip5209_voltage(4.0);
+    fake.inject_errors(I2C_ADDR_BAT, 1);
+    let available = Arc::new(AtomicBool::new(false));
+    let opener: I2cOpener = {
+        let available = available.clone();
+        let open = fake.opener();
+        Arc::new(move |addr| {
+            if available.load(Ordering::Relaxed) {
+                open(addr)
+            } else {
+                Err(Error::Other("I2C unavailable".to_string()))
+            }
+        })
+    };
+    let mut config = PiSugarConfig::default();
+    config.rtc_enabled = false;
+    let mut core = PiSugarCore::new_with_opener(config, opener).unwrap();
+    assert!(core.hardware_error().is_some());
+
+    // degraded start, history of the ring file
+    let dir = std::env::temp_dir().join(format!("pisugar-recover-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let sample = "2020-01-01T00:00:00+00:00,80,4,-0.5,false\n";
+    std::fs::write(dir.join(HISTORY_FILE), sample).unwrap();
+    core.load_state(&dir);
+    assert_eq!(core.history().query(None, None).len(), 1);
+
+    available.store(true, Ordering::Relaxed);
+    core.recover().unwrap();
+    assert!(core.hardware_error().is_none());
+    assert_eq!(core.history().query(None, None).len(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn history_query_range_bounded() {
+    let (_, core) = pisugar2(4.0);
+    let args = ["level", "avg", "10000000000000w", "last", "10000000000000w"];
+    assert!(HistoryQuery::parse(&args).is_none());
+
+    let query = HistoryQuery::parse(&["level", "avg", "1d", "last", "52w"]).unwrap();
+    assert!(query.run(core.history(), Local::now()).len() <= 365);
+}
+
+#[test]
+fn i2c_errors_keep_last_reading() {
+    let (fake, mut core) = pisugar2(4.0);
+    fake.set_ip5209_voltage(3.7);
+    fake.inject_errors(I2C_ADDR_BAT, 2);
+    core.status
+        .poll(&core.config, Instant::now() + Duration::from_secs(10))
+        .unwrap();
+    assert!((core.voltage() - 4.0).abs() < 0.01);
+}
+
+/// Press the button for some polls, polls from release to the tap event
+fn polls_to_tap<F>(fake: &FakeI2c, press_polls: usize, mut poll: F) -> (usize, TapType)
+where
+    F: FnMut(usize) -> Option<TapType>,
+{
+    fake.set_ip5209_button(true);
+    for i in 0..press_polls {
+        assert_eq!(poll(i), None);
+    }
+    fake.set_ip5209_button(false);
+    for i in 1..=TAP_LATENCY_POLLS * 2 {
+        if let Some(tap) = poll(press_polls + i) {
+            return (i, tap);
+        }
+    }
+    panic!("No tap detected");
+}
+
+#[test]
+fn single_tap_latency() {
+    let (fake, mut core) = pisugar2(4.0);
+    let t0 = Instant::now();
+    for press_polls in 1..=3 {
+        let (polls, tap) = polls_to_tap(&fake, press_polls, |i| {
+            let now = t0 + I2C_READ_INTERVAL * i as u32;
+            core.status.poll(&core.config, now).unwrap()
+        });
+        assert_eq!(tap, TapType::Single);
+        assert!(polls <= TAP_LATENCY_POLLS, "{} polls", polls);
+    }
+}
+
+#[test]
+fn single_tap_latency_under_load() {
+    let (fake, core) = pisugar2(4.0);
+    let core = Arc::new(Mutex::new(core));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // clients, snapshots as grafana and sse streams
+    let clients: Vec<_> = (0..16)
+        .map(|_| {
+            let core = core.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let _ = core.lock().unwrap().snapshot_status();
+                }
+            })
+        })
+        .collect();
+
+    // poller, as the server loop, wall-clock latency is left to the tap_latency bench
+    let (polls, tap) = polls_to_tap(&fake, 2, |_| {
+        thread::sleep(I2C_READ_INTERVAL);
+        let mut core = core.lock().unwrap();
+        let core = &mut *core;
+        core.status.poll(&core.config, Instant::now()).unwrap()
+    });
+    stop.store(true, Ordering::Relaxed);
+    for c in clients {
+        c.join().unwrap();
+    }
+    assert_eq!(tap, TapType::Single);
+    assert!(polls <= TAP_LATENCY_POLLS, "{} polls", polls);
+}
@@ -0,0 +1,62 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rerun on commits and checkouts, `HEAD`, the ref it points to and packed refs of the git dir,
+/// missing files are skipped, cargo would rerun on every build
+fn rerun_if_git_changed() {
+    let git_dir = Command::new("git")
+        .args(&["rev-parse", "--git-dir"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    let git_dir = match git_dir {
+        Some(git_dir) => git_dir,
+        None => return,
+    };
+    let mut paths = vec![git_dir.join("HEAD"), git_dir.join("packed-refs")];
+    if let Ok(head) = fs::read_to_string(git_dir.join("HEAD")) {
+        if let Some(head_ref) = head.trim().strip_prefix("ref: ") {
+            paths.push(git_dir.join(head_ref));
+        }
+    }
+    for path in paths.iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+/// Build info of `get version`, git commit and build time, `SOURCE_DATE_EPOCH` of reproducible builds,
+/// and grpc code of the proto
+fn main() {
+    // rerun triggers replace the default of any package change
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/pisugar/v1/pisugar.proto");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    rerun_if_git_changed();
+
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/pisugar/v1/pisugar.proto").expect("Failed to compile proto");
+
+    let commit = Command::new("git")
+        .args(&["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=PISUGAR_GIT_COMMIT={}", commit);
+
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=PISUGAR_BUILD_TIMESTAMP={}", timestamp);
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use pisugar_core::{token_fingerprint, Listener, OutputFormat, PiSugarCore, Role, SessionState};
+
+use crate::participant::ShutdownParticipant;
+use crate::watch::Watch;
+use crate::{Event, EventChannels, EventTx, StreamMode};
+
+/// Auth failed event
+pub const AUTH_FAILED: &str = "auth_failed";
+
+/// Cookie of session id, web ui
+#[cfg(any(feature = "http", feature = "ws"))]
+pub const SESSION_COOKIE: &str = "pisugar_session";
+
+/// Failed attempts before a source is banned
+const AUTH_MAX_FAILURES: u32 = 5;
+
+/// Backoff after the first failure, doubled on each failure
+const AUTH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Ban duration
+const AUTH_BAN: Duration = Duration::from_secs(600);
+
+/// Failures of a source
+struct Failures {
+    count: u32,
+    blocked_until: Instant,
+}
+
+/// Brute-force protection of `auth`, per source address
+pub struct AuthGuard {
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    event_tx: Arc<EventTx>,
+}
+
+impl AuthGuard {
+    pub fn new(event_tx: Arc<EventTx>) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            event_tx,
+        }
+    }
+
+    /// Whether source may attempt now
+    pub fn allowed(&self, peer: IpAddr) -> bool {
+        let now = Instant::now();
+        match self.failures.lock() {
+            Ok(mut failures) => {
+                // forget sources idle for a ban duration
+                failures.retain(|_, f| f.blocked_until + AUTH_BAN > now);
+                failures
+                    .get(&peer)
+                    .map(|f| f.blocked_until <= now)
+                    .unwrap_or(true)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Record a failure, exponential backoff and ban
+    pub fn failed(&self, peer: IpAddr) {
+        let now = Instant::now();
+        if let Ok(mut failures) = self.failures.lock() {
+            let f = failures.entry(peer).or_insert(Failures {
+                count: 0,
+                blocked_until: now,
+            });
+            f.count += 1;
+            if f.count >= AUTH_MAX_FAILURES {
+                f.blocked_until = now + AUTH_BAN;
+                log::warn!("Auth failed {} times from {}, banned", f.count, peer);
+            } else {
+                f.blocked_until = now + AUTH_BACKOFF * 2u32.pow(f.count - 1);
+                log::warn!("Auth failed {} times from {}", f.count, peer);
+            }
+        }
+        let _ = self
+            .event_tx
+            .send(Event::new(Bytes::from_static(AUTH_FAILED.as_bytes())));
+    }
+
+    /// Record a success, failures are reset
+    pub fn succeeded(&self, peer: IpAddr) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(&peer);
+        }
+    }
+}
+
+/// Connection session
+pub struct Session {
+    /// Listener of the connection, labels logs and stats
+    pub listener: Listener,
+    pub role: Option<Role>,
+    /// Fingerprint of the token of `auth`, sessions created are revoked with it
+    pub token: Option<String>,
+    pub peer: Option<IpAddr>,
+    pub guard: Arc<AuthGuard>,
+    pub event_tx: Arc<EventTx>,
+    /// Persisted session, set by `session new` or `session resume <id>`
+    pub id: Option<String>,
+    /// Output format, shared with the event stream of the connection
+    pub format: Arc<Mutex<OutputFormat>>,
+    /// Humanized durations in json responses, `humanize on|off`
+    pub humanize: bool,
+    /// Event channels, shared with the event stream of the connection
+    pub channels: Arc<Mutex<EventChannels>>,
+    /// Threshold watches, shared with the event stream of the connection
+    pub watches: Arc<Mutex<Vec<Watch>>>,
+    /// Stream mode, shared with the event stream of the connection
+    pub mode: Arc<Mutex<StreamMode>>,
+    /// Set by `shutdown_participant`
+    pub participant: Option<ShutdownParticipant>,
+    /// Requests counted by the caller, e.g. a rest api call of several requests is counted once
+    pub counted: bool,
+}
+
+impl Session {
+    pub fn new(
+        listener: Listener,
+        role: Option<Role>,
+        peer: Option<IpAddr>,
+        guard: Arc<AuthGuard>,
+        event_tx: Arc<EventTx>,
+    ) -> Self {
+        Self {
+            listener,
+            role,
+            token: None,
+            peer,
+            guard,
+            event_tx,
+            id: None,
+            format: Arc::new(Mutex::new(OutputFormat::Text)),
+            humanize: false,
+            channels: Arc::new(Mutex::new(EventChannels::Alert)),
+            watches: Arc::new(Mutex::new(Vec::new())),
+            mode: Arc::new(Mutex::new(StreamMode::Mixed)),
+            participant: None,
+            counted: false,
+        }
+    }
+
+    /// Whether peer may attempt auth now, always for local sessions
+    pub fn auth_allowed(&self) -> bool {
+        match self.peer {
+            Some(peer) => self.guard.allowed(peer),
+            None => true,
+        }
+    }
+
+    /// Record an auth attempt of peer
+    pub fn auth_attempted(&self, succeeded: bool) {
+        if let Some(peer) = self.peer {
+            if succeeded {
+                self.guard.succeeded(peer);
+            } else {
+                self.guard.failed(peer);
+            }
+        }
+    }
+
+    /// Output format of responses and events
+    pub fn format(&self) -> OutputFormat {
+        self.format.lock().map(|f| *f).unwrap_or_default()
+    }
+
+    pub fn set_format(&mut self, format: OutputFormat) {
+        if let Ok(mut f) = self.format.lock() {
+            *f = format;
+        }
+    }
+
+    /// Whether requests are ignored, `mode events`
+    pub fn events_only(&self) -> bool {
+        self.mode
+            .lock()
+            .map(|m| *m == StreamMode::Events)
+            .unwrap_or(false)
+    }
+
+    /// Restore role and preferences of a persisted session
+    pub fn resume(&mut self, id: &str, state: &SessionState) {
+        self.id = Some(id.to_string());
+        self.role = state.role;
+        self.token = state.token.clone();
+        self.set_format(state.format);
+        self.humanize = state.humanize;
+    }
+}
+
+/// Session id in a `Cookie` header
+#[cfg(any(feature = "http", feature = "ws"))]
+pub fn session_cookie(cookie: &str) -> Option<&str> {
+    let prefix = format!("{}=", SESSION_COOKIE);
+    cookie
+        .split(';')
+        .map(|c| c.trim())
+        .find(|c| c.starts_with(&prefix))
+        .map(|c| &c[prefix.len()..])
+}
+
+/// Persisted session of id, its token must still be configured, and its role held by a token
+pub fn resume_session(core: &PiSugarCore, id: &str) -> Option<SessionState> {
+    let state = core.sessions().get(id)?;
+    let tokens = &core.config().auth_tokens;
+    if let Some(token) = &state.token {
+        if !tokens.keys().any(|t| token_fingerprint(t) == *token) {
+            return None;
+        }
+    }
+    match state.role {
+        Some(role) if !tokens.is_empty() && !tokens.values().any(|r| *r == role) => None,
+        _ => Some(state.clone()),
+    }
+}
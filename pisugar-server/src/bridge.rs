@@ -0,0 +1,119 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use futures::stream;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use pisugar_core::{token_role, PiSugarCore};
+
+use crate::{event_stream, snapshot, EventRx, EventTx};
+
+/// Battery object interval
+const BRIDGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeout of the auth line
+const BRIDGE_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bridge message of battery object
+fn battery_message(core: &Mutex<PiSugarCore>) -> Option<String> {
+    let s = snapshot(core)?;
+    let msg = json!({
+        "type": "battery",
+        "timestamp": Local::now().to_rfc3339(),
+        "model": s.model,
+        "present": s.battery_present,
+        "level": s.battery,
+        "voltage": s.battery_v,
+        "current": s.battery_i,
+        "power": s.battery_power_w,
+        "charging": s.battery_charging,
+    });
+    Some(msg.to_string())
+}
+
+/// Bridge message of event
+fn event_message(event: &[u8]) -> String {
+    json!({
+        "type": "event",
+        "timestamp": Local::now().to_rfc3339(),
+        "event": String::from_utf8_lossy(event),
+    })
+    .to_string()
+}
+
+/// Whether the first line `auth <token>` may read, always if no tokens
+async fn authorize(
+    core: &Mutex<PiSugarCore>,
+    reader: &mut BufReader<tokio::io::ReadHalf<TcpStream>>,
+) -> bool {
+    let tokens_required = match core.lock() {
+        Ok(core) => !core.config().auth_tokens.is_empty(),
+        Err(_) => return false,
+    };
+    if !tokens_required {
+        return true;
+    }
+    let mut line = String::new();
+    match tokio::time::timeout(BRIDGE_AUTH_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(Ok(_)) => {}
+        _ => return false,
+    }
+    let token = line.trim().trim_start_matches("auth ").to_string();
+    match core.lock() {
+        Ok(core) => {
+            let role = token_role(&core.config().auth_tokens, &token);
+            role.is_some() && core.authorize(role, "get")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Push battery object and events as json lines
+async fn handle_bridge_stream(
+    core: Arc<Mutex<PiSugarCore>>,
+    stream: TcpStream,
+    event_rx: EventRx,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    if !authorize(&core, &mut reader).await {
+        log::warn!("Bridge connection unauthorized");
+        return Ok(());
+    }
+
+    let core_cloned = core.clone();
+    let battery = tokio::time::interval(BRIDGE_INTERVAL)
+        .filter_map(move |_| future::ready(battery_message(&core_cloned)));
+    let events = event_stream(event_rx).map(|e| event_message(&e));
+    let mut messages = stream::select(battery, events).boxed();
+    while let Some(msg) = messages.next().await {
+        writer.write_all(msg.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Serve json bridge, read-only json lines for industrial collectors (Node-RED, Telegraf, etc.)
+pub async fn serve_bridge(
+    mut listener: TcpListener,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+) {
+    while let Some(Ok(stream)) = listener.incoming().next().await {
+        if let Ok(peer) = stream.peer_addr() {
+            log::info!("Incoming bridge connection from: {}", peer);
+        }
+        let core = core.clone();
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = handle_bridge_stream(core, stream, event_rx).await {
+                log::debug!("Bridge connection closed: {}", e);
+            }
+        });
+    }
+}
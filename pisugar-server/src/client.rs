@@ -0,0 +1,93 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+/// Uds file of the client, if `--uds` is not set
+pub const UDS_DEFAULT: &str = "/tmp/pisugar-server.sock";
+
+/// Exit code of `wait-for` on timeout
+pub const EXIT_TIMEOUT: i32 = 1;
+
+/// Exit code of `wait-for` on errors
+pub const EXIT_ERROR: i32 = 2;
+
+/// Events and exit codes of `<event[=code]>,...`, code defaults to 0
+fn parse_events(events: &str) -> Result<Vec<(String, i32)>, String> {
+    events
+        .split(',')
+        .map(|e| {
+            let mut parts = e.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            if name.is_empty() {
+                return Err(format!("Empty event name: {}", events));
+            }
+            let code = match parts.next() {
+                Some(code) => code
+                    .trim()
+                    .parse::<i32>()
+                    .map_err(|e| format!("{}: {}", code, e))?,
+                None => 0,
+            };
+            Ok((name.to_string(), code))
+        })
+        .collect()
+}
+
+/// Send `wait_for` over the uds and read its reply
+fn request(uds: &str, names: &str, timeout: Option<&str>) -> io::Result<String> {
+    let mut stream = UnixStream::connect(uds)?;
+    let req = match timeout {
+        Some(timeout) => format!("wait_for {} {}\n", names, timeout),
+        None => format!("wait_for {}\n", names),
+    };
+    stream.write_all(req.as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed",
+            ));
+        }
+        // events of subscribed channels may be interleaved
+        if line.starts_with("wait_for: ") || line.starts_with("Invalid request") {
+            return Ok(line.trim_end().to_string());
+        }
+    }
+}
+
+/// Client of `wait-for <event[=code],...> [--timeout secs]`, exit code of the received event,
+/// `EXIT_TIMEOUT` on timeout and `EXIT_ERROR` on errors
+pub fn wait_for(uds: &str, events: &str, timeout: Option<&str>) -> i32 {
+    let events = match parse_events(events) {
+        Ok(events) => events,
+        Err(e) => {
+            eprintln!("Invalid events: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    let reply = match request(uds, &names.join(","), timeout) {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("Failed to wait on {}: {}", uds, e);
+            return EXIT_ERROR;
+        }
+    };
+    let event = match reply.strip_prefix("wait_for: ") {
+        Some("timeout") => return EXIT_TIMEOUT,
+        Some(event) => event,
+        None => {
+            eprintln!("{}", reply);
+            return EXIT_ERROR;
+        }
+    };
+    println!("{}", event);
+    let name = event.split(' ').next().unwrap_or_default();
+    events
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, code)| *code)
+        .unwrap_or(EXIT_ERROR)
+}
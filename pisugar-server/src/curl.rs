@@ -0,0 +1,56 @@
+use std::process::Stdio;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Timeout of a request (s)
+const CURL_TIMEOUT: &str = "10";
+
+/// Quoted string of a curl config file
+fn config_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Post body to url with curl, http and https, error is stderr of curl. Url, headers and body
+/// are passed as a config on stdin, tokens are not visible in the argv of curl
+pub async fn post(url: &str, headers: &[String], body: &str) -> Result<(), String> {
+    let mut config = format!("url = {}\n", config_quote(url));
+    for h in headers {
+        config.push_str(&format!("header = {}\n", config_quote(h)));
+    }
+    // data-raw, a body starting with `@` is not a file name
+    config.push_str(&format!("data-raw = {}\n", config_quote(body)));
+
+    let mut child = Command::new("curl")
+        .args(&["-fsS", "-m", CURL_TIMEOUT, "-K", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(config.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().await.map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
@@ -0,0 +1,811 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::prelude::*;
+use futures::stream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio_util::codec::{Decoder, FramedRead};
+
+use pisugar_core::{PiSugarCore, PiSugarSnapshot};
+
+use crate::{event_name, event_stream, snapshot, EventRx};
+
+/// Default system bus socket
+const DBUS_SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+/// Well-known name, also the interface
+const DBUS_NAME: &str = "org.pisugar.PowerManager";
+
+/// Object path of the power manager
+const DBUS_PATH: &str = "/org/pisugar/PowerManager";
+
+/// Interval of checking property changes
+const DBUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay of reconnecting
+const DBUS_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Max message size of the spec
+const DBUS_MAX_MESSAGE: usize = 128 * 1024 * 1024;
+
+/// Message types
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+/// Flag of method calls without replies
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// Header fields
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Standard interfaces
+const BUS_INTERFACE: &str = "org.freedesktop.DBus";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE_INTERFACE: &str = "org.freedesktop.DBus.Introspectable";
+const PEER_INTERFACE: &str = "org.freedesktop.DBus.Peer";
+
+/// Introspection data of the power manager
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.pisugar.PowerManager">
+    <property name="BatteryLevel" type="d" access="read"/>
+    <property name="Charging" type="b" access="read"/>
+    <property name="RtcTime" type="s" access="read">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="false"/>
+    </property>
+    <signal name="Tap">
+      <arg name="kind" type="s"/>
+    </signal>
+    <signal name="LowBattery">
+      <arg name="level" type="d"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed" type="a{sv}"/>
+      <arg name="invalidated" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Basic values and variants of messages
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bool(bool),
+    U32(u32),
+    Double(f64),
+    Str(String),
+    Path(String),
+    Signature(String),
+}
+
+impl Value {
+    fn signature(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "b",
+            Value::U32(_) => "u",
+            Value::Double(_) => "d",
+            Value::Str(_) => "s",
+            Value::Path(_) => "o",
+            Value::Signature(_) => "g",
+        }
+    }
+}
+
+/// Little endian marshaller, offsets relative to the start of header or body, both 8-aligned
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        while self.buf.len() % n != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn u8(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, s: &str) {
+        self.buf.push(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn value(&mut self, v: &Value) {
+        match v {
+            Value::Bool(b) => self.u32(*b as u32),
+            Value::U32(n) => self.u32(*n),
+            Value::Double(d) => {
+                self.align(8);
+                self.buf.extend_from_slice(&d.to_le_bytes());
+            }
+            Value::Str(s) | Value::Path(s) => self.str(s),
+            Value::Signature(s) => self.signature(s),
+        }
+    }
+
+    fn variant(&mut self, v: &Value) {
+        self.signature(v.signature());
+        self.value(v);
+    }
+
+    /// Array of elements aligned to n, the length excludes the padding of the first element
+    fn array(&mut self, n: usize, elements: impl FnOnce(&mut Self)) {
+        self.u32(0);
+        let at = self.buf.len() - 4;
+        self.align(n);
+        let start = self.buf.len();
+        elements(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[at..at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// Dict `a{sv}` of properties
+    fn properties(&mut self, properties: &[(&str, Value)]) {
+        self.array(8, |w| {
+            for (name, value) in properties {
+                w.align(8);
+                w.str(name);
+                w.variant(value);
+            }
+        });
+    }
+}
+
+/// Message of header fields and body of signature
+fn message(kind: u8, serial: u32, fields: &[(u8, Value)], signature: &str, body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.u8(b'l');
+    w.u8(kind);
+    w.u8(0);
+    w.u8(1);
+    w.u32(body.len() as u32);
+    w.u32(serial);
+    let signature = Value::Signature(signature.to_string());
+    let signature_field = if body.is_empty() {
+        None
+    } else {
+        Some((FIELD_SIGNATURE, signature))
+    };
+    w.array(8, |w| {
+        for (code, value) in fields.iter().chain(signature_field.iter()) {
+            w.align(8);
+            w.u8(*code);
+            w.variant(value);
+        }
+    });
+    w.align(8);
+    w.buf.extend_from_slice(body);
+    w.buf
+}
+
+/// Unmarshaller of either endianness
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn invalid() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "Invalid dbus message")
+    }
+
+    fn align(&mut self, n: usize) {
+        self.pos = (self.pos + n - 1) / n * n;
+    }
+
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(Self::invalid)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let mut b = [0_u8; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn text(&mut self, len: usize) -> io::Result<String> {
+        let s = self.bytes(len)?;
+        self.bytes(1)?;
+        String::from_utf8(s.to_vec()).map_err(|_| Self::invalid())
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    fn signature(&mut self) -> io::Result<String> {
+        let len = self.u8()? as usize;
+        self.text(len)
+    }
+
+    /// Header field values, s, o, g and u
+    fn value(&mut self, signature: &str) -> io::Result<Value> {
+        match signature {
+            "s" => Ok(Value::Str(self.str()?)),
+            "o" => Ok(Value::Path(self.str()?)),
+            "g" => Ok(Value::Signature(self.signature()?)),
+            "u" => Ok(Value::U32(self.u32()?)),
+            _ => Err(Self::invalid()),
+        }
+    }
+}
+
+/// Incoming message, the body is kept raw
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    sender: Option<String>,
+    signature: String,
+    big_endian: bool,
+    body: Vec<u8>,
+}
+
+impl Message {
+    /// Body reader, only if the signature matches
+    fn body(&self, signature: &str) -> io::Result<Reader<'_>> {
+        if self.signature != signature {
+            return Err(Reader::invalid());
+        }
+        Ok(Reader {
+            buf: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        })
+    }
+}
+
+/// Decoder of messages
+struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < 16 {
+            return Ok(None);
+        }
+        let big_endian = match src[0] {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err(Reader::invalid()),
+        };
+        let mut fixed = Reader {
+            buf: &src[..16],
+            pos: 4,
+            big_endian,
+        };
+        let body_len = fixed.u32()? as usize;
+        fixed.u32()?;
+        let fields_len = fixed.u32()? as usize;
+        let header_len = (16 + fields_len + 7) / 8 * 8;
+        let len = header_len + body_len;
+        if len > DBUS_MAX_MESSAGE {
+            return Err(Reader::invalid());
+        }
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let buf = src.split_to(len);
+        let mut r = Reader {
+            buf: &buf[..16 + fields_len],
+            pos: 1,
+            big_endian,
+        };
+        let mut message = Message {
+            kind: r.u8()?,
+            flags: r.u8()?,
+            big_endian,
+            ..Default::default()
+        };
+        r.pos = 8;
+        message.serial = r.u32()?;
+        r.pos = 16;
+        while r.pos < r.buf.len() {
+            r.align(8);
+            let code = r.u8()?;
+            let signature = r.signature()?;
+            match (code, r.value(&signature)?) {
+                (FIELD_PATH, Value::Path(s)) => message.path = Some(s),
+                (FIELD_INTERFACE, Value::Str(s)) => message.interface = Some(s),
+                (FIELD_MEMBER, Value::Str(s)) => message.member = Some(s),
+                (FIELD_ERROR_NAME, Value::Str(s)) => message.error_name = Some(s),
+                (FIELD_REPLY_SERIAL, Value::U32(n)) => message.reply_serial = Some(n),
+                (FIELD_SENDER, Value::Str(s)) => message.sender = Some(s),
+                (FIELD_SIGNATURE, Value::Signature(s)) => message.signature = s,
+                _ => {}
+            }
+        }
+        message.body = buf[header_len..].to_vec();
+        Ok(Some(message))
+    }
+}
+
+/// Read-only properties of a snapshot
+fn properties(snapshot: &PiSugarSnapshot) -> Vec<(&'static str, Value)> {
+    vec![
+        ("BatteryLevel", Value::Double(snapshot.battery)),
+        ("Charging", Value::Bool(snapshot.battery_charging)),
+        ("RtcTime", Value::Str(snapshot.rtc_time.to_rfc3339())),
+    ]
+}
+
+/// Properties of `PropertiesChanged`, `RtcTime` changes every second and is not signalled
+fn watched(properties: Vec<(&'static str, Value)>) -> Vec<(&'static str, Value)> {
+    properties
+        .into_iter()
+        .filter(|(name, _)| *name != "RtcTime")
+        .collect()
+}
+
+/// Outgoing messages with serials
+struct Connection {
+    serial: u32,
+}
+
+impl Connection {
+    fn next_serial(&mut self) -> u32 {
+        self.serial = self.serial.wrapping_add(1).max(1);
+        self.serial
+    }
+
+    fn call(&mut self, member: &str, signature: &str, body: &[u8]) -> (u32, Vec<u8>) {
+        let serial = self.next_serial();
+        let fields = [
+            (FIELD_PATH, Value::Path("/org/freedesktop/DBus".to_string())),
+            (FIELD_INTERFACE, Value::Str(BUS_INTERFACE.to_string())),
+            (FIELD_MEMBER, Value::Str(member.to_string())),
+            (FIELD_DESTINATION, Value::Str(BUS_INTERFACE.to_string())),
+        ];
+        (
+            serial,
+            message(METHOD_CALL, serial, &fields, signature, body),
+        )
+    }
+
+    fn signal(&mut self, interface: &str, member: &str, signature: &str, body: &[u8]) -> Vec<u8> {
+        let fields = [
+            (FIELD_PATH, Value::Path(DBUS_PATH.to_string())),
+            (FIELD_INTERFACE, Value::Str(interface.to_string())),
+            (FIELD_MEMBER, Value::Str(member.to_string())),
+        ];
+        message(SIGNAL, self.next_serial(), &fields, signature, body)
+    }
+
+    fn reply(&mut self, call: &Message, signature: &str, body: &[u8]) -> Vec<u8> {
+        let mut fields = vec![(FIELD_REPLY_SERIAL, Value::U32(call.serial))];
+        if let Some(sender) = &call.sender {
+            fields.push((FIELD_DESTINATION, Value::Str(sender.clone())));
+        }
+        message(METHOD_RETURN, self.next_serial(), &fields, signature, body)
+    }
+
+    fn error(&mut self, call: &Message, name: &str, text: &str) -> Vec<u8> {
+        let mut fields = vec![
+            (FIELD_ERROR_NAME, Value::Str(name.to_string())),
+            (FIELD_REPLY_SERIAL, Value::U32(call.serial)),
+        ];
+        if let Some(sender) = &call.sender {
+            fields.push((FIELD_DESTINATION, Value::Str(sender.clone())));
+        }
+        let mut w = Writer::default();
+        w.str(text);
+        message(ERROR, self.next_serial(), &fields, "s", &w.buf)
+    }
+
+    /// Reply of a method call, none if no reply is expected
+    fn handle_call(&mut self, core: &Mutex<PiSugarCore>, call: &Message) -> Vec<u8> {
+        let reply = self.dispatch(core, call).unwrap_or_else(|(name, text)| {
+            let name = format!("org.freedesktop.DBus.Error.{}", name);
+            self.error(call, &name, &text)
+        });
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Vec::new();
+        }
+        reply
+    }
+
+    fn dispatch(
+        &mut self,
+        core: &Mutex<PiSugarCore>,
+        call: &Message,
+    ) -> Result<Vec<u8>, (&'static str, String)> {
+        let invalid_args = |_| ("InvalidArgs", "Invalid arguments".to_string());
+        let unavailable = || ("Failed", "Core unavailable".to_string());
+        let interface = call.interface.as_deref().unwrap_or_default();
+        let member = call.member.as_deref().unwrap_or_default();
+        let path = call.path.as_deref().unwrap_or_default();
+        if path != DBUS_PATH {
+            return Err(("UnknownObject", format!("No object {}", path)));
+        }
+        let mut w = Writer::default();
+        match (interface, member) {
+            (PROPERTIES_INTERFACE, "Get") => {
+                let mut r = call.body("ss").map_err(invalid_args)?;
+                let iface = r.str().map_err(invalid_args)?;
+                let name = r.str().map_err(invalid_args)?;
+                if iface != DBUS_NAME && !iface.is_empty() {
+                    return Err(("UnknownInterface", format!("No interface {}", iface)));
+                }
+                let snapshot = snapshot(core).ok_or_else(unavailable)?;
+                let (_, value) = properties(&snapshot)
+                    .into_iter()
+                    .find(|(n, _)| *n == name)
+                    .ok_or_else(|| ("UnknownProperty", format!("No property {}", name)))?;
+                w.variant(&value);
+                Ok(self.reply(call, "v", &w.buf))
+            }
+            (PROPERTIES_INTERFACE, "GetAll") => {
+                let iface = call.body("s").and_then(|mut r| r.str());
+                let iface = iface.map_err(invalid_args)?;
+                if iface == DBUS_NAME || iface.is_empty() {
+                    let snapshot = snapshot(core).ok_or_else(unavailable)?;
+                    w.properties(&properties(&snapshot));
+                } else {
+                    w.properties(&[]);
+                }
+                Ok(self.reply(call, "a{sv}", &w.buf))
+            }
+            (PROPERTIES_INTERFACE, "Set") => {
+                Err(("PropertyReadOnly", "Properties are read-only".to_string()))
+            }
+            (INTROSPECTABLE_INTERFACE, "Introspect") | ("", "Introspect") => {
+                w.str(INTROSPECTION);
+                Ok(self.reply(call, "s", &w.buf))
+            }
+            (PEER_INTERFACE, "Ping") | ("", "Ping") => Ok(self.reply(call, "", &[])),
+            _ => Err((
+                "UnknownMethod",
+                format!("No method {} of interface {}", member, interface),
+            )),
+        }
+    }
+
+    /// `PropertiesChanged` of properties differing from the last ones
+    fn properties_changed(
+        &mut self,
+        last: &mut Vec<(&'static str, Value)>,
+        current: Vec<(&'static str, Value)>,
+    ) -> Vec<u8> {
+        let changed: Vec<_> = current
+            .iter()
+            .filter(|p| !last.contains(p))
+            .cloned()
+            .collect();
+        *last = current;
+        if changed.is_empty() {
+            return Vec::new();
+        }
+        let mut w = Writer::default();
+        w.str(DBUS_NAME);
+        w.properties(&changed);
+        w.array(4, |_| {});
+        self.signal(
+            PROPERTIES_INTERFACE,
+            "PropertiesChanged",
+            "sa{sv}as",
+            &w.buf,
+        )
+    }
+
+    /// Signals of taps and low battery
+    fn event_signal(&mut self, core: &Mutex<PiSugarCore>, e: &[u8]) -> Vec<u8> {
+        let e = String::from_utf8_lossy(e);
+        let mut w = Writer::default();
+        match event_name(&e) {
+            name @ "single" | name @ "double" | name @ "long" => {
+                w.str(name);
+                self.signal(DBUS_NAME, "Tap", "s", &w.buf)
+            }
+            "low_battery" => match snapshot(core) {
+                Some(snapshot) => {
+                    w.value(&Value::Double(snapshot.battery));
+                    self.signal(DBUS_NAME, "LowBattery", "d", &w.buf)
+                }
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Socket path of `DBUS_SYSTEM_BUS_ADDRESS`, `unix:path=...`, the default system bus if absent
+fn system_bus_path() -> String {
+    std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+        .ok()
+        .and_then(|addr| {
+            addr.split(';')
+                .filter_map(|a| a.strip_prefix("unix:"))
+                .flat_map(|a| a.split(','))
+                .find_map(|kv| kv.strip_prefix("path=").map(String::from))
+        })
+        .unwrap_or_else(|| DBUS_SYSTEM_BUS.to_string())
+}
+
+/// Connect and authenticate as the effective uid
+async fn connect(path: &str) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    let uid = unsafe { libc::geteuid() }.to_string();
+    let hex_uid: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+    let auth = format!("\0AUTH EXTERNAL {}\r\n", hex_uid);
+    stream.write_all(auth.as_bytes()).await?;
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        line.push(stream.read_u8().await?);
+    }
+    if !line.starts_with(b"OK ") {
+        let line = String::from_utf8_lossy(&line);
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Auth rejected: {}", line.trim()),
+        ));
+    }
+    stream.write_all(b"BEGIN\r\n").await?;
+    Ok(stream)
+}
+
+/// Trigger of the service loop
+enum Trigger {
+    Tick,
+    Event(Bytes),
+    Message(io::Result<Message>),
+    Closed,
+}
+
+/// Serve `org.pisugar.PowerManager` on the system bus, reconnected on errors
+pub async fn dbus_service(core: Arc<Mutex<PiSugarCore>>, event_rx: EventRx) {
+    let path = system_bus_path();
+    let ticks = tokio::time::interval(DBUS_INTERVAL).map(|_| Trigger::Tick);
+    let events = event_stream(event_rx).map(Trigger::Event);
+    let mut triggers = stream::select(ticks, events).boxed();
+    let mut failed = false;
+    loop {
+        let stream = match connect(&path).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                if !failed {
+                    log::warn!("Dbus connect to {} failed: {}", path, e);
+                }
+                failed = true;
+                tokio::time::delay_for(DBUS_RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        failed = false;
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut conn = Connection { serial: 0 };
+
+        let (_, hello) = conn.call("Hello", "", &[]);
+        let mut w = Writer::default();
+        w.str(DBUS_NAME);
+        w.u32(0x4); // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        let (request_name_serial, request_name) = conn.call("RequestName", "su", &w.buf);
+        let connected = [hello, request_name].concat();
+        if let Err(e) = writer.write_all(&connected).await {
+            log::warn!("Dbus write failed, reconnect: {}", e);
+            tokio::time::delay_for(DBUS_RECONNECT_DELAY).await;
+            continue;
+        }
+
+        let messages = FramedRead::new(reader, MessageCodec)
+            .map(Trigger::Message)
+            .chain(stream::once(future::ready(Trigger::Closed)));
+        let mut all = stream::select(messages, &mut triggers);
+        let mut last = Vec::new();
+        loop {
+            let packets = match all.next().await {
+                Some(Trigger::Tick) => match snapshot(&core) {
+                    Some(snapshot) => {
+                        conn.properties_changed(&mut last, watched(properties(&snapshot)))
+                    }
+                    None => Vec::new(),
+                },
+                Some(Trigger::Event(e)) => conn.event_signal(&core, &e),
+                Some(Trigger::Message(Ok(m))) if m.kind == METHOD_CALL => {
+                    conn.handle_call(&core, &m)
+                }
+                Some(Trigger::Message(Ok(m))) if m.reply_serial == Some(request_name_serial) => {
+                    let owner = m.kind == METHOD_RETURN
+                        && m.body("u").and_then(|mut r| r.u32()).ok() == Some(1);
+                    if owner {
+                        log::info!("Dbus serving {} on {}", DBUS_NAME, path);
+                    } else {
+                        log::error!(
+                            "Dbus name {} not acquired: {}",
+                            DBUS_NAME,
+                            m.error_name.as_deref().unwrap_or("exists")
+                        );
+                    }
+                    Vec::new()
+                }
+                Some(Trigger::Message(Ok(_))) => Vec::new(),
+                Some(Trigger::Message(Err(e))) => {
+                    log::warn!("Dbus read failed, reconnect: {}", e);
+                    break;
+                }
+                Some(Trigger::Closed) => {
+                    log::warn!("Dbus disconnected, reconnect");
+                    break;
+                }
+                None => return,
+            };
+            if packets.is_empty() {
+                continue;
+            }
+            if let Err(e) = writer.write_all(&packets).await {
+                log::warn!("Dbus write failed, reconnect: {}", e);
+                break;
+            }
+        }
+        tokio::time::delay_for(DBUS_RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Method call of the power manager with `ss` arguments
+    fn call(serial: u32, path: &str, args: (&str, &str)) -> Vec<u8> {
+        let fields = [
+            (FIELD_PATH, Value::Path(path.to_string())),
+            (
+                FIELD_INTERFACE,
+                Value::Str(PROPERTIES_INTERFACE.to_string()),
+            ),
+            (FIELD_MEMBER, Value::Str("Get".to_string())),
+            (FIELD_SENDER, Value::Str(":1.42".to_string())),
+        ];
+        let mut w = Writer::default();
+        w.str(args.0);
+        w.str(args.1);
+        message(METHOD_CALL, serial, &fields, "ss", &w.buf)
+    }
+
+    #[test]
+    fn method_call_round_trip() {
+        let buf = call(7, DBUS_PATH, (DBUS_NAME, "BatteryLevel"));
+        let mut src = BytesMut::from(&buf[..]);
+        let m = MessageCodec.decode(&mut src).unwrap().unwrap();
+        assert!(src.is_empty());
+        assert_eq!(m.kind, METHOD_CALL);
+        assert_eq!(m.serial, 7);
+        assert_eq!(m.path.as_deref(), Some(DBUS_PATH));
+        assert_eq!(m.interface.as_deref(), Some(PROPERTIES_INTERFACE));
+        assert_eq!(m.member.as_deref(), Some("Get"));
+        assert_eq!(m.sender.as_deref(), Some(":1.42"));
+        let mut r = m.body("ss").unwrap();
+        assert_eq!(r.str().unwrap(), DBUS_NAME);
+        assert_eq!(r.str().unwrap(), "BatteryLevel");
+        assert!(m.body("s").is_err());
+    }
+
+    #[test]
+    fn partial_messages() {
+        let first = call(1, DBUS_PATH, ("", "Charging"));
+        let second = call(2, "/", ("", "RtcTime"));
+        let buf = [first.as_slice(), second.as_slice()].concat();
+        let mut src = BytesMut::from(&buf[..10]);
+        assert!(MessageCodec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&buf[10..first.len() + 20]);
+        let m = MessageCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(m.serial, 1);
+        assert!(MessageCodec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&buf[first.len() + 20..]);
+        let m = MessageCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(m.serial, 2);
+        assert_eq!(m.path.as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let mut src = BytesMut::from(&call(9, DBUS_PATH, ("", ""))[..]);
+        let call = MessageCodec.decode(&mut src).unwrap().unwrap();
+        let mut conn = Connection { serial: 0 };
+        let mut w = Writer::default();
+        w.u32(1);
+        let reply = conn.reply(&call, "u", &w.buf);
+        let mut src = BytesMut::from(&reply[..]);
+        let m = MessageCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(m.kind, METHOD_RETURN);
+        assert_eq!(m.reply_serial, Some(9));
+        assert_eq!(m.body("u").and_then(|mut r| r.u32()).unwrap(), 1);
+
+        let error = conn.error(&call, "org.freedesktop.DBus.Error.Failed", "Failed");
+        let mut src = BytesMut::from(&error[..]);
+        let m = MessageCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(m.kind, ERROR);
+        assert_eq!(m.serial, 2);
+        assert_eq!(
+            m.error_name.as_deref(),
+            Some("org.freedesktop.DBus.Error.Failed")
+        );
+        assert_eq!(m.body("s").and_then(|mut r| r.str()).unwrap(), "Failed");
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let mut src = BytesMut::from(&[b'x'; 16][..]);
+        assert!(MessageCodec.decode(&mut src).is_err());
+
+        // fields length past the message
+        let mut buf = call(3, DBUS_PATH, ("", ""));
+        buf[12] = 0xff;
+        buf.extend_from_slice(&[0; 256]);
+        let mut src = BytesMut::from(&buf[..]);
+        assert!(MessageCodec.decode(&mut src).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::ffi::{CString, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use pisugar_core::{redact_secrets, HistorySample, PiSugarCore};
+
+/// History samples in bundles
+const HISTORY_TAIL: usize = 1000;
+
+/// Log lines in bundles
+const LOG_LINES: &str = "1000";
+
+/// Stdout of a command, or its error
+async fn command_output(program: &str, args: &[&str]) -> String {
+    match Command::new(program).args(args).output().await {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+        Ok(output) => String::from_utf8_lossy(&output.stderr).to_string(),
+        Err(e) => format!("{}: {}\n", program, e),
+    }
+}
+
+/// Diagnostics of a unit for remote support, files of a bundle
+pub struct Diagnostics {
+    files: Vec<(&'static str, String)>,
+}
+
+impl Diagnostics {
+    /// Collect redacted config, register dump, history tail and versions, under core lock
+    pub fn collect(core: &PiSugarCore) -> Self {
+        let mut config = serde_json::to_value(core.config()).unwrap_or_default();
+        redact_secrets(&mut config);
+        let config = serde_json::to_string_pretty(&config).unwrap_or_default();
+
+        let samples = core.history().query(None, None);
+        let mut history = String::from(HistorySample::CSV_HEADER);
+        history.push('\n');
+        for sample in samples
+            .iter()
+            .skip(samples.len().saturating_sub(HISTORY_TAIL))
+        {
+            history.push_str(&sample.to_csv());
+            history.push('\n');
+        }
+
+        let versions = format!(
+            "pisugar-server {}\nmodel {}\nhardware_id {}\n",
+            env!("CARGO_PKG_VERSION"),
+            core.model(),
+            core.status().hardware_id().unwrap_or("none")
+        );
+
+        Self {
+            files: vec![
+                ("config.json", config),
+                ("registers.txt", core.status().register_dump()),
+                ("history.csv", history),
+                ("versions.txt", versions),
+            ],
+        }
+    }
+
+    /// Write a tar.gz bundle to path, with recent logs and os versions
+    pub async fn write_bundle(mut self, path: &Path) -> io::Result<()> {
+        let logs = command_output(
+            "journalctl",
+            &["-u", "pisugar-server", "-n", LOG_LINES, "--no-pager"],
+        )
+        .await;
+        self.files.push(("logs.txt", logs));
+        let mut os = command_output("uname", &["-a"]).await;
+        os.push_str(
+            &tokio::fs::read_to_string("/etc/os-release")
+                .await
+                .unwrap_or_default(),
+        );
+        self.files.push(("os.txt", os));
+
+        let dir = bundle_dir()?;
+        let written = self.write_tar(&dir, path).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        written
+    }
+
+    async fn write_tar(&self, dir: &Path, path: &Path) -> io::Result<()> {
+        for (name, content) in self.files.iter() {
+            tokio::fs::write(dir.join(name), content).await?;
+        }
+        let output = Command::new("tar")
+            .arg("czf")
+            .arg(path)
+            .arg("-C")
+            .arg(dir)
+            .args(self.files.iter().map(|(name, _)| *name))
+            .output()
+            .await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::new(io::ErrorKind::Other, stderr.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// Private temporary dir of bundle files, created 0700 by mkdtemp, existing paths and symlinks
+/// are never reused
+pub fn bundle_dir() -> io::Result<PathBuf> {
+    let template = std::env::temp_dir().join("pisugar-diagnostics-XXXXXX");
+    let template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf = template.into_bytes_with_nul();
+    let dir = unsafe { libc::mkdtemp(buf.as_mut_ptr() as *mut libc::c_char) };
+    if dir.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    buf.pop();
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+}
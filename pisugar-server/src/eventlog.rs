@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::prelude::*;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::{event_name, event_stream_ids, Event, EventRx};
+
+/// Events kept for long-polling clients
+const EVENT_LOG_CAPACITY: usize = 256;
+
+/// Recent events by sequence number, for http long-polling, sequence starts at 1
+pub struct EventLog {
+    events: Mutex<VecDeque<(u64, Event)>>,
+    seq_tx: watch::Sender<u64>,
+    seq_rx: watch::Receiver<u64>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        let (seq_tx, seq_rx) = watch::channel(0);
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(EVENT_LOG_CAPACITY)),
+            seq_tx,
+            seq_rx,
+        }
+    }
+
+    fn push(&self, event: Event) {
+        let seq = match self.events.lock() {
+            Ok(mut events) => {
+                let seq = events.back().map(|(seq, _)| seq + 1).unwrap_or(1);
+                if events.len() == EVENT_LOG_CAPACITY {
+                    events.pop_front();
+                }
+                events.push_back((seq, event));
+                seq
+            }
+            Err(_) => return,
+        };
+        let _ = self.seq_tx.broadcast(seq);
+    }
+
+    /// Sequence of the last event
+    pub fn seq(&self) -> u64 {
+        *self.seq_rx.borrow()
+    }
+
+    /// Events after sequence, and whether events were missed, dropped or of an earlier run
+    pub fn since(&self, since: u64) -> (Vec<(u64, Event)>, bool) {
+        match self.events.lock() {
+            Ok(events) => {
+                let last = events.back().map(|(seq, _)| *seq).unwrap_or(0);
+                let (since, restarted) = if since > last {
+                    (0, true)
+                } else {
+                    (since, false)
+                };
+                let dropped = events
+                    .front()
+                    .map(|(seq, _)| *seq > since + 1)
+                    .unwrap_or(false);
+                let events = events
+                    .iter()
+                    .filter(|(seq, _)| *seq > since)
+                    .cloned()
+                    .collect();
+                (events, restarted || dropped)
+            }
+            Err(_) => (Vec::new(), false),
+        }
+    }
+
+    /// Wait until an event after sequence or timeout
+    pub async fn wait(&self, since: u64, timeout: Duration) {
+        let mut seq_rx = self.seq_rx.clone();
+        let newer = async move {
+            while let Some(seq) = seq_rx.recv().await {
+                if seq > since {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(timeout, newer).await;
+    }
+
+    /// Json of events after sequence, `{"seq": .., "missed": .., "events": [{"seq", "id", "event",
+    /// "data"}]}`
+    pub fn to_json(&self, since: u64) -> Value {
+        let (events, missed) = self.since(since);
+        let seq = events
+            .last()
+            .map(|(seq, _)| *seq)
+            .unwrap_or_else(|| self.seq());
+        let events: Vec<Value> = events
+            .iter()
+            .map(|(seq, e)| {
+                let data = String::from_utf8_lossy(&e.data);
+                let name = event_name(&data);
+                let data = data[name.len()..].trim_start();
+                json!({ "seq": seq, "id": &*e.id, "event": name, "data": data })
+            })
+            .collect();
+        json!({ "seq": seq, "missed": missed, "events": events })
+    }
+}
+
+/// Record broadcast events into log
+pub async fn record_events(event_log: Arc<EventLog>, event_rx: EventRx) {
+    let mut events = event_stream_ids(event_rx).boxed();
+    while let Some(event) = events.next().await {
+        event_log.push(event);
+    }
+}
@@ -0,0 +1,74 @@
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use pisugar_core::{event_json, split_request_id, OutputFormat};
+
+/// Websocket subprotocol of MessagePack frames
+pub const MSGPACK_PROTOCOL: &str = "msgpack";
+
+/// Encoder of websocket frames, text or MessagePack negotiated by `Sec-WebSocket-Protocol`
+#[derive(Debug, Copy, Clone)]
+pub struct FrameEncoder {
+    pub msgpack: bool,
+}
+
+impl FrameEncoder {
+    /// Negotiate of offered subprotocols, MessagePack only with feature `msgpack`
+    pub fn negotiate(protocols: Option<&str>) -> Self {
+        let offered = protocols
+            .map(|p| p.split(',').any(|p| p.trim() == MSGPACK_PROTOCOL))
+            .unwrap_or(false);
+        Self {
+            msgpack: cfg!(feature = "msgpack") && offered,
+        }
+    }
+
+    /// Response frame, `{"key": .., "cmd": .., "value": ..}` or `{"error": ..}` in MessagePack
+    pub fn response(&self, resp: String) -> Message {
+        if !self.msgpack {
+            return Message::Text(resp);
+        }
+        // json output format is rendered already
+        let (id, text) = split_request_id(&resp);
+        let value = serde_json::from_str(resp.trim_end())
+            .or_else(|_| serde_json::from_str(&OutputFormat::Json.render_id(id, text)))
+            .unwrap_or_else(|_| json!({ "error": resp.trim_end() }));
+        encode(&value)
+    }
+
+    /// Event frame, `event_json` in MessagePack, as json output format events
+    pub fn event(&self, event: &[u8]) -> Message {
+        let event = String::from_utf8_lossy(event).to_string();
+        if !self.msgpack {
+            return Message::Text(event);
+        }
+        encode(&event_json(&event))
+    }
+
+    /// Json frame, e.g. grafana data frames
+    pub fn json(&self, json: String) -> Message {
+        if !self.msgpack {
+            return Message::Text(json);
+        }
+        match serde_json::from_str::<Value>(&json) {
+            Ok(value) => encode(&value),
+            Err(_) => Message::Text(json),
+        }
+    }
+}
+
+#[cfg(feature = "msgpack")]
+fn encode(value: &Value) -> Message {
+    match rmp_serde::to_vec(value) {
+        Ok(buf) => Message::Binary(buf),
+        Err(e) => {
+            log::error!("MessagePack encode failed: {}", e);
+            Message::Text(value.to_string())
+        }
+    }
+}
+
+#[cfg(not(feature = "msgpack"))]
+fn encode(value: &Value) -> Message {
+    Message::Text(value.to_string())
+}
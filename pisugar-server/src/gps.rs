@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use pisugar_core::{sys_write_time, PiSugarCore};
+
+/// Default gpsd address
+const GPSD_ADDR: &str = "127.0.0.1:2947";
+
+/// Max offset before the clock is stepped (s)
+const GPS_MAX_OFFSET: i64 = 1;
+
+/// Min interval between two syncs
+const GPS_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Retry interval of gps source
+const GPS_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parse gpsd TPV report time
+fn parse_gpsd(line: &str) -> Option<DateTime<Utc>> {
+    let v: serde_json::Value = serde_json::from_str(line).ok()?;
+    if v.get("class")?.as_str()? != "TPV" || v.get("mode")?.as_u64()? < 2 {
+        return None;
+    }
+    let t = DateTime::parse_from_rfc3339(v.get("time")?.as_str()?).ok()?;
+    Some(t.into())
+}
+
+/// Parse NMEA RMC sentence time, e.g. $GPRMC,123519,A,4807.038,N,...,230394,...
+fn parse_nmea(line: &str) -> Option<DateTime<Utc>> {
+    let line = line.trim();
+    let fields: Vec<&str> = line.split('*').next()?.split(',').collect();
+    if !fields[0].ends_with("RMC") || fields.len() < 10 || fields[2] != "A" {
+        return None;
+    }
+    let (time, date) = (fields[1], fields[9]);
+    if time.len() < 6 || date.len() != 6 || !time.is_ascii() || !date.is_ascii() {
+        return None;
+    }
+    let n = |s: &str| s.parse::<u32>().ok();
+    let date = Utc.ymd_opt(
+        2000 + n(&date[4..6])? as i32,
+        n(&date[2..4])?,
+        n(&date[0..2])?,
+    );
+    date.single()?
+        .and_hms_opt(n(&time[0..2])?, n(&time[2..4])?, n(&time[4..6])?)
+}
+
+/// Discipline system clock and rtc
+fn discipline(core: &Arc<Mutex<PiSugarCore>>, t: DateTime<Utc>, synced_at: &mut Option<Instant>) {
+    if let Some(synced_at) = synced_at {
+        if synced_at.elapsed() < GPS_SYNC_INTERVAL {
+            return;
+        }
+    }
+    let t: DateTime<Local> = t.into();
+    let offset = (t - Local::now()).num_seconds();
+    if offset.abs() > GPS_MAX_OFFSET {
+        log::info!("GPS time {}, offset {}s, sync time", t, offset);
+        let hwclock = match core.lock() {
+            Ok(core) => {
+                if let Err(e) = core.write_time(t) {
+                    log::error!("{}", e);
+                }
+                core.sys_write_hwclock()
+            }
+            Err(_) => return,
+        };
+        sys_write_time(t, hwclock);
+    }
+    *synced_at = Some(Instant::now());
+}
+
+/// Read time lines from a source
+async fn read_source<R: AsyncRead + Unpin>(
+    core: &Arc<Mutex<PiSugarCore>>,
+    reader: R,
+    parse: fn(&str) -> Option<DateTime<Utc>>,
+) {
+    let mut synced_at = None;
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(t) = parse(line.as_str()) {
+            discipline(core, t, &mut synced_at);
+        }
+    }
+}
+
+/// GPS time source, `gpsd`, `gpsd:<addr>` or a NMEA serial device
+pub async fn gps_time_source(core: Arc<Mutex<PiSugarCore>>, source: String) {
+    loop {
+        if source.starts_with("gpsd") {
+            let addr = source.trim_start_matches("gpsd").trim_start_matches(':');
+            let addr = if addr.is_empty() { GPSD_ADDR } else { addr };
+            match TcpStream::connect(addr).await {
+                Ok(mut stream) => {
+                    log::info!("GPS connected to gpsd {}", addr);
+                    let watch = r#"?WATCH={"enable":true,"json":true};"#;
+                    if stream.write_all(watch.as_bytes()).await.is_ok() {
+                        read_source(&core, stream, parse_gpsd).await;
+                    }
+                }
+                Err(e) => log::warn!("GPS gpsd {}: {}", addr, e),
+            }
+        } else {
+            match tokio::fs::File::open(source.as_str()).await {
+                Ok(f) => {
+                    log::info!("GPS opened {}", source);
+                    read_source(&core, f, parse_nmea).await;
+                }
+                Err(e) => log::warn!("GPS {}: {}", source, e),
+            }
+        }
+        tokio::time::delay_for(GPS_RETRY_INTERVAL).await;
+    }
+}
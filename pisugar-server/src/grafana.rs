@@ -0,0 +1,34 @@
+use chrono::Local;
+use serde_json::json;
+
+use pisugar_core::PiSugarSnapshot;
+
+/// Grafana Live data frame of a status snapshot
+pub fn data_frame(snapshot: &PiSugarSnapshot) -> String {
+    let frame = json!({
+        "schema": {
+            "name": "pisugar",
+            "fields": [
+                {"name": "time", "type": "time"},
+                {"name": "battery", "type": "number"},
+                {"name": "battery_v", "type": "number"},
+                {"name": "battery_i", "type": "number"},
+                {"name": "battery_power_w", "type": "number"},
+                {"name": "battery_charging", "type": "boolean"},
+                {"name": "shutdown_eta", "type": "number"},
+            ]
+        },
+        "data": {
+            "values": [
+                [Local::now().timestamp_millis()],
+                [snapshot.battery],
+                [snapshot.battery_v],
+                [snapshot.battery_i],
+                [snapshot.battery_power_w],
+                [snapshot.battery_charging],
+                [snapshot.shutdown_eta],
+            ]
+        }
+    });
+    frame.to_string()
+}
@@ -0,0 +1,336 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use tokio::net::TcpListener;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use pisugar_core::{token_role, Listener, PiSugarCore};
+
+use crate::auth::{AuthGuard, Session};
+use crate::watch::{Watch, WATCH};
+use crate::{event_name, event_stream, execute_request, EventTx, TELEMETRY_INTERVAL};
+
+/// Generated of `proto/pisugar/v1/pisugar.proto`
+pub mod proto {
+    tonic::include_proto!("pisugar.v1");
+}
+
+use proto::command_request::Command;
+use proto::power_manager_server::{PowerManager, PowerManagerServer};
+use proto::{
+    CommandRequest, Event, EventsRequest, Field, GetRequest, HistoryQueryRequest, Reply, Tap,
+    WatchRequest,
+};
+
+/// Line protocol field of `get`
+fn field_name(field: Field) -> Option<&'static str> {
+    let name = match field {
+        Field::Unspecified => return None,
+        Field::Model => "model",
+        Field::Version => "version",
+        Field::PowerOnMode => "power_on_mode",
+        Field::PowerState => "power_state",
+        Field::InputSource => "input_source",
+        Field::Quiet => "quiet",
+        Field::HardwareId => "hardware_id",
+        Field::All => "all",
+        Field::BatteryPresent => "battery_present",
+        Field::Battery => "battery",
+        Field::BatteryV => "battery_v",
+        Field::BatteryI => "battery_i",
+        Field::BatteryIAvg => "battery_i_avg",
+        Field::BatteryPowerW => "battery_power_w",
+        Field::BatteryCharging => "battery_charging",
+        Field::BatteryFullAt => "battery_full_at",
+        Field::Stats => "stats",
+        Field::Listeners => "listeners",
+        Field::ShutdownHistory => "shutdown_history",
+        Field::ShutdownEta => "shutdown_eta",
+        Field::System => "system",
+        Field::Throttled => "throttled",
+        Field::RtcTime => "rtc_time",
+        Field::RtcTimeList => "rtc_time_list",
+        Field::RtcDrift => "rtc_drift",
+        Field::RtcAlarmFlag => "rtc_alarm_flag",
+        Field::RtcAlarmTime => "rtc_alarm_time",
+        Field::RtcAlarmTimeList => "rtc_alarm_time_list",
+        Field::RtcAlarmEnabled => "rtc_alarm_enabled",
+        Field::AlarmRepeat => "alarm_repeat",
+        Field::Schedule => "schedule",
+        Field::SafeShutdownLevel => "safe_shutdown_level",
+        Field::ButtonEnable => "button_enable",
+        Field::ButtonShell => "button_shell",
+        Field::Config => "config",
+        Field::Job => "job",
+        Field::Jobs => "jobs",
+    };
+    Some(name)
+}
+
+fn tap_name(tap: i32) -> Result<&'static str, Status> {
+    match Tap::from_i32(tap) {
+        Some(Tap::Single) => Ok("single"),
+        Some(Tap::Double) => Ok("double"),
+        Some(Tap::Long) => Ok("long"),
+        _ => Err(Status::invalid_argument("Unknown tap")),
+    }
+}
+
+/// Line protocol request of a command
+fn command_request(command: Command) -> Result<String, Status> {
+    let req = match command {
+        Command::CalibrateCurrentZero(_) => "calibrate current_zero".to_string(),
+        Command::SelfTest(_) => "self_test".to_string(),
+        Command::Refresh(_) => "refresh".to_string(),
+        Command::RtcClearFlag(_) => "rtc_clear_flag".to_string(),
+        Command::RtcFromPi(_) => "rtc_pi2rtc".to_string(),
+        Command::PiFromRtc(_) => "rtc_rtc2pi".to_string(),
+        Command::SetSysTime(c) => format!("set_sys_time {}", c.time),
+        Command::RtcWeb(_) => "rtc_web".to_string(),
+        Command::JobCancel(c) => format!("job cancel {}", c.id),
+        Command::RtcAlarmSet(c) => format!("rtc_alarm_set {} {}", c.time, c.repeat),
+        Command::RtcAlarmDisable(_) => "rtc_alarm_disable".to_string(),
+        Command::RtcTestWake(_) => "rtc_test_wake".to_string(),
+        Command::SetSafeShutdownLevel(c) => format!("set_safe_shutdown_level {}", c.level),
+        Command::SetPowerOnMode(c) => format!("set_power_on_mode {}", c.mode),
+        Command::SuspendFor(c) => {
+            let mode = if c.suspend { "suspend" } else { "halt" };
+            format!("suspend_for {} {}", c.seconds, mode)
+        }
+        Command::SetButtonEnable(c) => {
+            format!("set_button_enable {} {}", tap_name(c.tap)?, c.enable as u8)
+        }
+        Command::SetButtonShell(c) => format!("set_button_shell {} {}", tap_name(c.tap)?, c.shell),
+    };
+    Ok(req)
+}
+
+/// Argument of a line protocol request, a single word
+fn request_arg(arg: &str) -> Result<&str, Status> {
+    if arg.is_empty() || arg.contains(char::is_whitespace) {
+        return Err(Status::invalid_argument("Invalid argument"));
+    }
+    Ok(arg)
+}
+
+/// Bearer token of metadata
+fn bearer(metadata: &MetadataMap) -> Option<&str> {
+    let value = metadata.get("authorization")?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Control protocol over grpc, requests are executed as line protocol requests
+struct GrpcService {
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
+}
+
+impl GrpcService {
+    /// Session of a request, role of the bearer token
+    fn session<T>(&self, request: &Request<T>) -> Result<Session, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        let mut session = Session::new(
+            Listener::Grpc,
+            None,
+            peer,
+            self.guard.clone(),
+            self.event_tx.clone(),
+        );
+        if let Some(token) = bearer(request.metadata()) {
+            if !session.auth_allowed() {
+                log::warn!("Auth from {:?} blocked", peer);
+                return Err(Status::permission_denied("Auth blocked"));
+            }
+            let role = match self.core.lock() {
+                Ok(core) => token_role(&core.config().auth_tokens, token),
+                Err(_) => return Err(Status::internal("Lock failed")),
+            };
+            session.auth_attempted(role.is_some());
+            if role.is_none() {
+                log::warn!("Invalid token from {:?}", peer);
+                return Err(Status::unauthenticated("Invalid token"));
+            }
+            session.role = role;
+        }
+        Ok(session)
+    }
+
+    /// Whether session may execute command on the grpc listener, rejected requests are counted,
+    /// others are counted once executed. Unauthenticated without a token, permission denied if
+    /// the role or the listener does not allow the command
+    fn authorize(&self, session: &Session, cmd: &str) -> Result<(), Status> {
+        let mut core = self
+            .core
+            .lock()
+            .map_err(|_| Status::internal("Lock failed"))?;
+        // history and watch are readable by viewer, as on the line protocol
+        let role_cmd = match cmd {
+            "history" | "watch" => "get",
+            cmd => cmd,
+        };
+        let served = core.listener_allows(Listener::Grpc, cmd);
+        if served && core.authorize(session.role, role_cmd) {
+            return Ok(());
+        }
+        log::warn!("Unauthorized {:?} on grpc, rejected: {}", session.role, cmd);
+        let stats = core.listener_stats_mut(Listener::Grpc);
+        stats.requests += 1;
+        stats.rejected += 1;
+        if served && session.role.is_none() {
+            Err(Status::unauthenticated("Unauthorized"))
+        } else {
+            Err(Status::permission_denied("Permission denied"))
+        }
+    }
+
+    /// Execute a line protocol request, the value of `<cmd>: <value>`
+    fn run(&self, req: &str, mut session: Session) -> Result<Response<Reply>, Status> {
+        let cmd = req.split(' ').next().unwrap_or_default();
+        self.authorize(&session, cmd)?;
+        let resp = execute_request(self.core.clone(), req, &mut session);
+        let value = resp
+            .trim_end()
+            .splitn(2, ": ")
+            .nth(1)
+            .ok_or_else(|| Status::invalid_argument(resp.trim_end()))?;
+        if value == "hardware unavailable" {
+            return Err(Status::unavailable(value));
+        }
+        Ok(Response::new(Reply {
+            value: value.to_string(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl PowerManager for GrpcService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<Reply>, Status> {
+        let session = self.session(&request)?;
+        let get = request.into_inner();
+        let field = Field::from_i32(get.field)
+            .and_then(field_name)
+            .ok_or_else(|| Status::invalid_argument("Unknown field"))?;
+        let mut req = format!("get {}", field);
+        for arg in get.args.iter() {
+            req.push(' ');
+            req.push_str(request_arg(arg)?);
+        }
+        self.run(&req, session)
+    }
+
+    async fn history_query(
+        &self,
+        request: Request<HistoryQueryRequest>,
+    ) -> Result<Response<Reply>, Status> {
+        let session = self.session(&request)?;
+        let query = request.into_inner();
+        let req = format!(
+            "history query {} {} {} last {}",
+            request_arg(&query.field)?,
+            request_arg(&query.aggregation)?,
+            request_arg(&query.bucket)?,
+            request_arg(&query.range)?
+        );
+        self.run(&req, session)
+    }
+
+    async fn execute(&self, request: Request<CommandRequest>) -> Result<Response<Reply>, Status> {
+        let session = self.session(&request)?;
+        let command = request
+            .into_inner()
+            .command
+            .ok_or_else(|| Status::invalid_argument("No command"))?;
+        self.run(&command_request(command)?, session)
+    }
+
+    type EventsStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send + Sync>>;
+
+    async fn events(
+        &self,
+        request: Request<EventsRequest>,
+    ) -> Result<Response<Self::EventsStream>, Status> {
+        let session = self.session(&request)?;
+        self.authorize(&session, "get")?;
+        if let Ok(mut core) = self.core.lock() {
+            core.listener_stats_mut(Listener::Grpc).requests += 1;
+        }
+        let names = request.into_inner().names;
+        let events = event_stream(self.event_tx.subscribe()).filter_map(move |e| {
+            let e = String::from_utf8_lossy(&e);
+            let event = match e.as_ref() {
+                "single" | "double" | "long" => Event {
+                    name: "tap".to_string(),
+                    data: e.to_string(),
+                },
+                e => {
+                    let name = event_name(e);
+                    Event {
+                        name: name.to_string(),
+                        data: e[name.len()..].trim_start().to_string(),
+                    }
+                }
+            };
+            let subscribed = names.is_empty() || names.contains(&event.name);
+            future::ready(if subscribed { Some(Ok(event)) } else { None })
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send + Sync>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let session = self.session(&request)?;
+        self.authorize(&session, "watch")?;
+        if let Ok(mut core) = self.core.lock() {
+            core.listener_stats_mut(Listener::Grpc).requests += 1;
+        }
+        let watch = request.into_inner();
+        let op = if watch.below { "<" } else { ">" };
+        let threshold = watch.threshold.to_string();
+        let mut watch = Watch::parse(&[watch.field.as_str(), op, threshold.as_str()])
+            .ok_or_else(|| Status::invalid_argument("Invalid watch"))?;
+        // checked as the watches of a connection, one event and the stream ends
+        let core = self.core.clone();
+        let crossed = tokio::time::interval(TELEMETRY_INTERVAL)
+            .filter_map(move |_| {
+                let crossed = core.lock().ok().and_then(|core| watch.check(&core));
+                future::ready(crossed)
+            })
+            .take(1)
+            .map(|e| {
+                Ok(Event {
+                    name: WATCH.to_string(),
+                    data: e[WATCH.len()..].trim_start().to_string(),
+                })
+            });
+        Ok(Response::new(Box::pin(crossed)))
+    }
+}
+
+/// Serve the grpc control protocol
+pub async fn serve_grpc(
+    mut grpc_listener: TcpListener,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
+) {
+    let service = GrpcService {
+        core,
+        event_tx,
+        guard,
+    };
+    let served = Server::builder()
+        .add_service(PowerManagerServer::new(service))
+        .serve_with_incoming(grpc_listener.incoming())
+        .await;
+    if let Err(e) = served {
+        log::error!("Grpc server error: {}", e);
+    }
+}
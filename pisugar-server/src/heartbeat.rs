@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::stream;
+
+use pisugar_core::PiSugarCore;
+
+use crate::network::{NetworkInfo, NETWORK_CHANGED};
+use crate::{curl, event_name, event_stream, snapshot, EventRx};
+
+/// Default heartbeat interval (s)
+const HEARTBEAT_INTERVAL_DEFAULT: u64 = 300;
+
+/// Publish heartbeat with battery status every interval, an external monitor detects a dead unit by
+/// missing heartbeats. With `announce_network`, host name and addresses are included and the
+/// heartbeat is also published on `network_changed` to announce the new address
+pub async fn heartbeat(
+    core: Arc<Mutex<PiSugarCore>>,
+    url: String,
+    interval: u64,
+    event_rx: EventRx,
+) {
+    let interval = if interval == 0 {
+        HEARTBEAT_INTERVAL_DEFAULT
+    } else {
+        interval
+    };
+    log::info!("Heartbeat to {} every {}s", url, interval);
+
+    let ticks = tokio::time::interval(Duration::from_secs(interval)).map(|_| false);
+    let changes = event_stream(event_rx)
+        .filter(|e| future::ready(event_name(&String::from_utf8_lossy(e)) == NETWORK_CHANGED))
+        .map(|_| true);
+    let mut triggers = stream::select(ticks, changes).boxed();
+    let mut failed = false;
+    while let Some(changed) = triggers.next().await {
+        let announce = match core.lock() {
+            Ok(core) => core.config().announce_network,
+            Err(_) => break,
+        };
+        if changed && !announce {
+            continue;
+        }
+        let mut body = match snapshot(&core).map(|s| serde_json::to_value(&s)) {
+            Some(Ok(body)) => body,
+            _ => break,
+        };
+        if announce {
+            body["network"] = NetworkInfo::read().to_json();
+        }
+        let body = body.to_string();
+        let headers = ["Content-Type: application/json".to_string()];
+        match curl::post(&url, &headers, &body).await {
+            Ok(_) => {
+                if failed {
+                    log::info!("Heartbeat recovered");
+                }
+                failed = false;
+            }
+            Err(e) => {
+                if !failed {
+                    log::warn!("Heartbeat failed: {}", e);
+                }
+                failed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,822 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::prelude::*;
+use futures::prelude::*;
+use futures::stream;
+use hyper::header::{AUTHORIZATION, COOKIE, HOST, SET_COOKIE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper_staticfile::Static;
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+
+use pisugar_core::{
+    token_fingerprint, token_role, HistorySample, Listener, OutputFormat, PiSugarCore, Role,
+};
+
+use crate::auth::{resume_session, session_cookie, AuthGuard, Session, SESSION_COOKIE};
+use crate::diagnostics::{bundle_dir, Diagnostics};
+use crate::eventlog::EventLog;
+use crate::job::cancel_job;
+use crate::metrics::{exposition, METRICS_CONTENT_TYPE};
+use crate::version::build_info;
+use crate::{
+    debug_event, event_name, event_stream_ids, execute_request, snapshot, snapshot_json,
+    start_rtc_web, Event, EventChannels, EventRx, EventTx,
+};
+
+/// Battery status interval of server-sent events
+const SSE_BATTERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default seconds of long-polling
+const POLL_TIMEOUT_DEFAULT: u64 = 30;
+
+/// Max seconds of long-polling
+const POLL_TIMEOUT_MAX: u64 = 60;
+
+/// Server-sent event of a broadcast event, taps are sent as `tap`, arguments as data, the event
+/// id as id
+fn sse_event(e: &Event) -> String {
+    let data = String::from_utf8_lossy(&e.data);
+    match data.as_ref() {
+        "single" | "double" | "long" => format!("id: {}\nevent: tap\ndata: {}\n\n", e.id, data),
+        data => {
+            let name = event_name(data);
+            let data = data[name.len()..].trim_start();
+            let data = if data.is_empty() { name } else { data };
+            format!("id: {}\nevent: {}\ndata: {}\n\n", e.id, name, data)
+        }
+    }
+}
+
+/// Server-sent events of battery status, taps and other events, /api/events?channel=alert|data|all
+fn sse_events(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_rx: EventRx,
+) -> Response<Body> {
+    let channels = match parse_query(req.uri().query()).get("channel") {
+        Some(s) => match EventChannels::parse(s) {
+            Some(channels) => channels,
+            None => return text_response(StatusCode::BAD_REQUEST, "Unknown channel"),
+        },
+        None => EventChannels::All,
+    };
+    let (alert, data) = (channels.alert(), channels.data());
+    let taps = event_stream_ids(event_rx)
+        .filter(move |_| future::ready(alert))
+        .map(|e| sse_event(&e));
+    let battery = tokio::time::interval(SSE_BATTERY_INTERVAL)
+        .filter(move |_| future::ready(data))
+        .map(move |_| {
+            let data = match snapshot(&core) {
+                Some(snapshot) => serde_json::to_string(&snapshot).unwrap_or_default(),
+                None => String::new(),
+            };
+            format!("event: battery\ndata: {}\n\n", data)
+        });
+    let events = stream::select(taps, battery).map(Ok::<_, io::Error>);
+
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::wrap_stream(events))
+        .unwrap()
+}
+
+/// Since and timeout of /api/events/poll?since=<seq>&timeout=<seconds>
+fn poll_params(req: &Request<Body>) -> Option<(u64, Duration)> {
+    let query = parse_query(req.uri().query());
+    let since = match query.get("since") {
+        Some(s) => s.parse().ok()?,
+        None => 0,
+    };
+    let timeout = match query.get("timeout") {
+        Some(s) => s.parse::<u64>().ok()?.min(POLL_TIMEOUT_MAX),
+        None => POLL_TIMEOUT_DEFAULT,
+    };
+    Some((since, Duration::from_secs(timeout)))
+}
+
+/// Events after a sequence, waits for the next event if none, for clients without ws or sse
+async fn events_poll(event_log: Arc<EventLog>, since: u64, timeout: Duration) -> Response<Body> {
+    if since == event_log.seq() && timeout > Duration::from_secs(0) {
+        event_log.wait(since, timeout).await;
+    }
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(event_log.to_json(since).to_string()))
+        .unwrap()
+}
+
+/// Decode percent-encoded query value
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Parse query string
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for pair in query.unwrap_or_default().split('&') {
+        let mut kv = pair.splitn(2, '=');
+        if let Some(k) = kv.next() {
+            if !k.is_empty() {
+                let v = kv.next().unwrap_or_default();
+                params.insert(percent_decode(k), percent_decode(v));
+            }
+        }
+    }
+    params
+}
+
+/// Parse unix timestamp or rfc3339 time
+fn parse_time(s: &str) -> Option<DateTime<Local>> {
+    if let Ok(ts) = s.parse::<i64>() {
+        return Local.timestamp_opt(ts, 0).single();
+    }
+    DateTime::parse_from_rfc3339(s).ok().map(|t| t.into())
+}
+
+/// Plain text response with status code
+fn text_response(status: StatusCode, text: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(text.to_string()))
+        .unwrap()
+}
+
+/// Export history samples, /api/history?from=&to=&format=csv|json
+fn history_export(req: &Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let query = parse_query(req.uri().query());
+    let from = query.get("from").and_then(|s| parse_time(s));
+    let to = query.get("to").and_then(|s| parse_time(s));
+    let samples = match core.lock() {
+        Ok(core) => core.history().query(from, to),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+
+    match query.get("format").map(|s| s.as_str()).unwrap_or("json") {
+        "csv" => {
+            let mut body = String::from(HistorySample::CSV_HEADER);
+            body.push('\n');
+            for sample in &samples {
+                body.push_str(&sample.to_csv());
+                body.push('\n');
+            }
+            Response::builder()
+                .header("Content-Type", "text/csv")
+                .header(
+                    "Content-Disposition",
+                    "attachment; filename=\"pisugar-history.csv\"",
+                )
+                .body(Body::from(body))
+                .unwrap()
+        }
+        "json" => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                serde_json::to_string(&samples).unwrap_or_default(),
+            ))
+            .unwrap(),
+        _ => text_response(StatusCode::BAD_REQUEST, "Unknown format"),
+    }
+}
+
+/// Api token, `Authorization: Bearer <token>` or `?token=<token>`
+fn api_token(req: &Request<Body>) -> Option<String> {
+    if let Some(auth) = req.headers().get(AUTHORIZATION) {
+        if let Ok(auth) = auth.to_str() {
+            if auth.starts_with("Bearer ") {
+                return Some(auth["Bearer ".len()..].trim().to_string());
+            }
+        }
+    }
+    parse_query(req.uri().query()).remove("token")
+}
+
+/// Session id of request cookie
+fn api_session(req: &Request<Body>) -> Option<String> {
+    let cookie = req.headers().get(COOKIE)?.to_str().ok()?;
+    session_cookie(cookie).map(String::from)
+}
+
+/// Role of request, by api token or session cookie
+fn api_role(req: &Request<Body>, core: &PiSugarCore) -> Option<Role> {
+    if let Some(token) = api_token(req) {
+        return token_role(&core.config().auth_tokens, &token);
+    }
+    let id = api_session(req)?;
+    resume_session(core, &id)?.role
+}
+
+/// Whether peer may attempt the api token of request, attempts are recorded in the auth guard,
+/// backoff and ban of failed tokens as `auth`
+fn guard_api_token(
+    req: &Request<Body>,
+    core: &Mutex<PiSugarCore>,
+    guard: &AuthGuard,
+    peer: Option<IpAddr>,
+) -> bool {
+    let (token, peer) = match (api_token(req), peer) {
+        (Some(token), Some(peer)) => (token, peer),
+        _ => return true,
+    };
+    let valid = match core.lock() {
+        Ok(core) if core.config().auth_tokens.is_empty() => return true,
+        Ok(core) => token_role(&core.config().auth_tokens, &token).is_some(),
+        Err(_) => return false,
+    };
+    if !guard.allowed(peer) {
+        log::warn!("Auth from {} blocked", peer);
+        return false;
+    }
+    if valid {
+        guard.succeeded(peer);
+    } else {
+        guard.failed(peer);
+    }
+    true
+}
+
+/// Whether token of request may execute command on the http listener, read apis are authorized
+/// as `get`
+fn authorize_api(req: &Request<Body>, core: &Arc<Mutex<PiSugarCore>>, cmd: &str) -> bool {
+    match core.lock() {
+        Ok(mut core) => {
+            let role = api_role(req, &core);
+            let authorized = core.listener_allows(Listener::Http, cmd) && core.authorize(role, cmd);
+            if !authorized {
+                log::warn!(
+                    "Unauthorized {:?} on http, rejected: {}",
+                    role,
+                    req.uri().path()
+                );
+                core.listener_stats_mut(Listener::Http).rejected += 1;
+            }
+            authorized
+        }
+        Err(_) => false,
+    }
+}
+
+/// Create a session of request role, set as cookie, POST /api/session
+fn session_create(req: &Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let (id, ttl) = match core.lock() {
+        Ok(mut core) => {
+            let role = api_role(req, &core);
+            let token = match api_token(req) {
+                Some(token) => Some(token_fingerprint(&token)),
+                None => api_session(req)
+                    .and_then(|id| resume_session(&core, &id))
+                    .and_then(|state| state.token),
+            };
+            let ttl = core.config().session_ttl;
+            match core
+                .sessions_mut()
+                .create(role, token, OutputFormat::Text, false, ttl)
+            {
+                Ok(id) => (id, ttl),
+                Err(e) => {
+                    log::error!("Failed to create session: {}", e);
+                    return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Session failed");
+                }
+            }
+        }
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Strict",
+        SESSION_COOKIE, id, ttl
+    );
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .header(SET_COOKIE, cookie)
+        .body(Body::from(id))
+        .unwrap()
+}
+
+/// Remove session of cookie, DELETE /api/session
+fn session_remove(req: &Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    if let (Some(id), Ok(mut core)) = (api_session(req), core.lock()) {
+        if let Err(e) = core.sessions_mut().remove(&id) {
+            log::error!("Failed to remove session: {}", e);
+        }
+    }
+    let cookie = format!("{}=; Max-Age=0; Path=/", SESSION_COOKIE);
+    Response::builder()
+        .header(SET_COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Emit a synthetic event, /api/debug/emit?event=, as `debug emit <event>`
+fn debug_emit(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+) -> Response<Body> {
+    if !authorize_api(req, &core, "debug") {
+        return text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let enabled = core
+        .lock()
+        .map(|c| c.config().debug_enable)
+        .unwrap_or(false);
+    if !enabled {
+        return text_response(StatusCode::FORBIDDEN, "Debug disabled");
+    }
+    let query = parse_query(req.uri().query());
+    match query.get("event").and_then(|e| debug_event(e)) {
+        Some(event) => {
+            log::info!("Debug emit: {}", event);
+            let _ = event_tx.send(Event::new(Bytes::from_static(event.as_bytes())));
+            text_response(StatusCode::OK, event)
+        }
+        None => text_response(StatusCode::BAD_REQUEST, "Unknown event"),
+    }
+}
+
+/// Sync web time to rtc & pi in a job, POST /api/rtc_web, as `rtc_web`, `{"job": <id>}`
+fn rtc_web(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+) -> Response<Body> {
+    if !authorize_api(req, &core, "rtc_web") {
+        return text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let id = match core.lock() {
+        Ok(mut c) => {
+            if c.config().readonly {
+                return text_response(StatusCode::FORBIDDEN, "Read-only");
+            }
+            start_rtc_web(&mut c, core.clone(), event_tx)
+        }
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "job": id }).to_string()))
+        .unwrap()
+}
+
+/// Job of id, GET /api/job?id=, as `get job <id>`
+fn job_status(req: &Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let query = parse_query(req.uri().query());
+    let id = match query.get("id").and_then(|id| id.parse().ok()) {
+        Some(id) => id,
+        None => return text_response(StatusCode::BAD_REQUEST, "Invalid id"),
+    };
+    let job = match core.lock() {
+        Ok(core) => core.jobs().get(id).cloned(),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    match job {
+        Some(job) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(serde_json::to_string(&job).unwrap_or_default()))
+            .unwrap(),
+        None => text_response(StatusCode::NOT_FOUND, "No such job"),
+    }
+}
+
+/// Recent jobs, GET /api/jobs, as `get jobs`
+fn jobs_list(core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let jobs = match core.lock() {
+        Ok(core) => serde_json::to_string(&core.jobs().list()).unwrap_or_default(),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(jobs))
+        .unwrap()
+}
+
+/// Cancel a running job, DELETE /api/job?id=, as `job cancel <id>`
+fn job_cancel(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+) -> Response<Body> {
+    if !authorize_api(req, &core, "job") {
+        return text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let query = parse_query(req.uri().query());
+    let id = match query.get("id").and_then(|id| id.parse().ok()) {
+        Some(id) => id,
+        None => return text_response(StatusCode::BAD_REQUEST, "Invalid id"),
+    };
+    let event = match core.lock() {
+        Ok(mut c) => cancel_job(&mut c, id),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    match event {
+        Some(event) => {
+            let _ = event_tx.send(Event::new(event));
+            text_response(StatusCode::OK, "Cancelled")
+        }
+        None => text_response(StatusCode::NOT_FOUND, "No running job"),
+    }
+}
+
+/// Status snapshot, GET /api/status?humanize=1, as `get all`
+fn status(req: &Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let humanize = parse_query(req.uri().query())
+        .get("humanize")
+        .map(|h| h.as_str())
+        == Some("1");
+    match snapshot(&core) {
+        Some(snapshot) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(snapshot_json(&snapshot, humanize)))
+            .unwrap(),
+        None => text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    }
+}
+
+/// Prometheus metrics, GET /metrics
+fn metrics(core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let listener_stats = match core.lock() {
+        Ok(core) => core.listener_stats().clone(),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    match snapshot(&core) {
+        Some(snapshot) => Response::builder()
+            .header("Content-Type", METRICS_CONTENT_TYPE)
+            .body(Body::from(exposition(&snapshot, &listener_stats)))
+            .unwrap(),
+        None => text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    }
+}
+
+/// Value of a text response, json values (numbers, booleans, objects) are embedded
+fn api_value(resp: &str) -> Option<serde_json::Value> {
+    let line = resp.trim_end_matches('\n');
+    let value = &line[line.find(": ")? + 2..];
+    Some(serde_json::from_str(value).unwrap_or_else(|_| json!(value)))
+}
+
+/// Run requests in a session of the request role on the http listener, json object of values by
+/// key, `{"error": ..}` of the first failed request
+fn api_requests(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
+    peer: Option<IpAddr>,
+    requests: &[(&str, String)],
+) -> Response<Body> {
+    for (_, request) in requests {
+        let cmd = request.split(' ').next().unwrap_or_default();
+        if !authorize_api(req, &core, cmd) {
+            return text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+        }
+    }
+    let role = match core.lock() {
+        Ok(core) => api_role(req, &core),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    // counted once in `handle_http`
+    let mut session = Session::new(Listener::Http, role, peer, guard, event_tx);
+    session.counted = true;
+    let mut values = serde_json::Map::new();
+    for (key, request) in requests {
+        let resp = execute_request(core.clone(), request, &mut session);
+        match api_value(&resp) {
+            Some(value) => {
+                values.insert(key.to_string(), value);
+            }
+            None => {
+                let error = json!({ "error": resp.trim_end(), "request": request });
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(error.to_string()))
+                    .unwrap();
+            }
+        }
+    }
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(serde_json::Value::Object(values).to_string()))
+        .unwrap()
+}
+
+/// Requests of a rest api by method and path, none if not a rest api, error of invalid parameters
+fn rest_requests(req: &Request<Body>) -> Option<Result<Vec<(&'static str, String)>, &'static str>> {
+    let query = parse_query(req.uri().query());
+    let requests = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/api/battery") => vec![
+            ("level", "get battery".to_string()),
+            ("voltage", "get battery_v".to_string()),
+            ("intensity", "get battery_i".to_string()),
+            ("charging", "get battery_charging".to_string()),
+            ("power_state", "get power_state".to_string()),
+        ],
+        (&Method::GET, "/api/rtc/time") => vec![("time", "get rtc_time".to_string())],
+        (&Method::GET, "/api/rtc/alarm") => vec![
+            ("time", "get rtc_alarm_time".to_string()),
+            ("enabled", "get rtc_alarm_enabled".to_string()),
+            ("repeat", "get alarm_repeat".to_string()),
+        ],
+        (&Method::POST, "/api/rtc/alarm") => {
+            let time = match query.get("time") {
+                Some(time) if !time.is_empty() && !time.contains(char::is_whitespace) => time,
+                _ => return Some(Err("Invalid time")),
+            };
+            let repeat = match query.get("repeat").map(|r| r.parse::<u8>()) {
+                Some(Ok(repeat)) => repeat,
+                Some(Err(_)) => return Some(Err("Invalid repeat")),
+                None => 127,
+            };
+            vec![("result", format!("rtc_alarm_set {} {}", time, repeat))]
+        }
+        (&Method::DELETE, "/api/rtc/alarm") => {
+            vec![("result", "rtc_alarm_disable".to_string())]
+        }
+        (&Method::POST, "/api/rtc/sync") => match query.get("from").map(|f| f.as_str()) {
+            Some("pi") => vec![("result", "rtc_pi2rtc".to_string())],
+            Some("rtc") => vec![("result", "rtc_rtc2pi".to_string())],
+            _ => return Some(Err("Unknown source")),
+        },
+        _ => return None,
+    };
+    Some(Ok(requests))
+}
+
+/// Diagnostics bundle, /api/diagnostics, as `diagnostics bundle`
+async fn diagnostics_bundle(core: Arc<Mutex<PiSugarCore>>) -> Response<Body> {
+    let diagnostics = match core.lock() {
+        Ok(core) => Diagnostics::collect(&core),
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    let dir = match bundle_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::error!("Diagnostics bundle failed: {}", e);
+            return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Diagnostics failed");
+        }
+    };
+    let path = dir.join("pisugar-diagnostics.tar.gz");
+    let bundle = match diagnostics.write_bundle(&path).await {
+        Ok(_) => tokio::fs::read(&path).await,
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    match bundle {
+        Ok(bundle) => Response::builder()
+            .header("Content-Type", "application/gzip")
+            .header(
+                "Content-Disposition",
+                "attachment; filename=\"pisugar-diagnostics.tar.gz\"",
+            )
+            .body(Body::from(bundle))
+            .unwrap(),
+        Err(e) => {
+            log::error!("Diagnostics bundle failed: {}", e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "Diagnostics failed")
+        }
+    }
+}
+
+/// Host of request without port, host name of the pi if absent
+fn request_host(req: &Request<Body>) -> Option<String> {
+    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok());
+    match host {
+        Some(host) if host.ends_with(']') => Some(host.to_string()),
+        Some(host) => Some(host.rsplitn(2, ':').last().unwrap_or(host).to_string()),
+        None => pisugar_core::hostname().ok(),
+    }
+}
+
+/// Everything a client needs on startup in one call, GET /api/bootstrap, only the websocket
+/// endpoint, auth requirement and version if unauthorized
+fn bootstrap(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    ws_port: Option<u16>,
+) -> Response<Body> {
+    let (authorized, auth_required) = match core.lock() {
+        Ok(core) => {
+            let role = api_role(req, &core);
+            let authorized =
+                core.listener_allows(Listener::Http, "get") && core.authorize(role, "get");
+            (authorized, !core.authorize(None, "get"))
+        }
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    let ws = match (request_host(req), ws_port) {
+        (Some(host), Some(port)) => Some(format!("ws://{}:{}", host, port)),
+        _ => None,
+    };
+    let info = build_info();
+    let mut payload = json!({
+        "ws": ws,
+        "ws_port": ws_port,
+        "auth_required": auth_required,
+        "version": info.version,
+    });
+    if authorized {
+        let (model, hardware_id) = match core.lock() {
+            Ok(core) => (core.model(), core.status().hardware_id().map(String::from)),
+            Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+        };
+        payload["capabilities"] = json!(info.features);
+        payload["name"] = json!(pisugar_core::hostname().ok());
+        payload["model"] = json!(model);
+        payload["hardware_id"] = json!(hardware_id);
+        payload["status"] = json!(snapshot(&core));
+    }
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(payload.to_string()))
+        .unwrap()
+}
+
+/// Provisioning payload for QR pairing, /api/provision?role=viewer|operator|admin
+fn provision(
+    req: &Request<Body>,
+    core: Arc<Mutex<PiSugarCore>>,
+    ws_port: Option<u16>,
+) -> Response<Body> {
+    if !authorize_api(req, &core, "provision") {
+        return text_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let query = parse_query(req.uri().query());
+    let role: Role = match query.get("role").map(|r| r.as_str()).unwrap_or("viewer") {
+        "viewer" => Role::Viewer,
+        "operator" => Role::Operator,
+        "admin" => Role::Admin,
+        _ => return text_response(StatusCode::BAD_REQUEST, "Unknown role"),
+    };
+    let (model, hardware_id, token) = match core.lock() {
+        Ok(core) => {
+            let tokens = &core.config().auth_tokens;
+            let mut candidates: Vec<&String> = tokens
+                .iter()
+                .filter(|(_, r)| **r == role)
+                .map(|(t, _)| t)
+                .collect();
+            candidates.sort();
+            let token = candidates.first().map(|t| t.to_string());
+            if !tokens.is_empty() && token.is_none() {
+                return text_response(StatusCode::NOT_FOUND, "No token of role");
+            }
+            let hardware_id = core.status().hardware_id().map(String::from);
+            (core.model(), hardware_id, token)
+        }
+        Err(_) => return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Lock failed"),
+    };
+    let host = request_host(req);
+    let ws = match (&host, ws_port) {
+        (Some(host), Some(port)) => Some(format!("ws://{}:{}", host, port)),
+        _ => None,
+    };
+    let payload = json!({
+        "name": pisugar_core::hostname().ok(),
+        "model": model,
+        "hardware_id": hardware_id,
+        "ws": ws,
+        "token": token,
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(payload.to_string()))
+        .unwrap()
+}
+
+async fn handle_http(
+    req: Request<Body>,
+    static_: Static,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
+    peer: Option<IpAddr>,
+    event_log: Arc<EventLog>,
+    ws_port: Option<u16>,
+) -> io::Result<Response<Body>> {
+    if !guard_api_token(&req, &core, &guard, peer) {
+        return Ok(text_response(StatusCode::TOO_MANY_REQUESTS, "Auth blocked"));
+    }
+    if req.uri().path().starts_with("/api/") {
+        if let Ok(mut core) = core.lock() {
+            core.listener_stats_mut(Listener::Http).requests += 1;
+        }
+        if req.uri().path() != "/api/bootstrap" && !authorize_api(&req, &core, "get") {
+            return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+        }
+    }
+    match rest_requests(&req) {
+        Some(Ok(requests)) => {
+            return Ok(api_requests(&req, core, event_tx, guard, peer, &requests))
+        }
+        Some(Err(e)) => return Ok(text_response(StatusCode::BAD_REQUEST, e)),
+        None => {}
+    }
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/api/status") => Ok(status(&req, core)),
+        (&Method::GET, "/metrics") => {
+            if !authorize_api(&req, &core, "get") {
+                return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+            }
+            Ok(metrics(core))
+        }
+        (&Method::GET, "/api/events") => Ok(sse_events(&req, core, event_tx.subscribe())),
+        (&Method::GET, "/api/events/poll") => match poll_params(&req) {
+            Some((since, timeout)) => Ok(events_poll(event_log, since, timeout).await),
+            None => Ok(text_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid since or timeout",
+            )),
+        },
+        (&Method::GET, "/api/history") => Ok(history_export(&req, core)),
+        (&Method::GET, "/api/diagnostics") => {
+            if !authorize_api(&req, &core, "diagnostics") {
+                return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+            }
+            Ok(diagnostics_bundle(core).await)
+        }
+        (&Method::POST, "/api/debug/emit") => Ok(debug_emit(&req, core, event_tx)),
+        (&Method::POST, "/api/rtc_web") => Ok(rtc_web(&req, core, event_tx)),
+        (&Method::GET, "/api/job") => Ok(job_status(&req, core)),
+        (&Method::DELETE, "/api/job") => Ok(job_cancel(&req, core, event_tx)),
+        (&Method::GET, "/api/jobs") => Ok(jobs_list(core)),
+        (&Method::GET, "/api/bootstrap") => Ok(bootstrap(&req, core, ws_port)),
+        (&Method::GET, "/api/provision") => Ok(provision(&req, core, ws_port)),
+        (&Method::POST, "/api/session") => Ok(session_create(&req, core)),
+        (&Method::DELETE, "/api/session") => Ok(session_remove(&req, core)),
+        _ => static_.serve(req).await,
+    }
+}
+
+/// Serve web
+pub async fn serve_http(
+    http_listener: TcpListener,
+    web_dir: String,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
+    event_log: Arc<EventLog>,
+    ws_port: Option<u16>,
+) {
+    let static_ = Static::new(web_dir);
+
+    let make_service = make_service_fn(move |stream: &TcpStream| {
+        let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+        let static_ = static_.clone();
+        let core = core.clone();
+        if let Ok(mut core) = core.lock() {
+            core.listener_stats_mut(Listener::Http).connections += 1;
+        }
+        let event_tx = event_tx.clone();
+        let guard = guard.clone();
+        let event_log = event_log.clone();
+        future::ok::<_, hyper::Error>(service_fn(move |req| {
+            handle_http(
+                req,
+                static_.clone(),
+                core.clone(),
+                event_tx.clone(),
+                guard.clone(),
+                peer,
+                event_log.clone(),
+                ws_port,
+            )
+        }))
+    });
+
+    let incoming = hyper::server::accept::from_stream(http_listener);
+    let server = Server::builder(incoming).serve(make_service);
+
+    if let Err(e) = server.await {
+        log::error!("Http web server error: {}", e);
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use pisugar_core::{IdleSample, IdleTracker, PiSugarCore, PowerState, ShutdownCause};
+
+/// Idle sample interval
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Power off when on battery and idle for minutes of `idle_shutdown` policy
+pub async fn idle_shutdown(core: Arc<Mutex<PiSugarCore>>) {
+    let mut interval = tokio::time::interval(IDLE_INTERVAL);
+    let mut tracker = IdleTracker::default();
+    loop {
+        interval.tick().await;
+        let (policy, on_battery) = match core.lock() {
+            // discharging by power state, not charging is also full on external power
+            Ok(core) => {
+                let on_battery = match core.power_state() {
+                    PowerState::OnBattery | PowerState::Low | PowerState::Critical => true,
+                    _ => false,
+                };
+                (core.config().idle_shutdown.clone(), on_battery)
+            }
+            Err(_) => break,
+        };
+        if !policy.enabled {
+            tracker = IdleTracker::default();
+            continue;
+        }
+
+        // /proc is read outside of core lock
+        let sample = match IdleSample::read(policy.ssh_port) {
+            Ok(sample) => sample,
+            Err(e) => {
+                log::debug!("Idle sample error: {}", e);
+                continue;
+            }
+        };
+        let now = Instant::now();
+        if tracker.update(&policy, on_battery, &sample, Local::now().time(), now) {
+            log::warn!("Idle for {} minutes on battery, power off", policy.minutes);
+            tracker = IdleTracker::default();
+            if let Ok(mut core) = core.lock() {
+                if let Err(e) = core.power_off(ShutdownCause::Idle) {
+                    log::error!("Idle power off failed: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Default lock file, in /run writable by root only
+pub const LOCK_FILE: &str = "/run/pisugar-server.lock";
+
+/// Max wait for the old instance to exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Try exclusive lock, false if held by another process
+fn try_lock(f: &File) -> io::Result<bool> {
+    let ret = unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(false);
+    }
+    Err(e)
+}
+
+/// Pid of the lock holder
+fn read_pid(f: &mut File) -> Option<libc::pid_t> {
+    let mut s = String::new();
+    f.seek(SeekFrom::Start(0)).ok()?;
+    f.read_to_string(&mut s).ok()?;
+    s.trim().parse().ok().filter(|pid| *pid > 0)
+}
+
+/// Whether pid is a running pisugar-server, the pid file may be stale or forged
+fn is_instance(pid: libc::pid_t) -> bool {
+    let exe = match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+        Ok(exe) => exe,
+        Err(_) => return false,
+    };
+    // upgraded binaries are `<path> (deleted)`
+    let name = exe.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let name = name.trim_end_matches(" (deleted)");
+    let own = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_name().and_then(|n| n.to_str()).map(String::from));
+    name == env!("CARGO_PKG_NAME") || Some(name) == own.as_deref()
+}
+
+/// Lock instance, the lock is held until the returned file is dropped.
+/// With `replace`, the running instance is interrupted (cleaned up as ctrl+c) and waited.
+pub fn lock_instance(path: &Path, replace: bool) -> io::Result<File> {
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    if !try_lock(&f)? {
+        let pid = read_pid(&mut f);
+        if !replace {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Already running, pid {:?}, use --replace to take over", pid),
+            ));
+        }
+
+        match pid {
+            Some(pid) if is_instance(pid) => {
+                log::warn!("Replace running instance, pid {}", pid);
+                unsafe { libc::kill(pid, libc::SIGINT) };
+            }
+            Some(pid) => log::warn!(
+                "Replace running instance, pid {} is not pisugar-server",
+                pid
+            ),
+            None => log::warn!("Replace running instance, pid unknown"),
+        }
+        let now = Instant::now();
+        while !try_lock(&f)? {
+            if now.elapsed() > REPLACE_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Running instance did not exit",
+                ));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    f.set_len(0)?;
+    f.seek(SeekFrom::Start(0))?;
+    write!(f, "{}", std::process::id())?;
+    f.sync_all()?;
+    Ok(f)
+}
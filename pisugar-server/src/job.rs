@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+use pisugar_core::{Job, JobStore, PiSugarCore};
+
+use crate::{Event, EventTx};
+
+/// Running job of a long operation, progress and result are sent as `job` events
+pub struct JobHandle {
+    pub id: u64,
+    core: Arc<Mutex<PiSugarCore>>,
+    tx: Arc<EventTx>,
+}
+
+impl JobHandle {
+    /// Start a job of kind, under core lock
+    pub fn start(
+        core: &mut PiSugarCore,
+        core_arc: Arc<Mutex<PiSugarCore>>,
+        tx: Arc<EventTx>,
+        kind: &str,
+    ) -> Self {
+        let id = core.jobs_mut().start(kind);
+        log::info!("Job {} started: {}", id, kind);
+        Self {
+            id,
+            core: core_arc,
+            tx,
+        }
+    }
+
+    /// Update job, event of the updated job
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut JobStore) -> Option<&Job>,
+    {
+        let event = match self.core.lock() {
+            Ok(mut core) => f(core.jobs_mut()).map(|job| job.event()),
+            Err(_) => None,
+        };
+        if let Some(event) = event {
+            let _ = self.tx.send(Event::new(Bytes::from(event)));
+        }
+    }
+
+    /// Progress (%), ignored once cancelled
+    pub fn progress(&self, progress: u8) {
+        let id = self.id;
+        self.update(|jobs| jobs.set_progress(id, progress));
+    }
+
+    /// Whether cancelled, long operations check it between steps and before side effects
+    pub fn cancelled(&self) -> bool {
+        match self.core.lock() {
+            Ok(core) => !core.jobs().running(self.id),
+            Err(_) => true,
+        }
+    }
+
+    /// Finish with result or error, discarded if cancelled
+    pub fn finish(self, result: Result<String, String>) {
+        match &result {
+            Ok(_) => log::info!("Job {} done", self.id),
+            Err(e) => log::warn!("Job {} failed: {}", self.id, e),
+        }
+        let id = self.id;
+        self.update(|jobs| jobs.finish(id, result));
+    }
+}
+
+/// Cancel a running job under core lock, event of the cancelled job
+pub fn cancel_job(core: &mut PiSugarCore, id: u64) -> Option<Bytes> {
+    let job = core.jobs_mut().cancel(id)?;
+    log::info!("Job {} cancelled: {}", id, job.kind);
+    Some(Bytes::from(job.event()))
+}
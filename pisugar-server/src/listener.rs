@@ -0,0 +1,45 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+
+/// Max bind attempts on address in use
+const BIND_RETRIES: u32 = 5;
+
+/// Initial bind backoff, doubled on each retry
+const BIND_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Bind tcp listener, retry with backoff while the address is in use,
+/// then fall back to an ephemeral port if enabled
+pub async fn bind_with_retry(addr: &str, fallback: bool) -> io::Result<TcpListener> {
+    let sock_addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut backoff = BIND_BACKOFF;
+    let mut attempt = 1;
+    let err = loop {
+        match TcpListener::bind(sock_addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < BIND_RETRIES => {
+                log::warn!("Bind {} ({}/{}): {}", addr, attempt, BIND_RETRIES, e);
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+
+    if fallback && err.kind() == io::ErrorKind::AddrInUse {
+        let listener = TcpListener::bind(SocketAddr::new(sock_addr.ip(), 0)).await?;
+        log::warn!(
+            "Bind {} failed, fall back to {}",
+            addr,
+            listener.local_addr()?
+        );
+        return Ok(listener);
+    }
+    Err(err)
+}
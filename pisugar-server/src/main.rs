@@ -1,38 +1,335 @@
-use std::convert::TryInto;
+use std::collections::VecDeque;
 use std::fs::remove_file;
 use std::io;
-use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::*;
 use chrono::prelude::*;
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use futures::prelude::*;
 use futures::SinkExt;
 use futures_channel::mpsc::unbounded;
-use hyper::service::{make_service_fn, service_fn};
+#[cfg(feature = "ws")]
+use futures_channel::mpsc::UnboundedSender;
+#[cfg(feature = "http")]
 use hyper::Client;
-use hyper::Server;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::RecvError;
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse, Request as WsRequest, Response as WsResponse,
+};
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, COOKIE, SEC_WEBSOCKET_PROTOCOL};
+#[cfg(feature = "ws")]
+use tokio_tungstenite::tungstenite::Message;
 use tokio_util::codec::{BytesCodec, Framed};
 
+#[cfg(feature = "ws")]
+use auth::session_cookie;
+use auth::{resume_session, AuthGuard, Session};
+use diagnostics::Diagnostics;
+#[cfg(feature = "ws")]
+use frame::{FrameEncoder, MSGPACK_PROTOCOL};
+use job::cancel_job;
+#[cfg(feature = "http")]
+use job::JobHandle;
+use participant::ShutdownParticipant;
 use pisugar_core::{
-    sys_write_time, PiSugarConfig, PiSugarCore, SD3078Time, I2C_READ_INTERVAL, TIME_HOST,
+    boottime, execute_get, execute_history, humanize_secs, new_event_id, split_request_id,
+    sys_write_time, token_fingerprint, token_role, ClockWatch, Listener, OutputFormat,
+    PiSugarConfig, PiSugarCore, PiSugarSnapshot, Request, Role, SD3078Time, I2C_READ_INTERVAL,
+    TIME_HOST,
 };
+use watch::{Watch, MAX_WATCHES};
+use watchdog::{sd_notify, PollWatchdog, POLLER_STALLED, POLL_DEADLINE};
+
+mod auth;
+#[cfg(feature = "bridge")]
+mod bridge;
+mod client;
+mod curl;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod diagnostics;
+#[cfg(feature = "http")]
+mod eventlog;
+#[cfg(feature = "ws")]
+mod frame;
+mod gps;
+#[cfg(feature = "ws")]
+mod grafana;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod heartbeat;
+#[cfg(feature = "http")]
+mod http;
+mod idle;
+mod instance;
+mod job;
+mod listener;
+#[cfg(feature = "http")]
+mod metrics;
+mod modbus;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod network;
+mod notify;
+mod ntp;
+mod participant;
+mod privilege;
+mod syslog;
+mod version;
+mod watch;
+mod watchdog;
+
+/// Config save debounce delay
+const CONFIG_SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// Ed25519 public key in hex, baked at build time, config must be signed if set
+const CONFIG_PUBKEY: Option<&str> = option_env!("PISUGAR_CONFIG_PUBKEY");
+
+/// Commands changing config, rejected with signed config. Session settings (`humanize`, `format`,
+/// `mode`) are not config, `shutdown_command` and `rtc_kernel_driver` have no commands, modbus
+/// coil writes are rejected by modbus
+const CONFIG_COMMANDS: [&str; 5] = [
+    "calibrate",
+    "rtc_alarm_set",
+    "set_safe_shutdown_level",
+    "set_button_enable",
+    "set_button_shell",
+];
+
+/// Synthetic events of `debug emit <event>`
+const DEBUG_EVENTS: [&str; 8] = [
+    "single",
+    "double",
+    "long",
+    "battery_full",
+    "charge_complete",
+    "low_battery",
+    "power_loss",
+    "power_restored",
+];
+
+/// Debug event of name, static payload
+fn debug_event(name: &str) -> Option<&'static str> {
+    DEBUG_EVENTS.iter().copied().find(|e| *e == name)
+}
+
+/// Periodic shutdown eta event, `shutdown_eta <secs>`
+const SHUTDOWN_ETA: &str = "shutdown_eta";
+
+/// Interval of shutdown eta events
+const SHUTDOWN_ETA_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Self test event, dispatched by `self_test`
+const SELF_TEST: &str = "self_test";
+
+/// Time jump event, `time_jump <secs>`, negative backwards
+const TIME_JUMP: &str = "time_jump";
+
+/// Interval of checking time jumps
+const TIME_JUMP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Event name, first word of payload, e.g. `shutdown_eta` of `shutdown_eta 600`
+fn event_name(event: &str) -> &str {
+    event.split(' ').next().unwrap_or_default()
+}
+
+/// Min seconds of `suspend_for`, shutdown completes before the alarm
+const MIN_SUSPEND_SECONDS: u64 = 60;
 
-/// Websocket info
-const WS_JSON: &str = "_ws.json";
+/// Max seconds of `suspend_for`, the rtc alarm repeats weekly
+const MAX_SUSPEND_SECONDS: u64 = 6 * 24 * 3600;
 
-/// Tap event tx
-type EventTx = tokio::sync::watch::Sender<String>;
+/// Awake delay of restoring the alarm of config after suspend
+const SUSPEND_RESTORE_DELAY: Duration = Duration::from_secs(60);
 
-/// Tap event rx
-type EventRx = tokio::sync::watch::Receiver<String>;
+/// Max seconds of `wait_for <events> [timeout]`, also the timeout if not given
+const MAX_WAIT_SECONDS: u64 = 24 * 3600;
+
+/// Min interval of hardware reads of `refresh`, a fresher status is returned as is
+const REFRESH_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Retry interval of opening i2c in degraded mode
+const HARDWARE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Timeout of the time host request of `rtc_web`
+#[cfg(feature = "http")]
+const WEB_TIME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Broadcast event, the id is generated once on emit and carried to every sink, static payloads
+/// are shared without allocation
+#[derive(Debug, Clone)]
+struct Event {
+    id: Arc<str>,
+    data: Bytes,
+}
+
+impl Event {
+    fn new(data: Bytes) -> Self {
+        Self {
+            id: new_event_id().into(),
+            data,
+        }
+    }
+}
+
+/// Event tx
+type EventTx = broadcast::Sender<Event>;
+
+/// Event rx, subscribed from tx
+type EventRx = broadcast::Receiver<Event>;
+
+/// Events of a receiver, lagged events are dropped with a warning
+fn event_stream(event_rx: EventRx) -> impl Stream<Item = Bytes> {
+    event_stream_ids(event_rx).map(|e| e.data)
+}
+
+/// Events of a receiver with ids, lagged events are dropped with a warning
+fn event_stream_ids(event_rx: EventRx) -> impl Stream<Item = Event> {
+    event_rx.filter_map(|e| {
+        future::ready(match e {
+            Ok(e) => Some(e),
+            Err(RecvError::Lagged(n)) => {
+                log::warn!("Event receiver lagged, {} events dropped", n);
+                None
+            }
+            Err(RecvError::Closed) => None,
+        })
+    })
+}
+
+/// Periodic telemetry of the data channel, `telemetry <json>`
+const TELEMETRY: &str = "telemetry";
+
+/// Interval of telemetry
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event channels of a connection, `data` is periodic telemetry, `alert` is taps, thresholds and errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventChannels {
+    Alert,
+    Data,
+    All,
+}
+
+impl EventChannels {
+    /// Parse `alert`, `data` or `all`
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "alert" => Some(EventChannels::Alert),
+            "data" => Some(EventChannels::Data),
+            "all" => Some(EventChannels::All),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventChannels::Alert => "alert",
+            EventChannels::Data => "data",
+            EventChannels::All => "all",
+        }
+    }
+
+    fn alert(&self) -> bool {
+        *self != EventChannels::Data
+    }
+
+    fn data(&self) -> bool {
+        *self != EventChannels::Alert
+    }
+}
+
+/// Stream mode of a connection, `control` has no events, `events` has no responses
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StreamMode {
+    Mixed,
+    Control,
+    Events,
+}
+
+impl StreamMode {
+    /// Parse `mixed`, `control` or `events`
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "mixed" => Some(StreamMode::Mixed),
+            "control" => Some(StreamMode::Control),
+            "events" => Some(StreamMode::Events),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            StreamMode::Mixed => "mixed",
+            StreamMode::Control => "control",
+            StreamMode::Events => "events",
+        }
+    }
+}
+
+/// Alert events and telemetry of subscribed channels, channels may change while streaming,
+/// and crossings of threshold watches, none in `control` mode
+fn subscribed_stream(
+    core: Arc<Mutex<PiSugarCore>>,
+    event_rx: EventRx,
+    channels: Arc<Mutex<EventChannels>>,
+    watches: Arc<Mutex<Vec<Watch>>>,
+    mode: Arc<Mutex<StreamMode>>,
+) -> impl Stream<Item = Bytes> {
+    let channels_cloned = channels.clone();
+    let subscribed = move |alert: bool| {
+        channels_cloned
+            .lock()
+            .map(|c| if alert { c.alert() } else { c.data() })
+            .unwrap_or(false)
+    };
+    let subscribed_cloned = subscribed.clone();
+    let alerts = event_stream(event_rx).filter(move |_| future::ready(subscribed(true)));
+    let core_cloned = core.clone();
+    let telemetry = tokio::time::interval(TELEMETRY_INTERVAL).filter_map(move |_| {
+        let telemetry = if subscribed_cloned(false) {
+            snapshot(&core_cloned).map(|snapshot| {
+                let json = serde_json::to_string(&snapshot).unwrap_or_default();
+                Bytes::from(format!("{} {}", TELEMETRY, json))
+            })
+        } else {
+            None
+        };
+        future::ready(telemetry)
+    });
+    // crossed watches are removed, one notification each
+    let crossed = tokio::time::interval(TELEMETRY_INTERVAL)
+        .map(move |_| {
+            let mut crossed = Vec::new();
+            if let (Ok(core), Ok(mut watches)) = (core.lock(), watches.lock()) {
+                let mut i = 0;
+                while i < watches.len() {
+                    match watches[i].check(&core) {
+                        Some(event) => {
+                            crossed.push(Bytes::from(event));
+                            watches.remove(i);
+                        }
+                        None => i += 1,
+                    }
+                }
+            }
+            stream::iter(crossed)
+        })
+        .flatten();
+    stream::select(stream::select(alerts, telemetry), crossed).filter(move |_| {
+        let control = mode.lock().map(|m| *m == StreamMode::Control);
+        future::ready(control != Ok(true))
+    })
+}
 
 /// Poll pisugar status
 fn poll_pisugar_status(core: &mut PiSugarCore, tx: &EventTx) {
@@ -43,246 +340,799 @@ fn poll_pisugar_status(core: &mut PiSugarCore, tx: &EventTx) {
     let config = &mut core.config;
 
     if let Ok(Some(tap_type)) = status.poll(config, now) {
-        let _ = tx.broadcast(format!("{}", tap_type));
+        let _ = tx.send(Event::new(Bytes::from_static(tap_type.as_str().as_bytes())));
+    }
+
+    while let Some(event) = status.take_event() {
+        let _ = tx.send(Event::new(Bytes::from(event.to_string())));
+    }
+}
+
+/// Read rtc and battery out of the poll interval, `refresh`, at most every `REFRESH_MIN_INTERVAL`
+fn refresh_pisugar_status(core: &mut PiSugarCore, tx: &EventTx) {
+    let now = Instant::now();
+    let status = &mut core.status;
+    if now.saturating_duration_since(status.updated_at()) < REFRESH_MIN_INTERVAL {
+        log::debug!("Refresh skipped, state is fresh");
+        return;
+    }
+    log::debug!("Refreshing state");
+    status.refresh(&core.config, now);
+
+    while let Some(event) = status.take_event() {
+        let _ = tx.send(Event::new(Bytes::from(event.to_string())));
+    }
+}
+
+/// Json of snapshot, `<field>_human` durations added if humanized, e.g. `"shutdown_eta_human": "2h 15m"`
+fn snapshot_json(snapshot: &PiSugarSnapshot, humanize: bool) -> String {
+    let mut json = match serde_json::to_value(snapshot) {
+        Ok(json) => json,
+        Err(_) => return String::new(),
+    };
+    if humanize {
+        if let Some(eta) = snapshot.shutdown_eta {
+            json["shutdown_eta_human"] = serde_json::Value::String(humanize_secs(eta));
+        }
+    }
+    json.to_string()
+}
+
+/// Snapshot of status, host metrics are read after the core lock is released, so slow
+/// clients do not delay polling and tap detection
+fn snapshot(core: &Mutex<PiSugarCore>) -> Option<PiSugarSnapshot> {
+    let (snapshot, throttled, system) = {
+        let core = core.lock().ok()?;
+        let config = core.config();
+        (
+            core.snapshot_status(),
+            config.throttled_enable,
+            config.system_metrics_enable,
+        )
+    };
+    Some(snapshot.with_host_metrics(throttled, system))
+}
+
+/// Time of the time host, of the `Date` header, the job fails after a timeout
+#[cfg(feature = "http")]
+async fn web_time() -> Result<DateTime<FixedOffset>, String> {
+    let resp = tokio::time::timeout(
+        WEB_TIME_TIMEOUT,
+        Client::new().get(TIME_HOST.parse().unwrap()),
+    )
+    .await
+    .map_err(|_| "Time host timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+    let date = resp
+        .headers()
+        .get("Date")
+        .ok_or_else(|| "No date header".to_string())?;
+    let date = date.to_str().map_err(|e| e.to_string())?;
+    DateTime::parse_from_rfc2822(date).map_err(|e| e.to_string())
+}
+
+/// Sync web time to rtc & pi in a job, `job <id> <state> rtc_web` events
+#[cfg(feature = "http")]
+fn start_rtc_web(
+    core: &mut PiSugarCore,
+    core_arc: Arc<Mutex<PiSugarCore>>,
+    tx: Arc<EventTx>,
+) -> u64 {
+    let job = JobHandle::start(core, core_arc.clone(), tx, "rtc_web");
+    let id = job.id;
+    tokio::spawn(async move {
+        let dt = match web_time().await {
+            Ok(dt) => dt,
+            Err(e) => return job.finish(Err(e)),
+        };
+        job.progress(50);
+        if job.cancelled() {
+            return;
+        }
+        let (hwclock, result) = match core_arc.lock() {
+            Ok(core) => (
+                core.sys_write_hwclock(),
+                core.write_time(dt.into())
+                    .map(|_| dt.to_rfc3339())
+                    .map_err(|e| e.to_string()),
+            ),
+            Err(_) => return job.finish(Err("Lock failed".to_string())),
+        };
+        // date and hwclock run without the core lock
+        let sys_write = tokio::task::spawn_blocking(move || sys_write_time(dt.into(), hwclock));
+        if let Err(e) = sys_write.await {
+            log::error!("Failed to write time to system: {}", e);
+        }
+        job.finish(result);
+    });
+    id
+}
+
+/// Output format of a request, `json <request>` in json once, of the session otherwise
+fn request_format<'a>(req: &'a str, session: &Session) -> (OutputFormat, &'a str) {
+    match req.strip_prefix("json ") {
+        Some(req) => (OutputFormat::Json, req),
+        None => (session.format(), req),
+    }
+}
+
+/// Event in output format of the connection
+fn render_event(format: &Mutex<OutputFormat>, event: Bytes) -> Bytes {
+    match format.lock().map(|f| *f) {
+        Ok(OutputFormat::Json) => {
+            Bytes::from(OutputFormat::Json.render_event(&String::from_utf8_lossy(&event)))
+        }
+        _ => event,
+    }
+}
+
+/// Handle request, rendered in output format of the session, request id of `#<id>` echoed
+fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str, session: &mut Session) -> String {
+    let (id, req) = split_request_id(req);
+    let (format, req) = request_format(req, session);
+    let resp = execute_request(core, req, session);
+    format.render_id(id, &resp)
+}
+
+/// Wait for one of comma-separated events of `wait_for <events> [timeout]`, the connection is
+/// blocked until then or at most `MAX_WAIT_SECONDS`, none if not a wait request
+async fn wait_for_event(core: &Mutex<PiSugarCore>, req: &str, session: &Session) -> Option<String> {
+    let request = Request::parse(req)?;
+    let cmd = request.cmd();
+    if cmd != "wait_for" {
+        return None;
+    }
+    let err = "Invalid request.\n".to_string();
+
+    let authorized = core
+        .lock()
+        .map(|mut core| {
+            core.listener_stats_mut(session.listener).requests += 1;
+            core.listener_allows(session.listener, cmd) && core.authorize(session.role, "get")
+        })
+        .unwrap_or(false);
+    let names: Vec<String> = match request.arg(0) {
+        Some(names) if authorized => names.split(',').map(String::from).collect(),
+        _ => return Some(err),
+    };
+    let timeout = match request.arg(1).map(|s| s.parse::<u64>()) {
+        Some(Ok(secs)) if secs > 0 && secs <= MAX_WAIT_SECONDS => Duration::from_secs(secs),
+        Some(_) => return Some(err),
+        None => Duration::from_secs(MAX_WAIT_SECONDS),
+    };
+
+    let event_rx = session.event_tx.subscribe();
+    let mut events = event_stream(event_rx)
+        .filter(move |e| {
+            let e = String::from_utf8_lossy(e);
+            future::ready(names.iter().any(|n| n == event_name(&e)))
+        })
+        .boxed();
+    let event = tokio::time::timeout(timeout, events.next())
+        .await
+        .ok()
+        .flatten();
+    Some(match event {
+        Some(e) => format!("{}: {}\n", cmd, String::from_utf8_lossy(&e)),
+        None => format!("{}: timeout\n", cmd),
+    })
+}
+
+/// Diagnostics bundle of `diagnostics bundle <path>`, none if not a diagnostics request
+async fn diagnostics_bundle(
+    core: &Mutex<PiSugarCore>,
+    req: &str,
+    session: &Session,
+) -> Option<String> {
+    let request = Request::parse(req)?;
+    let cmd = request.cmd();
+    if cmd != "diagnostics" {
+        return None;
+    }
+    let err = "Invalid request.\n".to_string();
+
+    let path = match (request.arg(0), request.arg(1)) {
+        (Some("bundle"), Some(path)) => PathBuf::from(path),
+        _ => return Some(err),
+    };
+    let diagnostics = match core.lock() {
+        Ok(mut core) => {
+            core.listener_stats_mut(session.listener).requests += 1;
+            if !core.listener_allows(session.listener, cmd) || !core.authorize(session.role, cmd) {
+                log::warn!(
+                    "Unauthorized {:?} on {}, rejected: {}",
+                    session.role,
+                    session.listener.as_str(),
+                    req
+                );
+                core.listener_stats_mut(session.listener).rejected += 1;
+                return Some(err);
+            }
+            Diagnostics::collect(&core)
+        }
+        Err(_) => return Some(err),
+    };
+    match diagnostics.write_bundle(&path).await {
+        Ok(_) => Some(format!("{}: {}\n", cmd, path.display())),
+        Err(e) => {
+            log::error!("Diagnostics bundle failed: {}", e);
+            Some(err)
+        }
     }
 }
 
-/// Handle request
-fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
-    let parts: Vec<String> = req.split(" ").map(|s| s.to_string()).collect();
+/// Requests awaiting events or io, handled before `handle_request`, none if not one of them
+async fn handle_async_request(
+    core: &Mutex<PiSugarCore>,
+    req: &str,
+    session: &Session,
+) -> Option<String> {
+    let (id, req) = split_request_id(req);
+    let (format, req) = request_format(req, session);
+    let resp = match wait_for_event(core, req, session).await {
+        Some(resp) => resp,
+        None => diagnostics_bundle(core, req, session).await?,
+    };
+    Some(format.render_id(id, &resp))
+}
+
+/// Run request until done, or none if the connection is closed meanwhile, e.g. a `wait_for` of a
+/// gone client. Pipelined requests read meanwhile are queued
+async fn unless_closed<S, F>(
+    stream: &mut S,
+    queued: &mut VecDeque<S::Item>,
+    request: F,
+) -> Option<F::Output>
+where
+    S: Stream + Unpin,
+    F: Future,
+{
+    futures::pin_mut!(request);
+    loop {
+        match future::select(request, stream.next()).await {
+            future::Either::Left((resp, _)) => return Some(resp),
+            future::Either::Right((Some(item), pending)) => {
+                queued.push_back(item);
+                request = pending;
+            }
+            future::Either::Right((None, _)) => return None,
+        }
+    }
+}
+
+/// Execute request, role of the session is set by `auth <token>` or `session resume <id>`
+fn execute_request(core: Arc<Mutex<PiSugarCore>>, req: &str, session: &mut Session) -> String {
     let err = "Invalid request.\n".to_string();
 
-    log::debug!("Request: {}", req);
+    log::debug!("Request on {}: {}", session.listener.as_str(), req);
+
+    let request = match Request::parse(req) {
+        Some(request) => request,
+        None => {
+            log::warn!("Malformed request, {} bytes", req.len());
+            return err;
+        }
+    };
+    let cmd = request.cmd();
 
     let core_cloned = core.clone();
     if let Ok(mut core) = core.lock() {
-        if parts.len() > 0 {
-            match parts[0].as_str() {
-                "get" => {
-                    if parts.len() > 1 {
-                        let resp = match parts[1].as_str() {
-                            "model" => core.model().to_string(),
-                            "battery" => core.level().to_string(),
-                            "battery_v" => core.voltage().to_string(),
-                            "battery_i" => core.intensity().to_string(),
-                            "battery_charging" => core.charging().to_string(),
-                            "rtc_time" => format!("{:?}", core.read_time()),
-                            "rtc_time_list" => format!("{}", core.read_raw_time()),
-                            "rtc_alarm_flag" => match core.read_alarm_flag() {
-                                Ok(flag) => format!("{}", flag),
-                                Err(e) => {
-                                    log::error!("{}", e);
-                                    return err;
-                                }
-                            },
-                            "rtc_alarm_time" => match core.read_alarm_time() {
-                                Ok(time) => {
-                                    if let Ok(datetime) = time.try_into() {
-                                        let datetime: DateTime<Local> = datetime;
-                                        format!("{:?}", datetime)
-                                    } else {
-                                        return err;
-                                    }
-                                }
-                                Err(e) => {
-                                    log::error!("{}", e);
-                                    return err;
-                                }
-                            },
-                            "rtc_alarm_time_list" => match core.read_alarm_time() {
-                                Ok(time) => time.to_string(),
-                                Err(e) => {
-                                    log::error!("{}", e);
-                                    return err;
-                                }
-                            },
-                            "rtc_alarm_enabled" => match core.read_alarm_enabled() {
-                                Ok(enabled) => format!("{}", enabled),
-                                Err(e) => {
-                                    log::error!("{}", e);
-                                    return err;
-                                }
-                            },
-                            "alarm_repeat" => format!("{}", core.config().auto_wake_repeat),
-                            "safe_shutdown_level" => {
-                                format!("{}", core.config().auto_shutdown_level)
-                            }
-                            "button_enable" => {
-                                if parts.len() > 2 {
-                                    let enable = match parts[2].as_str() {
-                                        "single" => core.config().single_tap_enable,
-                                        "double" => core.config().double_tap_enable,
-                                        "long" => core.config().long_tap_enable,
-                                        _ => {
-                                            log::error!(
-                                                "{} {}: unknown tap type",
-                                                parts[0],
-                                                parts[1]
-                                            );
-                                            return err;
-                                        }
-                                    };
-                                    format!("{} {}", parts[2], enable)
-                                } else {
-                                    return err;
-                                }
-                            }
-                            "button_shell" => {
-                                if parts.len() > 2 {
-                                    let shell = match parts[2].as_str() {
-                                        "single" => core.config().single_tap_shell.as_str(),
-                                        "double" => core.config().double_tap_shell.as_str(),
-                                        "long" => core.config().long_tap_shell.as_str(),
-                                        _ => {
-                                            log::error!(
-                                                "{} {}: unknown tap type",
-                                                parts[0],
-                                                parts[1]
-                                            );
-                                            return err;
-                                        }
-                                    };
-                                    format!("{} {}", parts[2], shell)
-                                } else {
-                                    return err;
-                                }
-                            }
-                            _ => return err,
-                        };
+        if !session.counted {
+            core.listener_stats_mut(session.listener).requests += 1;
+        }
 
-                        return format!("{}: {}\n", parts[1], resp);
-                    };
+        // commands served by the listener, `listener_commands`
+        if !core.listener_allows(session.listener, cmd) {
+            log::warn!(
+                "Not served on {}, rejected: {}",
+                session.listener.as_str(),
+                req
+            );
+            core.listener_stats_mut(session.listener).rejected += 1;
+            return err;
+        }
+
+        // auth <token>
+        if cmd == "auth" {
+            if !session.auth_allowed() {
+                log::warn!("Auth from {:?} blocked", session.peer);
+                return err;
+            }
+            let tokens = &core.config().auth_tokens;
+            match request.arg(0).and_then(|t| token_role(tokens, t)) {
+                Some(r) => {
+                    session.auth_attempted(true);
+                    session.role = Some(r);
+                    session.token = request.arg(0).map(token_fingerprint);
+                    return format!("{}: {}\n", cmd, r.as_str());
+                }
+                None => {
+                    log::warn!("Invalid token from {:?}", session.peer);
+                    session.auth_attempted(false);
+                    return err;
                 }
-                "rtc_clear_flag" => {
-                    return match core.clear_alarm_flag() {
-                        Ok(_) => format!("{}: done\n", parts[0]),
+            }
+        }
+
+        // session new|resume <id>|end, role and output format persisted across reconnects
+        if cmd == "session" {
+            return match (request.arg(0), request.arg(1)) {
+                (Some("new"), None) if core.authorize(session.role, "get") => {
+                    let ttl = core.config().session_ttl;
+                    match core.sessions_mut().create(
+                        session.role,
+                        session.token.clone(),
+                        session.format(),
+                        session.humanize,
+                        ttl,
+                    ) {
+                        Ok(id) => {
+                            session.id = Some(id.clone());
+                            format!("{}: {}\n", cmd, id)
+                        }
                         Err(e) => {
-                            log::error!("{}", e);
+                            log::error!("Failed to create session: {}", e);
                             err
                         }
-                    };
+                    }
                 }
-                "rtc_pi2rtc" => {
-                    let now = Local::now();
-                    return match core.write_time(now) {
-                        Ok(_) => format!("{}: done\n", parts[0]),
-                        Err(e) => {
-                            log::error!("{}", e);
+                (Some("resume"), Some(id)) => {
+                    if !session.auth_allowed() {
+                        log::warn!("Session resume from {:?} blocked", session.peer);
+                        return err;
+                    }
+                    match resume_session(&core, id) {
+                        Some(state) => {
+                            session.auth_attempted(true);
+                            session.resume(id, &state);
+                            format!("{}: resumed\n", cmd)
+                        }
+                        None => {
+                            log::warn!("Invalid session from {:?}", session.peer);
+                            session.auth_attempted(false);
                             err
                         }
-                    };
+                    }
                 }
-                "rtc_rtc2pi" => {
-                    let t = core.read_time();
-                    sys_write_time(t);
-                    return format!("{}: done\n", parts[0]);
-                }
-                "rtc_web" => {
-                    tokio::spawn(async move {
-                        if let Ok(resp) = Client::new().get(TIME_HOST.parse().unwrap()).await {
-                            if let Some(date) = resp.headers().get("Date") {
-                                if let Ok(s) = date.to_str() {
-                                    if let Ok(dt) = DateTime::parse_from_rfc2822(s) {
-                                        if let Ok(core) = core_cloned.lock() {
-                                            sys_write_time(dt.into());
-                                            let _ = core.write_time(dt.into());
-                                        }
-                                    }
-                                }
-                            }
+                (Some("end"), None) => {
+                    if let Some(id) = session.id.take() {
+                        if let Err(e) = core.sessions_mut().remove(&id) {
+                            log::error!("Failed to remove session: {}", e);
                         }
-                    });
-                    return format!("{}: done\n", parts[0]);
-                }
-                "rtc_alarm_set" => {
-                    // rtc_alarm_set <iso8601 ignore ymd> weekday_repeat
-                    if parts.len() >= 3 {
-                        if let Ok(datetime) = parts[1].parse::<DateTime<FixedOffset>>() {
-                            let datetime: DateTime<Local> = datetime.into();
-                            let sd3078_time: SD3078Time = datetime.into();
-                            if let Ok(weekday_repeat) = parts[2].parse::<u8>() {
-                                match core.set_alarm(sd3078_time, weekday_repeat) {
-                                    Ok(_) => {
-                                        core.config_mut().auto_wake_repeat = weekday_repeat;
-                                        core.config_mut().auto_wake_time = Some(datetime);
-                                        if let Err(e) = core.save_config() {
-                                            log::warn!("{}", e);
-                                        }
-                                        return format!("{}: done\n", parts[0]);
-                                    }
-                                    Err(e) => log::error!("{}", e),
-                                }
-                            }
+                    }
+                    format!("{}: done\n", cmd)
+                }
+                _ => err,
+            };
+        }
+
+        // format text|json, output format of the session
+        if cmd == "format" {
+            return match request.arg(0).and_then(OutputFormat::parse) {
+                Some(format) => {
+                    session.set_format(format);
+                    if let Some(id) = &session.id {
+                        if let Err(e) = core.sessions_mut().set_format(id, format) {
+                            log::error!("Failed to save session: {}", e);
                         }
                     }
-                    return err;
+                    format!("{}: {}\n", cmd, request.arg(0).unwrap_or_default())
                 }
-                "rtc_alarm_disable" => {
-                    return match core.disable_alarm() {
-                        Ok(_) => format!("{}: done\n", parts[0]),
-                        Err(_) => err,
-                    };
+                None => err,
+            };
+        }
+
+        // humanize on|off, humanized durations in json responses of the session
+        if cmd == "humanize" {
+            let humanize = match request.arg(0) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return err,
+            };
+            session.humanize = humanize;
+            if let Some(id) = &session.id {
+                if let Err(e) = core.sessions_mut().set_humanize(id, humanize) {
+                    log::error!("Failed to save session: {}", e);
                 }
-                "set_safe_shutdown_level" => {
-                    if parts.len() >= 1 {
-                        if let Ok(level) = parts[1].parse::<f64>() {
-                            core.config_mut().auto_shutdown_level = level;
-                            if let Err(e) = core.save_config() {
-                                log::error!("{}", e);
-                            }
-                            return format!("{}: done\n", parts[0]);
-                        }
+            }
+            return format!("{}: {}\n", cmd, request.arg(0).unwrap_or_default());
+        }
+
+        // mode mixed|control|events, responses and events on separate connections, events is final
+        if cmd == "mode" {
+            return match (
+                request.arg(0).and_then(StreamMode::parse),
+                session.mode.lock(),
+            ) {
+                (Some(mode), Ok(mut m)) => {
+                    *m = mode;
+                    format!("{}: {}\n", cmd, mode.as_str())
+                }
+                _ => err,
+            };
+        }
+
+        // subscribe alert|data|all, telemetry is readable by viewer
+        if cmd == "subscribe" {
+            return match request.arg(0).and_then(EventChannels::parse) {
+                Some(channels) if !channels.data() || core.authorize(session.role, "get") => {
+                    if let Ok(mut c) = session.channels.lock() {
+                        *c = channels;
                     }
-                    return err;
+                    format!("{}: {}\n", cmd, channels.as_str())
+                }
+                _ => err,
+            };
+        }
+
+        // history query <field> <agg> <bucket> last <range>, readable by viewer
+        if cmd == "history" {
+            if !core.authorize(session.role, "get") {
+                return err;
+            }
+            return execute_history(&core, &request).unwrap_or(err);
+        }
+
+        // watch <field> <|> <threshold>|clear, one-shot threshold watches of the connection
+        if cmd == "watch" {
+            if !core.authorize(session.role, "get") {
+                return err;
+            }
+            let mut watches = match session.watches.lock() {
+                Ok(watches) => watches,
+                Err(_) => return err,
+            };
+            if request.args() == ["clear"] {
+                watches.clear();
+                return format!("{}: cleared\n", cmd);
+            }
+            return match Watch::parse(request.args()) {
+                Some(watch) if watches.len() < MAX_WATCHES => {
+                    watches.push(watch);
+                    format!("{}: {}\n", cmd, request.args().join(" "))
+                }
+                _ => err,
+            };
+        }
+
+        // refresh, a fresh snapshot without waiting for the next poll, host metrics are read
+        // after the core lock is released
+        if cmd == "refresh" {
+            if !core.authorize(session.role, "get") {
+                return err;
+            }
+            if core.hardware_error().is_some() {
+                return format!("{}: hardware unavailable\n", cmd);
+            }
+            refresh_pisugar_status(&mut core, &session.event_tx);
+            drop(core);
+            return match snapshot(&core_cloned) {
+                Some(snapshot) => {
+                    format!("{}: {}\n", cmd, snapshot_json(&snapshot, session.humanize))
+                }
+                None => err,
+            };
+        }
+
+        // shutdown_participant|shutdown_ack, automatic shutdown waits for acks of participants
+        if cmd == "shutdown_participant" || cmd == "shutdown_ack" {
+            if !core.authorize(session.role, "get") {
+                return err;
+            }
+            if cmd == "shutdown_participant" {
+                if session.participant.is_none() {
+                    let id = core.add_shutdown_participant();
+                    session.participant = Some(ShutdownParticipant::new(core_cloned.clone(), id));
+                }
+                return format!("{}: registered\n", cmd);
+            }
+            return match &session.participant {
+                Some(participant) if core.ack_shutdown(participant.id) => {
+                    format!("{}: done\n", cmd)
+                }
+                _ => err,
+            };
+        }
+
+        // authorization of role
+        if !core.authorize(session.role, cmd) {
+            log::warn!(
+                "Unauthorized {:?} on {}, rejected: {}",
+                session.role,
+                session.listener.as_str(),
+                req
+            );
+            core.listener_stats_mut(session.listener).rejected += 1;
+            return err;
+        }
+
+        // read-only, get only
+        if core.config().readonly && cmd != "get" {
+            log::warn!("Read-only, rejected: {}", req);
+            return err;
+        }
+
+        // signed config, immutable
+        if core.config_signed() && CONFIG_COMMANDS.contains(&cmd) {
+            log::warn!("Config is signed, rejected: {}", req);
+            return err;
+        }
+
+        // debug emit <event>, synthetic events for testing clients
+        if cmd == "debug" {
+            return match (request.arg(0), request.arg(1).and_then(debug_event)) {
+                (Some("emit"), Some(event)) if core.config().debug_enable => {
+                    log::info!("Debug emit: {}", event);
+                    let _ = session
+                        .event_tx
+                        .send(Event::new(Bytes::from_static(event.as_bytes())));
+                    format!("{}: emit {}\n", cmd, event)
+                }
+                _ => {
+                    log::warn!("Debug disabled or unknown event, rejected: {}", req);
+                    err
                 }
-                "rtc_test_wake" => {
-                    return match core.test_wake() {
-                        Ok(_) => format!("{}: wakeup after 1 min 30 sec\n", parts[0]),
+            };
+        }
+
+        // get version, build info, also in degraded mode
+        if cmd == "get" && request.arg(0) == Some("version") {
+            let info = serde_json::to_string(&version::build_info()).unwrap_or_default();
+            return format!("version: {}\n", info);
+        }
+
+        // degraded mode, i2c unavailable
+        if let Some(e) = core.hardware_error() {
+            log::warn!("Hardware unavailable ({}), rejected: {}", e, req);
+            return format!("{}: hardware unavailable\n", cmd);
+        }
+
+        // get all, host metrics are read after the core lock is released
+        if cmd == "get" && request.arg(0) == Some("all") {
+            drop(core);
+            return match snapshot(&core_cloned) {
+                Some(snapshot) => format!("all: {}\n", snapshot_json(&snapshot, session.humanize)),
+                None => err,
+            };
+        }
+
+        match cmd {
+            "get" => {
+                if request.arg(0).is_some() {
+                    return execute_get(&core, &request).unwrap_or(err);
+                }
+            }
+            "calibrate" => {
+                if request.arg(0) == Some("current_zero") {
+                    return match core.calibrate_current_zero() {
+                        Ok(offset) => format!("{}: current_zero {}\n", cmd, offset),
                         Err(e) => {
                             log::error!("{}", e);
                             err
                         }
                     };
                 }
-                "set_button_enable" => {
-                    if parts.len() > 2 {
-                        let enable = parts[2].as_str().ne("0");
-                        match parts[1].as_str() {
-                            "single" => core.config_mut().single_tap_enable = enable,
-                            "double" => core.config_mut().double_tap_enable = enable,
-                            "long" => core.config_mut().long_tap_enable = enable,
-                            _ => {
-                                return err;
-                            }
-                        }
-                        if let Err(e) = core.save_config() {
-                            log::error!("{}", e);
-                        }
-                        return format!("{}: done\n", parts[0]);
+                return err;
+            }
+            "self_test" => {
+                let mut report = core.self_test();
+                // event dispatch, a `self_test` event to all clients
+                let mut event_rx = session.event_tx.subscribe();
+                let _ = session
+                    .event_tx
+                    .send(Event::new(Bytes::from_static(SELF_TEST.as_bytes())));
+                let dispatched = std::iter::from_fn(|| event_rx.try_recv().ok())
+                    .any(|e| &e.data[..] == SELF_TEST.as_bytes());
+                let dispatched = if dispatched {
+                    Ok("ok")
+                } else {
+                    Err(pisugar_core::Error::Other("Event not received".to_string()))
+                };
+                report.check("event_dispatch", dispatched);
+                if !report.passed {
+                    log::warn!("Self test failed");
+                }
+                return format!(
+                    "{}: {}\n",
+                    cmd,
+                    serde_json::to_string(&report).unwrap_or_default()
+                );
+            }
+            "rtc_clear_flag" => {
+                return match core.clear_alarm_flag() {
+                    Ok(_) => format!("{}: done\n", cmd),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        err
+                    }
+                };
+            }
+            "rtc_pi2rtc" => {
+                let now = Local::now();
+                return match core.write_time(now) {
+                    Ok(_) => format!("{}: done\n", cmd),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        err
                     }
+                };
+            }
+            "rtc_rtc2pi" => {
+                if !core.rtc_enabled() {
+                    log::error!("RTC disabled");
                     return err;
                 }
-                "set_button_shell" => {
-                    if parts.len() > 2 {
-                        let cmd = parts[2..].join(" ");
-                        match parts[1].as_str() {
-                            "single" => core.config_mut().single_tap_shell = cmd,
-                            "double" => core.config_mut().double_tap_shell = cmd,
-                            "long" => core.config_mut().long_tap_shell = cmd,
-                            _ => {
-                                return err;
+                let t = core.read_time();
+                let hwclock = core.sys_write_hwclock();
+                drop(core);
+                sys_write_time(t, hwclock);
+                return format!("{}: done\n", cmd);
+            }
+            "set_sys_time" => {
+                // set_sys_time <iso8601>
+                if let Some(s) = request.arg(0) {
+                    if let Ok(datetime) = s.parse::<DateTime<FixedOffset>>() {
+                        let datetime: DateTime<Local> = datetime.into();
+                        let written = core.write_time(datetime);
+                        let hwclock = core.sys_write_hwclock();
+                        drop(core);
+                        sys_write_time(datetime, hwclock);
+                        return match written {
+                            Ok(_) => format!("{}: done\n", cmd),
+                            Err(e) => {
+                                log::error!("{}", e);
+                                err
+                            }
+                        };
+                    }
+                }
+                return err;
+            }
+            #[cfg(feature = "http")]
+            "rtc_web" => {
+                let id = start_rtc_web(&mut core, core_cloned, session.event_tx.clone());
+                return format!("{}: {}\n", cmd, id);
+            }
+            "job" => {
+                // job cancel <id>
+                if request.arg(0) == Some("cancel") {
+                    let id = request.arg(1).and_then(|id| id.parse().ok());
+                    if let Some(event) = id.and_then(|id| cancel_job(&mut core, id)) {
+                        let _ = session.event_tx.send(Event::new(event));
+                        return format!("{}: cancelled\n", cmd);
+                    }
+                }
+                return err;
+            }
+            "rtc_alarm_set" => {
+                // rtc_alarm_set <iso8601 ignore ymd> weekday_repeat
+                if let (Some(s), Some(repeat)) = (request.arg(0), request.arg(1)) {
+                    if let Ok(datetime) = s.parse::<DateTime<FixedOffset>>() {
+                        let datetime: DateTime<Local> = datetime.into();
+                        let sd3078_time: SD3078Time = datetime.into();
+                        if let Ok(weekday_repeat) = repeat.parse::<u8>() {
+                            match core.set_alarm(sd3078_time, weekday_repeat) {
+                                Ok(_) => {
+                                    core.config_mut().auto_wake_repeat = weekday_repeat;
+                                    core.config_mut().auto_wake_time = Some(datetime);
+                                    core.save_config_later();
+                                    return format!("{}: done\n", cmd);
+                                }
+                                Err(e) => log::error!("{}", e),
                             }
                         }
-                        if let Err(e) = core.save_config() {
-                            log::error!("{}", e);
+                    }
+                }
+                return err;
+            }
+            "rtc_alarm_disable" => {
+                return match core.disable_alarm() {
+                    Ok(_) => format!("{}: done\n", cmd),
+                    Err(_) => err,
+                };
+            }
+            "set_safe_shutdown_level" => {
+                if let Some(s) = request.arg(0) {
+                    if let Ok(level) = s.parse::<f64>() {
+                        core.config_mut().auto_shutdown_level = level;
+                        core.save_config_later();
+                        return format!("{}: done\n", cmd);
+                    }
+                }
+                return err;
+            }
+            "rtc_test_wake" => {
+                return match core.test_wake() {
+                    Ok(_) => format!("{}: wakeup after 1 min 30 sec\n", cmd),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        err
+                    }
+                };
+            }
+            "set_power_on_mode" => {
+                // power-on of PiSugar 2 boards is fixed in hardware
+                return match request.arg(0) {
+                    Some(mode) if mode == core.power_on_mode() => format!("{}: done\n", cmd),
+                    Some(mode) => {
+                        log::error!("{}: {} not supported by {}", cmd, mode, core.model());
+                        err
+                    }
+                    None => err,
+                };
+            }
+            "suspend_for" => {
+                // suspend_for <seconds> [halt|suspend], halt by default
+                let seconds = request
+                    .arg(0)
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .filter(|s| *s >= MIN_SUSPEND_SECONDS && *s <= MAX_SUSPEND_SECONDS);
+                let halt = match request.arg(1) {
+                    None | Some("halt") => true,
+                    Some("suspend") => false,
+                    _ => return err,
+                };
+                let seconds = match seconds {
+                    Some(seconds) => seconds,
+                    None => return err,
+                };
+                return match core.suspend_for(seconds, halt) {
+                    Ok(wakeup) => {
+                        if !halt {
+                            // monotonic timer is paused in suspend, alarm of config is armed
+                            // again after resume
+                            let core = core_cloned.clone();
+                            tokio::spawn(async move {
+                                tokio::time::delay_for(SUSPEND_RESTORE_DELAY).await;
+                                if let Ok(core) = core.lock() {
+                                    if let Err(e) = core.restore_alarm() {
+                                        log::warn!("Restore alarm failed: {}", e);
+                                    }
+                                }
+                            });
                         }
-                        return format!("{}: done\n", parts[0]);
+                        format!("{}: wakeup at {}\n", cmd, wakeup.to_rfc3339())
                     }
-                    return err;
+                    Err(e) => {
+                        log::error!("{}", e);
+                        err
+                    }
+                };
+            }
+            "set_button_enable" => {
+                if let (Some(tap), Some(s)) = (request.arg(0), request.arg(1)) {
+                    let enable = s.ne("0");
+                    match tap {
+                        "single" => core.config_mut().single_tap_enable = enable,
+                        "double" => core.config_mut().double_tap_enable = enable,
+                        "long" => core.config_mut().long_tap_enable = enable,
+                        _ => {
+                            return err;
+                        }
+                    }
+                    core.save_config_later();
+                    return format!("{}: done\n", cmd);
                 }
-                _ => return err,
+                return err;
             }
-        };
+            "set_button_shell" => {
+                if let (Some(tap), Some(shell)) = (request.arg(0), request.rest(1)) {
+                    let shell = shell.to_string();
+                    match tap {
+                        "single" => core.config_mut().single_tap_shell = shell,
+                        "double" => core.config_mut().double_tap_shell = shell,
+                        "long" => core.config_mut().long_tap_shell = shell,
+                        _ => {
+                            return err;
+                        }
+                    }
+                    core.save_config_later();
+                    return format!("{}: done\n", cmd);
+                }
+                return err;
+            }
+            _ => return err,
+        }
     }
 
     err
@@ -291,11 +1141,20 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
 async fn _handle_stream<T>(
     core: Arc<Mutex<PiSugarCore>>,
     stream: T,
-    event_rx: EventRx,
+    mut session: Session,
 ) -> io::Result<()>
 where
     T: 'static + AsyncRead + AsyncWrite + Send,
 {
+    let event_rx = session.event_tx.subscribe();
+    let events = subscribed_stream(
+        core.clone(),
+        event_rx,
+        session.channels.clone(),
+        session.watches.clone(),
+        session.mode.clone(),
+    );
+    let format = session.format.clone();
     let framed = Framed::new(stream, BytesCodec::new());
     let (sink, mut stream) = framed.split();
     let (tx, rx) = unbounded();
@@ -303,7 +1162,16 @@ where
     // handle request
     let mut tx_cloned = tx.clone();
     tokio::spawn(async move {
-        while let Some(Ok(buf)) = stream.next().await {
+        let mut queued = VecDeque::new();
+        loop {
+            let buf = match queued.pop_front() {
+                Some(item) => Some(item),
+                None => stream.next().await,
+            };
+            let buf = match buf {
+                Some(Ok(buf)) => buf,
+                _ => break,
+            };
             let req = String::from_utf8_lossy(buf.as_ref())
                 .replace("\r", "")
                 .replace("\n", "");
@@ -311,69 +1179,214 @@ where
                 log::debug!("Request ended");
                 break;
             }
-            let resp = handle_request(core.clone(), req.as_str());
+            if session.events_only() {
+                log::debug!("Request ignored in events mode: {}", req);
+                continue;
+            }
+            let pending = handle_async_request(&core, &req, &session);
+            let resp = match unless_closed(&mut stream, &mut queued, pending).await {
+                Some(Some(resp)) => resp,
+                Some(None) => handle_request(core.clone(), req.as_str(), &mut session),
+                None => {
+                    log::debug!("Connection closed, request cancelled: {}", req);
+                    break;
+                }
+            };
             tx_cloned
-                .send(resp)
+                .send(Bytes::from(resp))
                 .await
                 .expect("Unexpected channel failed");
         }
     });
 
     // button event
-    tokio::spawn(event_rx.map(Ok).forward(tx));
+    tokio::spawn(
+        events
+            .map(move |e| Ok(render_event(&format, e)))
+            .forward(tx),
+    );
 
     // send back
-    tokio::spawn(rx.map(|s| Ok(Bytes::from(s))).forward(sink));
+    tokio::spawn(rx.map(Ok).forward(sink));
 
     Ok(())
 }
 
+/// Count a connection of listener
+fn connected(core: &Mutex<PiSugarCore>, listener: Listener) {
+    if let Ok(mut core) = core.lock() {
+        core.listener_stats_mut(listener).connections += 1;
+    }
+}
+
 /// Handle tcp stream
 async fn handle_tcp_stream(
     core: Arc<Mutex<PiSugarCore>>,
     stream: TcpStream,
-    event_rx: EventRx,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
 ) -> io::Result<()> {
-    log::info!("Incoming tcp connection from: {}", stream.peer_addr()?);
-    _handle_stream(core, stream, event_rx).await
+    let peer = stream.peer_addr()?;
+    log::info!("Incoming tcp connection from: {}", peer);
+    connected(&core, Listener::Tcp);
+    let session = Session::new(Listener::Tcp, None, Some(peer.ip()), guard, event_tx);
+    _handle_stream(core, stream, session).await
+}
+
+/// Stream grafana live data frames
+#[cfg(feature = "ws")]
+async fn stream_grafana(
+    core: Arc<Mutex<PiSugarCore>>,
+    mut tx: UnboundedSender<Message>,
+    period: Duration,
+    encoder: FrameEncoder,
+) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let frame = match snapshot(&core) {
+            Some(snapshot) => encoder.json(grafana::data_frame(&snapshot)),
+            None => break,
+        };
+        if tx.send(frame).await.is_err() {
+            log::debug!("Grafana stream ended");
+            break;
+        }
+    }
 }
 
 /// Handle websocket request
+#[cfg(feature = "ws")]
 async fn handle_ws_connection(
     core: Arc<Mutex<PiSugarCore>>,
     stream: TcpStream,
-    event_rx: EventRx,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
 ) -> io::Result<()> {
-    log::info!("Incoming ws connection from: {}", stream.peer_addr()?);
+    let peer = stream.peer_addr()?;
+    log::info!("Incoming ws connection from: {}", peer);
+    let event_rx = event_tx.subscribe();
+    connected(&core, Listener::Ws);
+    let mut session = Session::new(Listener::Ws, None, Some(peer.ip()), guard, event_tx);
+    let events = subscribed_stream(
+        core.clone(),
+        event_rx,
+        session.channels.clone(),
+        session.watches.clone(),
+        session.mode.clone(),
+    );
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
+    // session cookie of web ui, resumed without `auth`, and frame encoding
+    let mut cookie = None;
+    let mut encoder = FrameEncoder { msgpack: false };
+    let callback = |req: &WsRequest, mut resp: WsResponse| -> Result<WsResponse, ErrorResponse> {
+        let headers = req.headers();
+        cookie = headers
+            .get(COOKIE)
+            .and_then(|c| c.to_str().ok())
+            .and_then(session_cookie)
+            .map(String::from);
+        encoder = FrameEncoder::negotiate(
+            headers
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|p| p.to_str().ok()),
+        );
+        if encoder.msgpack {
+            resp.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(MSGPACK_PROTOCOL),
+            );
+        }
+        Ok(resp)
+    };
+    let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
         .await?;
-    log::info!("WS connection established");
+    log::info!("WS connection established, msgpack: {}", encoder.msgpack);
+
+    if let Some(id) = cookie {
+        let state = core.lock().ok().and_then(|core| resume_session(&core, &id));
+        match state {
+            Some(state) => session.resume(&id, &state),
+            None => log::debug!("WS session cookie expired"),
+        }
+    }
 
-    let (tx, rx) = unbounded::<String>();
+    let format = session.format.clone();
+    let (tx, rx) = unbounded::<Message>();
     let (sink, mut stream) = ws_stream.split();
 
     // handle request
     let mut tx_cloned = tx.clone();
     tokio::spawn(async move {
-        while let Some(Ok(msg)) = stream.next().await {
+        let mut queued = VecDeque::new();
+        loop {
+            let msg = match queued.pop_front() {
+                Some(item) => Some(item),
+                None => stream.next().await,
+            };
+            let msg = match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            };
             if let Ok(msg) = msg.to_text() {
                 let req = msg.replace("\n", "");
-                let resp = handle_request(core.clone(), req.as_str());
+                if session.events_only() {
+                    log::debug!("Request ignored in events mode: {}", req);
+                    continue;
+                }
+                // stream grafana [interval_ms], readable by viewer, if the listener serves `get`
+                let authorized = core
+                    .lock()
+                    .map(|core| {
+                        core.listener_allows(session.listener, "get")
+                            && core.authorize(session.role, "get")
+                    })
+                    .unwrap_or(false);
+                if req.starts_with("stream grafana") && authorized {
+                    let interval = req
+                        .split(' ')
+                        .nth(2)
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .filter(|ms| *ms > 0)
+                        .unwrap_or(1000);
+                    let period = Duration::from_millis(interval);
+                    let tx = tx_cloned.clone();
+                    tokio::spawn(stream_grafana(core.clone(), tx, period, encoder));
+                    continue;
+                }
+                let pending = handle_async_request(&core, &req, &session);
+                let resp = match unless_closed(&mut stream, &mut queued, pending).await {
+                    Some(Some(resp)) => resp,
+                    Some(None) => handle_request(core.clone(), req.as_str(), &mut session),
+                    None => {
+                        log::debug!("Connection closed, request cancelled: {}", req);
+                        break;
+                    }
+                };
                 tx_cloned
-                    .send(resp)
+                    .send(encoder.response(resp))
                     .await
                     .expect("Unexpected channel failed");
             }
         }
     });
 
-    // button event
-    tokio::spawn(event_rx.map(Ok).forward(tx));
+    // button event, MessagePack events are structured already
+    tokio::spawn(
+        events
+            .map(move |e| {
+                if encoder.msgpack {
+                    Ok(encoder.event(&e))
+                } else {
+                    Ok(encoder.event(&render_event(&format, e)))
+                }
+            })
+            .forward(tx),
+    );
 
     // send back
-    tokio::spawn(rx.map(|s| Ok(s.into())).forward(sink));
+    tokio::spawn(rx.map(Ok).forward(sink));
 
     Ok(())
 }
@@ -382,34 +1395,79 @@ async fn handle_ws_connection(
 async fn handle_uds_stream(
     core: Arc<Mutex<PiSugarCore>>,
     stream: UnixStream,
-    event_rx: EventRx,
+    event_tx: Arc<EventTx>,
+    guard: Arc<AuthGuard>,
 ) -> io::Result<()> {
     log::info!("Incoming uds stream: {:?}", stream.peer_addr()?);
-    _handle_stream(core, stream, event_rx).await
+    // local uds is trusted, guarded by file permission
+    connected(&core, Listener::Uds);
+    let session = Session::new(Listener::Uds, Some(Role::Admin), None, guard, event_tx);
+    _handle_stream(core, stream, session).await
 }
 
-/// Clean up before exit
-fn clean_up(uds: Option<String>, web_dir: Option<String>) {
-    if let Some(uds) = uds {
-        let p: &Path = Path::new(uds.as_str());
-        if p.exists() {
-            match remove_file(p) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::warn!("Failed to remove uds file: {}", e);
-                }
+/// Periodic `shutdown_eta <secs>` event while a safe shutdown is within `shutdown_warning`
+async fn shutdown_eta_events(core: Arc<Mutex<PiSugarCore>>, event_tx: Arc<EventTx>) {
+    let mut interval = tokio::time::interval(SHUTDOWN_ETA_INTERVAL);
+    loop {
+        interval.tick().await;
+        let eta = match core.lock() {
+            Ok(core) => {
+                let warning = core.config().shutdown_warning;
+                core.shutdown_eta().filter(|eta| *eta <= warning)
+            }
+            Err(_) => break,
+        };
+        if let Some(eta) = eta {
+            log::warn!("Safe shutdown in {} seconds", eta);
+            let _ = event_tx.send(Event::new(Bytes::from(format!("{} {}", SHUTDOWN_ETA, eta))));
+        }
+    }
+}
+
+/// `time_jump <secs>` event on steps of the wall clock, deadlines of wall time are recomputed
+async fn time_jump_events(core: Arc<Mutex<PiSugarCore>>, event_tx: Arc<EventTx>) {
+    let mut watch = ClockWatch::default();
+    let mut interval = tokio::time::interval(TIME_JUMP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(jump) = watch.check(boottime(), Local::now()) {
+            log::warn!("Time jumped {} seconds", jump);
+            match core.lock() {
+                Ok(mut core) => core.time_jumped(jump),
+                Err(_) => break,
+            }
+            let _ = event_tx.send(Event::new(Bytes::from(format!("{} {}", TIME_JUMP, jump))));
+        }
+    }
+}
+
+/// Save queued config changes in background, off the request path
+async fn save_config_task(core: Arc<Mutex<PiSugarCore>>) {
+    let mut interval = tokio::time::interval(CONFIG_SAVE_DELAY);
+    loop {
+        interval.tick().await;
+        let save = match core.lock() {
+            Ok(mut core) => core.take_config_save(Instant::now(), CONFIG_SAVE_DELAY),
+            Err(_) => None,
+        };
+        if let Some((path, content)) = save {
+            log::info!("Save config to {}", path);
+            if let Err(e) = tokio::fs::write(path, content).await {
+                log::error!("Failed to save config: {}", e);
             }
         }
     }
+}
 
-    if let Some(web_dir) = web_dir {
-        let p: &Path = Path::new(web_dir.as_str());
-        let p = p.join(WS_JSON);
+/// Clean up before exit
+fn clean_up(uds: Option<String>) {
+    if let Some(uds) = uds {
+        let p: &Path = Path::new(uds.as_str());
         if p.exists() {
             match remove_file(p) {
                 Ok(_) => {}
                 Err(e) => {
-                    log::warn!("Failed to remove ws json file: {}", e);
+                    log::warn!("Failed to remove uds file: {}", e);
                 }
             }
         }
@@ -418,22 +1476,6 @@ fn clean_up(uds: Option<String>, web_dir: Option<String>) {
     exit(0)
 }
 
-/// Serve web
-async fn serve_http(http_addr: SocketAddr, web_dir: String) {
-    let static_ = hyper_staticfile::Static::new(web_dir);
-
-    let make_service = make_service_fn(move |_| {
-        let static_ = static_.clone();
-        future::ok::<_, hyper::Error>(service_fn(move |req| static_.clone().serve(req)))
-    });
-
-    let server = Server::bind(&http_addr).serve(make_service);
-
-    if let Err(e) = server.await {
-        log::error!("Http web server error: {}", e);
-    }
-}
-
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
@@ -447,8 +1489,16 @@ async fn main() -> std::io::Result<()> {
                 .short("c")
                 .long("config")
                 .value_name("FILE")
+                .env("PISUGAR_CONFIG")
                 .help("Config file in json format, e.g. /etc/pisugar.json"),
         )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("DIR")
+                .env("PISUGAR_STATE_DIR")
+                .help("State directory, e.g. /var/lib/pisugar-server, default to config dir"),
+        )
         .arg(
             Arg::with_name("tcp")
                 .short("t")
@@ -456,11 +1506,54 @@ async fn main() -> std::io::Result<()> {
                 .value_name("ADDR")
                 .help("Tcp listen address, e.g. 0.0.0.0:8082"),
         )
+        .arg(
+            Arg::with_name("modbus")
+                .long("modbus")
+                .value_name("ADDR")
+                .help("Modbus TCP listen address, e.g. 0.0.0.0:502"),
+        )
+        .arg(
+            Arg::with_name("mqtt")
+                .long("mqtt")
+                .value_name("URL")
+                .help("Mqtt broker of battery status and events, e.g. mqtt://user@host:1883"),
+        )
+        .arg(
+            Arg::with_name("mqtt-password-file")
+                .long("mqtt-password-file")
+                .value_name("FILE")
+                .help("Mqtt password of the first line of a credentials file"),
+        )
+        .arg(
+            Arg::with_name("mqtt-topic")
+                .long("mqtt-topic")
+                .value_name("PREFIX")
+                .default_value("pisugar/")
+                .help("Mqtt topic prefix"),
+        )
+        .arg(
+            Arg::with_name("dbus")
+                .long("dbus")
+                .help("Serve org.pisugar.PowerManager on the system bus"),
+        )
+        .arg(
+            Arg::with_name("grpc")
+                .long("grpc")
+                .value_name("ADDR")
+                .help("Grpc listen address, e.g. 0.0.0.0:8425"),
+        )
+        .arg(
+            Arg::with_name("json-bridge")
+                .long("json-bridge")
+                .value_name("ADDR")
+                .help("Json bridge listen address, e.g. 0.0.0.0:8424"),
+        )
         .arg(
             Arg::with_name("uds")
                 .short("u")
                 .long("uds")
                 .value_name("FILE")
+                .env("PISUGAR_UDS")
                 .help("Unix domain socket file, e.g. /tmp/pisugar.sock"),
         )
         .arg(
@@ -475,6 +1568,7 @@ async fn main() -> std::io::Result<()> {
                 .requires_all(&["http"])
                 .long("web")
                 .value_name("DIR")
+                .env("PISUGAR_WEB_DIR")
                 .help("Web content directory, e.g. web"),
         )
         .arg(
@@ -484,40 +1578,246 @@ async fn main() -> std::io::Result<()> {
                 .default_value("0.0.0.0:8080")
                 .help("Http server listen address, e.g. 0.0.0.0:8080"),
         )
+        .arg(
+            Arg::with_name("port-fallback")
+                .long("port-fallback")
+                .help("Fall back to an ephemeral port if a tcp/ws/http port is still in use"),
+        )
+        .arg(
+            Arg::with_name("lock")
+                .long("lock")
+                .value_name("FILE")
+                .env("PISUGAR_LOCK")
+                .help("Instance lock file, default to /run/pisugar-server.lock"),
+        )
+        .arg(
+            Arg::with_name("replace")
+                .long("replace")
+                .help("Take over a running instance instead of refusing to start"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .value_name("USER")
+                .help("Drop privileges to user after binding, e.g. pisugar"),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .value_name("GROUP")
+                .help("Drop privileges to group after binding, default to primary group of user"),
+        )
+        .arg(
+            Arg::with_name("hwclock-compat")
+                .long("hwclock-compat")
+                .help("Act as the hwclock, no `hwclock -w` and /etc/adjtime updated on rtc writes"),
+        )
+        .subcommand(
+            SubCommand::with_name("wait-for")
+                .about("Wait for events on the uds of a running server, exit with the code of the event")
+                .arg(
+                    Arg::with_name("events")
+                        .required(true)
+                        .value_name("EVENT[=CODE],...")
+                        .help("Comma-separated events and exit codes, e.g. power_restored,low_battery=3"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECS")
+                        .help("Timeout seconds, exit code 1, at most a day"),
+                ),
+        )
         .get_matches();
 
+    // client of a running server
+    if let Some(wait_for) = matches.subcommand_matches("wait-for") {
+        let uds = matches.value_of("uds").unwrap_or(client::UDS_DEFAULT);
+        let events = wait_for.value_of("events").unwrap();
+        exit(client::wait_for(uds, events, wait_for.value_of("timeout")));
+    }
+
+    // single instance, before touching i2c
+    let lock_path = match matches.value_of("lock") {
+        Some(lock) => PathBuf::from(lock),
+        None => PathBuf::from(instance::LOCK_FILE),
+    };
+    let _instance_lock = match instance::lock_instance(&lock_path, matches.is_present("replace")) {
+        Ok(f) => f,
+        Err(e) => {
+            log::error!("Failed to lock {}: {}", lock_path.display(), e);
+            exit(1);
+        }
+    };
+
     // core
-    let core = if matches.is_present("config") {
-        PiSugarCore::new_with_path(matches.value_of("config").unwrap(), true).unwrap()
+    let core = if let Some(public_key) = CONFIG_PUBKEY {
+        match matches.value_of("config") {
+            Some(config) => PiSugarCore::new_with_signed_path(config, public_key),
+            None => {
+                log::error!("Failed to start: signed config required");
+                exit(1);
+            }
+        }
+    } else if matches.is_present("config") {
+        PiSugarCore::new_with_path(matches.value_of("config").unwrap(), true)
     } else {
         let config = PiSugarConfig::default();
-        PiSugarCore::new(config).unwrap()
+        PiSugarCore::new(config).map(|core| {
+            core.check_shutdown_command();
+            core
+        })
+    };
+    let mut core = match core {
+        Ok(core) => core,
+        Err(e) => {
+            log::error!("Failed to start: {}", e);
+            exit(1);
+        }
     };
+    if let Some(e) = core.hardware_error() {
+        log::error!("Hardware unavailable, retry in background: {}", e);
+    }
+    if let Some(state_dir) = matches.value_of("state") {
+        core.load_state(Path::new(state_dir));
+    }
+    if matches.is_present("hwclock-compat") {
+        core.set_hwclock_compat(true);
+    }
+    let gps_source = core.config().gps_source.clone();
+    let ntp_cooperate = core.config().ntp_cooperate && core.config().rtc_enabled;
+    let event_buffer = core.config().event_buffer.max(1);
+    let heartbeat_url = core.config().heartbeat_url.clone();
+    let heartbeat_interval = core.config().heartbeat_interval;
+    let syslog_server = core.config().syslog_server.clone();
+    let notifiers = core.config().notifiers.clone();
     let core = Arc::new(Mutex::new(core));
 
-    // event watch
-    let (event_tx, event_rx) = tokio::sync::watch::channel("".to_string());
+    // gps time source
+    if !gps_source.is_empty() {
+        tokio::spawn(gps::gps_time_source(core.clone(), gps_source));
+    }
+
+    // chrony/ntpd cooperation
+    if ntp_cooperate {
+        tokio::spawn(ntp::ntp_cooperate(core.clone()));
+    }
+
+    // events, receivers are subscribed per connection
+    let (event_tx, _) = broadcast::channel(event_buffer);
+    let event_tx = Arc::new(event_tx);
+
+    // remote syslog
+    if !syslog_server.is_empty() {
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(syslog::syslog_forward(
+            core.clone(),
+            syslog_server,
+            event_rx,
+        ));
+    }
+
+    // network changes
+    tokio::spawn(network::network_events(event_tx.clone()));
+
+    // heartbeat, dead-man monitoring
+    if !heartbeat_url.is_empty() {
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(heartbeat::heartbeat(
+            core.clone(),
+            heartbeat_url,
+            heartbeat_interval,
+            event_rx,
+        ));
+    }
+
+    // mqtt publisher
+    #[cfg(feature = "mqtt")]
+    if let Some(url) = matches.value_of("mqtt") {
+        let password_file = matches.value_of("mqtt-password-file").map(String::from);
+        let prefix = matches.value_of("mqtt-topic").unwrap().to_string();
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(mqtt::mqtt_publish(
+            core.clone(),
+            url.to_string(),
+            password_file,
+            prefix,
+            event_rx,
+        ));
+    }
+
+    #[cfg(not(feature = "mqtt"))]
+    if matches.is_present("mqtt") {
+        log::warn!("Mqtt disabled, rebuild with feature `mqtt`");
+    }
+
+    // dbus service
+    #[cfg(feature = "dbus")]
+    if matches.is_present("dbus") {
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(dbus::dbus_service(core.clone(), event_rx));
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    if matches.is_present("dbus") {
+        log::warn!("Dbus disabled, rebuild with feature `dbus`");
+    }
+
+    // idle shutdown policy
+    tokio::spawn(idle::idle_shutdown(core.clone()));
+
+    // shutdown countdown
+    tokio::spawn(shutdown_eta_events(core.clone(), event_tx.clone()));
+
+    // time jumps
+    tokio::spawn(time_jump_events(core.clone(), event_tx.clone()));
+
+    // notifications
+    if !notifiers.is_empty() {
+        let event_rx = event_tx.subscribe();
+        tokio::spawn(notify::notify_events(core.clone(), notifiers, event_rx));
+    }
+
+    // auth brute-force protection
+    let auth_guard = Arc::new(AuthGuard::new(event_tx.clone()));
 
     // CTRL+C signal handling
     let uds = matches.value_of("uds").and_then(|x| Some(x.to_string()));
-    let web_dir = matches.value_of("web").and_then(|x| Some(x.to_string()));
+    let core_cloned = core.clone();
     ctrlc::set_handler(move || {
-        clean_up(uds.clone(), web_dir.clone());
+        if let Ok(mut core) = core_cloned.lock() {
+            core.flush_state();
+            // queued config changes, not yet saved by `save_config_task`
+            if let Some((path, content)) =
+                core.take_config_save(Instant::now(), Duration::from_secs(0))
+            {
+                log::info!("Save config to {}", path);
+                if let Err(e) = std::fs::write(path, content) {
+                    log::error!("Failed to save config: {}", e);
+                }
+            }
+        }
+        clean_up(uds.clone());
     })
     .expect("Failed to setup ctrl+c");
 
+    let port_fallback = matches.is_present("port-fallback");
+
     // tcp
     if matches.is_present("tcp") {
         let tcp_addr = matches.value_of("tcp").unwrap();
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx.clone();
-        match TcpListener::bind(tcp_addr).await {
+        let event_tx_cloned = event_tx.clone();
+        let auth_guard_cloned = auth_guard.clone();
+        match listener::bind_with_retry(tcp_addr, port_fallback).await {
             Ok(mut tcp_listener) => {
+                log::info!("TCP listening on {}", tcp_listener.local_addr()?);
                 tokio::spawn(async move {
-                    log::info!("TCP listening...");
                     while let Some(Ok(stream)) = tcp_listener.incoming().next().await {
                         let core = core_cloned.clone();
-                        let _ = handle_tcp_stream(core, stream, event_rx_cloned.clone()).await;
+                        let event_tx = event_tx_cloned.clone();
+                        let guard = auth_guard_cloned.clone();
+                        let _ = handle_tcp_stream(core, stream, event_tx, guard).await;
                     }
                     log::info!("TCP stopped");
                 });
@@ -528,18 +1828,29 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    // ws
+    // ws, bound port is told to web ui and provisioning
+    #[cfg_attr(
+        not(all(feature = "ws", feature = "http")),
+        allow(unused_mut, unused_variables, unused_assignments)
+    )]
+    let mut ws_port: Option<u16> = None;
+    #[cfg(feature = "ws")]
     if matches.is_present("ws") {
         let ws_addr = matches.value_of("ws").unwrap();
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx.clone();
-        match tokio::net::TcpListener::bind(ws_addr).await {
+        let event_tx_cloned = event_tx.clone();
+        let auth_guard_cloned = auth_guard.clone();
+        match listener::bind_with_retry(ws_addr, port_fallback).await {
             Ok(mut ws_listener) => {
+                log::info!("WS listening on {}", ws_listener.local_addr()?);
+                ws_port = Some(ws_listener.local_addr()?.port());
+
                 tokio::spawn(async move {
-                    log::info!("WS listening...");
                     while let Some(Ok(stream)) = ws_listener.incoming().next().await {
                         let core = core_cloned.clone();
-                        let _ = handle_ws_connection(core, stream, event_rx_cloned.clone()).await;
+                        let event_tx = event_tx_cloned.clone();
+                        let guard = auth_guard_cloned.clone();
+                        let _ = handle_ws_connection(core, stream, event_tx, guard).await;
                     }
                     log::info!("WS stopped");
                 });
@@ -550,18 +1861,101 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    #[cfg(not(feature = "ws"))]
+    if matches.is_present("ws") {
+        log::warn!("Websocket disabled, rebuild with feature `ws`");
+    }
+
+    // modbus tcp
+    if matches.is_present("modbus") {
+        let modbus_addr = matches.value_of("modbus").unwrap();
+        let core_cloned = core.clone();
+        match listener::bind_with_retry(modbus_addr, port_fallback).await {
+            Ok(modbus_listener) => {
+                log::info!("Modbus listening on {}", modbus_listener.local_addr()?);
+                tokio::spawn(async move {
+                    modbus::serve_modbus(modbus_listener, core_cloned).await;
+                    log::info!("Modbus stopped");
+                });
+            }
+            Err(e) => {
+                log::warn!("Modbus bind error: {}", e);
+            }
+        }
+    }
+
+    // grpc
+    #[cfg(feature = "grpc")]
+    if matches.is_present("grpc") {
+        let grpc_addr = matches.value_of("grpc").unwrap();
+        let core_cloned = core.clone();
+        let event_tx_cloned = event_tx.clone();
+        let auth_guard_cloned = auth_guard.clone();
+        match listener::bind_with_retry(grpc_addr, port_fallback).await {
+            Ok(grpc_listener) => {
+                log::info!("Grpc listening on {}", grpc_listener.local_addr()?);
+                tokio::spawn(async move {
+                    grpc::serve_grpc(
+                        grpc_listener,
+                        core_cloned,
+                        event_tx_cloned,
+                        auth_guard_cloned,
+                    )
+                    .await;
+                    log::info!("Grpc stopped");
+                });
+            }
+            Err(e) => {
+                log::warn!("Grpc bind error: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    if matches.is_present("grpc") {
+        log::warn!("Grpc disabled, rebuild with feature `grpc`");
+    }
+
+    // json bridge
+    #[cfg(feature = "bridge")]
+    if matches.is_present("json-bridge") {
+        let bridge_addr = matches.value_of("json-bridge").unwrap();
+        let core_cloned = core.clone();
+        let event_tx_cloned = event_tx.clone();
+        match listener::bind_with_retry(bridge_addr, port_fallback).await {
+            Ok(bridge_listener) => {
+                log::info!("Json bridge listening on {}", bridge_listener.local_addr()?);
+                tokio::spawn(async move {
+                    bridge::serve_bridge(bridge_listener, core_cloned, event_tx_cloned).await;
+                    log::info!("Json bridge stopped");
+                });
+            }
+            Err(e) => {
+                log::warn!("Json bridge bind error: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "bridge"))]
+    if matches.is_present("json-bridge") {
+        log::warn!("Json bridge disabled, rebuild with feature `bridge`");
+    }
+
     // uds
     if matches.is_present("uds") {
         let uds_addr = matches.value_of("uds").unwrap();
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx;
+        let event_tx_cloned = event_tx.clone();
+        let auth_guard_cloned = auth_guard.clone();
         match tokio::net::UnixListener::bind(uds_addr) {
             Ok(mut uds_listener) => {
                 tokio::spawn(async move {
                     log::info!("UDS listening...");
                     while let Some(Ok(stream)) = uds_listener.incoming().next().await {
                         let core = core_cloned.clone();
-                        let _ = handle_uds_stream(core, stream, event_rx_cloned.clone()).await;
+                        let event_tx = event_tx_cloned.clone();
+                        let guard = auth_guard_cloned.clone();
+                        let _ = handle_uds_stream(core, stream, event_tx, guard).await;
                     }
                     log::info!("UDS stopped");
                 });
@@ -573,37 +1967,110 @@ async fn main() -> std::io::Result<()> {
     }
 
     // http web
+    #[cfg(feature = "http")]
     if matches.is_present("http") && matches.is_present("web") {
         let web_dir = matches.value_of("web").unwrap().to_string();
-        let http_addr = matches.value_of("http").unwrap().parse().unwrap();
-        let web_dir_cloned = web_dir.clone();
-        tokio::spawn(async move {
-            log::info!("Http web server listening...");
-            let _ = serve_http(http_addr, web_dir).await;
-            log::info!("Http web server stopped");
-        });
-        // Write a _ws.json file
-        if matches.is_present("ws") {
-            let ws_addr = matches.value_of("ws").unwrap();
-            let ws_sock_addr: SocketAddr = ws_addr.parse().unwrap();
-            let content = format!("{{\"wsPort\": \"{}\"}}", ws_sock_addr.port());
-            let filename = PathBuf::from(web_dir_cloned).join("_ws.json");
-            let mut file = OpenOptions::default()
-                .create(true)
-                .write(true)
-                .open(filename)
-                .await?;
-            file.set_len(0).await?;
-            file.write_all(content.as_bytes()).await?;
+        let http_addr = matches.value_of("http").unwrap();
+        let core_cloned = core.clone();
+        let event_tx_cloned = event_tx.clone();
+        let auth_guard_cloned = auth_guard.clone();
+        // events of long-polling clients
+        let event_log = Arc::new(eventlog::EventLog::new());
+        tokio::spawn(eventlog::record_events(
+            event_log.clone(),
+            event_tx.subscribe(),
+        ));
+        match listener::bind_with_retry(http_addr, port_fallback).await {
+            Ok(http_listener) => {
+                log::info!(
+                    "Http web server listening on {}",
+                    http_listener.local_addr()?
+                );
+                tokio::spawn(async move {
+                    http::serve_http(
+                        http_listener,
+                        web_dir,
+                        core_cloned,
+                        event_tx_cloned,
+                        auth_guard_cloned,
+                        event_log,
+                        ws_port,
+                    )
+                    .await;
+                    log::info!("Http web server stopped");
+                });
+            }
+            Err(e) => {
+                log::warn!("Http bind error: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "http"))]
+    if matches.is_present("web") {
+        log::warn!("Http web server disabled, rebuild with feature `http`");
+    }
+
+    // drop privileges, sockets are bound and i2c is opened
+    if matches.is_present("user") || matches.is_present("group") {
+        let owned: Vec<&Path> = matches
+            .value_of("uds")
+            .map(Path::new)
+            .filter(|p| p.exists())
+            .into_iter()
+            .collect();
+        let user = matches.value_of("user");
+        let group = matches.value_of("group");
+        if let Err(e) = privilege::drop_privileges(user, group, &owned) {
+            log::error!("Failed to drop privileges: {}", e);
+            exit(1);
         }
     }
 
+    // config saver
+    tokio::spawn(save_config_task(core.clone()));
+
+    // poller watchdog
+    let poll_watchdog = Arc::new(PollWatchdog::new());
+    watchdog::spawn_watchdog(poll_watchdog.clone(), event_tx.clone());
+    sd_notify("READY=1");
+
     // polling
     let core_cloned = core.clone();
     let mut interval = tokio::time::interval(I2C_READ_INTERVAL);
+    let mut retried_at = Instant::now();
+    let mut recover_failed = false;
     loop {
         interval.tick().await;
         let mut core = core_cloned.lock().expect("unexpected lock failed");
+        if core.hardware_error().is_some() {
+            if retried_at.elapsed() > HARDWARE_RETRY_INTERVAL {
+                retried_at = Instant::now();
+                // the first failure is an error, retries are logged at debug until recovered
+                match core.recover() {
+                    Ok(_) => {
+                        recover_failed = false;
+                        log::info!("Hardware recovered");
+                    }
+                    Err(e) if !recover_failed => {
+                        recover_failed = true;
+                        log::error!("Hardware unavailable, failed to recover: {}", e);
+                    }
+                    Err(e) => log::debug!("Hardware still unavailable: {}", e),
+                }
+            }
+            poll_watchdog.feed();
+            continue;
+        }
+        let poll_at = Instant::now();
         poll_pisugar_status(&mut core, &event_tx);
+        if poll_at.elapsed() > POLL_DEADLINE {
+            log::error!("Poll took {:?}, reopen i2c", poll_at.elapsed());
+            let _ = event_tx.send(Event::new(Bytes::from_static(POLLER_STALLED.as_bytes())));
+            if let Err(e) = core.reopen() {
+                log::error!("Reopen i2c failed: {}", e);
+            }
+        }
+        poll_watchdog.feed();
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use pisugar_core::{Listener, ListenerStats, PiSugarSnapshot};
+
+/// Content type of the Prometheus text exposition format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Escaped label value, backslash, double quote and line feed
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn bool_gauge(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Prometheus gauges of a status snapshot, labelled by model and hardware id (empty if unknown)
+/// to tell boards apart, unknown values are left out, and counters of listeners labelled by
+/// listener
+pub fn exposition(
+    snapshot: &PiSugarSnapshot,
+    listener_stats: &BTreeMap<Listener, ListenerStats>,
+) -> String {
+    let gauges = [
+        (
+            "pisugar_battery_present",
+            "Whether battery is present",
+            Some(bool_gauge(snapshot.battery_present)),
+        ),
+        (
+            "pisugar_battery_level_percent",
+            "Battery level",
+            Some(snapshot.battery),
+        ),
+        (
+            "pisugar_battery_voltage_volts",
+            "Battery voltage",
+            Some(snapshot.battery_v),
+        ),
+        (
+            "pisugar_battery_current_amperes",
+            "Battery current, positive when charging",
+            Some(snapshot.battery_i),
+        ),
+        (
+            "pisugar_battery_power_watts",
+            "Battery power",
+            Some(snapshot.battery_power_w),
+        ),
+        (
+            "pisugar_battery_charging",
+            "Whether battery is charging",
+            Some(bool_gauge(snapshot.battery_charging)),
+        ),
+        (
+            "pisugar_rtc_drift_seconds",
+            "Rtc time minus system time",
+            snapshot.rtc_drift,
+        ),
+        (
+            "pisugar_shutdown_eta_seconds",
+            "Seconds until safe shutdown",
+            snapshot.shutdown_eta.map(|eta| eta as f64),
+        ),
+    ];
+
+    let labels = format!(
+        "model=\"{}\",hardware_id=\"{}\"",
+        label_value(&snapshot.model),
+        label_value(snapshot.hardware_id.as_deref().unwrap_or_default())
+    );
+    let mut body = String::new();
+    for (name, help, value) in gauges.iter() {
+        if let Some(value) = value {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} gauge", name);
+            let _ = writeln!(body, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+
+    let counters: [(&str, &str, fn(&ListenerStats) -> u64); 3] = [
+        (
+            "pisugar_listener_connections_total",
+            "Connections of listener",
+            |s| s.connections,
+        ),
+        (
+            "pisugar_listener_requests_total",
+            "Requests of listener",
+            |s| s.requests,
+        ),
+        (
+            "pisugar_listener_rejected_total",
+            "Requests rejected by listener commands or role",
+            |s| s.rejected,
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        if listener_stats.is_empty() {
+            break;
+        }
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} counter", name);
+        for (listener, stats) in listener_stats {
+            let _ = writeln!(
+                body,
+                "{}{{{},listener=\"{}\"}} {}",
+                name,
+                labels,
+                listener.as_str(),
+                value(stats)
+            );
+        }
+    }
+    body
+}
@@ -0,0 +1,189 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use pisugar_core::{PiSugarCore, MODEL_V2_PRO};
+
+/// Function codes
+const FC_READ_COILS: u8 = 0x01;
+const FC_READ_INPUT_REGISTERS: u8 = 0x04;
+const FC_WRITE_SINGLE_COIL: u8 = 0x05;
+
+/// Exception codes
+const EX_ILLEGAL_FUNCTION: u8 = 0x01;
+const EX_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const EX_ILLEGAL_DATA_VALUE: u8 = 0x03;
+const EX_SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+/// Max pdu length of modbus
+const MAX_PDU_LEN: usize = 253;
+
+/// Input registers: level x10, voltage mV, current mA (signed), power mW (signed), charging,
+/// battery present, model (0 PiSugar 2, 1 PiSugar 2 Pro)
+const INPUT_REGISTERS: u16 = 7;
+
+/// Coils: single, double and long tap enable
+const COILS: u16 = 3;
+
+/// Input registers of core
+fn input_registers(core: &PiSugarCore) -> [u16; INPUT_REGISTERS as usize] {
+    [
+        (core.level() * 10.0).round() as u16,
+        (core.voltage() * 1000.0).round() as u16,
+        (core.intensity() * 1000.0).round() as i16 as u16,
+        (core.power() * 1000.0).round() as i16 as u16,
+        core.charging() as u16,
+        core.battery_present() as u16,
+        (core.model() == MODEL_V2_PRO) as u16,
+    ]
+}
+
+/// Coils of core
+fn coils(core: &PiSugarCore) -> [bool; COILS as usize] {
+    let config = core.config();
+    [
+        config.single_tap_enable,
+        config.double_tap_enable,
+        config.long_tap_enable,
+    ]
+}
+
+/// Exception response
+fn exception(fc: u8, code: u8) -> Vec<u8> {
+    vec![fc | 0x80, code]
+}
+
+/// Start and quantity of a read request, checked against size
+fn read_range(pdu: &[u8], size: u16) -> Result<(usize, usize), u8> {
+    if pdu.len() != 5 {
+        return Err(EX_ILLEGAL_DATA_VALUE);
+    }
+    let start = u16::from_be_bytes([pdu[1], pdu[2]]);
+    let quantity = u16::from_be_bytes([pdu[3], pdu[4]]);
+    if quantity == 0 || quantity > 125 {
+        return Err(EX_ILLEGAL_DATA_VALUE);
+    }
+    if start as u32 + quantity as u32 > size as u32 {
+        return Err(EX_ILLEGAL_DATA_ADDRESS);
+    }
+    Ok((start as usize, quantity as usize))
+}
+
+/// Handle request pdu, response pdu
+fn handle_pdu(core: &Mutex<PiSugarCore>, pdu: &[u8]) -> Vec<u8> {
+    let fc = match pdu.first() {
+        Some(fc) => *fc,
+        None => return exception(0, EX_ILLEGAL_FUNCTION),
+    };
+    let mut core = match core.lock() {
+        Ok(core) => core,
+        Err(_) => return exception(fc, EX_SERVER_DEVICE_FAILURE),
+    };
+
+    match fc {
+        FC_READ_INPUT_REGISTERS => match read_range(pdu, INPUT_REGISTERS) {
+            Ok((start, quantity)) => {
+                let registers = input_registers(&core);
+                let mut resp = vec![fc, (quantity * 2) as u8];
+                for r in &registers[start..start + quantity] {
+                    resp.extend_from_slice(&r.to_be_bytes());
+                }
+                resp
+            }
+            Err(code) => exception(fc, code),
+        },
+        FC_READ_COILS => match read_range(pdu, COILS) {
+            Ok((start, quantity)) => {
+                let coils = coils(&core);
+                let mut bits = vec![0u8; (quantity + 7) / 8];
+                for (i, c) in coils[start..start + quantity].iter().enumerate() {
+                    if *c {
+                        bits[i / 8] |= 1 << (i % 8);
+                    }
+                }
+                let mut resp = vec![fc, bits.len() as u8];
+                resp.extend(bits);
+                resp
+            }
+            Err(code) => exception(fc, code),
+        },
+        FC_WRITE_SINGLE_COIL => {
+            if pdu.len() != 5 {
+                return exception(fc, EX_ILLEGAL_DATA_VALUE);
+            }
+            // no authentication in modbus, writable only without tokens
+            let config = core.config();
+            if config.readonly || !config.auth_tokens.is_empty() || core.config_signed() {
+                log::warn!("Modbus write rejected");
+                return exception(fc, EX_ILLEGAL_FUNCTION);
+            }
+            let addr = u16::from_be_bytes([pdu[1], pdu[2]]);
+            let enable = match u16::from_be_bytes([pdu[3], pdu[4]]) {
+                0xff00 => true,
+                0x0000 => false,
+                _ => return exception(fc, EX_ILLEGAL_DATA_VALUE),
+            };
+            match addr {
+                0 => core.config_mut().single_tap_enable = enable,
+                1 => core.config_mut().double_tap_enable = enable,
+                2 => core.config_mut().long_tap_enable = enable,
+                _ => return exception(fc, EX_ILLEGAL_DATA_ADDRESS),
+            }
+            core.save_config_later();
+            pdu.to_vec()
+        }
+        _ => exception(fc, EX_ILLEGAL_FUNCTION),
+    }
+}
+
+/// Handle a modbus tcp connection, mbap header and pdu
+async fn handle_modbus_stream(
+    core: Arc<Mutex<PiSugarCore>>,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    let mut header = [0u8; 7];
+    loop {
+        if let Err(e) = stream.read_exact(&mut header).await {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(());
+            }
+            return Err(e);
+        }
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if protocol != 0 || length < 2 || length - 1 > MAX_PDU_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid mbap header",
+            ));
+        }
+        let mut pdu = vec![0u8; length - 1];
+        stream.read_exact(&mut pdu).await?;
+
+        let resp = handle_pdu(&core, &pdu);
+        let mut frame = Vec::with_capacity(7 + resp.len());
+        frame.extend_from_slice(&header[0..4]);
+        frame.extend_from_slice(&((resp.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(resp);
+        stream.write_all(&frame).await?;
+    }
+}
+
+/// Serve modbus tcp slave
+pub async fn serve_modbus(mut listener: TcpListener, core: Arc<Mutex<PiSugarCore>>) {
+    while let Some(Ok(stream)) = listener.incoming().next().await {
+        if let Ok(peer) = stream.peer_addr() {
+            log::info!("Incoming modbus connection from: {}", peer);
+        }
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_modbus_stream(core, stream).await {
+                log::debug!("Modbus connection error: {}", e);
+            }
+        });
+    }
+}
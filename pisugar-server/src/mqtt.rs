@@ -0,0 +1,335 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Local;
+use futures::prelude::*;
+use futures::stream;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use pisugar_core::{render_payload, PiSugarCore};
+
+use crate::network::{NetworkInfo, NETWORK_CHANGED};
+use crate::{event_name, event_stream_ids, snapshot, Event, EventRx};
+
+/// Default port of mqtt
+const MQTT_PORT: u16 = 1883;
+
+/// Interval of battery status
+const MQTT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keep alive (s), status is published more often, no pings needed
+const MQTT_KEEP_ALIVE: u16 = 60;
+
+/// Timeout of connect and connack
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay of reconnecting
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Read buffer of broker packets, drained and dropped
+const MQTT_READ_BUFFER: usize = 256;
+
+/// Packet types
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+
+/// Broker of `mqtt://[user@]host[:port]`, password of a credentials file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttBroker {
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl MqttBroker {
+    /// Parse broker url, port 1883 if absent. Passwords are not accepted in the url, they are
+    /// visible in the process list
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("mqtt://")?.trim_end_matches('/');
+        let (userinfo, host) = match rest.rfind('@') {
+            Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+            None => (None, rest),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let addr = if host.ends_with(']') || !host.contains(':') {
+            format!("{}:{}", host, MQTT_PORT)
+        } else {
+            host.to_string()
+        };
+        if userinfo.map(|u| u.contains(':')) == Some(true) {
+            return None;
+        }
+        Some(Self {
+            addr,
+            username: userinfo.map(String::from),
+            password: None,
+        })
+    }
+
+    /// Password of the first line of a credentials file, e.g. of systemd `LoadCredential`
+    pub fn load_password(&mut self, path: &Path) -> io::Result<()> {
+        let password = std::fs::read_to_string(path)?;
+        self.password = password.lines().next().map(String::from);
+        Ok(())
+    }
+}
+
+/// Append remaining length
+fn put_len(buf: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            b |= 0x80;
+        }
+        buf.push(b);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/// Append length-prefixed string
+fn put_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+/// Packet of fixed header and body
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![header];
+    put_len(&mut buf, body.len());
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Connect of mqtt 3.1.1, clean session, retained `offline` as will of the status topic
+fn connect_packet(broker: &MqttBroker, client_id: &str, status_topic: &str) -> Vec<u8> {
+    let mut flags = 0b0010_0110; // will retain, will flag, clean session
+    if broker.username.is_some() {
+        flags |= 0b1000_0000;
+    }
+    if broker.password.is_some() {
+        flags |= 0b0100_0000;
+    }
+    let mut body = Vec::new();
+    put_str(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&MQTT_KEEP_ALIVE.to_be_bytes());
+    put_str(&mut body, client_id.as_bytes());
+    put_str(&mut body, status_topic.as_bytes());
+    put_str(&mut body, b"offline");
+    if let Some(username) = &broker.username {
+        put_str(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &broker.password {
+        put_str(&mut body, password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+/// Publish at QoS 0
+fn publish_packet(topic: &str, payload: &str, retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload.as_bytes());
+    packet(PUBLISH | retain as u8, &body)
+}
+
+/// Connect to broker, `online` is published to the status topic once accepted
+async fn connect(
+    broker: &MqttBroker,
+    client_id: &str,
+    status_topic: &str,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(broker.addr.as_str()).await?;
+    stream
+        .write_all(&connect_packet(broker, client_id, status_topic))
+        .await?;
+    let mut connack = [0_u8; 4];
+    stream.read_exact(&mut connack).await?;
+    if connack[0] != CONNACK || connack[3] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Connection refused, return code {}", connack[3]),
+        ));
+    }
+    stream
+        .write_all(&publish_packet(status_topic, "online", true))
+        .await?;
+    Ok(stream)
+}
+
+/// Payload of kind (`state`, `event`), of `mqtt_templates` in config, snapshot fields and
+/// `timestamp` as context, none if the core is unavailable
+fn payload(
+    core: &Mutex<PiSugarCore>,
+    kind: &str,
+    event: Option<(&str, &str, &str)>,
+) -> Option<String> {
+    let mut ctx = serde_json::to_value(snapshot(core)?).ok()?;
+    ctx["timestamp"] = json!(Local::now().to_rfc3339());
+    if let Some((id, name, data)) = event {
+        ctx["id"] = json!(id);
+        ctx["event"] = json!(name);
+        if !data.is_empty() {
+            ctx["data"] = json!(data);
+        }
+    }
+    let templates = core.lock().ok()?.config().mqtt_templates.clone();
+    Some(render_payload(&templates, kind, &ctx))
+}
+
+/// Retained battery status of `<prefix>state`
+fn state_message(core: &Mutex<PiSugarCore>, prefix: &str) -> Vec<u8> {
+    match payload(core, "state", None) {
+        Some(payload) => publish_packet(&format!("{}state", prefix), &payload, true),
+        None => Vec::new(),
+    }
+}
+
+/// Event message of `<prefix>event`, taps included, arguments as `data`, the event id as `id`
+fn event_message(core: &Mutex<PiSugarCore>, prefix: &str, e: &Event) -> Vec<u8> {
+    let data = String::from_utf8_lossy(&e.data);
+    let name = event_name(&data);
+    let data = data[name.len()..].trim_start();
+    match payload(core, "event", Some((&e.id, name, data))) {
+        Some(payload) => publish_packet(&format!("{}event", prefix), &payload, false),
+        None => Vec::new(),
+    }
+}
+
+/// Retained host name and addresses of `<prefix>network` if `announce_network`, clients tracking
+/// the pi find its new address
+fn network_message(core: &Mutex<PiSugarCore>, prefix: &str) -> Vec<u8> {
+    let announce = core
+        .lock()
+        .map(|core| core.config().announce_network)
+        .unwrap_or(false);
+    if !announce {
+        return Vec::new();
+    }
+    let network = NetworkInfo::read().to_json().to_string();
+    publish_packet(&format!("{}network", prefix), &network, true)
+}
+
+/// Packets of a trigger, the snapshot (vcgencmd) is read on a blocking thread
+async fn trigger_packets(
+    core: &Arc<Mutex<PiSugarCore>>,
+    prefix: &str,
+    trigger: Option<Event>,
+) -> Vec<u8> {
+    let core = core.clone();
+    let prefix = prefix.to_string();
+    let packets = tokio::task::spawn_blocking(move || match trigger {
+        None => state_message(&core, &prefix),
+        Some(e) => {
+            let mut packets = event_message(&core, &prefix, &e);
+            if event_name(&String::from_utf8_lossy(&e.data)) == NETWORK_CHANGED {
+                packets.extend(network_message(&core, &prefix));
+            }
+            packets
+        }
+    });
+    packets.await.unwrap_or_default()
+}
+
+/// Publish battery status every interval and events to a mqtt broker, reconnected on errors
+pub async fn mqtt_publish(
+    core: Arc<Mutex<PiSugarCore>>,
+    url: String,
+    password_file: Option<String>,
+    prefix: String,
+    event_rx: EventRx,
+) {
+    let mut broker = match MqttBroker::parse(&url) {
+        Some(broker) => broker,
+        None => {
+            log::error!("Invalid mqtt url, password in url not accepted: {}", url);
+            return;
+        }
+    };
+    if let Some(path) = password_file {
+        if let Err(e) = broker.load_password(Path::new(&path)) {
+            log::error!("Mqtt password file {}: {}", path, e);
+            return;
+        }
+    }
+    let prefix = if prefix.is_empty() || prefix.ends_with('/') {
+        prefix
+    } else {
+        format!("{}/", prefix)
+    };
+    let hostname = pisugar_core::hostname().unwrap_or_default();
+    let client_id = format!("pisugar-{}", hostname.trim());
+    let status_topic = format!("{}status", prefix);
+    log::info!("Mqtt publishing to {} as {}", broker.addr, prefix);
+
+    let ticks = tokio::time::interval(MQTT_INTERVAL).map(|_| None);
+    let events = event_stream_ids(event_rx).map(Some);
+    let mut triggers = stream::select(ticks, events).boxed();
+    let mut failed = false;
+    loop {
+        let connected = tokio::time::timeout(
+            MQTT_CONNECT_TIMEOUT,
+            connect(&broker, &client_id, &status_topic),
+        )
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out")));
+        let mut stream = match connected {
+            Ok(mut stream) => {
+                log::info!("Mqtt connected to {}", broker.addr);
+                failed = false;
+                let network = network_message(&core, &prefix);
+                if let Err(e) = stream.write_all(&network).await {
+                    log::warn!("Mqtt publish failed, reconnect: {}", e);
+                    tokio::time::delay_for(MQTT_RECONNECT_DELAY).await;
+                    continue;
+                }
+                stream
+            }
+            Err(e) => {
+                if !failed {
+                    log::warn!("Mqtt connect to {} failed: {}", broker.addr, e);
+                }
+                failed = true;
+                tokio::time::delay_for(MQTT_RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+
+        // broker packets are drained, eof is a dropped connection
+        let (mut reader, mut writer) = stream.split();
+        let mut buf = [0_u8; MQTT_READ_BUFFER];
+        loop {
+            let trigger = tokio::select! {
+                read = reader.read(&mut buf) => {
+                    match read {
+                        Ok(0) => log::warn!("Mqtt connection closed by broker, reconnect"),
+                        Ok(_) => continue,
+                        Err(e) => log::warn!("Mqtt read failed, reconnect: {}", e),
+                    }
+                    break;
+                }
+                trigger = triggers.next() => trigger,
+            };
+            let packets = match trigger {
+                Some(trigger) => trigger_packets(&core, &prefix, trigger).await,
+                None => return,
+            };
+            if let Err(e) = writer.write_all(&packets).await {
+                log::warn!("Mqtt publish failed, reconnect: {}", e);
+                break;
+            }
+        }
+        tokio::time::delay_for(MQTT_RECONNECT_DELAY).await;
+    }
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+
+use crate::{Event, EventTx};
+
+/// Network change event, `network_changed <hostname> <addr,..|none>`
+pub const NETWORK_CHANGED: &str = "network_changed";
+
+/// Interval of address checks, DHCP leases change rarely
+const NETWORK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Host name and addresses of the pi
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkInfo {
+    pub hostname: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl NetworkInfo {
+    /// Read host name and addresses, empty on errors
+    pub fn read() -> Self {
+        let hostname = pisugar_core::hostname().unwrap_or_default();
+        let addrs = pisugar_core::ip_addrs().unwrap_or_else(|e| {
+            log::debug!("Read addresses failed: {}", e);
+            Vec::new()
+        });
+        Self { hostname, addrs }
+    }
+
+    /// Event payload
+    pub fn event(&self) -> String {
+        let addrs: Vec<String> = self.addrs.iter().map(|a| a.to_string()).collect();
+        let addrs = if addrs.is_empty() {
+            "none".to_string()
+        } else {
+            addrs.join(",")
+        };
+        format!("{} {} {}", NETWORK_CHANGED, self.hostname, addrs)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "hostname": self.hostname, "addrs": self.addrs })
+    }
+}
+
+/// `network_changed` event on changes of the host name or addresses, e.g. a new DHCP lease
+pub async fn network_events(event_tx: Arc<EventTx>) {
+    let mut last = NetworkInfo::read();
+    log::info!("Network of {}: {:?}", last.hostname, last.addrs);
+    let mut interval = tokio::time::interval(NETWORK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let info = NetworkInfo::read();
+        if info != last {
+            let event = info.event();
+            log::info!("Network changed: {}", event);
+            let _ = event_tx.send(Event::new(Bytes::from(event)));
+            last = info;
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::prelude::*;
+
+use pisugar_core::{Notifier, NotifyRequest, PiSugarCore};
+
+use crate::{curl, event_name, event_stream_ids, snapshot, EventRx, SHUTDOWN_ETA, TIME_JUMP};
+
+/// Attempts of a notification
+const NOTIFY_ATTEMPTS: u32 = 3;
+
+/// Delay before a retry, doubled after each
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Post a notification, retried with the same event id on failure
+async fn post_retried(n: Notifier, req: NotifyRequest) {
+    let mut delay = NOTIFY_RETRY_DELAY;
+    for attempt in 1..=NOTIFY_ATTEMPTS {
+        match curl::post(&req.url, &req.headers, &req.body).await {
+            Ok(_) => return,
+            Err(e) => log::warn!(
+                "Notify {:?} failed ({}/{}): {}",
+                n.backend,
+                attempt,
+                NOTIFY_ATTEMPTS,
+                e
+            ),
+        }
+        if attempt < NOTIFY_ATTEMPTS {
+            tokio::time::delay_for(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+/// Send notifications of events to ntfy, gotify and pushover
+pub async fn notify_events(
+    core: Arc<Mutex<PiSugarCore>>,
+    notifiers: Vec<Notifier>,
+    event_rx: EventRx,
+) {
+    let mut events = event_stream_ids(event_rx).boxed();
+    while let Some(e) = events.next().await {
+        let event = String::from_utf8_lossy(&e.data).to_string();
+        let name = event_name(&event);
+        // periodic and routine events only if listed
+        let listed_only = name == SHUTDOWN_ETA || name == TIME_JUMP;
+        let targets: Vec<&Notifier> = notifiers
+            .iter()
+            .filter(|n| n.accepts(name))
+            .filter(|n| !listed_only || !n.events.is_empty())
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
+
+        let title = format!("PiSugar: {}", event);
+        let message = match snapshot(&core) {
+            Some(s) => format!(
+                "{}, battery {:.0}% {:.2}V{}",
+                event,
+                s.battery,
+                s.battery_v,
+                if s.battery_charging { " charging" } else { "" }
+            ),
+            None => event.clone(),
+        };
+        for n in targets {
+            let req = n.request(&e.id, &title, &message);
+            tokio::spawn(post_retried(n.clone(), req));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use tokio::process::Command;
+
+use pisugar_core::{sys_write_time, PiSugarCore};
+
+/// Check interval of ntp status
+const NTP_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Max offset between rtc and system clock (s)
+const NTP_MAX_OFFSET: i64 = 1;
+
+/// Min offset of stepping the system clock to the rtc (s), smaller offsets are left to the time
+/// daemon once it synchronizes
+const NTP_STEP_OFFSET: i64 = 60;
+
+/// Query chrony or ntpd synchronized status, None if neither is available
+pub async fn ntp_synchronized() -> Option<bool> {
+    // chrony, "Leap status     : Normal"
+    if let Ok(output) = Command::new("chronyc").arg("tracking").output().await {
+        if output.status.success() {
+            let s = String::from_utf8_lossy(&output.stdout);
+            for line in s.lines() {
+                if line.starts_with("Leap status") {
+                    return Some(!line.contains("Not synchronised"));
+                }
+            }
+        }
+    }
+
+    // ntpd, exit code 0 synchronized, 1 not synchronized
+    if let Ok(output) = Command::new("ntpstat").output().await {
+        match output.status.code() {
+            Some(0) => return Some(true),
+            Some(1) => return Some(false),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Whether systemd-timesyncd is active, it sets the system clock once the network is up
+async fn timesyncd_active() -> bool {
+    Command::new("systemctl")
+        .args(&["is-active", "--quiet", "systemd-timesyncd"])
+        .status()
+        .await
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Cooperate with chrony/ntpd, pi => rtc when synchronized. When unsynchronized, rtc => pi only if
+/// the offset exceeds `NTP_STEP_OFFSET` and systemd-timesyncd is not active
+pub async fn ntp_cooperate(core: Arc<Mutex<PiSugarCore>>) {
+    let mut interval = tokio::time::interval(NTP_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let synchronized = ntp_synchronized().await;
+        let timesyncd = synchronized != Some(true) && timesyncd_active().await;
+        log::debug!(
+            "NTP synchronized: {:?}, timesyncd: {}",
+            synchronized,
+            timesyncd
+        );
+
+        // date and hwclock run without the core lock
+        let step = if let Ok(core) = core.lock() {
+            if !core.rtc_enabled() {
+                continue;
+            }
+            let rtc_time = core.read_time();
+            let offset = (rtc_time - Local::now()).num_seconds();
+            if offset.abs() <= NTP_MAX_OFFSET {
+                continue;
+            }
+            match synchronized {
+                Some(true) => {
+                    log::info!("NTP synchronized, rtc offset {}s, sync pi => rtc", offset);
+                    if let Err(e) = core.write_time(Local::now()) {
+                        log::error!("{}", e);
+                    }
+                    None
+                }
+                _ if offset.abs() < NTP_STEP_OFFSET => {
+                    log::debug!("NTP unsynchronized, rtc offset {}s, not stepped", offset);
+                    None
+                }
+                _ if timesyncd => {
+                    log::debug!(
+                        "NTP unsynchronized, rtc offset {}s, left to timesyncd",
+                        offset
+                    );
+                    None
+                }
+                _ => {
+                    log::info!("NTP unsynchronized, rtc offset {}s, sync rtc => pi", offset);
+                    Some((rtc_time, core.sys_write_hwclock()))
+                }
+            }
+        } else {
+            None
+        };
+        if let Some((rtc_time, hwclock)) = step {
+            sys_write_time(rtc_time, hwclock);
+        }
+    }
+}
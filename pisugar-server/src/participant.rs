@@ -0,0 +1,23 @@
+use std::sync::{Arc, Mutex};
+
+use pisugar_core::PiSugarCore;
+
+/// Shutdown participant of a connection, unregistered when the connection ends
+pub struct ShutdownParticipant {
+    core: Arc<Mutex<PiSugarCore>>,
+    pub id: u64,
+}
+
+impl ShutdownParticipant {
+    pub fn new(core: Arc<Mutex<PiSugarCore>>, id: u64) -> Self {
+        Self { core, id }
+    }
+}
+
+impl Drop for ShutdownParticipant {
+    fn drop(&mut self) {
+        if let Ok(mut core) = self.core.lock() {
+            core.remove_shutdown_participant(self.id);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+
+/// Uid and primary gid of a user
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let pw = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if pw.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("User not found: {}", name),
+        ));
+    }
+    unsafe { Ok(((*pw).pw_uid, (*pw).pw_gid)) }
+}
+
+/// Gid of a group
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let gr = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if gr.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Group not found: {}", name),
+        ));
+    }
+    unsafe { Ok((*gr).gr_gid) }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drop root privileges to user/group, supplementary groups of user (e.g. i2c) are kept.
+/// Files in `owned` (e.g. uds) are chowned first, so that they can be cleaned up later.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, owned: &[&Path]) -> io::Result<()> {
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user_gid,
+    };
+
+    for path in owned {
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ret = unsafe {
+            libc::chown(
+                c_path.as_ptr(),
+                uid.unwrap_or(libc::uid_t::MAX),
+                gid.unwrap_or(libc::gid_t::MAX),
+            )
+        };
+        check(ret)?;
+    }
+
+    // gid first, no permission after setuid
+    if let Some(gid) = gid {
+        match (user, user_gid) {
+            (Some(user), Some(user_gid)) => {
+                let c_user = CString::new(user)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                check(unsafe { libc::initgroups(c_user.as_ptr(), user_gid as _) })?;
+            }
+            _ => check(unsafe { libc::setgroups(0, std::ptr::null()) })?,
+        }
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some(uid) = uid {
+        check(unsafe { libc::setuid(uid) })?;
+    }
+
+    log::info!("Dropped privileges, uid {:?} gid {:?}", uid, gid);
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use tokio::net::UdpSocket;
+
+use pisugar_core::PiSugarCore;
+
+use crate::{event_name, event_stream, snapshot, EventRx};
+
+/// Default syslog port
+const SYSLOG_PORT: u16 = 514;
+
+/// Facility daemon
+const FACILITY_DAEMON: u8 = 3;
+
+/// Severities
+const SEVERITY_ERR: u8 = 3;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+
+/// Severity of event
+fn severity(event: &str) -> u8 {
+    match event {
+        "poller_stalled" => SEVERITY_ERR,
+        "auth_failed" | "low_battery" | "power_loss" | "shutdown_eta" | "time_jump" => {
+            SEVERITY_WARNING
+        }
+        _ => SEVERITY_NOTICE,
+    }
+}
+
+/// Host name, nil value of RFC5424 if unknown
+fn hostname() -> String {
+    pisugar_core::hostname().unwrap_or_else(|_| "-".to_string())
+}
+
+/// RFC5424 message, msg id is the event name
+fn format_rfc5424(severity: u8, hostname: &str, msg_id: &str, msg: &str) -> String {
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        FACILITY_DAEMON * 8 + severity,
+        Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        hostname,
+        env!("CARGO_PKG_NAME"),
+        std::process::id(),
+        msg_id,
+        msg
+    )
+}
+
+/// Battery summary of core
+fn battery_summary(core: &Mutex<PiSugarCore>) -> String {
+    match snapshot(core) {
+        Some(s) => format!(
+            "level={:.1} voltage={:.3} charging={}",
+            s.battery, s.battery_v, s.battery_charging
+        ),
+        None => String::new(),
+    }
+}
+
+/// Syslog sender over udp, `host[:port]` resolved once at start
+struct Syslog {
+    server: String,
+    addr: SocketAddr,
+    hostname: String,
+    socket: UdpSocket,
+}
+
+impl Syslog {
+    async fn send(&mut self, severity: u8, msg_id: &str, msg: &str) {
+        let line = format_rfc5424(severity, &self.hostname, msg_id, msg);
+        if let Err(e) = self.socket.send_to(line.as_bytes(), &self.addr).await {
+            log::debug!("Syslog to {} failed: {}", self.server, e);
+        }
+    }
+}
+
+/// Forward power events to a remote syslog server in RFC5424, wake reason and last shutdown are
+/// sent on start
+pub async fn syslog_forward(core: Arc<Mutex<PiSugarCore>>, server: String, event_rx: EventRx) {
+    let server = if server.contains(':') {
+        server
+    } else {
+        format!("{}:{}", server, SYSLOG_PORT)
+    };
+    let addr = match tokio::net::lookup_host(server.as_str()).await {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            None => {
+                log::error!("Syslog server {}: no address", server);
+                return;
+            }
+        },
+        Err(e) => {
+            log::error!("Syslog server {}: {}", server, e);
+            return;
+        }
+    };
+    let bind = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("Syslog socket error: {}", e);
+            return;
+        }
+    };
+    log::info!("Syslog forwarding to {} ({})", server, addr);
+    let mut syslog = Syslog {
+        server,
+        addr,
+        hostname: hostname(),
+        socket,
+    };
+
+    // wake reason and last shutdown
+    let (wake, last_shutdown) = match core.lock() {
+        Ok(core) => {
+            let wake = match core.read_alarm_flag() {
+                Ok(true) => "rtc_alarm",
+                _ => "power_on",
+            };
+            (wake, core.shutdown_history().records().back().cloned())
+        }
+        Err(_) => return,
+    };
+    let mut msg = format!("wake={} {}", wake, battery_summary(&core));
+    if let Some(r) = last_shutdown {
+        let cause = serde_json::to_value(r.cause).unwrap_or_default();
+        msg.push_str(&format!(
+            " last_shutdown={} last_shutdown_at={} last_shutdown_level={:.1}",
+            cause.as_str().unwrap_or("-"),
+            r.time.to_rfc3339(),
+            r.level
+        ));
+    }
+    syslog.send(SEVERITY_NOTICE, "start", &msg).await;
+
+    let mut events = event_stream(event_rx).boxed();
+    while let Some(e) = events.next().await {
+        let event = String::from_utf8_lossy(&e);
+        let name = event_name(&event);
+        let mut msg = battery_summary(&core);
+        if name != event {
+            msg = format!("{}, {}", event, msg);
+        }
+        syslog.send(severity(name), name, &msg).await;
+    }
+}
@@ -0,0 +1,48 @@
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+/// Compiled-in features
+const FEATURES: [(&str, bool); 9] = [
+    ("http", cfg!(feature = "http")),
+    ("metrics", cfg!(feature = "http")),
+    ("ws", cfg!(feature = "ws")),
+    ("bridge", cfg!(feature = "bridge")),
+    ("msgpack", cfg!(feature = "msgpack")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("dbus", cfg!(feature = "dbus")),
+];
+
+/// Build info of `get version`
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+    pub build_date: Option<String>,
+    pub features: Vec<&'static str>,
+}
+
+/// Build info of this binary, commit is none if not built from a git checkout
+pub fn build_info() -> BuildInfo {
+    let commit = env!("PISUGAR_GIT_COMMIT");
+    let build_date = env!("PISUGAR_BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|t| Utc.timestamp_opt(t, 0).single())
+        .map(|t| t.to_rfc3339());
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: if commit.is_empty() {
+            None
+        } else {
+            Some(commit)
+        },
+        build_date,
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect(),
+    }
+}
@@ -0,0 +1,100 @@
+use pisugar_core::PiSugarCore;
+
+/// Watch event, `watch <field> <op> <threshold> <value>`
+pub const WATCH: &str = "watch";
+
+/// Max watches of a connection
+pub const MAX_WATCHES: usize = 16;
+
+/// Watched battery value
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchField {
+    Level,
+    Voltage,
+    Intensity,
+}
+
+impl WatchField {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "level" => Some(WatchField::Level),
+            "voltage" => Some(WatchField::Voltage),
+            "intensity" => Some(WatchField::Intensity),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            WatchField::Level => "level",
+            WatchField::Voltage => "voltage",
+            WatchField::Intensity => "intensity",
+        }
+    }
+
+    fn value(&self, core: &PiSugarCore) -> f64 {
+        match self {
+            WatchField::Level => core.level(),
+            WatchField::Voltage => core.voltage(),
+            WatchField::Intensity => core.intensity(),
+        }
+    }
+}
+
+/// One-shot threshold watch of a connection, `<field> <|> <threshold>`, notified when crossed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub field: WatchField,
+    /// Below if true, otherwise above
+    pub below: bool,
+    pub threshold: f64,
+    /// Seen on the other side, a watch already past its threshold waits for the next crossing
+    armed: bool,
+}
+
+impl Watch {
+    /// Parse watch arguments, e.g. `level < 40`
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        match args {
+            [field, op, threshold] => Some(Self {
+                field: WatchField::parse(field)?,
+                below: match *op {
+                    "<" => true,
+                    ">" => false,
+                    _ => return None,
+                },
+                threshold: threshold.parse().ok().filter(|t: &f64| t.is_finite())?,
+                armed: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Event of a crossing, `None` until crossed
+    pub fn check(&mut self, core: &PiSugarCore) -> Option<String> {
+        if !core.battery_present() {
+            return None;
+        }
+        let value = self.field.value(core);
+        let passed = if self.below {
+            value < self.threshold
+        } else {
+            value > self.threshold
+        };
+        if !passed {
+            self.armed = true;
+            return None;
+        }
+        if !self.armed {
+            return None;
+        }
+        Some(format!(
+            "{} {} {} {} {}",
+            WATCH,
+            self.field.as_str(),
+            if self.below { "<" } else { ">" },
+            self.threshold,
+            value
+        ))
+    }
+}
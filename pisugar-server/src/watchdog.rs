@@ -0,0 +1,94 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::{Event, EventTx};
+
+/// Max duration of a single poll, or between two polls
+pub const POLL_DEADLINE: Duration = Duration::from_secs(2);
+
+/// Poller stalled event
+pub const POLLER_STALLED: &str = "poller_stalled";
+
+/// Notify systemd, e.g. READY=1 or WATCHDOG=1
+pub fn sd_notify(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Ok(socket) = UnixDatagram::unbound() {
+            if let Err(e) = socket.send_to(state.as_bytes(), path) {
+                log::debug!("sd_notify: {}", e);
+            }
+        }
+    }
+}
+
+/// Systemd watchdog ping interval, half of WATCHDOG_USEC
+fn sd_watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Poller watchdog, fed by the polling loop
+pub struct PollWatchdog {
+    started_at: Instant,
+    fed_at: AtomicU64,
+    notified_at: AtomicU64,
+    stalled: AtomicBool,
+    sd_interval: Option<Duration>,
+}
+
+impl PollWatchdog {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            fed_at: AtomicU64::new(0),
+            notified_at: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            sd_interval: sd_watchdog_interval(),
+        }
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+
+    /// Poller made progress, ping systemd watchdog
+    pub fn feed(&self) {
+        let now = self.elapsed_ms();
+        self.fed_at.store(now, Ordering::SeqCst);
+        if self.stalled.swap(false, Ordering::SeqCst) {
+            log::info!("Poller resumed");
+        }
+        if let Some(interval) = self.sd_interval {
+            let notified_at = self.notified_at.load(Ordering::SeqCst);
+            if now >= notified_at + interval.as_millis() as u64 {
+                sd_notify("WATCHDOG=1");
+                self.notified_at.store(now, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Check poller progress, true when it newly stalled
+    fn check(&self) -> bool {
+        let fed_at = self.fed_at.load(Ordering::SeqCst);
+        if self.elapsed_ms() > fed_at + POLL_DEADLINE.as_millis() as u64 {
+            return !self.stalled.swap(true, Ordering::SeqCst);
+        }
+        false
+    }
+}
+
+/// Watch poller progress in a thread, the polling loop may be blocked by i2c
+pub fn spawn_watchdog(watchdog: Arc<PollWatchdog>, event_tx: Arc<EventTx>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if watchdog.check() {
+            log::error!("Poller stalled");
+            let _ = event_tx.send(Event::new(Bytes::from_static(POLLER_STALLED.as_bytes())));
+        }
+    });
+}